@@ -0,0 +1,72 @@
+/*
+ * C interface of herro, available when the library is built with the ffi
+ * feature:
+ *
+ *   cargo rustc --release --lib --features ffi --crate-type cdylib
+ *
+ * Errors in the worker threads abort the process, same as in the command line
+ * tool.
+ */
+
+#ifndef HERRO_H
+#define HERRO_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Input read, qual is NULL for reads without qualities (all or none of the
+ * reads have qualities). */
+typedef struct {
+    const char *id;
+    const uint8_t *seq;
+    const uint8_t *qual;
+    size_t length;
+} herro_read;
+
+/* Correction parameters, zero values select the defaults. */
+typedef struct {
+    /* Number of feature generation threads */
+    size_t threads;
+    /* Cuda device id, or -1 for the CPU */
+    int device;
+    /* Number of windows per inference batch */
+    size_t batch_size;
+    /* Window size, taken from the model spec if 0 */
+    uint32_t window_size;
+} herro_options;
+
+typedef struct {
+    char *id;
+    uint8_t *seq;
+    size_t length;
+} herro_corrected_read;
+
+/* Corrected reads, released with herro_free_corrected. */
+typedef struct {
+    herro_corrected_read *reads;
+    size_t n_reads;
+} herro_corrected_reads;
+
+/* Corrects the reads using their all-vs-all overlaps in PAF (paf_len bytes).
+ * Returns 0 on success, or -1 on error with the message given by
+ * herro_last_error. */
+int herro_correct(const char *model_path, const herro_options *options,
+                  const herro_read *reads, size_t n_reads, const uint8_t *paf,
+                  size_t paf_len, herro_corrected_reads *corrected);
+
+/* Releases the reads returned by herro_correct. */
+void herro_free_corrected(herro_corrected_reads *corrected);
+
+/* Message of the last error on the calling thread, or NULL. Valid until the
+ * next call of herro_correct on the same thread. */
+const char *herro_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* HERRO_H */
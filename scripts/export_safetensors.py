@@ -0,0 +1,55 @@
+#!/usr/bin/env python
+import argparse
+
+# Parameters read by the candle backend, besides the numbered blocks of the backbone
+REQUIRED_PARAMETERS = [
+    "embedding.weight",
+    "qn.context_read.0.weight",
+    "qn.context_read.1.running_mean",
+    "qn.context_pos.0.weight",
+    "qn.context_pos.1.running_mean",
+    "qn.backbone.0.pointwise_residual.weight",
+    "qn.backbone.0.layers.0.depthwise.weight",
+    "fc_info.weight",
+    "fc_bases.weight",
+]
+
+
+def export(model, output):
+    import torch
+    from safetensors.torch import save_file
+
+    module = torch.jit.load(model, map_location="cpu")
+    module.eval()
+
+    # Batch norm counters are only used in training
+    state_dict = {
+        name: tensor.detach().contiguous()
+        for name, tensor in module.state_dict().items()
+        if not name.endswith("num_batches_tracked")
+    }
+
+    missing = [name for name in REQUIRED_PARAMETERS if name not in state_dict]
+    if missing:
+        raise SystemExit(
+            f"{model} is not supported by the candle backend, missing parameters: {', '.join(missing)}")
+
+    save_file(state_dict, output)
+
+
+def get_args():
+    parser = argparse.ArgumentParser(
+        description="Export the weights of a TorchScript model to safetensors for --backend candle")
+
+    parser.add_argument("model", type=str, help="TorchScript model")
+    parser.add_argument("output", type=str, help="Path to the safetensors file")
+    return parser.parse_args()
+
+
+def main():
+    args = get_args()
+    export(args.model, args.output)
+
+
+if __name__ == "__main__":
+    main()
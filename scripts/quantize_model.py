@@ -0,0 +1,59 @@
+#!/usr/bin/env python
+import argparse
+import json
+import os
+
+ONNX_EXTENSION = ".onnx"
+
+
+def quantize_torchscript(model, output):
+    import torch
+    from torch.ao.quantization import default_dynamic_qconfig, quantize_dynamic_jit
+
+    module = torch.jit.load(model, map_location="cpu")
+    module.eval()
+
+    # Weights of linear layers are stored as int8, activations are quantized at runtime
+    quantized = quantize_dynamic_jit(module, {"": default_dynamic_qconfig})
+    torch.jit.save(quantized, output)
+
+
+def quantize_onnx(model, output):
+    from onnxruntime.quantization import QuantType, quantize_dynamic
+
+    quantize_dynamic(model, output, weight_type=QuantType.QInt8)
+
+
+def copy_spec(model, output):
+    spec_path = os.path.splitext(model)[0] + ".json"
+    if not os.path.exists(spec_path):
+        return
+
+    # herro runs models marked as quantized only with --precision int8
+    with open(spec_path) as f:
+        spec = json.load(f)
+    spec["quantized"] = True
+    with open(os.path.splitext(output)[0] + ".json", "w") as f:
+        json.dump(spec, f, indent=4)
+
+
+def get_args():
+    parser = argparse.ArgumentParser(
+        description="Dynamic int8 quantization of a model for --precision int8")
+
+    parser.add_argument("model", type=str, help="TorchScript or ONNX model")
+    parser.add_argument("output", type=str, help="Path to the quantized model")
+    return parser.parse_args()
+
+
+def main():
+    args = get_args()
+    if args.model.endswith(ONNX_EXTENSION):
+        quantize_onnx(args.model, args.output)
+    else:
+        quantize_torchscript(args.model, args.output)
+    copy_spec(args.model, args.output)
+
+
+if __name__ == "__main__":
+    main()
@@ -1,7 +1,17 @@
 #![allow(dead_code)]
 use itertools::Itertools;
 
+use crate::error::{HerroError, Result};
 use crate::overlaps::Strand;
+use crate::Aligner;
+
+#[cfg(feature = "block-aligner")]
+mod block;
+#[cfg(feature = "edlib")]
+mod edlib;
+mod simd;
+#[cfg(feature = "wfa")]
+mod wfa;
 
 #[derive(Debug, PartialEq, Copy, Clone, Eq)]
 pub enum CigarOp {
@@ -135,6 +145,343 @@ pub(crate) fn get_proper_cigar(cigar: &[CigarOp], is_target: bool, strand: Stran
     .collect()
 }
 
+/// Band width used for realigning overlaps in both directions from the diagonal.
+pub(crate) const ALIGNMENT_BAND_WIDTH: usize = 500;
+
+/// Narrowest band of the adaptive banded alignment
+const MIN_ADAPTIVE_BAND_WIDTH: usize = 32;
+/// Part of the divergent bases (times the overlap length) by which the adaptive
+/// band is widened, most indels are balanced by nearby ones
+const ADAPTIVE_BAND_FACTOR: f32 = 0.05;
+
+/// Aligner used for realigning the overlaps without CIGARs. Overlaps are
+/// aligned globally, and the returned CIGAR is given from the target's point of
+/// view with matches and mismatches reported as `CigarOp::Match`. Divergence
+/// of the reads is given if it is estimated from the overlap.
+pub(crate) trait PairwiseAligner: Send {
+    fn align(&mut self, target: &[u8], query: &[u8], divergence: Option<f32>) -> Vec<CigarOp>;
+
+    /// Number of pairs passed together to `align_batch`
+    fn batch_size(&self) -> usize {
+        1
+    }
+
+    /// Aligns the pairs of target and query with their divergences, one at a
+    /// time unless the aligner aligns multiple pairs at once.
+    fn align_batch(&mut self, pairs: &[AlignmentPair]) -> Vec<Vec<CigarOp>> {
+        pairs
+            .iter()
+            .map(|&(target, query, divergence)| self.align(target, query, divergence))
+            .collect()
+    }
+}
+
+/// Target and query sequences with the divergence of the overlap
+pub(crate) type AlignmentPair<'a> = (&'a [u8], &'a [u8], Option<f32>);
+
+/// Banded alignment around the diagonal, see `align`.
+pub(crate) struct BandedAligner {
+    band_width: usize,
+}
+
+impl PairwiseAligner for BandedAligner {
+    fn align(&mut self, target: &[u8], query: &[u8], _divergence: Option<f32>) -> Vec<CigarOp> {
+        align(target, query, self.band_width)
+    }
+}
+
+/// Banded alignment whose band is derived from the divergence of the overlap,
+/// so the common low-divergence overlaps are aligned in a narrow band. If the
+/// alignment reaches the edge of the band, it is repeated with a band twice as
+/// wide, up to the band of `BandedAligner`, which is also used if the
+/// divergence is not known.
+pub(crate) struct AdaptiveBandedAligner;
+
+impl AdaptiveBandedAligner {
+    fn band_width(len: usize, divergence: f32) -> usize {
+        let extra = (divergence.clamp(0., 1.) * ADAPTIVE_BAND_FACTOR * len as f32) as usize;
+        (MIN_ADAPTIVE_BAND_WIDTH + extra).min(ALIGNMENT_BAND_WIDTH)
+    }
+}
+
+impl PairwiseAligner for AdaptiveBandedAligner {
+    fn align(&mut self, target: &[u8], query: &[u8], divergence: Option<f32>) -> Vec<CigarOp> {
+        let mut band_width = match divergence {
+            Some(d) => Self::band_width(target.len().max(query.len()), d),
+            None => ALIGNMENT_BAND_WIDTH,
+        };
+
+        loop {
+            let (cigar, reaches_edge) = banded_align(target, query, band_width);
+            if !reaches_edge || band_width >= ALIGNMENT_BAND_WIDTH {
+                return cigar;
+            }
+
+            band_width = (2 * band_width).min(ALIGNMENT_BAND_WIDTH);
+        }
+    }
+}
+
+/// Creates the configured aligner, each feature generation thread has its own.
+pub(crate) fn pairwise_aligner(aligner: Aligner) -> Result<Box<dyn PairwiseAligner>> {
+    let aligner: Box<dyn PairwiseAligner> = match aligner {
+        Aligner::Banded => Box::new(BandedAligner {
+            band_width: ALIGNMENT_BAND_WIDTH,
+        }),
+        Aligner::Adaptive => Box::new(AdaptiveBandedAligner),
+        Aligner::Simd => Box::new(simd::SimdAligner::new()),
+        #[cfg(feature = "wfa")]
+        Aligner::Wfa => Box::new(wfa::WfaAligner::new()),
+        #[cfg(not(feature = "wfa"))]
+        Aligner::Wfa => {
+            return Err(HerroError::InvalidConfig(
+                "herro was built without the wfa feature".to_string(),
+            ))
+        }
+        #[cfg(feature = "edlib")]
+        Aligner::Edlib => Box::new(edlib::EdlibAligner),
+        #[cfg(not(feature = "edlib"))]
+        Aligner::Edlib => {
+            return Err(HerroError::InvalidConfig(
+                "herro was built without the edlib feature".to_string(),
+            ))
+        }
+        #[cfg(feature = "block-aligner")]
+        Aligner::BlockAligner => Box::new(block::BlockAligner),
+        #[cfg(not(feature = "block-aligner"))]
+        Aligner::BlockAligner => {
+            return Err(HerroError::InvalidConfig(
+                "herro was built without the block-aligner feature".to_string(),
+            ))
+        }
+    };
+
+    Ok(aligner)
+}
+
+/// Aligner created by `pairwise_aligner` with the parameters which determine
+/// its CIGARs, stored with the cached CIGARs.
+pub(crate) fn aligner_settings(aligner: Aligner) -> String {
+    format!("{:?} aligner, band width {}", aligner, ALIGNMENT_BAND_WIDTH)
+}
+
+/// Appends the operation to the CIGAR, merging it with the last operation of the
+/// same kind. Mismatches are added as matches.
+fn push_op(cigar: &mut Vec<CigarOp>, op: CigarOp) {
+    let op = match op {
+        CigarOp::Mismatch(l) => CigarOp::Match(l),
+        op => op,
+    };
+
+    match cigar.last_mut() {
+        Some(last) if std::mem::discriminant(last) == std::mem::discriminant(&op) => {
+            *last = last.with_length(last.get_length() + op.get_length())
+        }
+        _ => cigar.push(op),
+    }
+}
+
+const TB_DIAG: u8 = 0;
+const TB_DELETION: u8 = 1;
+const TB_INSERTION: u8 = 2;
+
+/// Global banded alignment with unit (edit distance) costs. The band follows the
+/// diagonal from (0, 0) to (tlen, qlen) and is widened by its slope, so it stays
+/// connected for queries much longer than the target. Returned CIGAR is given from the target's
+/// point of view with matches and mismatches reported as `CigarOp::Match`.
+/// Indels are left-aligned.
+pub(crate) fn align(target: &[u8], query: &[u8], band_width: usize) -> Vec<CigarOp> {
+    banded_align(target, query, band_width).0
+}
+
+/// Maximum size of the traceback of a banded alignment, longer alignments are
+/// split at the middle row of the target
+const MAX_TRACEBACK_BYTES: usize = 16 << 20;
+
+/// Band of the alignment of a target of `n` and a query of `m` bases. The
+/// band of a row covers the diagonal up to the center of the next row, so
+/// the bands of adjacent rows overlap even if the query is much longer than
+/// the target.
+#[derive(Debug, Clone, Copy)]
+struct Band {
+    n: usize,
+    m: usize,
+    width: usize,
+}
+
+impl Band {
+    fn center(&self, i: usize) -> usize {
+        (i as u64 * self.m as u64 / self.n as u64) as usize
+    }
+
+    /// Columns [lo, hi] of the row
+    fn range(&self, i: usize) -> (usize, usize) {
+        let next = self.center((i + 1).min(self.n));
+        (
+            self.center(i).saturating_sub(self.width),
+            (next + self.width).min(self.m),
+        )
+    }
+}
+
+/// Fills the rows of the band for the target and query, which may be a prefix
+/// of the aligned target. Returns the scores of the last row. The traceback of
+/// each cell is stored at the offset of its row, if it is given.
+fn fill_band(
+    target: &[u8],
+    query: &[u8],
+    band: Band,
+    mut traceback: Option<(&mut [u8], &[usize])>,
+) -> Vec<u32> {
+    // First row: only insertions
+    let (_, hi) = band.range(0);
+    let mut prev: Vec<u32> = (0..=hi as u32).collect();
+    if let Some((traceback, _)) = traceback.as_mut() {
+        traceback[..=hi].fill(TB_INSERTION);
+    }
+    let mut prev_lo = 0;
+
+    let mut curr: Vec<u32> = Vec::with_capacity(prev.len());
+    for i in 1..=target.len() {
+        let (lo, hi) = band.range(i);
+        let prev_score = |j: usize, prev: &[u32]| {
+            if j < prev_lo || j - prev_lo >= prev.len() {
+                u32::MAX
+            } else {
+                prev[j - prev_lo]
+            }
+        };
+
+        curr.clear();
+        for j in lo..=hi {
+            let (score, tb) = if j == 0 {
+                // First column: only deletions
+                (i as u32, TB_DELETION)
+            } else {
+                let mismatch = (target[i - 1] != query[j - 1]) as u32;
+                let diag = prev_score(j - 1, &prev).saturating_add(mismatch);
+                let del = prev_score(j, &prev).saturating_add(1);
+                let ins = if j > lo {
+                    curr[j - lo - 1].saturating_add(1)
+                } else {
+                    u32::MAX
+                };
+
+                if diag <= del && diag <= ins {
+                    (diag, TB_DIAG)
+                } else if del <= ins {
+                    (del, TB_DELETION)
+                } else {
+                    (ins, TB_INSERTION)
+                }
+            };
+
+            curr.push(score);
+            if let Some((traceback, offsets)) = traceback.as_mut() {
+                traceback[offsets[i] + j - lo] = tb;
+            }
+        }
+
+        std::mem::swap(&mut prev, &mut curr);
+        prev_lo = lo;
+    }
+
+    prev
+}
+
+/// Banded alignment, see `align`. Also returns whether the alignment reaches the
+/// edge of the band, in which case a wider band could give a better alignment.
+fn banded_align(target: &[u8], query: &[u8], band_width: usize) -> (Vec<CigarOp>, bool) {
+    split_banded_align(target, query, band_width, MAX_TRACEBACK_BYTES)
+}
+
+/// Banded alignment with a traceback of at most `max_traceback` bytes. Larger
+/// alignments are split at the middle row, in the column where the best path
+/// crosses it, which is found from the scores of the row computed from both
+/// ends (Hirschberg), and the halves are aligned separately.
+fn split_banded_align(
+    target: &[u8],
+    query: &[u8],
+    band_width: usize,
+    max_traceback: usize,
+) -> (Vec<CigarOp>, bool) {
+    let (n, m) = (target.len(), query.len());
+    if n == 0 || m == 0 {
+        let cigar = match (n, m) {
+            (0, 0) => Vec::new(),
+            (0, m) => vec![CigarOp::Insertion(m as u32)],
+            (n, _) => vec![CigarOp::Deletion(n as u32)],
+        };
+        return (cigar, false);
+    }
+
+    let band = Band {
+        n,
+        m,
+        width: band_width,
+    };
+    let mut offsets = Vec::with_capacity(n + 2);
+    offsets.push(0);
+    for i in 0..=n {
+        let (lo, hi) = band.range(i);
+        offsets.push(offsets[i] + hi - lo + 1);
+    }
+
+    if offsets[n + 1] > max_traceback && n > 1 {
+        let mid = n / 2;
+        let forward = fill_band(&target[..mid], query, band, None);
+        let rev_target: Vec<_> = target[mid..].iter().rev().copied().collect();
+        let rev_query: Vec<_> = query.iter().rev().copied().collect();
+        let backward = fill_band(&rev_target, &rev_query, band, None);
+
+        // Column of the middle row with the lowest score of the whole path
+        let (forward_lo, _) = band.range(mid);
+        let (backward_lo, _) = band.range(n - mid);
+        let split = (forward_lo..forward_lo + forward.len())
+            .filter(|&j| m - j >= backward_lo && m - j - backward_lo < backward.len())
+            .min_by_key(|&j| forward[j - forward_lo].saturating_add(backward[m - j - backward_lo]))
+            .unwrap_or_else(|| band.center(mid));
+
+        let (mut cigar, first_edge) =
+            split_banded_align(&target[..mid], &query[..split], band_width, max_traceback);
+        let (second, second_edge) =
+            split_banded_align(&target[mid..], &query[split..], band_width, max_traceback);
+        second.into_iter().for_each(|op| push_op(&mut cigar, op));
+        return (cigar, first_edge || second_edge);
+    }
+
+    let mut traceback = vec![TB_DIAG; offsets[n + 1]];
+    fill_band(target, query, band, Some((&mut traceback, &offsets)));
+
+    // Traceback from (n, m)
+    let mut ops = Vec::new();
+    let mut reaches_edge = false;
+    let (mut i, mut j) = (n, m);
+    while i > 0 || j > 0 {
+        let (lo, hi) = band.range(i);
+        reaches_edge |= (j == lo && lo > 0) || (j == hi && hi < m);
+        let op = match traceback[offsets[i] + j - lo] {
+            TB_DIAG => {
+                i -= 1;
+                j -= 1;
+                CigarOp::Match(1)
+            }
+            TB_DELETION => {
+                i -= 1;
+                CigarOp::Deletion(1)
+            }
+            _ => {
+                j -= 1;
+                CigarOp::Insertion(1)
+            }
+        };
+        push_op(&mut ops, op);
+    }
+
+    ops.reverse();
+    (ops, reaches_edge)
+}
+
 pub(crate) fn fix_cigar(cigar: &mut Vec<CigarOp>, target: &[u8], query: &[u8]) -> (u32, u32) {
     // Left-alignment of indels
     // https://github.com/lh3/minimap2/blob/master/align.c#L91
@@ -251,7 +598,11 @@ pub(crate) fn fix_cigar(cigar: &mut Vec<CigarOp>, target: &[u8], query: &[u8]) -
 
 #[cfg(test)]
 mod tests {
-    use super::{fix_cigar, CigarOp};
+    use super::simd::SimdAligner;
+    use super::{
+        align, banded_align, fix_cigar, push_op, split_banded_align, AdaptiveBandedAligner,
+        CigarOp, PairwiseAligner, ALIGNMENT_BAND_WIDTH,
+    };
 
     #[test]
     fn fix_cigar_test1() {
@@ -295,4 +646,182 @@ mod tests {
             [CigarOp::Match(5), CigarOp::Deletion(1), CigarOp::Match(4)]
         )
     }
+
+    #[test]
+    fn align_test1() {
+        let target = "ACGTACGTACGT".as_bytes();
+        let query = "ACGTACCTACGT".as_bytes();
+
+        assert_eq!(align(target, query, 3), [CigarOp::Match(12)]);
+    }
+
+    #[test]
+    fn align_test2() {
+        let target = "ACGTACGTTTACGTACGT".as_bytes();
+        let query = "ACGTACGTACGTACGT".as_bytes();
+
+        assert_eq!(
+            align(target, query, 3),
+            [CigarOp::Match(7), CigarOp::Deletion(2), CigarOp::Match(9)]
+        );
+    }
+
+    #[test]
+    fn align_test3() {
+        let target = "ACGTACGTACGTACGT".as_bytes();
+        let query = "ACGTACGTGGGACGTACGT".as_bytes();
+
+        assert_eq!(
+            align(target, query, 4),
+            [CigarOp::Match(8), CigarOp::Insertion(3), CigarOp::Match(8)]
+        );
+    }
+
+    #[test]
+    fn push_merged_ops() {
+        let mut cigar = Vec::new();
+        for op in [
+            CigarOp::Match(3),
+            CigarOp::Mismatch(1),
+            CigarOp::Match(2),
+            CigarOp::Insertion(1),
+            CigarOp::Insertion(2),
+            CigarOp::Deletion(1),
+        ] {
+            push_op(&mut cigar, op);
+        }
+
+        assert_eq!(
+            cigar,
+            [
+                CigarOp::Match(6),
+                CigarOp::Insertion(3),
+                CigarOp::Deletion(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn adaptive_band() {
+        // Deletion of 10 bases in the middle of the query
+        let target = "ACGTTGCAAGCTTAGCCATGGATCCGTACGATCGATTGCA".as_bytes();
+        let query = "ACGTTGCAAGCTTAGCCATGATCGATTGCA".as_bytes();
+        let expected = align(target, query, target.len());
+
+        assert!(banded_align(target, query, 2).1);
+        assert_eq!(banded_align(target, query, 12), (expected.clone(), false));
+
+        // Band is widened from the narrowest one for the low divergence
+        assert_eq!(AdaptiveBandedAligner::band_width(40, 0.05), 32);
+        let cigar = AdaptiveBandedAligner.align(target, query, Some(0.05));
+        assert_eq!(cigar, expected);
+        assert_eq!(AdaptiveBandedAligner.align(target, query, None), expected);
+    }
+
+    #[test]
+    fn steep_band() {
+        // Query is longer than the target by more than the band width per base
+        let target = "ACGTTGCA".as_bytes();
+        let query = "AAAAACCCCCGGGGGTTTTTTTTTTGGGGGCCCCCAAAAA".as_bytes();
+        let expected = align(target, query, query.len());
+
+        let cigar = align(target, query, 1);
+        assert_eq!(
+            edit_distance(target, query, &cigar),
+            edit_distance(target, query, &expected)
+        );
+    }
+
+    #[test]
+    fn split_alignment() {
+        let mut state = 5u64;
+        let mut random = |n: u64| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) % n
+        };
+        let target: Vec<_> = (0..3000).map(|_| b"ACGT"[random(4) as usize]).collect();
+        let mut query = Vec::new();
+        for &b in target.iter() {
+            match random(40) {
+                0 => query.push(b"ACGT"[random(4) as usize]),
+                1 => (),
+                2 => query.extend([b, b"ACGT"[random(4) as usize]]),
+                _ => query.push(b),
+            }
+        }
+
+        // Traceback of at most 20 kB is split into pieces of about 100 rows
+        let (expected, _) = banded_align(&target, &query, 100);
+        let (cigar, reaches_edge) = split_banded_align(&target, &query, 100, 20_000);
+        assert!(!reaches_edge);
+        assert_eq!(
+            edit_distance(&target, &query, &cigar),
+            edit_distance(&target, &query, &expected)
+        );
+    }
+
+    /// Edit distance of the alignment, None if the CIGAR does not span both sequences
+    fn edit_distance(target: &[u8], query: &[u8], cigar: &[CigarOp]) -> Option<usize> {
+        let (mut tpos, mut qpos, mut dist) = (0, 0, 0);
+        for op in cigar {
+            let len = op.get_length() as usize;
+            match op {
+                CigarOp::Match(_) | CigarOp::Mismatch(_) => {
+                    let (t, q) = (target.get(tpos..tpos + len)?, query.get(qpos..qpos + len)?);
+                    dist += t.iter().zip(q).filter(|(a, b)| a != b).count();
+                    (tpos, qpos) = (tpos + len, qpos + len);
+                }
+                CigarOp::Deletion(_) => (tpos, dist) = (tpos + len, dist + len),
+                CigarOp::Insertion(_) => (qpos, dist) = (qpos + len, dist + len),
+            }
+        }
+
+        (tpos == target.len() && qpos == query.len()).then_some(dist)
+    }
+
+    #[test]
+    fn simd_batch() {
+        // Random reads with 5% of substitutions and short indels in the queries
+        let mut state = 17u64;
+        let mut random = |n: u64| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) % n
+        };
+        let mut seqs = Vec::new();
+        for i in 0..40 {
+            let len = 100 + 50 * i;
+            let target: Vec<_> = (0..len).map(|_| b"ACGT"[random(4) as usize]).collect();
+            let mut query = Vec::new();
+            for &b in target.iter() {
+                match random(60) {
+                    0 => query.push(b"ACGT"[random(4) as usize]),
+                    1 => (),
+                    2 => query.extend([b, b"ACGT"[random(4) as usize]]),
+                    _ => query.push(b),
+                }
+            }
+            seqs.push((target, query));
+        }
+
+        // Deletion longer than the band and empty sequences
+        let target = seqs[30].0.clone();
+        let query = [&target[..500], &target[700..]].concat();
+        seqs.push((target, query));
+        seqs.push((b"ACGT".to_vec(), Vec::new()));
+
+        let pairs: Vec<_> = seqs.iter().map(|(t, q)| (&t[..], &q[..], None)).collect();
+        let cigars = SimdAligner::new().align_batch(&pairs);
+        assert_eq!(cigars.len(), pairs.len());
+        for ((target, query, _), cigar) in pairs.into_iter().zip(cigars) {
+            let expected = align(target, query, ALIGNMENT_BAND_WIDTH);
+            assert_eq!(
+                edit_distance(target, query, &cigar),
+                edit_distance(target, query, &expected)
+            );
+        }
+    }
 }
@@ -0,0 +1,51 @@
+use block_aligner::cigar::{Cigar, Operation};
+use block_aligner::scan_block::{Block, PaddedBytes};
+use block_aligner::scores::{Gaps, NucMatrix, NW1};
+
+use super::{align, push_op, CigarOp, PairwiseAligner};
+
+/// Blocks start small and grow up to the maximum size where the alignment is
+/// difficult, e.g. around long indels
+const MIN_BLOCK_SIZE: usize = 32;
+const MAX_BLOCK_SIZE: usize = 1024;
+/// Gaps scored as in the unit costs of NW1, with a small opening penalty
+const GAPS: Gaps = Gaps {
+    open: -2,
+    extend: -1,
+};
+
+/// Global alignment with adaptive block sizes and SIMD-parallel scoring.
+pub(crate) struct BlockAligner;
+
+impl PairwiseAligner for BlockAligner {
+    fn align(&mut self, target: &[u8], query: &[u8], _divergence: Option<f32>) -> Vec<CigarOp> {
+        if target.is_empty() || query.is_empty() {
+            return align(target, query, 0);
+        }
+
+        let q = PaddedBytes::from_bytes::<NucMatrix>(query, MAX_BLOCK_SIZE);
+        let r = PaddedBytes::from_bytes::<NucMatrix>(target, MAX_BLOCK_SIZE);
+        let mut block = Block::<true, false>::new(query.len(), target.len(), MAX_BLOCK_SIZE);
+        block.align(&q, &r, &NW1, GAPS, MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE, 0);
+
+        let res = block.res();
+        let mut trace = Cigar::new(res.query_idx, res.reference_idx);
+        block
+            .trace()
+            .cigar_eq(&q, &r, res.query_idx, res.reference_idx, &mut trace);
+
+        let mut cigar = Vec::new();
+        for op_len in trace.to_vec() {
+            let len = op_len.len as u32;
+            let op = match op_len.op {
+                Operation::M | Operation::Eq | Operation::X => CigarOp::Match(len),
+                Operation::I => CigarOp::Insertion(len),
+                Operation::D => CigarOp::Deletion(len),
+                Operation::Sentinel => continue,
+            };
+            push_op(&mut cigar, op);
+        }
+
+        cigar
+    }
+}
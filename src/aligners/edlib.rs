@@ -0,0 +1,41 @@
+use edlib_rs::edlibrs::{
+    edlibAlignRs, EdlibAlignConfigRs, EdlibAlignModeRs, EdlibAlignTaskRs, EDLIB_STATUS_OK,
+};
+
+use super::{align, push_op, CigarOp, PairwiseAligner, ALIGNMENT_BAND_WIDTH};
+
+/// Edit distance aligner using the bit-vector algorithm of edlib, which is
+/// robust for noisy reads with many indels.
+pub(crate) struct EdlibAligner;
+
+impl PairwiseAligner for EdlibAligner {
+    fn align(&mut self, target: &[u8], query: &[u8], _divergence: Option<f32>) -> Vec<CigarOp> {
+        if target.is_empty() || query.is_empty() {
+            return align(target, query, 0);
+        }
+
+        let config = EdlibAlignConfigRs {
+            mode: EdlibAlignModeRs::EDLIB_MODE_NW,
+            task: EdlibAlignTaskRs::EDLIB_TASK_PATH,
+            ..Default::default()
+        };
+        let result = edlibAlignRs(query, target, &config);
+        let alignment = match result.alignment {
+            Some(alignment) if result.status == EDLIB_STATUS_OK => alignment,
+            _ => return align(target, query, ALIGNMENT_BAND_WIDTH),
+        };
+
+        // Edlib inserts into the target the bases which are only in the query
+        let mut cigar = Vec::new();
+        for op in alignment {
+            let op = match op {
+                0 | 3 => CigarOp::Match(1),
+                1 => CigarOp::Insertion(1),
+                _ => CigarOp::Deletion(1),
+            };
+            push_op(&mut cigar, op);
+        }
+
+        cigar
+    }
+}
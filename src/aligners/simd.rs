@@ -0,0 +1,556 @@
+#[cfg(target_arch = "aarch64")]
+use std::arch::aarch64::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+use super::{
+    align, push_op, AlignmentPair, CigarOp, PairwiseAligner, ALIGNMENT_BAND_WIDTH, TB_DELETION,
+    TB_DIAG, TB_INSERTION,
+};
+
+/// Number of pairs aligned together, the 16-bit scores of a cell for all
+/// lanes fill an AVX2 register (or two NEON registers)
+const LANES: usize = 16;
+
+/// Target bases aligned per chunk
+const CHUNK_LEN: usize = 256;
+/// Target bases aligned after the chunk so the end of the chunk is placed on
+/// the best path, they are realigned in the next chunk
+const LOOKAHEAD: usize = 64;
+/// Maximum number of rows of a chunk
+const MAX_ROWS: usize = CHUNK_LEN + LOOKAHEAD;
+/// Diagonals aligned in a chunk on each side of its start
+const BAND_WIDTH: usize = 64;
+const N_DIAGS: usize = 2 * BAND_WIDTH + 1;
+/// Score of the cells outside the band or the chunk of a lane
+const OUTSIDE: u16 = u16::MAX / 2;
+
+type Cells<T> = [[T; LANES]; N_DIAGS];
+
+/// Pair aligned in a lane, positions are the start of its next chunk
+struct Lane<'a> {
+    idx: usize,
+    target: &'a [u8],
+    query: &'a [u8],
+    tpos: usize,
+    qpos: usize,
+    cigar: Vec<CigarOp>,
+}
+
+/// Part of the DP matrix of a lane aligned in a step, cells of a chunk start
+/// at the positions of its lane
+#[derive(Debug, Clone, Copy, Default)]
+struct Chunk {
+    rows: usize,
+    cols: usize,
+    last: bool,
+}
+
+impl Lane<'_> {
+    /// Returns the next chunk, or None if the end of the alignment lies
+    /// outside the band of the last chunk.
+    fn chunk(&self) -> Option<Chunk> {
+        let rows = self.target.len() - self.tpos;
+        let cols = self.query.len() - self.qpos;
+        if rows <= MAX_ROWS {
+            let chunk = Chunk {
+                rows,
+                cols,
+                last: true,
+            };
+            return (rows.abs_diff(cols) < BAND_WIDTH).then_some(chunk);
+        }
+
+        Some(Chunk {
+            rows: MAX_ROWS,
+            cols: cols.min(MAX_ROWS + BAND_WIDTH),
+            last: false,
+        })
+    }
+}
+
+/// Traces the path of the lane back from the end of its chunk. Returns the
+/// path as unit operations from the start of the chunk, or None if the path
+/// reaches the edge of the band.
+fn trace(aligner: &SimdAligner, l: usize, (rows, end_col): (usize, usize)) -> Option<Vec<CigarOp>> {
+    let mut ops = Vec::new();
+    let (mut i, mut k) = (rows, end_col + BAND_WIDTH - rows);
+    while i > 0 || k != BAND_WIDTH {
+        if k == 0 || k == N_DIAGS - 1 {
+            return None;
+        }
+
+        match aligner.traceback[i][k][l] {
+            TB_DIAG => {
+                ops.push(CigarOp::Match(1));
+                i -= 1;
+            }
+            TB_DELETION => {
+                ops.push(CigarOp::Deletion(1));
+                i -= 1;
+                k += 1;
+            }
+            _ => {
+                ops.push(CigarOp::Insertion(1));
+                k -= 1;
+            }
+        }
+    }
+
+    ops.reverse();
+    Some(ops)
+}
+
+/// Aligns the pairs in the lanes of the aligner, which are filled together
+/// (inter-sequence parallelism). Pairs are aligned in chunks of the target
+/// with a fixed band of diagonals around the start of each chunk, and lanes
+/// are refilled with the next pairs as soon as their pairs are aligned. Pairs
+/// whose alignment reaches the edge of the band are aligned with `align`.
+fn align_chunked(aligner: &mut SimdAligner, pairs: &[AlignmentPair]) -> Vec<Vec<CigarOp>> {
+    let mut cigars = vec![Vec::new(); pairs.len()];
+    let mut queue = pairs.iter().enumerate();
+    let mut lanes: Vec<Option<Lane>> = (0..LANES).map(|_| None).collect();
+
+    loop {
+        // Empty lanes take the next pairs, pairs with an empty sequence need
+        // no DP
+        for lane in lanes.iter_mut().filter(|l| l.is_none()) {
+            for (idx, &(target, query, _)) in queue.by_ref() {
+                if target.is_empty() || query.is_empty() {
+                    cigars[idx] = align(target, query, 0);
+                    continue;
+                }
+
+                *lane = Some(Lane {
+                    idx,
+                    target,
+                    query,
+                    tpos: 0,
+                    qpos: 0,
+                    cigar: Vec::new(),
+                });
+                break;
+            }
+        }
+        if lanes.iter().all(Option::is_none) {
+            return cigars;
+        }
+
+        let mut chunks = vec![Chunk::default(); lanes.len()];
+        for (l, slot) in lanes.iter_mut().enumerate() {
+            let Some(lane) = slot else {
+                continue;
+            };
+
+            match lane.chunk() {
+                Some(chunk) => chunks[l] = chunk,
+                None => {
+                    cigars[lane.idx] = align(lane.target, lane.query, ALIGNMENT_BAND_WIDTH);
+                    *slot = None;
+                }
+            }
+        }
+
+        let last_rows = aligner.fill(&lanes, &chunks);
+        for (l, slot) in lanes.iter_mut().enumerate() {
+            let Some(lane) = slot else {
+                continue;
+            };
+
+            // Last chunk ends at the end of both sequences, others at the
+            // best cell of the last row
+            let Chunk { rows, cols, last } = chunks[l];
+            let end_col = match last {
+                true => Some(cols),
+                false => (0..N_DIAGS)
+                    .filter_map(|k| (rows + k).checked_sub(BAND_WIDTH).map(|j| (k, j)))
+                    .filter(|&(_, j)| j <= cols)
+                    .min_by_key(|&(k, _)| last_rows[l][k])
+                    .map(|(_, j)| j),
+            };
+
+            let ops = end_col.and_then(|end_col| trace(aligner, l, (rows, end_col)));
+            let Some(ops) = ops else {
+                cigars[lane.idx] = align(lane.target, lane.query, ALIGNMENT_BAND_WIDTH);
+                *slot = None;
+                continue;
+            };
+
+            if last {
+                for op in ops {
+                    push_op(&mut lane.cigar, op);
+                }
+                cigars[lane.idx] = std::mem::take(&mut lane.cigar);
+                *slot = None;
+                continue;
+            }
+
+            // Chunk is cut after its first CHUNK_LEN target bases
+            let (mut tlen, mut qlen) = (0, 0);
+            for op in ops {
+                if tlen == CHUNK_LEN {
+                    break;
+                }
+
+                match op {
+                    CigarOp::Match(_) => (tlen, qlen) = (tlen + 1, qlen + 1),
+                    CigarOp::Deletion(_) => tlen += 1,
+                    _ => qlen += 1,
+                }
+                push_op(&mut lane.cigar, op);
+            }
+            lane.tpos += tlen;
+            lane.qpos += qlen;
+        }
+    }
+}
+
+/// Instructions used for the cell updates, detected when the aligner is
+/// created. Other CPUs use the scalar updates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Isa {
+    Scalar,
+    #[cfg(target_arch = "x86_64")]
+    Avx2,
+    #[cfg(target_arch = "aarch64")]
+    Neon,
+}
+
+impl Isa {
+    fn detect() -> Self {
+        #[cfg(target_arch = "x86_64")]
+        if is_x86_feature_detected!("avx2") {
+            return Isa::Avx2;
+        }
+        #[cfg(target_arch = "aarch64")]
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            return Isa::Neon;
+        }
+
+        Isa::Scalar
+    }
+}
+
+/// Bases and bounds of the lanes for a row of cells. Bases are transposed, so
+/// the bases of all lanes at a position are contiguous.
+struct Row<'a> {
+    /// Index of the row in the chunks
+    i: usize,
+    /// Target base of the row in each lane
+    target: &'a [u8; LANES],
+    /// Query bases of each column, the first column has none
+    queries: &'a [[u8; LANES]],
+    /// Columns of the chunk of each lane
+    cols: &'a [u16; LANES],
+    /// All bits set for the lanes whose chunk contains the row
+    inside: [u16; LANES],
+}
+
+impl Row<'_> {
+    /// First diagonal of the row which is in the matrix, cells before it
+    /// have negative columns
+    fn first_diag(&self) -> usize {
+        BAND_WIDTH.saturating_sub(self.i)
+    }
+}
+
+/// Updates the cells of the row, the reference for the SIMD updates.
+fn fill_row_scalar(row: &Row, prev: &Cells<u16>, curr: &mut Cells<u16>, tb: &mut Cells<u8>) {
+    for k in row.first_diag()..N_DIAGS {
+        let j = row.i + k - BAND_WIDTH;
+        for l in 0..LANES {
+            let mismatch = (row.target[l] != row.queries[j][l]) as u16;
+            let diag = prev[k][l] + mismatch;
+            let del = prev.get(k + 1).map_or(OUTSIDE, |c| c[l]) + 1;
+            let ins = match k > 0 {
+                true => curr[k - 1][l] + 1,
+                false => OUTSIDE + 1,
+            };
+
+            let (score, op) = if diag <= del && diag <= ins {
+                (diag, TB_DIAG)
+            } else if del <= ins {
+                (del, TB_DELETION)
+            } else {
+                (ins, TB_INSERTION)
+            };
+
+            let inside = row.inside[l] != 0 && j as u16 <= row.cols[l];
+            curr[k][l] = if inside { score.min(OUTSIDE) } else { OUTSIDE };
+            tb[k][l] = op;
+        }
+    }
+}
+
+/// Updates the cells of the row as `fill_row_scalar`, a cell of all lanes at
+/// once in an AVX2 register.
+///
+/// # Safety
+/// The CPU has to support AVX2.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn fill_row_avx2(row: &Row, prev: &Cells<u16>, curr: &mut Cells<u16>, tb: &mut Cells<u8>) {
+    let load = |cell: &[u16; LANES]| _mm256_loadu_si256(cell.as_ptr() as *const __m256i);
+    let one = _mm256_set1_epi16(1);
+    let outside = _mm256_set1_epi16(OUTSIDE as i16);
+    let (tb_diag, tb_del, tb_ins) = (
+        _mm256_set1_epi16(TB_DIAG as i16),
+        _mm256_set1_epi16(TB_DELETION as i16),
+        _mm256_set1_epi16(TB_INSERTION as i16),
+    );
+    // Unsigned a <= b
+    let le = |a, b| _mm256_cmpeq_epi16(_mm256_min_epu16(a, b), a);
+
+    let target = _mm_loadu_si128(row.target.as_ptr() as *const __m128i);
+    let cols = load(row.cols);
+    let inside = load(&row.inside);
+    let mut ins_in = outside;
+    for k in row.first_diag()..N_DIAGS {
+        let j = row.i + k - BAND_WIDTH;
+        let query = _mm_loadu_si128(row.queries[j].as_ptr() as *const __m128i);
+        let mismatch = _mm_andnot_si128(_mm_cmpeq_epi8(target, query), _mm_set1_epi8(1));
+
+        let diag = _mm256_add_epi16(load(&prev[k]), _mm256_cvtepu8_epi16(mismatch));
+        let del = match prev.get(k + 1) {
+            Some(cell) => _mm256_add_epi16(load(cell), one),
+            None => _mm256_add_epi16(outside, one),
+        };
+        let ins = _mm256_add_epi16(ins_in, one);
+
+        let gap = _mm256_min_epu16(del, ins);
+        let score = _mm256_min_epu16(diag, gap);
+        let op = _mm256_blendv_epi8(tb_ins, tb_del, le(del, ins));
+        let op = _mm256_blendv_epi8(op, tb_diag, le(diag, gap));
+
+        let inside = _mm256_andnot_si256(
+            _mm256_cmpgt_epi16(_mm256_set1_epi16(j as i16), cols),
+            inside,
+        );
+        let score = _mm256_blendv_epi8(outside, _mm256_min_epu16(score, outside), inside);
+        _mm256_storeu_si256(curr[k].as_mut_ptr() as *mut __m256i, score);
+        ins_in = score;
+
+        // Traceback values fit in the low bytes, which are packed in order
+        let op = _mm256_permute4x64_epi64(_mm256_packus_epi16(op, op), 0b1000);
+        _mm_storeu_si128(
+            tb[k].as_mut_ptr() as *mut __m128i,
+            _mm256_castsi256_si128(op),
+        );
+    }
+}
+
+/// Updates the cells of the row as `fill_row_scalar`, a cell of all lanes at
+/// once in two NEON registers.
+///
+/// # Safety
+/// The CPU has to support NEON.
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn fill_row_neon(row: &Row, prev: &Cells<u16>, curr: &mut Cells<u16>, tb: &mut Cells<u8>) {
+    const HALF: usize = LANES / 2;
+    let one = vdupq_n_u16(1);
+    let outside = vdupq_n_u16(OUTSIDE);
+    let (tb_diag, tb_del, tb_ins) = (
+        vdupq_n_u16(TB_DIAG as u16),
+        vdupq_n_u16(TB_DELETION as u16),
+        vdupq_n_u16(TB_INSERTION as u16),
+    );
+
+    for h in (0..LANES).step_by(HALF) {
+        let target = vld1_u8(row.target[h..].as_ptr());
+        let cols = vld1q_u16(row.cols[h..].as_ptr());
+        let inside = vld1q_u16(row.inside[h..].as_ptr());
+        let mut ins_in = outside;
+        for k in row.first_diag()..N_DIAGS {
+            let j = row.i + k - BAND_WIDTH;
+            let query = vld1_u8(row.queries[j][h..].as_ptr());
+            let matches = vandq_u16(vmovl_u8(vceq_u8(target, query)), one);
+
+            let diag = vaddq_u16(vld1q_u16(prev[k][h..].as_ptr()), vsubq_u16(one, matches));
+            let del = match prev.get(k + 1) {
+                Some(cell) => vaddq_u16(vld1q_u16(cell[h..].as_ptr()), one),
+                None => vaddq_u16(outside, one),
+            };
+            let ins = vaddq_u16(ins_in, one);
+
+            let gap = vminq_u16(del, ins);
+            let score = vminq_u16(diag, gap);
+            let op = vbslq_u16(vcleq_u16(del, ins), tb_del, tb_ins);
+            let op = vbslq_u16(vcleq_u16(diag, gap), tb_diag, op);
+
+            let inside = vandq_u16(vcleq_u16(vdupq_n_u16(j as u16), cols), inside);
+            let score = vbslq_u16(inside, vminq_u16(score, outside), outside);
+            vst1q_u16(curr[k][h..].as_mut_ptr(), score);
+            ins_in = score;
+
+            vst1_u8(tb[k][h..].as_mut_ptr(), vmovn_u16(op));
+        }
+    }
+}
+
+/// Chunked banded alignment of `LANES` pairs at once, see `align_chunked`.
+/// Cells store the lanes contiguously, and the cells of a
+/// row are updated with AVX2 or NEON instructions for all lanes at once if the
+/// CPU supports them.
+pub(crate) struct SimdAligner {
+    isa: Isa,
+    traceback: Vec<Cells<u8>>,
+}
+
+impl SimdAligner {
+    pub(crate) fn new() -> Self {
+        SimdAligner {
+            isa: Isa::detect(),
+            traceback: vec![[[TB_DIAG; LANES]; N_DIAGS]; MAX_ROWS + 1],
+        }
+    }
+
+    fn fill_row(&mut self, row: &Row, prev: &Cells<u16>, curr: &mut Cells<u16>) {
+        let tb = &mut self.traceback[row.i];
+        match self.isa {
+            Isa::Scalar => fill_row_scalar(row, prev, curr, tb),
+            // SAFETY: AVX2 is supported, it was detected at runtime
+            #[cfg(target_arch = "x86_64")]
+            Isa::Avx2 => unsafe { fill_row_avx2(row, prev, curr, tb) },
+            // SAFETY: NEON is supported, it was detected at runtime
+            #[cfg(target_arch = "aarch64")]
+            Isa::Neon => unsafe { fill_row_neon(row, prev, curr, tb) },
+        }
+    }
+
+    /// Fills the DP matrices of the chunks of all lanes, empty lanes have
+    /// empty chunks. Cell (i, k) of a chunk is on the diagonal k - BAND_WIDTH,
+    /// so the lanes update the same cells together. Cells outside the band or
+    /// the chunk of a lane have the score `OUTSIDE`. Returns the scores of the
+    /// last row of each chunk.
+    fn fill(&mut self, lanes: &[Option<Lane>], chunks: &[Chunk]) -> Vec<[u16; N_DIAGS]> {
+        let n_rows = chunks.iter().map(|c| c.rows).max().unwrap_or(0);
+        let mut last_rows = vec![[OUTSIDE; N_DIAGS]; LANES];
+
+        // Bases of the chunks, columns of the chunks end at most a band width
+        // after their last row
+        let mut targets = vec![[0; LANES]; n_rows + 1];
+        let mut queries = vec![[0; LANES]; n_rows + BAND_WIDTH + 1];
+        let mut cols = [0; LANES];
+        for (l, (lane, chunk)) in lanes.iter().zip(chunks).enumerate() {
+            let Some(lane) = lane else {
+                continue;
+            };
+
+            let target = &lane.target[lane.tpos..lane.tpos + chunk.rows];
+            let query = &lane.query[lane.qpos..lane.qpos + chunk.cols];
+            target
+                .iter()
+                .zip(&mut targets[1..])
+                .for_each(|(&b, row)| row[l] = b);
+            query
+                .iter()
+                .zip(&mut queries[1..])
+                .for_each(|(&b, col)| col[l] = b);
+            cols[l] = chunk.cols as u16;
+        }
+
+        // First row: only insertions
+        let mut prev = [[OUTSIDE; LANES]; N_DIAGS];
+        for j in 0..=BAND_WIDTH {
+            for (l, chunk) in chunks.iter().enumerate() {
+                if j <= chunk.cols {
+                    prev[BAND_WIDTH + j][l] = j as u16;
+                }
+            }
+            self.traceback[0][BAND_WIDTH + j] = [TB_INSERTION; LANES];
+        }
+
+        for (i, target) in targets.iter().enumerate().skip(1) {
+            let mut inside = [0; LANES];
+            for (l, (lane, chunk)) in lanes.iter().zip(chunks).enumerate() {
+                if lane.is_some() && i <= chunk.rows {
+                    inside[l] = u16::MAX;
+                }
+            }
+            let row = Row {
+                i,
+                target,
+                queries: &queries,
+                cols: &cols,
+                inside,
+            };
+
+            let mut curr = [[OUTSIDE; LANES]; N_DIAGS];
+            self.fill_row(&row, &prev, &mut curr);
+
+            for (l, chunk) in chunks.iter().enumerate() {
+                if chunk.rows == i {
+                    for k in 0..N_DIAGS {
+                        last_rows[l][k] = curr[k][l];
+                    }
+                }
+            }
+            prev = curr;
+        }
+
+        last_rows
+    }
+}
+
+impl PairwiseAligner for SimdAligner {
+    fn align(&mut self, target: &[u8], query: &[u8], divergence: Option<f32>) -> Vec<CigarOp> {
+        self.align_batch(&[(target, query, divergence)])
+            .pop()
+            .unwrap()
+    }
+
+    fn batch_size(&self) -> usize {
+        4 * LANES
+    }
+
+    fn align_batch(&mut self, pairs: &[AlignmentPair]) -> Vec<Vec<CigarOp>> {
+        align_chunked(self, pairs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Isa, SimdAligner};
+    use crate::aligners::PairwiseAligner;
+
+    #[test]
+    fn same_cigars_as_scalar() {
+        let mut state = 5u64;
+        let mut random = |n: u64| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) % n
+        };
+        let mut seqs = Vec::new();
+        for i in 0..40 {
+            let target: Vec<_> = (0..200 + 97 * i)
+                .map(|_| b"ACGT"[random(4) as usize])
+                .collect();
+            let mut query = Vec::new();
+            for &b in target.iter() {
+                match random(20) {
+                    0 => query.push(b"ACGT"[random(4) as usize]),
+                    1 => (),
+                    2 => query.extend([b, b"ACGT"[random(4) as usize]]),
+                    _ => query.push(b),
+                }
+            }
+            seqs.push((target, query));
+        }
+        let pairs: Vec<_> = seqs.iter().map(|(t, q)| (&t[..], &q[..], None)).collect();
+
+        let mut scalar = SimdAligner {
+            isa: Isa::Scalar,
+            ..SimdAligner::new()
+        };
+        let mut detected = SimdAligner::new();
+        assert_eq!(
+            detected.align_batch(&pairs),
+            scalar.align_batch(&pairs),
+            "{:?} and scalar cell updates differ",
+            detected.isa
+        );
+    }
+}
@@ -0,0 +1,58 @@
+use lib_wfa2::affine_wavefront::{AffineWavefronts, AlignmentStatus, MemoryMode};
+
+use super::{align, push_op, CigarOp, PairwiseAligner, ALIGNMENT_BAND_WIDTH};
+
+/// Mismatch, gap opening and gap extension penalties (WFA2 defaults)
+const WFA_PENALTIES: (i32, i32, i32) = (4, 6, 2);
+
+/// Gap-affine wavefront aligner. Its cost grows with the divergence of the
+/// reads instead of their length, so it is fast for accurate reads.
+pub(crate) struct WfaAligner {
+    aligner: AffineWavefronts,
+}
+
+impl WfaAligner {
+    pub(crate) fn new() -> Self {
+        let (mismatch, gap_opening, gap_extension) = WFA_PENALTIES;
+        let aligner = AffineWavefronts::with_penalties_and_memory_mode(
+            0,
+            mismatch,
+            gap_opening,
+            gap_extension,
+            MemoryMode::Ultralow,
+        );
+
+        WfaAligner { aligner }
+    }
+}
+
+// Aligner is owned and used by a single feature generation thread
+unsafe impl Send for WfaAligner {}
+
+impl PairwiseAligner for WfaAligner {
+    fn align(&mut self, target: &[u8], query: &[u8], _divergence: Option<f32>) -> Vec<CigarOp> {
+        if target.is_empty() || query.is_empty() {
+            return align(target, query, 0);
+        }
+
+        // Target is the pattern, so deletions consume the target and
+        // insertions the query
+        let status = self.aligner.align(target, query);
+        if status != AlignmentStatus::Completed {
+            return align(target, query, ALIGNMENT_BAND_WIDTH);
+        }
+
+        let mut cigar = Vec::new();
+        for op in self.aligner.cigar() {
+            let op = match op {
+                b'M' | b'X' | b'=' => CigarOp::Match(1),
+                b'I' => CigarOp::Insertion(1),
+                b'D' => CigarOp::Deletion(1),
+                _ => continue,
+            };
+            push_op(&mut cigar, op);
+        }
+
+        cigar
+    }
+}
@@ -0,0 +1,349 @@
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+
+use rustc_hash::{FxHashMap as HashMap, FxHasher};
+use tracing::{info, warn};
+
+use crate::aligners::CigarOp;
+use crate::error::{HerroError, Result};
+use crate::faidx::read_at;
+use crate::haec_io::HAECRecord;
+use crate::overlaps::{parse_cigar, Alignment, Overlap};
+
+const CACHE_PREFIX: &str = "cigars.";
+const CACHE_SUFFIX: &str = ".tsv";
+/// Reads are hashed in chunks of this many reads, so the fingerprint does not
+/// depend on the number of threads
+const FINGERPRINT_CHUNK: usize = 1024;
+
+/// CIGARs of the realigned overlaps stored in a cache directory, so later runs
+/// on the same reads and overlaps do not align them again. Each run appends
+/// the CIGARs it computes to a new file in the directory, as TSV lines with
+/// the query id, start, end, strand, target id, start, end and the CIGAR.
+///
+/// Each file starts with a header with the herro version, the aligner
+/// settings and a fingerprint of the reads. Files with another header were
+/// computed for other reads or with another aligner and are not used. Only
+/// the position of each CIGAR in the files is kept in memory, CIGARs are read
+/// when the overlap is looked up.
+pub(crate) struct AlignmentCache {
+    files: Vec<File>,
+    /// Hash of the key -> (file, offset and length of the line)
+    index: HashMap<u64, (u32, u64, u32)>,
+    writer: Mutex<BufWriter<File>>,
+}
+
+/// Key of the overlap, the line of the CIGAR up to the CIGAR itself.
+fn cache_key(overlap: &Overlap, reads: &[HAECRecord]) -> Vec<u8> {
+    let mut key = Vec::new();
+    key.extend_from_slice(&reads[overlap.qid as usize].id);
+    write!(
+        key,
+        "\t{}\t{}\t{}\t",
+        overlap.qstart, overlap.qend, overlap.strand
+    )
+    .unwrap();
+    key.extend_from_slice(&reads[overlap.tid as usize].id);
+    write!(key, "\t{}\t{}\t", overlap.tstart, overlap.tend).unwrap();
+    key
+}
+
+/// Header of the cache files, for the given aligner settings and reads.
+fn cache_header(aligner: &str, fingerprint: u64) -> Vec<u8> {
+    format!(
+        "#herro {}\t{}\treads {:016x}\n",
+        env!("CARGO_PKG_VERSION"),
+        aligner,
+        fingerprint
+    )
+    .into_bytes()
+}
+
+/// Fingerprint of the ids and sequences of the reads. Indexed reads are
+/// fetched from their file.
+pub(crate) fn reads_fingerprint(reads: &[HAECRecord], threads: usize) -> Result<u64> {
+    let chunks: Vec<_> = reads.chunks(FINGERPRINT_CHUNK).collect();
+    let threads = threads.clamp(1, chunks.len().max(1));
+    let mut hashes: Vec<(usize, u64)> = thread::scope(|s| {
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                let chunks = &chunks;
+                s.spawn(move || -> Result<Vec<_>> {
+                    let mut buffer = Vec::new();
+                    let mut hashes = Vec::new();
+                    for (i, chunk) in chunks.iter().enumerate().skip(t).step_by(threads) {
+                        let mut hasher = FxHasher::default();
+                        for read in chunk.iter() {
+                            buffer.resize(read.seq.len(), 0);
+                            read.seq.get_sequence(&mut buffer)?;
+                            read.id.hash(&mut hasher);
+                            buffer.hash(&mut hasher);
+                        }
+                        hashes.push((i, hasher.finish()));
+                    }
+
+                    Ok(hashes)
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Result<Vec<_>>>()
+    })?
+    .into_iter()
+    .flatten()
+    .collect();
+    hashes.sort_unstable();
+
+    let mut hasher = FxHasher::default();
+    hashes.iter().for_each(|(_, hash)| hash.hash(&mut hasher));
+    Ok(hasher.finish())
+}
+
+fn hash_key(key: &[u8]) -> u64 {
+    let mut hasher = FxHasher::default();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Splits the line into the key and the CIGAR, None if the line has fewer
+/// than eight columns.
+fn split_line(line: &[u8]) -> Option<(&[u8], &[u8])> {
+    let pos = line
+        .iter()
+        .enumerate()
+        .filter(|(_, &c)| c == b'\t')
+        .nth(6)
+        .map(|(i, _)| i)?;
+    Some(line.split_at(pos + 1))
+}
+
+fn write_cigar(mut writer: impl Write, cigar: &[CigarOp]) -> io::Result<()> {
+    for op in cigar {
+        let c = match op {
+            CigarOp::Match(_) | CigarOp::Mismatch(_) => 'M',
+            CigarOp::Insertion(_) => 'I',
+            CigarOp::Deletion(_) => 'D',
+        };
+        write!(writer, "{}{}", op.get_length(), c)?;
+    }
+    Ok(())
+}
+
+impl AlignmentCache {
+    /// Indexes the cache files in the directory, which is created if it does
+    /// not exist, and creates the file for the CIGARs of this run. Files with
+    /// other aligner settings or reads, and
+    /// incomplete lines of interrupted runs are ignored.
+    pub(crate) fn open<P: AsRef<Path>>(
+        dir: P,
+        aligner: &str,
+        reads: &[HAECRecord],
+        threads: usize,
+    ) -> Result<Self> {
+        let dir = dir.as_ref();
+        let header = cache_header(aligner, reads_fingerprint(reads, threads)?);
+        fs::create_dir_all(dir).map_err(|e| HerroError::from(e).in_file(dir))?;
+
+        let mut paths: Vec<_> = fs::read_dir(dir)
+            .map_err(|e| HerroError::from(e).in_file(dir))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| {
+                        name.starts_with(CACHE_PREFIX) && name.ends_with(CACHE_SUFFIX)
+                    })
+            })
+            .collect();
+        paths.sort();
+
+        let mut files = Vec::with_capacity(paths.len());
+        let mut index = HashMap::default();
+        let mut n_ignored = 0;
+        for path in paths.iter() {
+            let file = File::open(path).map_err(|e| HerroError::from(e).in_file(path))?;
+            let fid = files.len() as u32;
+            let is_indexed = Self::index_file(&file, &header, fid, &mut index)
+                .map_err(|e| HerroError::from(e).in_file(path))?;
+            match is_indexed {
+                true => files.push(file),
+                false => n_ignored += 1,
+            }
+        }
+        if n_ignored > 0 {
+            warn!(
+                "Ignoring {} alignment cache files computed for other reads or with another aligner",
+                n_ignored
+            );
+        }
+        info!(
+            "Alignment cache has {} CIGARs in {} files",
+            index.len(),
+            files.len()
+        );
+
+        // New file gets the first unused number
+        let path = (paths.len()..)
+            .map(|n| dir.join(format!("{}{}{}", CACHE_PREFIX, n, CACHE_SUFFIX)))
+            .find(|path| !path.exists())
+            .unwrap();
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|e| HerroError::from(e).in_file(&path))?;
+        let mut writer = BufWriter::new(file);
+        writer
+            .write_all(&header)
+            .map_err(|e| HerroError::from(e).in_file(&path))?;
+
+        Ok(AlignmentCache {
+            files,
+            index,
+            writer: Mutex::new(writer),
+        })
+    }
+
+    /// Indexes the CIGARs of the file, returns false without indexing them if
+    /// the file does not start with the header.
+    fn index_file(
+        file: &File,
+        header: &[u8],
+        fid: u32,
+        index: &mut HashMap<u64, (u32, u64, u32)>,
+    ) -> io::Result<bool> {
+        let mut reader = BufReader::new(file);
+        let mut line = Vec::new();
+        reader.read_until(b'\n', &mut line)?;
+        if line != header {
+            return Ok(false);
+        }
+
+        let mut offset = header.len() as u64;
+        loop {
+            line.clear();
+            let len = reader.read_until(b'\n', &mut line)?;
+            if len == 0 || line.last() != Some(&b'\n') {
+                return Ok(true);
+            }
+
+            if let Some((key, _)) = split_line(&line) {
+                index.insert(hash_key(key), (fid, offset, len as u32 - 1));
+            }
+            offset += len as u64;
+        }
+    }
+
+    /// Returns the cached CIGAR of the overlap.
+    pub(crate) fn get(&self, overlap: &Overlap, reads: &[HAECRecord]) -> Option<Vec<CigarOp>> {
+        let key = cache_key(overlap, reads);
+        let &(fid, offset, len) = self.index.get(&hash_key(&key))?;
+
+        let mut line = vec![0; len as usize];
+        let mut read = 0;
+        while read < line.len() {
+            match read_at(
+                &self.files[fid as usize],
+                &mut line[read..],
+                offset + read as u64,
+            ) {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => read += n,
+            }
+        }
+
+        // Hashes of different keys can collide
+        let (line_key, cigar) = split_line(&line)?;
+        if line_key != key {
+            return None;
+        }
+        parse_cigar(cigar).ok()
+    }
+
+    /// Appends the CIGARs of the alignments to the file of this run. Errors
+    /// are reported, but the CIGARs are still used.
+    pub(crate) fn insert<'a>(
+        &self,
+        alignments: impl IntoIterator<Item = &'a Alignment>,
+        reads: &[HAECRecord],
+    ) {
+        let mut writer = self.writer.lock().unwrap();
+        let result = alignments.into_iter().try_for_each(|aln| {
+            writer.write_all(&cache_key(&aln.overlap, reads))?;
+            write_cigar(&mut *writer, &aln.cigar)?;
+            writer.write_all(b"\n")
+        });
+
+        if let Err(e) = result {
+            warn!("Cannot write to the alignment cache: {}", e);
+        }
+    }
+
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::AlignmentCache;
+    use crate::aligners::CigarOp;
+    use crate::haec_io::{HAECRecord, HAECSeq};
+    use crate::overlaps::{Alignment, Overlap, Strand};
+
+    #[test]
+    fn cached_cigars() {
+        let dir = std::env::temp_dir().join(format!("herro-aln-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let records = |seq: &[u8]| -> Vec<_> {
+            [&b"r0"[..], b"r1", b"r2"]
+                .into_iter()
+                .map(|id| HAECRecord {
+                    id: id.to_vec().into(),
+                    description: None,
+                    seq: HAECSeq::from(seq),
+                    qual: vec![b'!'; 10].into(),
+                })
+                .collect()
+        };
+        let reads = records(b"ACGTACGTAC");
+        let overlap = |qid, strand| Overlap::new(qid, 10, 2, 8, strand, 0, 10, 1, 8);
+        let cigar = vec![CigarOp::Match(3), CigarOp::Deletion(1), CigarOp::Match(3)];
+
+        let cache = AlignmentCache::open(&dir, "banded", &reads, 2).unwrap();
+        assert!(cache.get(&overlap(1, Strand::Forward), &reads).is_none());
+        cache.insert(
+            [&Alignment::new(overlap(1, Strand::Forward), cigar.clone())],
+            &reads,
+        );
+        cache.flush().unwrap();
+
+        // Second run finds the CIGAR written by the first one
+        let cache = AlignmentCache::open(&dir, "banded", &reads, 1).unwrap();
+        assert_eq!(cache.get(&overlap(1, Strand::Forward), &reads), Some(cigar));
+        assert!(cache.get(&overlap(1, Strand::Reverse), &reads).is_none());
+        assert!(cache.get(&overlap(2, Strand::Forward), &reads).is_none());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        // CIGARs of another aligner or other reads with the same ids are not used
+        let cache = AlignmentCache::open(&dir, "simd", &reads, 1).unwrap();
+        assert!(cache.get(&overlap(1, Strand::Forward), &reads).is_none());
+        let other_reads = records(b"ACGTTCGTAC");
+        let cache = AlignmentCache::open(&dir, "banded", &other_reads, 1).unwrap();
+        assert!(cache
+            .get(&overlap(1, Strand::Forward), &other_reads)
+            .is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
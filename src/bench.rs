@@ -0,0 +1,530 @@
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::unbounded;
+use rustc_hash::FxHashSet as HashSet;
+use tracing::info;
+
+use crate::aligners::pairwise_aligner;
+use crate::config::validate_window_stride;
+use crate::error::{HerroError, Result};
+use crate::features::{align_overlaps, encode_features, InferenceOutput, WindowParams};
+use crate::inference::{
+    check_quantization, feature_encoder, inference_worker, load_models,
+    resolve_ensemble_window_size, resolve_feature_layout, resolve_feature_set, resolve_top_k,
+    verify_model, ModelSpec,
+};
+use crate::overlaps::{alignment_reader, Alignment, OverlapCounts};
+use crate::telemetry::DeviceStats;
+use crate::{parse_reads, Aligner, AlnMode, CorrectionConfig};
+
+/// Length of the synthetic reads, before the errors are added
+const SYNTHETIC_READ_LENGTH: usize = 30_000;
+const SYNTHETIC_COVERAGE: usize = 20;
+/// Percent of the bases of the synthetic reads with a substitution, deletion
+/// and insertion, similar to older nanopore reads
+const SYNTHETIC_ERRORS: (u64, u64, u64) = (2, 1, 2);
+/// Phred quality of the bases of the synthetic reads (Q20)
+const SYNTHETIC_QUAL: u8 = b'5';
+const MIN_SYNTHETIC_OVERLAP: usize = 2_000;
+const BASES: &[u8; 4] = b"ACGT";
+
+/// Throughput of the stages of the correction measured by `bench`.
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    n_reads: usize,
+    stages: Vec<StageThroughput>,
+}
+
+#[derive(Debug, Clone)]
+struct StageThroughput {
+    /// Name of the stage with its threads or devices
+    name: String,
+    elapsed: Duration,
+    count: u64,
+    unit: &'static str,
+    /// Bases of the target reads covered by the processed overlaps
+    bases: Option<u64>,
+}
+
+impl StageThroughput {
+    fn per_second(&self, n: u64) -> f64 {
+        n as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<40}{}", "Target reads", self.n_reads)?;
+        for stage in self.stages.iter() {
+            write!(
+                f,
+                "{:<40}{} {} in {:.2} s, {:.1} {}/s",
+                stage.name,
+                stage.count,
+                stage.unit,
+                stage.elapsed.as_secs_f64(),
+                stage.per_second(stage.count),
+                stage.unit
+            )?;
+            if let Some(bases) = stage.bases {
+                write!(f, ", {:.2} Mb/s", stage.per_second(bases) / 1e6)?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Measures the throughput of the alignment, feature generation and inference
+/// on the first `n_reads` target reads with their overlaps, or on a synthetic
+/// dataset if no reads are given. Stages run one after another with all of
+/// their threads, so each of them is measured on its own.
+pub fn bench<T: AsRef<Path>>(
+    model_paths: &[&str],
+    dataset: Option<(T, T)>,
+    n_reads: usize,
+    config: &CorrectionConfig,
+) -> Result<BenchReport> {
+    if n_reads == 0 {
+        return Err(HerroError::InvalidConfig(
+            "number of benchmark reads must be positive".to_string(),
+        ));
+    }
+
+    match dataset {
+        Some((reads, overlaps)) => run_bench(model_paths, (reads, overlaps), n_reads, config),
+        None => {
+            let dir = env::temp_dir().join(format!("herro-bench-{}", process::id()));
+            let paths = write_synthetic_dataset(&dir, n_reads)
+                .map_err(|e| HerroError::from(e).in_file(&dir))?;
+            info!(
+                "Generated {} synthetic reads of {} bases with {}x coverage",
+                n_reads, SYNTHETIC_READ_LENGTH, SYNTHETIC_COVERAGE
+            );
+            let report = run_bench(model_paths, paths, n_reads, config);
+            let _ = fs::remove_dir_all(&dir);
+            report
+        }
+    }
+}
+
+fn run_bench<T: AsRef<Path>>(
+    model_paths: &[&str],
+    (reads_path, overlaps_path): (T, T),
+    n_reads: usize,
+    config: &CorrectionConfig,
+) -> Result<BenchReport> {
+    let specs = model_paths
+        .iter()
+        .map(|&path| {
+            File::open(path).map_err(|e| HerroError::from(e).in_file(path))?;
+            ModelSpec::load(path.as_ref())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    check_quantization(config.precision, &specs)?;
+    let window_size = resolve_ensemble_window_size(config.window_size, &specs)?;
+    let window_stride = config.window_stride.unwrap_or(window_size);
+    validate_window_stride(window_size, window_stride)?;
+    let top_k = resolve_top_k(config.top_k, &specs)?;
+    let window_params = WindowParams {
+        size: window_size,
+        stride: window_stride,
+        top_k,
+        min_accuracy: config.overlap_filter.min_window_accuracy,
+        selection: config.overlap_filter.window_reads,
+    };
+    let layout = resolve_feature_layout(
+        config.qual_encoding,
+        config.homopolymer_channel,
+        config.feature_layout,
+        &specs,
+    )?;
+    let encoder = feature_encoder(resolve_feature_set(config.feature_set, &specs)?, layout);
+
+    let reads = parse_reads(
+        &reads_path,
+        window_size,
+        true,
+        false,
+        config.duplicate_ids,
+        true,
+    )?;
+    let max_len = reads.iter().map(|r| r.seq.len()).max().unwrap();
+    let n_reads = n_reads.min(reads.len());
+    let skipped: HashSet<_> = (n_reads as u32..reads.len() as u32).collect();
+
+    // Progress is not shown, but the notifications have to be received
+    let (pbar_sender, _pbar_receiver) = unbounded();
+    let (alns_sender, alns_receiver) = unbounded();
+    alignment_reader(
+        &reads,
+        &reads_path,
+        AlnMode::Overlaps(vec![overlaps_path]),
+        config.overlaps_format,
+        config.threads,
+        config.overlap_filter,
+        config.trust_cigar,
+        config.target_batch.unwrap_or_default(),
+        &skipped,
+        false,
+        alns_sender,
+        pbar_sender.clone(),
+        &OverlapCounts::default(),
+    )?;
+    let targets: Vec<_> = alns_receiver.into_iter().collect();
+    let mut stages = Vec::with_capacity(4);
+
+    let n_devices = config.devices.len();
+    let align_threads = config.align_threads.unwrap_or(config.threads) * n_devices;
+    let overlaps = targets.iter().flat_map(|(_, alns)| alns.iter());
+    let n_overlaps = overlaps.clone().count() as u64;
+    let n_bases = overlaps
+        .map(|aln| (aln.overlap.tend - aln.overlap.tstart) as u64)
+        .sum();
+    let align = |aligner: Aligner, targets: Vec<(u32, Vec<Alignment>)>| -> Result<_> {
+        let aligners = (0..align_threads)
+            .map(|_| {
+                Ok((
+                    pairwise_aligner(aligner)?,
+                    vec![0; max_len],
+                    vec![0; max_len],
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let start = Instant::now();
+        let targets = parallel_map(
+            aligners,
+            targets,
+            |(aligner, tbuf, qbuf), (rid, mut alns)| {
+                align_overlaps(&mut alns, &reads, (aligner.as_mut(), None, tbuf, qbuf))?;
+                Ok((rid, alns))
+            },
+        )
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+        let stage = StageThroughput {
+            name: format!(
+                "Alignment, {:?} aligner ({} threads)",
+                aligner, align_threads
+            ),
+            elapsed: start.elapsed(),
+            count: n_overlaps,
+            unit: "overlaps",
+            bases: Some(n_bases),
+        };
+        Ok((targets, stage))
+    };
+    // Other aligners are compared with the banded one on the same overlaps
+    if config.aligner != Aligner::Banded {
+        let (_, stage) = align(Aligner::Banded, targets.clone())?;
+        stages.push(stage);
+    }
+    let (targets, stage) = align(config.aligner, targets)?;
+    stages.push(stage);
+
+    let encode_threads = config.encode_threads.unwrap_or(config.threads) * n_devices;
+    let (infer_sender, infer_receiver) = unbounded();
+    let outputs = (0..encode_threads)
+        .map(|_| {
+            let output = InferenceOutput::new(
+                infer_sender.clone(),
+                config.batch_size,
+                (config.min_coverage as usize, top_k),
+                encoder.clone(),
+                pbar_sender.clone(),
+            );
+            (output, vec![0; max_len], vec![0; max_len])
+        })
+        .collect();
+    drop(infer_sender);
+    let start = Instant::now();
+    parallel_map(outputs, targets, |(output, tbuf, qbuf), (rid, alns)| {
+        encode_features(rid, &reads, alns, window_params, &[], (tbuf, qbuf), output)
+    })
+    .into_iter()
+    .collect::<Result<()>>()?;
+    let data: Vec<_> = infer_receiver.into_iter().collect();
+    let n_windows = data.iter().map(|d| d.n_windows() as u64).sum();
+    stages.push(StageThroughput {
+        name: format!("Feature generation ({} threads)", encode_threads),
+        elapsed: start.elapsed(),
+        count: n_windows,
+        unit: "windows",
+        bases: None,
+    });
+
+    // Each inference worker has its own copy of the models, as in the correction
+    let workers = config.workers_per_device;
+    let models = config
+        .devices
+        .iter()
+        .flat_map(|&d| iter::repeat_n(d, workers))
+        .map(|d| {
+            let model = load_models(config, model_paths, d)?;
+            verify_model(model.as_ref(), (window_size, top_k), encoder.as_ref())?;
+            Ok(model)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let device_stats: Vec<_> = config
+        .devices
+        .iter()
+        .map(|&d| DeviceStats::new(d, workers))
+        .collect();
+    let (infer_sender, infer_receiver) = unbounded();
+    data.into_iter().for_each(|d| infer_sender.send(d).unwrap());
+    drop(infer_sender);
+    let start = Instant::now();
+    thread::scope(|s| {
+        for (i, model) in models.into_iter().enumerate() {
+            // Predictions are not decoded
+            let (decode_sender, decode_receiver) = unbounded();
+            let infer_r = infer_receiver.clone();
+            let pbar_s = pbar_sender.clone();
+            let stats = &device_stats[i / workers];
+            s.spawn(move || inference_worker(model, infer_r, decode_sender, pbar_s, stats));
+            s.spawn(move || decode_receiver.into_iter().for_each(drop));
+        }
+    });
+    let devices: Vec<_> = config.devices.iter().map(|d| d.to_string()).collect();
+    stages.push(StageThroughput {
+        name: format!(
+            "Inference ({}, batch size {})",
+            devices.join(","),
+            config.batch_size
+        ),
+        elapsed: start.elapsed(),
+        count: n_windows,
+        unit: "windows",
+        bases: None,
+    });
+
+    Ok(BenchReport { n_reads, stages })
+}
+
+/// Processes the items with a thread for each state, returns the outputs in no
+/// particular order.
+fn parallel_map<S, I, O, F>(states: Vec<S>, items: Vec<I>, f: F) -> Vec<O>
+where
+    S: Send,
+    I: Send,
+    O: Send,
+    F: Fn(&mut S, I) -> O + Sync,
+{
+    let (sender, receiver) = unbounded();
+    items
+        .into_iter()
+        .for_each(|item| sender.send(item).unwrap());
+    drop(sender);
+
+    thread::scope(|s| {
+        let handles: Vec<_> = states
+            .into_iter()
+            .map(|mut state| {
+                let receiver = receiver.clone();
+                let f = &f;
+                s.spawn(move || {
+                    receiver
+                        .into_iter()
+                        .map(|item| f(&mut state, item))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect()
+    })
+}
+
+/// Deterministic generator of the synthetic dataset (SplitMix64).
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut x = self.0;
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+        x ^ (x >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn base(&mut self) -> u8 {
+        BASES[self.below(4) as usize]
+    }
+}
+
+/// Synthetic read sampled from the genome.
+struct SyntheticRead {
+    seq: Vec<u8>,
+    /// Start of the read in the genome
+    start: usize,
+    reverse: bool,
+}
+
+impl SyntheticRead {
+    /// Span of the genome interval `[start, end)` in the coordinates of the
+    /// read, estimated from the read length since the errors shift the bases.
+    fn span(&self, start: usize, end: usize) -> (usize, usize) {
+        let len = self.seq.len();
+        let pos = |g: usize| (g - self.start) * len / SYNTHETIC_READ_LENGTH;
+        match self.reverse {
+            false => (pos(start), pos(end)),
+            true => (len - pos(end), len - pos(start)),
+        }
+    }
+}
+
+fn synthetic_reads(n_reads: usize) -> Vec<SyntheticRead> {
+    let mut rng = SplitMix64(0);
+    let genome_len =
+        (n_reads * SYNTHETIC_READ_LENGTH / SYNTHETIC_COVERAGE).max(2 * SYNTHETIC_READ_LENGTH);
+    let genome: Vec<_> = (0..genome_len).map(|_| rng.base()).collect();
+
+    let (substitutions, deletions, insertions) = SYNTHETIC_ERRORS;
+    (0..n_reads)
+        .map(|_| {
+            let start = rng.below((genome_len - SYNTHETIC_READ_LENGTH) as u64) as usize;
+            let mut seq = Vec::with_capacity(SYNTHETIC_READ_LENGTH + SYNTHETIC_READ_LENGTH / 10);
+            for &base in genome[start..start + SYNTHETIC_READ_LENGTH].iter() {
+                let error = rng.below(100);
+                if error < substitutions {
+                    let i = BASES.iter().position(|&b| b == base).unwrap();
+                    seq.push(BASES[(i + 1 + rng.below(3) as usize) % 4]);
+                } else if error < substitutions + deletions {
+                    continue;
+                } else if error < substitutions + deletions + insertions {
+                    seq.extend([rng.base(), base]);
+                } else {
+                    seq.push(base);
+                }
+            }
+
+            let reverse = rng.below(2) == 1;
+            if reverse {
+                seq.reverse();
+                seq.iter_mut().for_each(|b| {
+                    *b = match *b {
+                        b'A' => b'T',
+                        b'C' => b'G',
+                        b'G' => b'C',
+                        _ => b'A',
+                    }
+                });
+            }
+
+            SyntheticRead {
+                seq,
+                start,
+                reverse,
+            }
+        })
+        .collect()
+}
+
+/// Writes synthetic reads sampled with errors from a random genome and their
+/// overlaps without CIGARs, so all of them are aligned. Returns the paths of
+/// the reads and the overlaps.
+fn write_synthetic_dataset(dir: &Path, n_reads: usize) -> io::Result<(PathBuf, PathBuf)> {
+    fs::create_dir_all(dir)?;
+    let reads = synthetic_reads(n_reads);
+
+    let reads_path = dir.join("reads.fastq");
+    let mut writer = BufWriter::new(File::create(&reads_path)?);
+    for (i, read) in reads.iter().enumerate() {
+        writeln!(writer, "@synthetic_{}", i)?;
+        writer.write_all(&read.seq)?;
+        writer.write_all(b"\n+\n")?;
+        writer.write_all(&vec![SYNTHETIC_QUAL; read.seq.len()])?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+
+    // Each pair of reads is overlapped once, as in the minimap2 all-vs-all overlaps
+    let mut order: Vec<_> = (0..reads.len()).collect();
+    order.sort_by_key(|&i| reads[i].start);
+    let overlaps_path = dir.join("overlaps.paf");
+    let mut writer = BufWriter::new(File::create(&overlaps_path)?);
+    for (k, &t) in order.iter().enumerate() {
+        let target = &reads[t];
+        let end = target.start + SYNTHETIC_READ_LENGTH;
+        for &q in order[k + 1..].iter() {
+            let query = &reads[q];
+            if query.start + MIN_SYNTHETIC_OVERLAP > end {
+                break;
+            }
+
+            let (qstart, qend) = query.span(query.start, end);
+            let (tstart, tend) = target.span(query.start, end);
+            let strand = if query.reverse == target.reverse {
+                '+'
+            } else {
+                '-'
+            };
+            let block = tend - tstart;
+            writeln!(
+                writer,
+                "synthetic_{}\t{}\t{}\t{}\t{}\tsynthetic_{}\t{}\t{}\t{}\t{}\t{}\t60",
+                q,
+                query.seq.len(),
+                qstart,
+                qend,
+                strand,
+                t,
+                target.seq.len(),
+                tstart,
+                tend,
+                block * 9 / 10,
+                block
+            )?;
+        }
+    }
+    writer.flush()?;
+
+    Ok((reads_path, overlaps_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{write_synthetic_dataset, SYNTHETIC_READ_LENGTH};
+    use crate::haec_io;
+
+    #[test]
+    fn synthetic_dataset() {
+        let dir = std::env::temp_dir().join(format!("herro-bench-test-{}", std::process::id()));
+        let (reads_path, overlaps_path) = write_synthetic_dataset(&dir, 40).unwrap();
+
+        let reads = haec_io::get_reads(&reads_path, 0, true).unwrap();
+        assert_eq!(reads.len(), 40);
+        assert!(reads
+            .iter()
+            .all(|r| r.seq.len().abs_diff(SYNTHETIC_READ_LENGTH) < SYNTHETIC_READ_LENGTH / 20));
+
+        let overlaps = fs::read_to_string(&overlaps_path).unwrap();
+        assert!(overlaps.lines().count() > 40);
+        for line in overlaps.lines() {
+            let fields: Vec<_> = line.split('\t').collect();
+            let number = |i: usize| fields[i].parse::<usize>().unwrap();
+            assert_ne!(fields[0], fields[5]);
+            assert!(number(2) < number(3) && number(3) <= number(1));
+            assert!(number(7) < number(8) && number(8) <= number(6));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
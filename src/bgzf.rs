@@ -0,0 +1,186 @@
+use std::io::{self, Write};
+use std::thread;
+
+use flate2::{write::DeflateEncoder, Compression, Crc};
+
+/// Maximum size of the uncompressed data in a block, same as in bgzip
+const BLOCK_SIZE: usize = 0xff00;
+const MAX_COMPRESSED_BLOCK_SIZE: usize = 0x10000;
+const FOOTER_SIZE: usize = 8;
+
+/// Gzip header with the BC extra field, BSIZE is set after compression
+const BLOCK_HEADER: [u8; 18] = [
+    0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 0x06, 0, b'B', b'C', 0x02, 0, 0, 0,
+];
+
+/// Empty block which marks the end of the BGZF file
+const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 0x06, 0, b'B', b'C', 0x02, 0, 0x1b, 0, 0x03, 0, 0,
+    0, 0, 0, 0, 0, 0, 0,
+];
+
+/// Writer for the blocked gzip format (BGZF) produced by bgzip. Output can be
+/// read by any gzip decompressor. Data is compressed in batches of blocks, one
+/// block per thread. Remaining data and the EOF block are written by `finish`,
+/// or on drop, where their errors cannot be returned.
+pub(crate) struct BgzfWriter<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+    threads: usize,
+    finished: bool,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub(crate) fn new(inner: W, threads: usize) -> Self {
+        let threads = threads.max(1);
+
+        Self {
+            inner,
+            buffer: Vec::with_capacity(threads * BLOCK_SIZE),
+            threads,
+            finished: false,
+        }
+    }
+
+    /// Compresses and writes the first `length` bytes of the buffer.
+    fn write_blocks(&mut self, length: usize) -> io::Result<()> {
+        let data = &self.buffer[..length];
+        let blocks: Vec<_> = if length <= BLOCK_SIZE {
+            vec![compress_block(data)]
+        } else {
+            thread::scope(|s| {
+                let handles: Vec<_> = data
+                    .chunks(BLOCK_SIZE)
+                    .map(|chunk| s.spawn(move || compress_block(chunk)))
+                    .collect();
+
+                handles.into_iter().map(|h| h.join().unwrap()).collect()
+            })
+        };
+
+        for block in blocks {
+            self.inner.write_all(&block?)?;
+        }
+
+        self.buffer.drain(..length);
+        Ok(())
+    }
+
+    pub(crate) fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+
+        if !self.buffer.is_empty() {
+            self.write_blocks(self.buffer.len())?;
+        }
+        self.inner.write_all(&EOF_BLOCK)?;
+        self.inner.flush()?;
+
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+
+        if self.buffer.len() >= self.threads * BLOCK_SIZE {
+            let length = self.buffer.len() / BLOCK_SIZE * BLOCK_SIZE;
+            self.write_blocks(length)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.write_blocks(self.buffer.len())?;
+        }
+
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for BgzfWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+pub(crate) fn compress_block(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut block = deflate(data, Compression::default())?;
+
+    // Incompressible data has to be stored to fit into a single block
+    if block.len() + FOOTER_SIZE > MAX_COMPRESSED_BLOCK_SIZE {
+        block = deflate(data, Compression::none())?;
+    }
+
+    let mut crc = Crc::new();
+    crc.update(data);
+    block.extend_from_slice(&crc.sum().to_le_bytes());
+    block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+    let bsize = (block.len() - 1) as u16;
+    block[16..18].copy_from_slice(&bsize.to_le_bytes());
+
+    Ok(block)
+}
+
+fn deflate(data: &[u8], level: Compression) -> io::Result<Vec<u8>> {
+    let mut block = Vec::with_capacity(BLOCK_HEADER.len() + data.len() + FOOTER_SIZE);
+    block.extend_from_slice(&BLOCK_HEADER);
+
+    let mut encoder = DeflateEncoder::new(block, level);
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use flate2::read::MultiGzDecoder;
+
+    use super::{BgzfWriter, BLOCK_SIZE, EOF_BLOCK};
+
+    #[test]
+    fn write_bgzf() {
+        // Pseudo-random data, so some blocks are stored without compression
+        let mut state = 7u32;
+        let data: Vec<u8> = (0..5 * BLOCK_SIZE + 123)
+            .map(|i| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                if i < 2 * BLOCK_SIZE {
+                    b"ACGT"[i % 4]
+                } else {
+                    (state >> 16) as u8
+                }
+            })
+            .collect();
+
+        let mut output = Vec::new();
+        {
+            let mut writer = BgzfWriter::new(&mut output, 3);
+            data.chunks(1000).for_each(|c| writer.write_all(c).unwrap());
+        }
+        assert!(output.ends_with(&EOF_BLOCK));
+
+        // Check block sizes stored in the BC field
+        let (mut offset, mut n_blocks) = (0, 0);
+        while offset < output.len() {
+            let bsize = u16::from_le_bytes([output[offset + 16], output[offset + 17]]);
+            offset += bsize as usize + 1;
+            n_blocks += 1;
+        }
+        assert_eq!(offset, output.len());
+        assert_eq!(n_blocks, 7);
+
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(&output[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+    }
+}
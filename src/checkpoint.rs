@@ -0,0 +1,240 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use rustc_hash::FxHashSet as HashSet;
+
+use crate::error::{HerroError, Result};
+use crate::LINE_ENDING;
+
+/// Number of corrected reads between two checkpoints
+pub(crate) const CHECKPOINT_INTERVAL: usize = 1000;
+
+/// Prefix of the line which stores the output size. Read ids cannot contain
+/// tabs, so these lines cannot be mistaken for ids.
+const OUTPUT_SIZE_PREFIX: &[u8] = b"#\t";
+
+/// Checkpoint file stores the ids of corrected reads. After every batch of ids,
+/// a line with the size of the flushed output is written, which marks the ids
+/// before it as completed.
+pub(crate) fn checkpoint_path<P: AsRef<Path>>(output_path: P) -> PathBuf {
+    let mut path = output_path.as_ref().as_os_str().to_owned();
+    path.push(".checkpoint");
+    PathBuf::from(path)
+}
+
+/// State of the interrupted run stored in the checkpoint.
+#[derive(Debug, Default)]
+pub(crate) struct Checkpoint {
+    /// Ids of reads whose corrected sequences were written to the output
+    pub(crate) completed: HashSet<Vec<u8>>,
+    /// Size of the output when the last checkpoint was written
+    pub(crate) output_size: u64,
+    /// Size of the checkpoint file up to the last output size line
+    checkpoint_size: u64,
+}
+
+/// Reads the checkpoint file. Ids written after the last output size line are
+/// ignored since their corrected reads might not be in the output.
+pub(crate) fn read_checkpoint(reader: impl BufRead) -> io::Result<Checkpoint> {
+    let mut checkpoint = Checkpoint::default();
+    let mut pending = Vec::new();
+    let mut size = 0;
+
+    for line in reader.split(LINE_ENDING) {
+        let line = line?;
+        size += line.len() as u64 + 1;
+
+        match line.strip_prefix(OUTPUT_SIZE_PREFIX) {
+            Some(output_size) => {
+                checkpoint.output_size = std::str::from_utf8(output_size)
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "invalid output size")
+                    })?;
+                checkpoint.checkpoint_size = size;
+                checkpoint.completed.extend(pending.drain(..));
+            }
+            None => pending.push(line),
+        }
+    }
+
+    Ok(checkpoint)
+}
+
+/// Opens the output and its checkpoint file. If `resume` is set and the
+/// checkpoint exists, the output is truncated to the last checkpoint and the
+/// completed read ids are returned. Otherwise, both files are created. Without
+/// `checkpoint` and `resume`, only the output is created and no checkpoints
+/// are written.
+pub(crate) fn open_output<P: AsRef<Path>>(
+    output_path: P,
+    resume: bool,
+    checkpoint: bool,
+) -> Result<(File, CheckpointWriter, HashSet<Vec<u8>>)> {
+    let output_path = output_path.as_ref();
+    let checkpoint_path = checkpoint_path(output_path);
+    if !checkpoint && !resume {
+        // Checkpoint of an earlier run does not match the new output
+        remove_checkpoint(output_path)?;
+        let output =
+            File::create(output_path).map_err(|e| HerroError::from(e).in_file(output_path))?;
+        return Ok((output, CheckpointWriter::disabled(), HashSet::default()));
+    }
+
+    let checkpoint = if resume && checkpoint_path.exists() {
+        let file = File::open(&checkpoint_path)
+            .map_err(|e| HerroError::from(e).in_file(&checkpoint_path))?;
+        let checkpoint = read_checkpoint(BufReader::new(file))
+            .map_err(|e| HerroError::from(e).in_file(&checkpoint_path))?;
+
+        Some(checkpoint)
+    } else {
+        None
+    };
+
+    let (output, checkpoint_file, completed) = match checkpoint {
+        Some(checkpoint) => {
+            let output = truncate_file(output_path, checkpoint.output_size)
+                .map_err(|e| HerroError::from(e).in_file(output_path))?;
+            let checkpoint_file = truncate_file(&checkpoint_path, checkpoint.checkpoint_size)
+                .map_err(|e| HerroError::from(e).in_file(&checkpoint_path))?;
+
+            (output, checkpoint_file, checkpoint.completed)
+        }
+        None => {
+            let output =
+                File::create(output_path).map_err(|e| HerroError::from(e).in_file(output_path))?;
+            let checkpoint_file = File::create(&checkpoint_path)
+                .map_err(|e| HerroError::from(e).in_file(&checkpoint_path))?;
+
+            (output, checkpoint_file, HashSet::default())
+        }
+    };
+
+    let size_handle = output
+        .try_clone()
+        .map_err(|e| HerroError::from(e).in_file(output_path))?;
+    let writer = CheckpointWriter::new(checkpoint_file, size_handle);
+
+    Ok((output, writer, completed))
+}
+
+/// Opens the file for appending after truncating it to `size` bytes.
+fn truncate_file(path: &Path, size: u64) -> io::Result<File> {
+    let file = OpenOptions::new().append(true).open(path)?;
+    if file.metadata()?.len() < size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "file is shorter than recorded in the checkpoint",
+        ));
+    }
+
+    file.set_len(size)?;
+    Ok(file)
+}
+
+/// Records corrected reads in the checkpoint file. Files are flushed but not
+/// synced, so checkpoints survive an interrupted or killed run, but not a
+/// crash of the system.
+pub(crate) struct CheckpointWriter {
+    /// Checkpoint file and a handle of the output, None if checkpoints are
+    /// disabled
+    files: Option<(BufWriter<File>, File)>,
+    n_pending: usize,
+}
+
+impl CheckpointWriter {
+    fn new(checkpoint_file: File, output: File) -> Self {
+        Self {
+            files: Some((BufWriter::new(checkpoint_file), output)),
+            n_pending: 0,
+        }
+    }
+
+    fn disabled() -> Self {
+        Self {
+            files: None,
+            n_pending: 0,
+        }
+    }
+
+    /// Records the read whose corrected sequences were written to the output.
+    /// Returns true if the checkpoint should be saved.
+    pub(crate) fn add(&mut self, id: &[u8]) -> io::Result<bool> {
+        let Some((writer, _)) = self.files.as_mut() else {
+            return Ok(false);
+        };
+        writer.write_all(id)?;
+        writer.write_all(&[LINE_ENDING])?;
+        self.n_pending += 1;
+
+        Ok(self.n_pending >= CHECKPOINT_INTERVAL)
+    }
+
+    /// Flushes the output and marks the recorded reads as completed.
+    pub(crate) fn save(&mut self, output_writer: &mut dyn Write) -> io::Result<()> {
+        output_writer.flush()?;
+        let Some((writer, output)) = self.files.as_mut() else {
+            return Ok(());
+        };
+        let output_size = output.metadata()?.len();
+
+        writer.write_all(OUTPUT_SIZE_PREFIX)?;
+        writeln!(writer, "{}", output_size)?;
+        writer.flush()?;
+
+        self.n_pending = 0;
+        Ok(())
+    }
+}
+
+/// Removes the checkpoint after the output was successfully written, if it
+/// exists.
+pub(crate) fn remove_checkpoint<P: AsRef<Path>>(output_path: P) -> Result<()> {
+    let path = checkpoint_path(output_path);
+    match fs::remove_file(&path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(HerroError::from(e).in_file(&path)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{checkpoint_path, open_output, read_checkpoint};
+
+    #[test]
+    fn read_completed_ids() {
+        let checkpoint = "r1\nr2\n#\t120\nr3\n#\t180\nr4\nr5\n";
+        let checkpoint = read_checkpoint(checkpoint.as_bytes()).unwrap();
+
+        let mut completed: Vec<_> = checkpoint.completed.into_iter().collect();
+        completed.sort();
+        assert_eq!(completed, [b"r1", b"r2", b"r3"]);
+        assert_eq!(checkpoint.output_size, 180);
+        assert_eq!(checkpoint.checkpoint_size, 21);
+    }
+
+    #[test]
+    fn disabled_checkpoints() {
+        let dir = std::env::temp_dir().join(format!("herro-checkpoint-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("reads.fasta");
+        fs::write(checkpoint_path(&output), "r1\n#\t10\n").unwrap();
+
+        // Stale checkpoint is removed and no new one is written
+        let (_, mut checkpoint, completed) = open_output(&output, false, false).unwrap();
+        assert!(completed.is_empty());
+        assert!(!checkpoint.add(b"r1").unwrap());
+        assert!(!checkpoint_path(&output).exists());
+
+        let (_, _, completed) = open_output(&output, false, true).unwrap();
+        assert!(completed.is_empty());
+        assert!(checkpoint_path(&output).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
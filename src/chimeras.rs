@@ -0,0 +1,133 @@
+use tracing::debug;
+
+use crate::haec_io::HAECRecord;
+use crate::overlaps::Alignment;
+use crate::ChimeraMode;
+
+/// Minimum number of bases by which an overlap has to extend past the position
+/// on each side to span it
+const JUNCTION_FLANK: u32 = 1000;
+/// Minimum number of overlaps ending at the junction on each side
+const MIN_JUNCTION_SUPPORT: usize = 3;
+/// Maximum number of spanning overlaps relative to the overlaps ending at the
+/// junction
+const MAX_SPANNING_FRACTION: f32 = 0.1;
+
+/// Finds the chimeric junctions of the target read from its overlaps. Reads
+/// from the same molecule overlap each other across any position, while at the
+/// junction of a chimera, overlaps from both sides end and almost none of them
+/// span it. Candidate positions are the ends of the overlaps, and junctions are
+/// at least twice the flank apart.
+pub(crate) fn find_junctions(tid: u32, read_len: u32, alns: &[Alignment]) -> Vec<u32> {
+    let spans: Vec<_> = alns.iter().map(|a| a.target_span(tid)).collect();
+
+    let mut candidates: Vec<_> = spans
+        .iter()
+        .flat_map(|&(start, end)| [start, end])
+        .filter(|&p| p >= JUNCTION_FLANK && p + JUNCTION_FLANK <= read_len)
+        .filter_map(|p| {
+            let (mut left, mut right, mut spanning) = (0, 0, 0);
+            for &(start, end) in spans.iter() {
+                let covers_left = start + JUNCTION_FLANK <= p;
+                let covers_right = end >= p + JUNCTION_FLANK;
+                match (covers_left, covers_right) {
+                    (true, true) => spanning += 1,
+                    (true, false) if end + JUNCTION_FLANK > p => left += 1,
+                    (false, true) if start < p + JUNCTION_FLANK => right += 1,
+                    _ => (),
+                }
+            }
+
+            let support = left.min(right);
+            let is_junction = support >= MIN_JUNCTION_SUPPORT
+                && spanning as f32 <= MAX_SPANNING_FRACTION * support as f32;
+            is_junction.then_some((support, p))
+        })
+        .collect();
+
+    // Best supported junction of the nearby candidates is kept
+    candidates.sort_unstable_by(|a, b| b.cmp(a));
+    let mut junctions: Vec<u32> = Vec::new();
+    for (_, p) in candidates {
+        if junctions
+            .iter()
+            .all(|j| j.abs_diff(p) >= 2 * JUNCTION_FLANK)
+        {
+            junctions.push(p);
+        }
+    }
+
+    junctions.sort_unstable();
+    junctions
+}
+
+/// Detects the chimeric junctions of the target read and removes the overlaps
+/// spanning them. Returns the junctions at which the corrected read is split,
+/// which are empty if the parts are not split.
+pub(crate) fn resolve_chimeras(
+    tid: u32,
+    reads: &[HAECRecord],
+    alns: &mut Vec<Alignment>,
+    mode: ChimeraMode,
+) -> Vec<u32> {
+    let read = &reads[tid as usize];
+    let junctions = find_junctions(tid, read.seq.len() as u32, alns);
+    if junctions.is_empty() {
+        return junctions;
+    }
+
+    debug!(
+        "Chimeric junctions in {} at {:?}",
+        String::from_utf8_lossy(&read.id),
+        junctions
+    );
+    alns.retain(|aln| {
+        let (start, end) = aln.target_span(tid);
+        !junctions
+            .iter()
+            .any(|&j| start + JUNCTION_FLANK <= j && end >= j + JUNCTION_FLANK)
+    });
+
+    match mode {
+        ChimeraMode::Detect => Vec::new(),
+        ChimeraMode::Split => junctions,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::overlaps::{Alignment, Overlap, Strand};
+
+    use super::find_junctions;
+
+    #[test]
+    fn chimeric_junction() {
+        let aln = |qid, tstart, tend| {
+            let overlap = Overlap::new(
+                qid,
+                20_000,
+                0,
+                tend - tstart,
+                Strand::Forward,
+                0,
+                20_000,
+                tstart,
+                tend,
+            );
+            Alignment::new(overlap, Vec::new())
+        };
+
+        // Reads from the two molecules end at 10,000, one read spans it
+        let mut alns: Vec<_> = (1..5).map(|i| aln(i, i * 1000, 10_000)).collect();
+        alns.extend((5..9).map(|i| aln(i, 10_000, 20_000 - i * 500)));
+        alns.push(aln(9, 0, 20_000));
+        assert!(find_junctions(0, 20_000, &alns).is_empty());
+
+        alns.pop();
+        assert_eq!(find_junctions(0, 20_000, &alns), [10_000]);
+
+        // Overlaps are staggered along a regular read
+        let alns: Vec<_> = (1..20).map(|i| aln(i, i * 500, 10_000 + i * 500)).collect();
+        assert!(find_junctions(0, 20_000, &alns).is_empty());
+    }
+}
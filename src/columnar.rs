@@ -0,0 +1,200 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::Arc;
+
+use arrow::array::{
+    ArrayRef, BinaryBuilder, ListBuilder, StringBuilder, UInt16Builder, UInt32Builder, UInt8Builder,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::RecordBatch;
+use ndarray::Array2;
+
+use crate::error::{HerroError, Result};
+use crate::features::SupportedPos;
+
+/// Number of windows in a record batch (row group for parquet)
+const BATCH_WINDOWS: usize = 64;
+
+/// Columnar file format of the features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TableFormat {
+    Arrow,
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl TableFormat {
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            TableFormat::Arrow => "arrow",
+            #[cfg(feature = "parquet")]
+            TableFormat::Parquet => "parquet",
+        }
+    }
+}
+
+enum Writer {
+    Arrow(FileWriter<BufWriter<File>>),
+    #[cfg(feature = "parquet")]
+    Parquet(parquet::arrow::ArrowWriter<File>),
+}
+
+/// Writes the features with one row per window, buffered into record batches.
+/// Bases and quals (u8) are stored as row-major [length, width] matrices.
+pub(crate) struct TableWriter {
+    writer: Writer,
+    schema: SchemaRef,
+    n_rows: usize,
+    read_id: StringBuilder,
+    window_id: UInt16Builder,
+    length: UInt32Builder,
+    width: UInt32Builder,
+    bases: BinaryBuilder,
+    quals: BinaryBuilder,
+    positions: ListBuilder<UInt16Builder>,
+    insertions: ListBuilder<UInt8Builder>,
+    ids: ListBuilder<StringBuilder>,
+}
+
+fn schema() -> Schema {
+    let list = |data_type| DataType::List(Arc::new(Field::new("item", data_type, true)));
+    Schema::new(vec![
+        Field::new("read_id", DataType::Utf8, false),
+        Field::new("window_id", DataType::UInt16, false),
+        Field::new("length", DataType::UInt32, false),
+        Field::new("width", DataType::UInt32, false),
+        Field::new("bases", DataType::Binary, false),
+        Field::new("quals", DataType::Binary, false),
+        Field::new("positions", list(DataType::UInt16), false),
+        Field::new("insertions", list(DataType::UInt8), false),
+        Field::new("ids", list(DataType::Utf8), false),
+    ])
+}
+
+impl TableWriter {
+    pub(crate) fn create(path: &Path, format: TableFormat) -> Result<Self> {
+        let schema = Arc::new(schema());
+        let file = File::create(path).map_err(|e| HerroError::from(e).in_file(path))?;
+        let writer = match format {
+            TableFormat::Arrow => FileWriter::try_new(BufWriter::new(file), &schema)
+                .map(Writer::Arrow)
+                .map_err(|e| HerroError::Arrow(e.to_string()).in_file(path))?,
+            #[cfg(feature = "parquet")]
+            TableFormat::Parquet => {
+                use parquet::basic::{Compression, ZstdLevel};
+                use parquet::file::properties::WriterProperties;
+
+                let props = WriterProperties::builder()
+                    .set_compression(Compression::ZSTD(ZstdLevel::default()))
+                    .build();
+                parquet::arrow::ArrowWriter::try_new(file, schema.clone(), Some(props))
+                    .map(Writer::Parquet)
+                    .map_err(|e| HerroError::Arrow(e.to_string()).in_file(path))?
+            }
+        };
+
+        Ok(Self {
+            writer,
+            schema,
+            n_rows: 0,
+            read_id: StringBuilder::new(),
+            window_id: UInt16Builder::new(),
+            length: UInt32Builder::new(),
+            width: UInt32Builder::new(),
+            bases: BinaryBuilder::new(),
+            quals: BinaryBuilder::new(),
+            positions: ListBuilder::new(UInt16Builder::new()),
+            insertions: ListBuilder::new(UInt8Builder::new()),
+            ids: ListBuilder::new(StringBuilder::new()),
+        })
+    }
+
+    pub(crate) fn write(
+        &mut self,
+        rname: &str,
+        window_id: u16,
+        ids: &[&str],
+        bases: Array2<u8>,
+        quals: Array2<f32>,
+        supported: Vec<SupportedPos>,
+    ) -> Result<()> {
+        let (length, width) = bases.dim();
+        self.read_id.append_value(rname);
+        self.window_id.append_value(window_id);
+        self.length.append_value(length as u32);
+        self.width.append_value(width as u32);
+
+        let bases: Vec<_> = bases.iter().copied().collect();
+        self.bases.append_value(bases);
+        let quals: Vec<_> = quals.iter().map(|&q| q as u8).collect();
+        self.quals.append_value(quals);
+
+        for pos in supported.iter() {
+            self.positions.values().append_value(pos.pos);
+            self.insertions.values().append_value(pos.ins);
+        }
+        self.positions.append(true);
+        self.insertions.append(true);
+
+        for id in ids {
+            self.ids.values().append_value(id);
+        }
+        self.ids.append(true);
+
+        self.n_rows += 1;
+        if self.n_rows == BATCH_WINDOWS {
+            self.write_batch()?;
+        }
+
+        Ok(())
+    }
+
+    fn write_batch(&mut self) -> Result<()> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.read_id.finish()),
+            Arc::new(self.window_id.finish()),
+            Arc::new(self.length.finish()),
+            Arc::new(self.width.finish()),
+            Arc::new(self.bases.finish()),
+            Arc::new(self.quals.finish()),
+            Arc::new(self.positions.finish()),
+            Arc::new(self.insertions.finish()),
+            Arc::new(self.ids.finish()),
+        ];
+        let batch = RecordBatch::try_new(self.schema.clone(), columns)
+            .map_err(|e| HerroError::Arrow(e.to_string()))?;
+
+        match self.writer {
+            Writer::Arrow(ref mut writer) => writer
+                .write(&batch)
+                .map_err(|e| HerroError::Arrow(e.to_string()))?,
+            #[cfg(feature = "parquet")]
+            Writer::Parquet(ref mut writer) => writer
+                .write(&batch)
+                .map_err(|e| HerroError::Arrow(e.to_string()))?,
+        }
+
+        self.n_rows = 0;
+        Ok(())
+    }
+
+    /// Writes the remaining windows and the file footer.
+    pub(crate) fn finish(mut self) -> Result<()> {
+        if self.n_rows > 0 {
+            self.write_batch()?;
+        }
+
+        match self.writer {
+            Writer::Arrow(mut writer) => writer
+                .finish()
+                .map_err(|e| HerroError::Arrow(e.to_string())),
+            #[cfg(feature = "parquet")]
+            Writer::Parquet(writer) => writer
+                .close()
+                .map(|_| ())
+                .map_err(|e| HerroError::Arrow(e.to_string())),
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::io::{self, prelude::*};
+
+use crossbeam_channel::Receiver;
+
+use crate::consensus::ConsensusWindow;
+use crate::haec_io::HAECRecord;
+
+/// Mean probability of the bases predicted by the model in a corrected window.
+pub(crate) struct WindowConfidence {
+    pub(crate) wid: u16,
+    pub(crate) n_total_wins: u16,
+    pub(crate) mean: f32,
+}
+
+impl WindowConfidence {
+    /// Returns None for windows which are not corrected or have no positions
+    /// predicted by the model.
+    pub(crate) fn new(window: &ConsensusWindow) -> Option<Self> {
+        if window.n_alns < 2 {
+            return None;
+        }
+
+        let probs = window.bases_probs.as_ref().filter(|p| !p.is_empty())?;
+        Some(WindowConfidence {
+            wid: window.wid,
+            n_total_wins: window.n_total_wins,
+            mean: probs.iter().sum::<f32>() / probs.len() as f32,
+        })
+    }
+
+    /// Part of the target read corrected by the window, [start, end). Windows
+    /// overlapping their neighbours are reported up to the middle of the
+    /// overlaps, so intervals of a read do not overlap.
+    fn interval(
+        &self,
+        read_len: usize,
+        (window_size, window_stride): (u32, u32),
+    ) -> (usize, usize) {
+        let (wid, stride) = (self.wid as usize, window_stride as usize);
+        let half_overlap = ((window_size - window_stride) / 2) as usize;
+
+        let start = match wid {
+            0 => 0,
+            _ => wid * stride + half_overlap,
+        };
+        let end = match self.wid + 1 == self.n_total_wins {
+            true => read_len,
+            false => (wid + 1) * stride + half_overlap,
+        };
+        (start.min(read_len), end.min(read_len))
+    }
+}
+
+/// Writes the mean confidence of the corrected windows as a bedGraph track in
+/// the coordinates of the target reads.
+pub(crate) fn confidence_writer(
+    reads: &[HAECRecord],
+    mut writer: impl Write,
+    windows: (u32, u32),
+    receiver: Receiver<(usize, Vec<WindowConfidence>)>,
+) -> io::Result<()> {
+    for (rid, confidences) in receiver {
+        let read = &reads[rid];
+        for confidence in confidences {
+            let (start, end) = confidence.interval(read.seq.len(), windows);
+            writer.write_all(&read.id)?;
+            writeln!(writer, "\t{}\t{}\t{:.4}", start, end, confidence.mean)?;
+        }
+    }
+
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WindowConfidence;
+
+    #[test]
+    fn window_intervals() {
+        let confidence = |wid, n_total_wins| WindowConfidence {
+            wid,
+            n_total_wins,
+            mean: 1.,
+        };
+
+        // Non-overlapping windows
+        assert_eq!(confidence(0, 3).interval(10_000, (4096, 4096)), (0, 4096));
+        assert_eq!(
+            confidence(2, 3).interval(10_000, (4096, 4096)),
+            (8192, 10_000)
+        );
+
+        // Windows starting every 2048 bases
+        assert_eq!(confidence(0, 4).interval(10_000, (4096, 2048)), (0, 3072));
+        assert_eq!(
+            confidence(1, 4).interval(10_000, (4096, 2048)),
+            (3072, 5120)
+        );
+        assert_eq!(
+            confidence(3, 4).interval(10_000, (4096, 2048)),
+            (7168, 10_000)
+        );
+    }
+}
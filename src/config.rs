@@ -0,0 +1,1432 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread;
+
+use crate::error::{HerroError, Result};
+use crate::features::{DEFAULT_TOP_K, MAX_TOP_K};
+use crate::{
+    Aligner, Backend, ChimeraMode, Device, DuplicateIds, Ensemble, FeatureFormat, FeatureSet,
+    OnnxProvider, OutputFormat, OverlapFilter, OverlapsFormat, Precision, QualEncoding,
+    TargetBatch,
+};
+
+pub(crate) const DEFAULT_WINDOW_SIZE: u32 = 4096;
+const DEFAULT_BATCH_SIZE: usize = 64;
+/// Smaller batches are used on the CPU, since larger ones do not improve the throughput
+const DEFAULT_CPU_BATCH_SIZE: usize = 8;
+/// Cores used by each model replica on the CPU. Batches are split between the
+/// replicas, since a single forward pass does not scale to many cores.
+const CPU_THREADS_PER_REPLICA: usize = 4;
+/// Windows need at least two supporting reads to be corrected
+const DEFAULT_MIN_COVERAGE: u8 = 2;
+/// Seconds between the logs of the device throughput and memory
+const DEFAULT_TELEMETRY_INTERVAL: u64 = 60;
+
+/// Number of available cores, or 1 if it cannot be determined
+pub(crate) fn available_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Shard of the target reads, given as `i/N` with `1 <= i <= N`. Reads are
+/// assigned to shards by the hash of their ids, so every run (or node) with the
+/// same number of shards gets the same reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadShard {
+    index: u64,
+    count: u64,
+}
+
+impl ReadShard {
+    pub fn new(index: u64, count: u64) -> Result<Self> {
+        if count == 0 || index == 0 || index > count {
+            return Err(HerroError::InvalidConfig(format!(
+                "invalid shard {}/{}, shard index must be between 1 and the number of shards",
+                index, count
+            )));
+        }
+
+        Ok(Self { index, count })
+    }
+
+    /// Returns true if the read with the given id belongs to the shard.
+    pub(crate) fn contains(&self, id: &[u8]) -> bool {
+        fnv1a(id) % self.count == self.index - 1
+    }
+}
+
+impl fmt::Display for ReadShard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+impl FromStr for ReadShard {
+    type Err = HerroError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (index, count) = s
+            .split_once('/')
+            .and_then(|(i, n)| Some((i.parse().ok()?, n.parse().ok()?)))
+            .ok_or_else(|| {
+                HerroError::InvalidConfig(format!("invalid shard {}, expected i/N", s))
+            })?;
+
+        Self::new(index, count)
+    }
+}
+
+/// 64-bit FNV-1a hash, which is stable across platforms and versions.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Parameters for feature generation, created with `FeatureGenConfig::builder()`.
+#[derive(Debug, Clone)]
+pub struct FeatureGenConfig {
+    pub(crate) threads: usize,
+    pub(crate) window_size: u32,
+    pub(crate) top_k: usize,
+    pub(crate) overlap_filter: OverlapFilter,
+    pub(crate) trust_cigar: bool,
+    pub(crate) aligner: Aligner,
+    pub(crate) align_cache: Option<PathBuf>,
+    pub(crate) quiet: bool,
+    pub(crate) progress_json: Option<PathBuf>,
+    pub(crate) shard: Option<ReadShard>,
+    pub(crate) read_ids: Option<PathBuf>,
+    pub(crate) feature_format: FeatureFormat,
+    pub(crate) compress_features: bool,
+    pub(crate) indexed_reads: bool,
+    pub(crate) duplicate_ids: DuplicateIds,
+    pub(crate) target_batch: TargetBatch,
+    pub(crate) overlaps_format: Option<OverlapsFormat>,
+}
+
+impl FeatureGenConfig {
+    pub fn builder() -> FeatureGenConfigBuilder {
+        FeatureGenConfigBuilder::default()
+    }
+}
+
+impl Default for FeatureGenConfig {
+    fn default() -> Self {
+        Self {
+            threads: 1,
+            window_size: DEFAULT_WINDOW_SIZE,
+            top_k: DEFAULT_TOP_K,
+            overlap_filter: OverlapFilter::default(),
+            trust_cigar: true,
+            aligner: Aligner::default(),
+            align_cache: None,
+            quiet: false,
+            progress_json: None,
+            shard: None,
+            read_ids: None,
+            feature_format: FeatureFormat::Npy,
+            compress_features: false,
+            indexed_reads: false,
+            duplicate_ids: DuplicateIds::default(),
+            target_batch: TargetBatch::default(),
+            overlaps_format: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FeatureGenConfigBuilder {
+    config: FeatureGenConfig,
+    threads: Option<usize>,
+    max_threads: Option<usize>,
+}
+
+impl FeatureGenConfigBuilder {
+    /// Number of feature generation threads (default all available cores)
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Maximum total number of threads, which caps the number of feature
+    /// generation threads
+    pub fn max_threads(mut self, max_threads: usize) -> Self {
+        self.max_threads = Some(max_threads);
+        self
+    }
+
+    /// Size of the window used for target chunking
+    pub fn window_size(mut self, window_size: u32) -> Self {
+        self.config.window_size = window_size;
+        self
+    }
+
+    /// Maximum number of supporting reads of a window (default 30)
+    pub fn top_k(mut self, top_k: usize) -> Self {
+        self.config.top_k = top_k;
+        self
+    }
+
+    pub fn overlap_filter(mut self, overlap_filter: OverlapFilter) -> Self {
+        self.config.overlap_filter = overlap_filter;
+        self
+    }
+
+    /// Use CIGARs from the alignments instead of realigning overlaps
+    pub fn trust_cigar(mut self, trust_cigar: bool) -> Self {
+        self.config.trust_cigar = trust_cigar;
+        self
+    }
+
+    /// Aligner used for realigning overlaps without CIGARs
+    pub fn aligner(mut self, aligner: Aligner) -> Self {
+        self.config.aligner = aligner;
+        self
+    }
+
+    /// Directory in which the CIGARs of realigned overlaps are cached across
+    /// runs
+    pub fn align_cache(mut self, dir: PathBuf) -> Self {
+        self.config.align_cache = Some(dir);
+        self
+    }
+
+    /// Do not show progress bars
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.config.quiet = quiet;
+        self
+    }
+
+    /// Write the progress as newline-delimited JSON events into the file, or
+    /// to stderr for `-`
+    pub fn progress_json(mut self, path: PathBuf) -> Self {
+        self.config.progress_json = Some(path);
+        self
+    }
+
+    /// Process only the target reads in the shard
+    pub fn shard(mut self, shard: ReadShard) -> Self {
+        self.config.shard = Some(shard);
+        self
+    }
+
+    /// Process only the target reads listed in the file, one id per line
+    pub fn read_ids(mut self, path: PathBuf) -> Self {
+        self.config.read_ids = Some(path);
+        self
+    }
+
+    /// Format of the written features
+    pub fn feature_format(mut self, feature_format: FeatureFormat) -> Self {
+        self.config.feature_format = feature_format;
+        self
+    }
+
+    /// Compress the npy features with zstd (`.npy.zst` files)
+    pub fn compress_features(mut self, compress_features: bool) -> Self {
+        self.config.compress_features = compress_features;
+        self
+    }
+
+    /// Fetch bases and qualities from the faidx-indexed reads file when they
+    /// are needed instead of loading all reads into memory
+    pub fn indexed_reads(mut self, indexed_reads: bool) -> Self {
+        self.config.indexed_reads = indexed_reads;
+        self
+    }
+
+    /// Handling of reads with the same id
+    pub fn duplicate_ids(mut self, duplicate_ids: DuplicateIds) -> Self {
+        self.config.duplicate_ids = duplicate_ids;
+        self
+    }
+
+    /// Format of the given overlaps, instead of detecting it from the file
+    /// extension
+    pub fn overlaps_format(mut self, overlaps_format: OverlapsFormat) -> Self {
+        self.config.overlaps_format = Some(overlaps_format);
+        self
+    }
+
+    /// Process the target reads in batches of N reads, only the overlaps of
+    /// one batch are kept in memory
+    pub fn batch_reads(mut self, n_reads: usize) -> Self {
+        self.config.target_batch = TargetBatch::Reads(n_reads);
+        self
+    }
+
+    /// Process the target reads in batches of at most N bases
+    pub fn batch_bases(mut self, n_bases: u64) -> Self {
+        self.config.target_batch = TargetBatch::Bases(n_bases);
+        self
+    }
+
+    pub fn build(self) -> Result<FeatureGenConfig> {
+        let mut config = self.config;
+        validate_max_threads(self.max_threads)?;
+        config.threads = self.threads.unwrap_or_else(available_threads);
+        if let Some(max_threads) = self.max_threads {
+            config.threads = config.threads.min(max_threads);
+        }
+        validate_common(
+            config.threads,
+            config.window_size,
+            &config.overlap_filter,
+            config.target_batch,
+            config.aligner,
+        )?;
+        validate_top_k(config.top_k)?;
+
+        let (format_name, format_enabled) = match config.feature_format {
+            FeatureFormat::Npy => ("npy", true),
+            FeatureFormat::Hdf5 => ("hdf5", cfg!(feature = "hdf5")),
+            FeatureFormat::Arrow => ("arrow", cfg!(feature = "arrow")),
+            FeatureFormat::Parquet => ("parquet", cfg!(feature = "parquet")),
+        };
+        if !format_enabled {
+            return Err(HerroError::InvalidConfig(format!(
+                "{} features require herro built with the {} feature",
+                format_name, format_name
+            )));
+        }
+        if config.compress_features && config.feature_format != FeatureFormat::Npy {
+            return Err(HerroError::InvalidConfig(format!(
+                "only npy features are compressed, {} features use their own compression",
+                format_name
+            )));
+        }
+
+        Ok(config)
+    }
+}
+
+/// Parameters for error correction, created with `CorrectionConfig::builder()`.
+#[derive(Debug, Clone)]
+pub struct CorrectionConfig {
+    pub(crate) threads: usize,
+    pub(crate) align_threads: Option<usize>,
+    pub(crate) encode_threads: Option<usize>,
+    pub(crate) writer_threads: Option<usize>,
+    /// Taken from the model spec if not given
+    pub(crate) window_size: Option<u32>,
+    /// Taken from the model spec if not given
+    pub(crate) top_k: Option<usize>,
+    /// Same as the window size (non-overlapping windows) if not given
+    pub(crate) window_stride: Option<u32>,
+    /// Minimum number of supporting reads of the corrected windows
+    pub(crate) min_coverage: u8,
+    /// Minimum number of supporting reads of the leading and trailing windows
+    pub(crate) trim_ends: Option<u8>,
+    pub(crate) overlap_filter: OverlapFilter,
+    pub(crate) trust_cigar: bool,
+    pub(crate) aligner: Aligner,
+    pub(crate) align_cache: Option<PathBuf>,
+    /// Directory written by the `features` subcommand, read instead of the
+    /// overlaps
+    pub(crate) from_features: Option<PathBuf>,
+    pub(crate) devices: Vec<Device>,
+    pub(crate) batch_size: usize,
+    pub(crate) output_format: OutputFormat,
+    pub(crate) sharded_output: bool,
+    pub(crate) quiet: bool,
+    pub(crate) progress_json: Option<PathBuf>,
+    pub(crate) resume: bool,
+    pub(crate) checkpoint: bool,
+    pub(crate) deterministic: bool,
+    pub(crate) backend: Backend,
+    pub(crate) intra_op_threads: usize,
+    pub(crate) workers_per_device: usize,
+    pub(crate) telemetry_interval: u64,
+    pub(crate) precision: Precision,
+    pub(crate) ensemble: Ensemble,
+    /// Taken from the model spec if not given
+    pub(crate) qual_encoding: Option<QualEncoding>,
+    /// Used also if the model spec lists it
+    pub(crate) homopolymer_channel: bool,
+    /// Taken from the model spec if not given
+    pub(crate) feature_layout: Option<u32>,
+    /// Taken from the model spec if not given
+    pub(crate) feature_set: Option<FeatureSet>,
+    pub(crate) trt_engine_cache: Option<PathBuf>,
+    pub(crate) shard: Option<ReadShard>,
+    pub(crate) read_ids: Option<PathBuf>,
+    pub(crate) keep_uncorrected: bool,
+    pub(crate) tag_uncorrected: bool,
+    pub(crate) keep_descriptions: bool,
+    pub(crate) keep_tags: bool,
+    pub(crate) indexed_reads: bool,
+    pub(crate) duplicate_ids: DuplicateIds,
+    /// 100,000 reads, or taken from the memory budget, if not given
+    pub(crate) target_batch: Option<TargetBatch>,
+    pub(crate) overlaps_format: Option<OverlapsFormat>,
+    /// Capacities of the channels between the pipeline stages, unbounded if None
+    /// (the default of the first is 50,000, or taken from the memory budget)
+    pub(crate) aln_capacity: Option<usize>,
+    /// Two batches per feature generation thread if not given
+    pub(crate) features_capacity: Option<usize>,
+    pub(crate) consensus_capacity: Option<usize>,
+    pub(crate) writer_capacity: Option<usize>,
+    /// Memory budget of the feature batches waiting for inference in bytes,
+    /// batches over the budget are spilled to disk
+    pub(crate) spill_memory: Option<u64>,
+    /// Temporary directory if not given
+    pub(crate) spill_dir: Option<PathBuf>,
+    /// Memory budget of the whole run in bytes, from which the settings above
+    /// which are not given are derived
+    pub(crate) max_memory: Option<u64>,
+    pub(crate) confidence_bed: Option<PathBuf>,
+    pub(crate) edits_tsv: Option<PathBuf>,
+    pub(crate) haplotypes_tsv: Option<PathBuf>,
+    pub(crate) chimeras: Option<ChimeraMode>,
+    pub(crate) run_report: Option<PathBuf>,
+}
+
+impl CorrectionConfig {
+    pub fn builder() -> CorrectionConfigBuilder {
+        CorrectionConfigBuilder::default()
+    }
+}
+
+impl Default for CorrectionConfig {
+    fn default() -> Self {
+        Self {
+            threads: 1,
+            align_threads: None,
+            encode_threads: None,
+            writer_threads: None,
+            window_size: None,
+            top_k: None,
+            window_stride: None,
+            min_coverage: DEFAULT_MIN_COVERAGE,
+            trim_ends: None,
+            overlap_filter: OverlapFilter::default(),
+            trust_cigar: true,
+            aligner: Aligner::default(),
+            align_cache: None,
+            from_features: None,
+            devices: vec![Device::Cuda(0)],
+            batch_size: DEFAULT_BATCH_SIZE,
+            output_format: OutputFormat::Fasta,
+            sharded_output: false,
+            quiet: false,
+            progress_json: None,
+            resume: false,
+            checkpoint: false,
+            deterministic: false,
+            backend: Backend::default(),
+            intra_op_threads: 1,
+            workers_per_device: 1,
+            telemetry_interval: DEFAULT_TELEMETRY_INTERVAL,
+            precision: Precision::Fp32,
+            ensemble: Ensemble::default(),
+            qual_encoding: None,
+            homopolymer_channel: false,
+            feature_layout: None,
+            feature_set: None,
+            trt_engine_cache: None,
+            shard: None,
+            read_ids: None,
+            keep_uncorrected: false,
+            tag_uncorrected: false,
+            keep_descriptions: false,
+            keep_tags: false,
+            indexed_reads: false,
+            duplicate_ids: DuplicateIds::default(),
+            target_batch: None,
+            overlaps_format: None,
+            aln_capacity: None,
+            features_capacity: None,
+            consensus_capacity: None,
+            writer_capacity: None,
+            spill_memory: None,
+            spill_dir: None,
+            max_memory: None,
+            confidence_bed: None,
+            edits_tsv: None,
+            haplotypes_tsv: None,
+            chimeras: None,
+            run_report: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CorrectionConfigBuilder {
+    config: CorrectionConfig,
+    // Defaults of these parameters depend on the devices
+    batch_size: Option<usize>,
+    intra_op_threads: Option<usize>,
+    workers_per_device: Option<usize>,
+    threads: Option<usize>,
+    max_threads: Option<usize>,
+}
+
+impl CorrectionConfigBuilder {
+    /// Number of feature generation threads per device (default all available
+    /// cores divided between the devices)
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Maximum total number of threads. Threads per device are capped to their
+    /// share of it, and the intra-op threads of the model to all of it
+    pub fn max_threads(mut self, max_threads: usize) -> Self {
+        self.max_threads = Some(max_threads);
+        self
+    }
+
+    /// Number of threads per device realigning the overlaps (default the
+    /// number of feature generation threads)
+    pub fn align_threads(mut self, threads: usize) -> Self {
+        self.config.align_threads = Some(threads);
+        self
+    }
+
+    /// Number of threads per device encoding the features of the aligned
+    /// overlaps (default the number of feature generation threads)
+    pub fn encode_threads(mut self, threads: usize) -> Self {
+        self.config.encode_threads = Some(threads);
+        self
+    }
+
+    /// Number of consensus workers per device and compression threads of each
+    /// output (default one consensus worker and the number of feature
+    /// generation threads for compression)
+    pub fn writer_threads(mut self, threads: usize) -> Self {
+        self.config.writer_threads = Some(threads);
+        self
+    }
+
+    /// Size of the window used for target chunking, which has to match the
+    /// model spec (default from the model spec, or 4096)
+    pub fn window_size(mut self, window_size: u32) -> Self {
+        self.config.window_size = Some(window_size);
+        self
+    }
+
+    /// Maximum number of supporting reads of a window, which has to match the
+    /// model spec (default from the model spec, or 30)
+    pub fn top_k(mut self, top_k: usize) -> Self {
+        self.config.top_k = Some(top_k);
+        self
+    }
+
+    pub fn overlap_filter(mut self, overlap_filter: OverlapFilter) -> Self {
+        self.config.overlap_filter = overlap_filter;
+        self
+    }
+
+    /// Distance between the starts of adjacent windows, which overlap if it is
+    /// smaller than the window size. It has to divide the window size and be at
+    /// least 1/8 of it (default window size)
+    pub fn window_stride(mut self, window_stride: u32) -> Self {
+        self.config.window_stride = Some(window_stride);
+        self
+    }
+
+    /// Use CIGARs from the alignments instead of realigning overlaps
+    pub fn trust_cigar(mut self, trust_cigar: bool) -> Self {
+        self.config.trust_cigar = trust_cigar;
+        self
+    }
+
+    /// Aligner used for realigning overlaps without CIGARs
+    pub fn aligner(mut self, aligner: Aligner) -> Self {
+        self.config.aligner = aligner;
+        self
+    }
+
+    /// Directory in which the CIGARs of realigned overlaps are cached across
+    /// runs
+    pub fn align_cache(mut self, dir: PathBuf) -> Self {
+        self.config.align_cache = Some(dir);
+        self
+    }
+
+    /// Correct the reads with the features written to the directory by the
+    /// `features` subcommand in the npy format, instead of generating them
+    /// from the overlaps
+    pub fn from_features(mut self, dir: PathBuf) -> Self {
+        self.config.from_features = Some(dir);
+        self
+    }
+
+    /// Devices used for inference
+    pub fn devices(mut self, devices: Vec<Device>) -> Self {
+        self.config.devices = devices;
+        self
+    }
+
+    /// Inference batch size per device (default 64, or 8 on the CPU)
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = Some(batch_size);
+        self
+    }
+
+    /// Number of threads used by the model for a single batch (default 1, or
+    /// the available cores divided between the inference workers on the CPU)
+    pub fn intra_op_threads(mut self, intra_op_threads: usize) -> Self {
+        self.intra_op_threads = Some(intra_op_threads);
+        self
+    }
+
+    /// Number of inference workers per device, each with its own copy of the
+    /// model and queue of inferred batches (default 1, or one per 4 available
+    /// cores on the CPU)
+    pub fn workers_per_device(mut self, workers_per_device: usize) -> Self {
+        self.workers_per_device = Some(workers_per_device);
+        self
+    }
+
+    /// Seconds between the logs of the throughput, utilization and memory of
+    /// each device, 0 disables them (default 60)
+    pub fn telemetry_interval(mut self, seconds: u64) -> Self {
+        self.config.telemetry_interval = seconds;
+        self
+    }
+
+    pub fn output_format(mut self, output_format: OutputFormat) -> Self {
+        self.config.output_format = output_format;
+        self
+    }
+
+    /// Write one output shard per device and merge them at the end
+    pub fn sharded_output(mut self, sharded_output: bool) -> Self {
+        self.config.sharded_output = sharded_output;
+        self
+    }
+
+    /// Do not show progress bars
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.config.quiet = quiet;
+        self
+    }
+
+    /// Write the progress as newline-delimited JSON events into the file, or
+    /// to stderr for `-`
+    pub fn progress_json(mut self, path: PathBuf) -> Self {
+        self.config.progress_json = Some(path);
+        self
+    }
+
+    /// Skip reads corrected by the interrupted run, using its checkpoint
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.config.resume = resume;
+        self
+    }
+
+    /// Record the corrected reads in a checkpoint next to the output, so an
+    /// interrupted run can be resumed. Resumed runs always write checkpoints
+    pub fn checkpoint(mut self, checkpoint: bool) -> Self {
+        self.config.checkpoint = checkpoint;
+        self
+    }
+
+    /// Write the corrected reads in the order of the input and use a fixed
+    /// seed. Runs on the same input produce identical output on the CPU, while
+    /// on GPUs some kernels may still differ between runs
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.config.deterministic = deterministic;
+        self
+    }
+
+    /// Library used to run the model
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.config.backend = backend;
+        self
+    }
+
+    /// Precision of the model and its inputs, half precision requires the tch
+    /// backend or the TensorRT execution provider (fp16 only). Int8 requires
+    /// a quantized model run on the CPU with the tch or onnx backend
+    pub fn precision(mut self, precision: Precision) -> Self {
+        self.config.precision = precision;
+        self
+    }
+
+    /// How the predictions are combined if multiple models are given
+    pub fn ensemble(mut self, ensemble: Ensemble) -> Self {
+        self.config.ensemble = ensemble;
+        self
+    }
+
+    /// Encoding of the base qualities expected by models without a spec, has
+    /// to match the spec of the models otherwise
+    pub fn qual_encoding(mut self, qual_encoding: QualEncoding) -> Self {
+        self.config.qual_encoding = Some(qual_encoding);
+        self
+    }
+
+    /// Give the homopolymer run length of each base to models without a spec
+    /// as an additional channel, models with a spec have to list it
+    pub fn homopolymer_channel(mut self, homopolymer_channel: bool) -> Self {
+        self.config.homopolymer_channel = homopolymer_channel;
+        self
+    }
+
+    /// Feature layout version of models without a spec, has to match the spec
+    /// of the models otherwise. Version 2 adds the sequence context channel
+    pub fn feature_layout(mut self, version: u32) -> Self {
+        self.config.feature_layout = Some(version);
+        self
+    }
+
+    /// Encoder of the features of models without a spec, has to match the spec
+    /// of the models otherwise
+    pub fn feature_set(mut self, feature_set: FeatureSet) -> Self {
+        self.config.feature_set = Some(feature_set);
+        self
+    }
+
+    /// Correct only the target reads in the shard
+    pub fn shard(mut self, shard: ReadShard) -> Self {
+        self.config.shard = Some(shard);
+        self
+    }
+
+    /// Correct only the target reads listed in the file, one id per line
+    pub fn read_ids(mut self, path: PathBuf) -> Self {
+        self.config.read_ids = Some(path);
+        self
+    }
+
+    /// Write target reads without usable overlaps unchanged, so the output
+    /// contains all target reads
+    pub fn keep_uncorrected(mut self, keep_uncorrected: bool) -> Self {
+        self.config.keep_uncorrected = keep_uncorrected;
+        self
+    }
+
+    /// Append `uncorrected` to the headers of the reads written unchanged
+    pub fn tag_uncorrected(mut self, tag_uncorrected: bool) -> Self {
+        self.config.tag_uncorrected = tag_uncorrected;
+        self
+    }
+
+    /// Copy the descriptions of the input reads (everything after the id in
+    /// their headers) to the headers of the corrected reads
+    pub fn keep_descriptions(mut self, keep_descriptions: bool) -> Self {
+        self.config.keep_descriptions = keep_descriptions;
+        self
+    }
+
+    /// Copy the basecaller tags of the input reads which remain valid after
+    /// the correction (read group, channel, read number, start time, ...) to
+    /// the headers of the corrected reads
+    pub fn keep_tags(mut self, keep_tags: bool) -> Self {
+        self.config.keep_tags = keep_tags;
+        self
+    }
+
+    /// Fetch bases and qualities from the faidx-indexed reads file when they
+    /// are needed instead of loading all reads into memory
+    pub fn indexed_reads(mut self, indexed_reads: bool) -> Self {
+        self.config.indexed_reads = indexed_reads;
+        self
+    }
+
+    /// Handling of reads with the same id
+    pub fn duplicate_ids(mut self, duplicate_ids: DuplicateIds) -> Self {
+        self.config.duplicate_ids = duplicate_ids;
+        self
+    }
+
+    /// Format of the given overlaps, instead of detecting it from the file
+    /// extension
+    pub fn overlaps_format(mut self, overlaps_format: OverlapsFormat) -> Self {
+        self.config.overlaps_format = Some(overlaps_format);
+        self
+    }
+
+    /// Process the target reads in batches of N reads, only the overlaps of
+    /// one batch are kept in memory
+    pub fn batch_reads(mut self, n_reads: usize) -> Self {
+        self.config.target_batch = Some(TargetBatch::Reads(n_reads));
+        self
+    }
+
+    /// Process the target reads in batches of at most N bases
+    pub fn batch_bases(mut self, n_bases: u64) -> Self {
+        self.config.target_batch = Some(TargetBatch::Bases(n_bases));
+        self
+    }
+
+    /// Directory in which TensorRT engines are stored and reused by later runs
+    pub fn trt_engine_cache(mut self, path: PathBuf) -> Self {
+        self.config.trt_engine_cache = Some(path);
+        self
+    }
+
+    /// Number of reads with their overlaps waiting for feature generation
+    /// (default 50,000)
+    pub fn aln_capacity(mut self, capacity: usize) -> Self {
+        self.config.aln_capacity = Some(capacity);
+        self
+    }
+
+    /// Number of feature batches waiting for inference (default two per
+    /// feature generation thread)
+    pub fn features_capacity(mut self, capacity: usize) -> Self {
+        self.config.features_capacity = Some(capacity);
+        self
+    }
+
+    /// Number of inferred batches waiting for consensus on each consensus
+    /// worker (default unbounded)
+    pub fn consensus_capacity(mut self, capacity: usize) -> Self {
+        self.config.consensus_capacity = Some(capacity);
+        self
+    }
+
+    /// Number of corrected reads waiting to be written on each writer
+    /// (default unbounded)
+    pub fn writer_capacity(mut self, capacity: usize) -> Self {
+        self.config.writer_capacity = Some(capacity);
+        self
+    }
+
+    /// Memory budget in bytes of the feature batches waiting for inference,
+    /// batches over the budget are spilled to disk and read back when the
+    /// inference catches up (default unlimited)
+    pub fn spill_memory(mut self, bytes: u64) -> Self {
+        self.config.spill_memory = Some(bytes);
+        self
+    }
+
+    /// Directory of the spilled features (default the temporary directory)
+    pub fn spill_dir(mut self, dir: PathBuf) -> Self {
+        self.config.spill_dir = Some(dir);
+        self
+    }
+
+    /// Memory budget in bytes of the whole run. Target read batches, channel
+    /// capacities and the spill budget which are not given are sized to stay
+    /// under it, and the overlaps are pruned if the batches would be too small
+    pub fn max_memory(mut self, bytes: u64) -> Self {
+        self.config.max_memory = Some(bytes);
+        self
+    }
+
+    /// Write the mean model confidence of the corrected windows into the
+    /// bedGraph file, in the coordinates of the target reads
+    pub fn confidence_bed(mut self, path: PathBuf) -> Self {
+        self.config.confidence_bed = Some(path);
+        self
+    }
+
+    /// Write the corrections applied to the target reads into the TSV file,
+    /// one row per substitution, deletion or insertion
+    pub fn edits_tsv(mut self, path: PathBuf) -> Self {
+        self.config.edits_tsv = Some(path);
+        self
+    }
+
+    /// Write the groups of the supporting reads of the corrected windows into
+    /// the TSV file, reads on the haplotype of the target are in group 1
+    pub fn haplotypes_tsv(mut self, path: PathBuf) -> Self {
+        self.config.haplotypes_tsv = Some(path);
+        self
+    }
+
+    /// Detect chimeric target reads from the coverage of their overlaps, and
+    /// remove the overlaps spanning the junctions or also split the reads
+    pub fn chimeras(mut self, mode: ChimeraMode) -> Self {
+        self.config.chimeras = Some(mode);
+        self
+    }
+
+    /// Write a JSON summary of the run: input counts, overlaps dropped by each
+    /// filter, processed windows and reads, wall time of the stages and peak
+    /// memory
+    pub fn run_report(mut self, path: PathBuf) -> Self {
+        self.config.run_report = Some(path);
+        self
+    }
+
+    /// Leave the windows with fewer than `min_coverage` supporting reads
+    /// uncorrected
+    pub fn min_coverage(mut self, min_coverage: u8) -> Self {
+        self.config.min_coverage = min_coverage;
+        self
+    }
+
+    /// Trim the leading and trailing windows of the corrected reads which have
+    /// fewer than `min_cov` supporting reads
+    pub fn trim_ends(mut self, min_cov: u8) -> Self {
+        self.config.trim_ends = Some(min_cov);
+        self
+    }
+
+    pub fn build(self) -> Result<CorrectionConfig> {
+        let mut config = self.config;
+        validate_max_threads(self.max_threads)?;
+        let n_devices = config.devices.len().max(1);
+        let budget = self.max_threads.unwrap_or_else(available_threads);
+        let per_device = (budget / n_devices).max(1);
+        let cap = |threads: usize| match self.max_threads {
+            Some(_) => threads.min(per_device),
+            None => threads,
+        };
+        config.threads = self.threads.map_or(per_device, cap);
+        config.align_threads = config.align_threads.map(cap);
+        config.encode_threads = config.encode_threads.map(cap);
+        config.writer_threads = config.writer_threads.map(cap);
+        validate_common(
+            config.threads,
+            config.window_size.unwrap_or(DEFAULT_WINDOW_SIZE),
+            &config.overlap_filter,
+            config.target_batch.unwrap_or_default(),
+            config.aligner,
+        )?;
+
+        if let (Some(window_size), Some(stride)) = (config.window_size, config.window_stride) {
+            validate_window_stride(window_size, stride)?;
+        }
+        if config.from_features.is_some() {
+            // Features are generated with non-overlapping windows and without
+            // the overlaps needed to find chimeric junctions
+            if config
+                .window_stride
+                .is_some_and(|s| Some(s) != config.window_size)
+            {
+                return Err(HerroError::InvalidConfig(
+                    "features are generated with non-overlapping windows, window stride cannot be set"
+                        .to_string(),
+                ));
+            }
+            if config.chimeras.is_some() {
+                return Err(HerroError::InvalidConfig(
+                    "chimeras cannot be handled with precomputed features".to_string(),
+                ));
+            }
+        }
+        if config.deterministic && config.resume {
+            return Err(HerroError::InvalidConfig(
+                "resumed runs append to the output, so they cannot be deterministic".to_string(),
+            ));
+        }
+        if let Some(top_k) = config.top_k {
+            validate_top_k(top_k)?;
+        }
+        // Checked again with the number of supporting reads of the model spec
+        validate_min_coverage(
+            (config.min_coverage, config.trim_ends),
+            config.top_k.unwrap_or(DEFAULT_TOP_K),
+        )?;
+        if config.devices.is_empty() {
+            return Err(HerroError::InvalidConfig(
+                "at least one device is required".to_string(),
+            ));
+        }
+        let cpu = config.devices.contains(&Device::Cpu);
+        let single = cpu || config.devices.contains(&Device::Mps);
+        if single && config.devices.len() > 1 {
+            return Err(HerroError::InvalidConfig(
+                "CPU or MPS cannot be used together with other devices".to_string(),
+            ));
+        }
+        let devices = &config.devices;
+        if (1..devices.len()).any(|i| devices[..i].contains(&devices[i])) {
+            return Err(HerroError::InvalidConfig(
+                "devices have to be unique".to_string(),
+            ));
+        }
+
+        config.batch_size = match (self.batch_size, cpu) {
+            (Some(batch_size), _) => batch_size,
+            (None, true) => DEFAULT_CPU_BATCH_SIZE,
+            (None, false) => DEFAULT_BATCH_SIZE,
+        };
+        // The CPU is split into model replicas, which infer batches in parallel
+        let cpu_threads = self
+            .max_threads
+            .map_or(available_threads(), |t| t.min(available_threads()));
+        config.workers_per_device = match (self.workers_per_device, cpu) {
+            (Some(workers), _) => workers,
+            (None, true) => (cpu_threads / CPU_THREADS_PER_REPLICA).max(1),
+            (None, false) => 1,
+        };
+        config.intra_op_threads = match (self.intra_op_threads, cpu) {
+            (Some(threads), _) => threads,
+            (None, true) => (cpu_threads / config.workers_per_device.max(1)).max(1),
+            (None, false) => 1,
+        };
+        if let Some(max_threads) = self.max_threads {
+            config.intra_op_threads = config.intra_op_threads.min(max_threads);
+        }
+
+        let (backend_name, backend_enabled) = match config.backend {
+            Backend::Tch => ("tch", cfg!(feature = "tch")),
+            Backend::Onnx(_) => ("onnx", cfg!(feature = "onnx")),
+            Backend::Candle => ("candle", cfg!(feature = "candle")),
+        };
+        if !backend_enabled {
+            return Err(HerroError::InvalidConfig(format!(
+                "{} backend requires herro built with the {} feature",
+                backend_name, backend_name
+            )));
+        }
+        let tensorrt = config.backend == Backend::Onnx(OnnxProvider::TensorRt);
+        let precision_supported = match config.precision {
+            Precision::Fp32 => true,
+            Precision::Fp16 => config.backend == Backend::Tch || tensorrt,
+            Precision::Bf16 => config.backend == Backend::Tch,
+            Precision::Int8 => {
+                if config.devices.iter().any(|&d| d != Device::Cpu) {
+                    return Err(HerroError::InvalidConfig(
+                        "int8 precision is supported only on the CPU".to_string(),
+                    ));
+                }
+                config.backend != Backend::Candle
+            }
+        };
+        if !precision_supported {
+            return Err(HerroError::InvalidConfig(format!(
+                "{:?} precision is not supported by the backend, half precision requires the tch backend or TensorRT (fp16) and int8 the tch or onnx backend",
+                config.precision
+            )));
+        }
+        if config.trt_engine_cache.is_some() && !tensorrt {
+            return Err(HerroError::InvalidConfig(
+                "TensorRT engine cache requires the tensorrt execution provider".to_string(),
+            ));
+        }
+        if config.tag_uncorrected && !config.keep_uncorrected {
+            return Err(HerroError::InvalidConfig(
+                "tagging uncorrected reads requires keeping them".to_string(),
+            ));
+        }
+        if (config.keep_descriptions || config.keep_tags) && config.indexed_reads {
+            return Err(HerroError::InvalidConfig(
+                "descriptions of the indexed reads are not loaded, they cannot be kept".to_string(),
+            ));
+        }
+        if config.keep_descriptions && config.keep_tags {
+            return Err(HerroError::InvalidConfig(
+                "tags are already kept with the descriptions".to_string(),
+            ));
+        }
+        if config.batch_size == 0 {
+            return Err(HerroError::InvalidConfig(
+                "batch size must be positive".to_string(),
+            ));
+        }
+        if config.intra_op_threads == 0 {
+            return Err(HerroError::InvalidConfig(
+                "number of intra-op threads must be positive".to_string(),
+            ));
+        }
+        let thread_counts = [
+            config.align_threads,
+            config.encode_threads,
+            config.writer_threads,
+        ];
+        if thread_counts.contains(&Some(0)) {
+            return Err(HerroError::InvalidConfig(
+                "number of alignment, encoding and writer threads must be positive".to_string(),
+            ));
+        }
+        if config.workers_per_device == 0 {
+            return Err(HerroError::InvalidConfig(
+                "number of workers per device must be positive".to_string(),
+            ));
+        }
+        let capacities = [
+            config.aln_capacity,
+            config.features_capacity,
+            config.consensus_capacity,
+            config.writer_capacity,
+        ];
+        if capacities.contains(&Some(0)) {
+            return Err(HerroError::InvalidConfig(
+                "channel capacities must be positive".to_string(),
+            ));
+        }
+        if config.spill_memory == Some(0) {
+            return Err(HerroError::InvalidConfig(
+                "spill memory budget must be positive".to_string(),
+            ));
+        }
+        if config.max_memory == Some(0) {
+            return Err(HerroError::InvalidConfig(
+                "memory budget must be positive".to_string(),
+            ));
+        }
+
+        Ok(config)
+    }
+}
+
+/// Windows overlap by less than 7/8 of their size, so that overlapping windows
+/// do not start within the first bases of the read, which are always in the
+/// first window.
+pub(crate) fn validate_window_stride(window_size: u32, window_stride: u32) -> Result<()> {
+    if window_stride == 0
+        || !window_size.is_multiple_of(window_stride)
+        || 8 * window_stride < window_size
+    {
+        return Err(HerroError::InvalidConfig(format!(
+            "window stride {} has to divide the window size {} and be at least 1/8 of it",
+            window_stride, window_size
+        )));
+    }
+
+    Ok(())
+}
+
+fn validate_top_k(top_k: usize) -> Result<()> {
+    if !(1..=MAX_TOP_K).contains(&top_k) {
+        return Err(HerroError::InvalidConfig(format!(
+            "number of supporting reads per window {} has to be between 1 and {}",
+            top_k, MAX_TOP_K
+        )));
+    }
+
+    Ok(())
+}
+
+/// Minimum coverages of the windows and the read ends have to be reachable
+/// with `top_k` supporting reads per window.
+pub(crate) fn validate_min_coverage(
+    (min_coverage, trim_ends): (u8, Option<u8>),
+    top_k: usize,
+) -> Result<()> {
+    if min_coverage < 2 || min_coverage as usize > top_k {
+        return Err(HerroError::InvalidConfig(format!(
+            "minimum coverage {} has to be between 2 and {}",
+            min_coverage, top_k
+        )));
+    }
+    if let Some(min_cov) = trim_ends.filter(|&c| c < 2 || c as usize > top_k) {
+        return Err(HerroError::InvalidConfig(format!(
+            "minimum coverage {} of the read ends has to be between 2 and {}",
+            min_cov, top_k
+        )));
+    }
+
+    Ok(())
+}
+
+fn validate_max_threads(max_threads: Option<usize>) -> Result<()> {
+    match max_threads {
+        Some(0) => Err(HerroError::InvalidConfig(
+            "maximum number of threads must be positive".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn validate_common(
+    threads: usize,
+    window_size: u32,
+    overlap_filter: &OverlapFilter,
+    target_batch: TargetBatch,
+    aligner: Aligner,
+) -> Result<()> {
+    if threads == 0 {
+        return Err(HerroError::InvalidConfig(
+            "number of threads must be positive".to_string(),
+        ));
+    }
+    if window_size == 0 {
+        return Err(HerroError::InvalidConfig(
+            "window size must be positive".to_string(),
+        ));
+    }
+    if overlap_filter.max_span_ratio < 1. {
+        return Err(HerroError::InvalidConfig(
+            "maximum span ratio must be at least 1".to_string(),
+        ));
+    }
+    if overlap_filter.max_coverage == Some(0) {
+        return Err(HerroError::InvalidConfig(
+            "maximum coverage must be positive".to_string(),
+        ));
+    }
+    if let Some(accuracy) = overlap_filter
+        .min_window_accuracy
+        .filter(|a| !(0. ..=1.).contains(a))
+    {
+        return Err(HerroError::InvalidConfig(format!(
+            "minimum window accuracy must be between 0 and 1, got {}",
+            accuracy
+        )));
+    }
+    if overlap_filter.extend > overlap_filter.ol_threshold {
+        return Err(HerroError::InvalidConfig(
+            "overlap extension cannot be larger than the overlap threshold".to_string(),
+        ));
+    }
+    if matches!(target_batch, TargetBatch::Reads(0) | TargetBatch::Bases(0)) {
+        return Err(HerroError::InvalidConfig(
+            "target batch size must be positive".to_string(),
+        ));
+    }
+
+    let (aligner_name, aligner_enabled) = match aligner {
+        Aligner::Banded | Aligner::Adaptive | Aligner::Simd => ("banded", true),
+        Aligner::Wfa => ("wfa", cfg!(feature = "wfa")),
+        Aligner::Edlib => ("edlib", cfg!(feature = "edlib")),
+        Aligner::BlockAligner => ("block-aligner", cfg!(feature = "block-aligner")),
+    };
+    if !aligner_enabled {
+        return Err(HerroError::InvalidConfig(format!(
+            "{} aligner requires herro built with the {} feature",
+            aligner_name, aligner_name
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        Aligner, Backend, ChimeraMode, Device, FeatureFormat, OnnxProvider, OutputFormat,
+        OverlapFilter, Precision,
+    };
+
+    use super::{CorrectionConfig, FeatureGenConfig, ReadShard};
+
+    #[test]
+    fn build_configs() {
+        let config = FeatureGenConfig::builder()
+            .threads(8)
+            .trust_cigar(false)
+            .build()
+            .unwrap();
+        assert_eq!((config.threads, config.window_size), (8, 4096));
+        assert!(!config.trust_cigar);
+
+        let config = CorrectionConfig::builder()
+            .devices(vec![Device::Cuda(0), Device::Cuda(1)])
+            .output_format(OutputFormat::Fastq)
+            .build()
+            .unwrap();
+        assert_eq!(config.devices, [Device::Cuda(0), Device::Cuda(1)]);
+        assert_eq!(config.batch_size, 64);
+        assert_eq!(config.intra_op_threads, 1);
+        assert_eq!(config.output_format, OutputFormat::Fastq);
+
+        let config = CorrectionConfig::builder()
+            .devices(vec![Device::Cpu])
+            .intra_op_threads(4)
+            .build()
+            .unwrap();
+        assert_eq!(config.batch_size, 8);
+        assert_eq!(config.intra_op_threads, 4);
+
+        // Cores of the CPU are divided between the model replicas
+        let config = CorrectionConfig::builder()
+            .devices(vec![Device::Cpu])
+            .max_threads(10)
+            .build()
+            .unwrap();
+        let replicas = (super::available_threads().min(10) / 4).max(1);
+        assert_eq!(config.workers_per_device, replicas);
+        assert!(config.intra_op_threads * replicas <= 10);
+        let config = CorrectionConfig::builder()
+            .devices(vec![Device::Cpu])
+            .workers_per_device(2)
+            .max_threads(8)
+            .build()
+            .unwrap();
+        let threads = (super::available_threads().min(8) / 2).max(1);
+        assert_eq!(config.intra_op_threads, threads);
+
+        let n_cores = super::available_threads();
+        let config = FeatureGenConfig::builder().build().unwrap();
+        assert_eq!(config.threads, n_cores);
+        let config = CorrectionConfig::builder().build().unwrap();
+        assert_eq!(config.threads, n_cores);
+
+        // Threads are capped to the share of each device
+        let config = CorrectionConfig::builder()
+            .devices(vec![Device::Cuda(0), Device::Cuda(1)])
+            .threads(16)
+            .encode_threads(2)
+            .max_threads(8)
+            .build()
+            .unwrap();
+        assert_eq!(config.threads, 4);
+        assert_eq!(config.encode_threads, Some(2));
+        let config = CorrectionConfig::builder()
+            .devices(vec![Device::Cpu])
+            .max_threads(1)
+            .build()
+            .unwrap();
+        assert_eq!((config.threads, config.intra_op_threads), (1, 1));
+        assert_eq!(config.workers_per_device, 1);
+        assert!(CorrectionConfig::builder().max_threads(0).build().is_err());
+
+        let workers = CorrectionConfig::builder().workers_per_device(0).build();
+        assert!(workers.is_err());
+        let threads = CorrectionConfig::builder().align_threads(0).build();
+        assert!(threads.is_err());
+        let threads = CorrectionConfig::builder()
+            .align_threads(4)
+            .encode_threads(2)
+            .writer_threads(2)
+            .build();
+        assert!(threads.is_ok());
+    }
+
+    #[test]
+    fn invalid_configs() {
+        assert!(FeatureGenConfig::builder().threads(0).build().is_err());
+        let extend = OverlapFilter {
+            ol_threshold: 1000,
+            ..Default::default()
+        };
+        assert!(FeatureGenConfig::builder()
+            .overlap_filter(extend)
+            .build()
+            .is_err());
+        let accuracy = OverlapFilter {
+            min_window_accuracy: Some(85.),
+            ..Default::default()
+        };
+        assert!(CorrectionConfig::builder()
+            .overlap_filter(accuracy)
+            .build()
+            .is_err());
+        let hdf5 = FeatureGenConfig::builder()
+            .feature_format(FeatureFormat::Hdf5)
+            .build();
+        assert_eq!(hdf5.is_ok(), cfg!(feature = "hdf5"));
+        let parquet = FeatureGenConfig::builder()
+            .feature_format(FeatureFormat::Parquet)
+            .build();
+        assert_eq!(parquet.is_ok(), cfg!(feature = "parquet"));
+        assert!(FeatureGenConfig::builder()
+            .feature_format(FeatureFormat::Parquet)
+            .compress_features(true)
+            .build()
+            .is_err());
+        let wfa = FeatureGenConfig::builder().aligner(Aligner::Wfa).build();
+        assert_eq!(wfa.is_ok(), cfg!(feature = "wfa"));
+        assert!(CorrectionConfig::builder().devices(vec![]).build().is_err());
+        assert!(CorrectionConfig::builder().batch_size(0).build().is_err());
+        assert!(CorrectionConfig::builder().batch_reads(0).build().is_err());
+        assert!(FeatureGenConfig::builder().batch_bases(0).build().is_err());
+        assert!(CorrectionConfig::builder()
+            .intra_op_threads(0)
+            .build()
+            .is_err());
+        assert!(CorrectionConfig::builder().aln_capacity(0).build().is_err());
+        assert!(CorrectionConfig::builder().max_memory(0).build().is_err());
+        let stride = |window_size, stride| {
+            CorrectionConfig::builder()
+                .window_size(window_size)
+                .window_stride(stride)
+                .build()
+        };
+        assert!(stride(4096, 2048).is_ok());
+        assert!(stride(4096, 512).is_ok());
+        assert!(stride(4096, 256).is_err());
+        assert!(stride(4096, 3000).is_err());
+        assert!(stride(4096, 0).is_err());
+        assert!(CorrectionConfig::builder().min_coverage(5).build().is_ok());
+        assert!(CorrectionConfig::builder().min_coverage(1).build().is_err());
+        assert!(CorrectionConfig::builder().trim_ends(3).build().is_ok());
+        assert!(CorrectionConfig::builder().trim_ends(1).build().is_err());
+        assert!(CorrectionConfig::builder().trim_ends(31).build().is_err());
+        assert!(CorrectionConfig::builder()
+            .top_k(40)
+            .trim_ends(31)
+            .build()
+            .is_ok());
+        assert!(CorrectionConfig::builder().top_k(0).build().is_err());
+        assert!(FeatureGenConfig::builder().top_k(255).build().is_err());
+        assert!(CorrectionConfig::builder()
+            .writer_capacity(0)
+            .build()
+            .is_err());
+        assert!(CorrectionConfig::builder()
+            .devices(vec![Device::Cpu, Device::Cuda(0)])
+            .build()
+            .is_err());
+        assert!(CorrectionConfig::builder()
+            .devices(vec![Device::Mps, Device::Mps])
+            .build()
+            .is_err());
+        assert!(CorrectionConfig::builder()
+            .devices(vec![Device::Cuda(1), Device::Cuda(1)])
+            .build()
+            .is_err());
+
+        let onnx = CorrectionConfig::builder()
+            .backend(Backend::Onnx(OnnxProvider::Cpu))
+            .build();
+        assert_eq!(onnx.is_ok(), cfg!(feature = "onnx"));
+
+        let candle = CorrectionConfig::builder().backend(Backend::Candle).build();
+        assert_eq!(candle.is_ok(), cfg!(feature = "candle"));
+
+        let fp16 = CorrectionConfig::builder()
+            .backend(Backend::Candle)
+            .precision(Precision::Fp16)
+            .build();
+        assert!(fp16.is_err());
+
+        let trt = CorrectionConfig::builder()
+            .backend(Backend::Onnx(OnnxProvider::TensorRt))
+            .precision(Precision::Bf16)
+            .build();
+        assert!(trt.is_err());
+
+        let int8 = || CorrectionConfig::builder().precision(Precision::Int8);
+        assert!(int8().devices(vec![Device::Cpu]).build().is_ok());
+        assert!(int8().devices(vec![Device::Cuda(0)]).build().is_err());
+        let int8_candle = int8().devices(vec![Device::Cpu]).backend(Backend::Candle);
+        assert!(int8_candle.build().is_err());
+        let trt_cache = CorrectionConfig::builder()
+            .trt_engine_cache("engines".into())
+            .build();
+        assert!(trt_cache.is_err());
+
+        let tag = CorrectionConfig::builder().tag_uncorrected(true).build();
+        assert!(tag.is_err());
+        let descriptions = CorrectionConfig::builder()
+            .keep_descriptions(true)
+            .indexed_reads(true)
+            .build();
+        assert!(descriptions.is_err());
+        let tags = CorrectionConfig::builder()
+            .keep_tags(true)
+            .keep_descriptions(true)
+            .build();
+        assert!(tags.is_err());
+
+        let from_features = || CorrectionConfig::builder().from_features("features".into());
+        assert!(from_features().build().is_ok());
+        assert!(from_features().window_stride(2048).build().is_err());
+        assert!(from_features()
+            .chimeras(ChimeraMode::Split)
+            .build()
+            .is_err());
+
+        let deterministic = CorrectionConfig::builder().deterministic(true);
+        assert!(deterministic.clone().build().is_ok());
+        assert!(deterministic.resume(true).build().is_err());
+    }
+
+    #[test]
+    fn read_shards() {
+        assert_eq!(
+            "2/3".parse::<ReadShard>().unwrap(),
+            ReadShard::new(2, 3).unwrap()
+        );
+        assert!("0/3".parse::<ReadShard>().is_err());
+        assert!("4/3".parse::<ReadShard>().is_err());
+        assert!("1-3".parse::<ReadShard>().is_err());
+
+        // Each read belongs to exactly one shard
+        let shards: Vec<_> = (1..=3).map(|i| ReadShard::new(i, 3).unwrap()).collect();
+        for id in ["read1", "read2", "a3f1c2d4-0b5e", ""] {
+            let n = shards.iter().filter(|s| s.contains(id.as_bytes())).count();
+            assert_eq!(n, 1);
+        }
+    }
+}
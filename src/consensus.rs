@@ -1,5 +1,6 @@
 use ndarray::Array2;
 
+use std::ops::Range;
 use std::{cmp::Reverse, collections::BinaryHeap};
 
 use crossbeam_channel::{Receiver, Sender};
@@ -9,12 +10,21 @@ use itertools::MinMaxResult::*;
 use ndarray::{s, Axis};
 use rustc_hash::FxHashMap as HashMap;
 
+use crate::aligners::{align, CigarOp};
+use crate::confidence::WindowConfidence;
+use crate::edits::{add_edit, Edit};
 use crate::features::SupportedPos;
+use crate::haplotypes::WindowGroups;
 
 use crate::inference::BASES_MAP;
 
 const BASES_UPPER: [u8; 10] = [b'A', b'C', b'G', b'T', b'*', b'A', b'C', b'G', b'T', b'*'];
-const BASES_UPPER_COUNTER: [usize; 10] = [0, 1, 2, 3, 4, 0, 1, 2, 3, 4];
+/// Class of the encoded bases, same for both strands
+pub(crate) const BASES_UPPER_COUNTER: [usize; 10] = [0, 1, 2, 3, 4, 0, 1, 2, 3, 4];
+const MAX_QUAL: u8 = 50;
+
+/// Band width for aligning the consensi of overlapping windows.
+const JUNCTION_BAND_WIDTH: usize = 64;
 
 // Bases, tidx, supported, logits
 pub(crate) struct ConsensusWindow {
@@ -26,8 +36,13 @@ pub(crate) struct ConsensusWindow {
     pub(crate) quals: Array2<f32>,
     pub(crate) indices: Vec<usize>,
     pub(crate) supported: Vec<SupportedPos>,
+    /// Ids of the supporting reads, in the order of their rows
+    pub(crate) qids: Vec<u32>,
+    /// Positions in the window at which the corrected read is split
+    pub(crate) breaks: Vec<u16>,
     pub(crate) info_logits: Option<Vec<f32>>,
     pub(crate) bases_logits: Option<Vec<u8>>,
+    pub(crate) bases_probs: Option<Vec<f32>>,
 }
 
 impl ConsensusWindow {
@@ -40,8 +55,10 @@ impl ConsensusWindow {
         quals: Array2<f32>,
         indices: Vec<usize>,
         supported: Vec<SupportedPos>,
+        qids: Vec<u32>,
         info_logits: Option<Vec<f32>>,
         bases_logits: Option<Vec<u8>>,
+        bases_probs: Option<Vec<f32>>,
     ) -> Self {
         Self {
             rid,
@@ -52,14 +69,60 @@ impl ConsensusWindow {
             quals,
             indices,
             supported,
+            qids,
+            breaks: Vec::new(),
             info_logits,
             bases_logits,
+            bases_probs,
         }
     }
 }
 
 pub type ConsensusData = Vec<ConsensusWindow>;
 
+/// Senders of the optional reports of the corrected reads
+#[derive(Clone, Default)]
+pub(crate) struct ReportSenders {
+    /// Mean confidences of the corrected windows
+    pub(crate) confidences: Option<Sender<(usize, Vec<WindowConfidence>)>>,
+    /// Corrections applied to the reads
+    pub(crate) edits: Option<Sender<(usize, Vec<Edit>)>>,
+    /// Groups of the supporting reads of the corrected windows
+    pub(crate) haplotypes: Option<Sender<(usize, Vec<WindowGroups>)>>,
+}
+
+/// Corrected sequence with Phred base qualities (without the ASCII offset)
+pub(crate) struct CorrectedSeq {
+    pub(crate) seq: Vec<u8>,
+    pub(crate) qual: Vec<u8>,
+}
+
+impl CorrectedSeq {
+    fn new() -> Self {
+        Self {
+            seq: Vec::new(),
+            qual: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, base: u8, p_correct: f32) {
+        self.seq.push(base);
+        self.qual.push(phred_quality(p_correct));
+    }
+
+    /// Appends the bases of the other sequence in the given range.
+    fn extend(&mut self, other: &CorrectedSeq, range: Range<usize>) {
+        self.seq.extend_from_slice(&other.seq[range.clone()]);
+        self.qual.extend_from_slice(&other.qual[range]);
+    }
+}
+
+/// Converts the probability that the base is correct into the Phred quality.
+fn phred_quality(p_correct: f32) -> u8 {
+    let p_error = (1. - p_correct).max(10f32.powf(-(MAX_QUAL as f32) / 10.));
+    (-10. * p_error.log10()).round().min(MAX_QUAL as f32) as u8
+}
+
 #[allow(dead_code)]
 fn two_most_frequent<'a, I>(elements: I) -> Vec<(usize, u8)>
 where
@@ -81,14 +144,288 @@ where
     heap.into_sorted_vec().into_iter().map(|r| r.0).collect()
 }
 
-fn consensus(data: ConsensusData, counts: &mut [u8]) -> Option<Vec<Vec<u8>>> {
+/// Corrected window with, for every target position of the window, the index
+/// in the corrected sequence where its bases (with the following insertions)
+/// start.
+struct CorrectedWindow {
+    seq: CorrectedSeq,
+    starts: Vec<usize>,
+    /// Empty if the edits are not recorded
+    edits: Vec<Edit>,
+    /// Indices in the corrected sequence at which the read is split
+    splits: Vec<usize>,
+}
+
+impl CorrectedWindow {
+    /// Index in the corrected window where the target position starts,
+    /// positions past the end of the window start at the end of the sequence.
+    fn start_of(&self, pos: usize) -> usize {
+        self.starts.get(pos).copied().unwrap_or(self.seq.seq.len())
+    }
+}
+
+/// Corrects a single window starting at `window_start` in the target read.
+fn window_consensus(
+    window: &ConsensusWindow,
+    window_start: usize,
+    counts: &mut [u8],
+    record_edits: bool,
+) -> CorrectedWindow {
+    let mut corrected = CorrectedSeq::new();
+    let mut starts = Vec::new();
+    let mut edits = Vec::new();
+
+    // Don't analyze empty rows: LxR -> LxN
+    //let n_rows = (window.n_alns + 1).min(TOP_K + 1);
+    let n_rows = window.n_alns + 1;
+    let bases = window.bases.slice(s![.., ..n_rows as usize]);
+    let maybe_info = match window.supported.len() {
+        0 => HashMap::default(),
+        _ => window
+            .supported
+            .iter()
+            .zip(window.bases_logits.as_ref().unwrap().iter())
+            .zip(window.bases_probs.as_ref().unwrap().iter())
+            .map(|((supp, bl), bp)| (*supp, (*bl, *bp)))
+            .collect(),
+    };
+
+    let (mut pos, mut ins) = (-1i32, 0);
+    for col in bases.axis_iter(Axis(0)) {
+        if col[0] == BASES_MAP[b'*' as usize] {
+            ins += 1;
+        } else {
+            pos += 1;
+            ins = 0;
+            starts.push(corrected.seq.len());
+        }
+        // Inserted bases are reported before the next target base
+        let read_pos = (window_start as i32 + pos + (ins > 0) as i32) as usize;
+
+        if let Some((b, p)) = maybe_info.get(&SupportedPos::new(pos as u16, ins)) {
+            let base = match *b {
+                0 => b'A',
+                1 => b'C',
+                2 => b'G',
+                3 => b'T',
+                4 => b'*',
+                _ => panic!("Unrecognized base"),
+            };
+
+            /*if *il > 0.0 {
+                println!(
+                    "{}\t{}\t{}",
+                    std::str::from_utf8(&read.id).unwrap(),
+                    corrected_seqs.len(),
+                    corrected.len(),
+                );
+            }*/
+
+            /*println!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                std::str::from_utf8(&read.id).unwrap(),
+                wid,
+                pos,
+                ins,
+                corrected_seqs.len(),
+                corrected.len(),
+                'S',
+                base
+            );*/
+            if record_edits {
+                let tbase = BASES_UPPER[col[0] as usize];
+                add_edit(&mut edits, read_pos, (tbase, base), *p, corrected.seq.len());
+            }
+            if base != b'*' {
+                corrected.push(base, *p);
+            }
+        } else {
+            // Count bases
+            counts.iter_mut().for_each(|c| *c = 0);
+            col.iter().for_each(|&b| {
+                if b != BASES_MAP[b'.' as usize] {
+                    counts[BASES_UPPER_COUNTER[b as usize]] += 1;
+                }
+            });
+
+            // Get two most common bases and counts - (c, b)
+            let (mc0, mc1) = counts
+                .iter()
+                .enumerate()
+                .sorted_by_key(|(_, c)| Reverse(*c))
+                .take(2)
+                .map(|(i, c)| (*c, BASES_UPPER[i]))
+                .collect_tuple()
+                .unwrap();
+            let tbase = BASES_UPPER[col[0] as usize];
+
+            let base = if mc0.0 < 2 || (mc0.0 == mc1.0 && (mc0.1 == tbase || mc1.1 == tbase)) {
+                tbase
+            } else {
+                mc0.1
+            };
+
+            /*println!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                std::str::from_utf8(&read.id).unwrap(),
+                wid,
+                pos,
+                ins,
+                corrected_seqs.len(),
+                corrected.len(),
+                "N",
+                base,
+            );*/
+            // Fraction of the supporting reads is used as the probability
+            let n_total: u32 = counts.iter().map(|&c| c as u32).sum();
+            let n_base = counts[BASES_UPPER_COUNTER[BASES_MAP[base as usize] as usize]];
+            let prob = n_base as f32 / n_total as f32;
+            if record_edits {
+                add_edit(
+                    &mut edits,
+                    read_pos,
+                    (tbase, base),
+                    prob,
+                    corrected.seq.len(),
+                );
+            }
+            if base != b'*' {
+                corrected.push(base, prob);
+            }
+        }
+    }
+
+    let mut corrected = CorrectedWindow {
+        seq: corrected,
+        starts,
+        edits,
+        splits: Vec::new(),
+    };
+    corrected.splits = window
+        .breaks
+        .iter()
+        .map(|&p| corrected.start_of(p as usize))
+        .collect();
+    corrected
+}
+
+/// Appends the range of the corrected window to the corrected sequence, with
+/// the edits applied in the range. The sequence is completed at the splits in
+/// the range and the rest of the range starts a new sequence.
+fn add_window(
+    (corrected_seqs, corrected, edits): (&mut Vec<CorrectedSeq>, &mut CorrectedSeq, &mut Vec<Edit>),
+    window: CorrectedWindow,
+    range: Range<usize>,
+) {
+    let mut start = range.start;
+    for &split in window.splits.iter().filter(|s| range.contains(s)) {
+        corrected.extend(&window.seq, start..split);
+        if !corrected.seq.is_empty() {
+            corrected_seqs.push(std::mem::replace(corrected, CorrectedSeq::new()));
+        }
+        start = split;
+    }
+
+    corrected.extend(&window.seq, start..range.end);
+    edits.extend(window.edits.into_iter().filter(|e| range.contains(&e.idx)));
+}
+
+/// Finds the junction of two consecutive overlapping windows. Consensi of both
+/// windows around the middle of the overlap are aligned and the windows are
+/// joined at the matching base closest to the middle, so bases which are
+/// inserted or deleted in only one of the consensi are neither duplicated nor
+/// lost. Returns the end of the previous window and the start of the next one.
+fn junction(
+    prev: &CorrectedWindow,
+    next: &CorrectedWindow,
+    (window_size, window_stride): (u32, u32),
+) -> (usize, usize) {
+    let (stride, overlap) = (
+        window_stride as usize,
+        (window_size - window_stride) as usize,
+    );
+    let half_overlap = overlap / 2;
+
+    // Junctions of consecutive windows are searched at most half of the stride
+    // from the middle, so they stay in order
+    let radius = half_overlap.min(stride / 2);
+    let (pstart, pend) = (
+        prev.start_of(stride + half_overlap - radius),
+        prev.start_of(stride + half_overlap + radius),
+    );
+    let (nstart, nend) = (
+        next.start_of(half_overlap - radius),
+        next.start_of(half_overlap + radius),
+    );
+    let midpoint = (
+        prev.start_of(stride + half_overlap),
+        next.start_of(half_overlap),
+    );
+
+    let (target, query) = (&prev.seq.seq[pstart..pend], &next.seq.seq[nstart..nend]);
+    let distance = |tpos: usize| tpos.abs_diff(target.len() / 2);
+    let (mut tpos, mut qpos) = (0, 0);
+    let mut best: Option<(usize, usize)> = None;
+    for op in align(target, query, JUNCTION_BAND_WIDTH) {
+        match op {
+            CigarOp::Match(l) | CigarOp::Mismatch(l) => {
+                for _ in 0..l {
+                    let closer = match best {
+                        Some((t, _)) => distance(tpos) < distance(t),
+                        None => true,
+                    };
+                    if target[tpos] == query[qpos] && closer {
+                        best = Some((tpos, qpos));
+                    }
+
+                    tpos += 1;
+                    qpos += 1;
+                }
+            }
+            CigarOp::Deletion(l) => tpos += l as usize,
+            CigarOp::Insertion(l) => qpos += l as usize,
+        }
+    }
+
+    best.map_or(midpoint, |(t, q)| (pstart + t, nstart + q))
+}
+
+/// Builds the corrected sequences of the read from its windows. Windows start
+/// every `window_stride` bases, and consecutive overlapping windows are joined
+/// at the junction found by aligning their consensi. Windows without a
+/// corrected neighbour are used up to the middle of their overlap. Leading and
+/// trailing windows with fewer than `min_end_alns` supporting reads are trimmed.
+/// Edits of the read are added to `edits` if given.
+fn consensus(
+    data: ConsensusData,
+    (window_size, window_stride): (u32, u32),
+    min_end_alns: u8,
+    counts: &mut [u8],
+    edits: Option<&mut Vec<Edit>>,
+) -> Option<Vec<CorrectedSeq>> {
+    let half_overlap = ((window_size - window_stride) / 2) as usize;
+    let window_end = |window: &ConsensusWindow, corrected: &CorrectedWindow| match window.wid + 1
+        == window.n_total_wins
+    {
+        true => corrected.seq.seq.len(),
+        false => corrected.start_of(window_stride as usize + half_overlap),
+    };
+    let record_edits = edits.is_some();
+    let mut read_edits = Vec::new();
+
     let mut corrected_seqs = Vec::new();
-    let mut corrected: Vec<u8> = Vec::new();
+    let mut corrected = CorrectedSeq::new();
 
     let minmax = data
         .iter()
         .enumerate()
-        .filter_map(|(idx, win)| if win.n_alns > 1 { Some(idx) } else { None })
+        .filter_map(|(idx, win)| {
+            if win.n_alns > 1 && win.n_alns >= min_end_alns {
+                Some(idx)
+            } else {
+                None
+            }
+        })
         .minmax();
     let (wid_st, wid_en) = match minmax {
         NoElements => {
@@ -98,128 +435,71 @@ fn consensus(data: ConsensusData, counts: &mut [u8]) -> Option<Vec<Vec<u8>>> {
         MinMax(st, en) => (st, en + 1),
     };
 
+    // Previous corrected window, which is not yet added from the given index
+    let mut pending: Option<(&ConsensusWindow, CorrectedWindow, usize)> = None;
     for window in data[wid_st..wid_en].iter() {
-        /*if window.n_alns < 2 {
-            let start = wid * window_size;
-            let end = ((wid + 1) * window_size).min(uncorrected.len());
-
-            corrected.extend(&uncorrected[start..end]);
-            continue;
-        }*/
         if window.n_alns < 2 {
+            if let Some((prev, prev_corrected, from)) = pending.take() {
+                let end = window_end(prev, &prev_corrected);
+                add_window(
+                    (&mut corrected_seqs, &mut corrected, &mut read_edits),
+                    prev_corrected,
+                    from..end,
+                );
+            }
+
             corrected_seqs.push(corrected);
-            corrected = Vec::new();
+            corrected = CorrectedSeq::new();
             continue;
         }
 
-        // Don't analyze empty rows: LxR -> LxN
-        //let n_rows = (window.n_alns + 1).min(TOP_K + 1);
-        let n_rows = window.n_alns + 1;
-        let bases = window.bases.slice(s![.., ..n_rows as usize]);
-        let maybe_info = match window.supported.len() {
-            0 => HashMap::default(),
-            _ => window
-                .supported
-                .iter()
-                .zip(window.info_logits.as_ref().unwrap().iter())
-                .zip(window.bases_logits.as_ref().unwrap().iter())
-                .map(|((supp, il), bl)| (*supp, (*il, *bl)))
-                .collect(),
-        };
-
-        let (mut pos, mut ins) = (-1i32, 0);
-        for col in bases.axis_iter(Axis(0)) {
-            if col[0] == BASES_MAP[b'*' as usize] {
-                ins += 1;
-            } else {
-                pos += 1;
-                ins = 0;
+        let window_start = window.wid as usize * window_stride as usize;
+        let window_corrected = window_consensus(window, window_start, counts, record_edits);
+        let from = match pending.take() {
+            Some((_, prev_corrected, prev_from)) => {
+                let (prev_end, from) = junction(
+                    &prev_corrected,
+                    &window_corrected,
+                    (window_size, window_stride),
+                );
+                let range = prev_from..prev_end;
+                add_window(
+                    (&mut corrected_seqs, &mut corrected, &mut read_edits),
+                    prev_corrected,
+                    range,
+                );
+                from
             }
-
-            if let Some((_, b)) = maybe_info.get(&SupportedPos::new(pos as u16, ins)) {
-                let base = match *b {
-                    0 => b'A',
-                    1 => b'C',
-                    2 => b'G',
-                    3 => b'T',
-                    4 => b'*',
-                    _ => panic!("Unrecognized base"),
-                };
-
-                /*if *il > 0.0 {
-                    println!(
-                        "{}\t{}\t{}",
-                        std::str::from_utf8(&read.id).unwrap(),
-                        corrected_seqs.len(),
-                        corrected.len(),
-                    );
-                }*/
-
-                /*println!(
-                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
-                    std::str::from_utf8(&read.id).unwrap(),
-                    wid,
-                    pos,
-                    ins,
-                    corrected_seqs.len(),
-                    corrected.len(),
-                    'S',
-                    base
-                );*/
-                if base != b'*' {
-                    corrected.push(base);
-                }
-            } else {
-                // Count bases
-                counts.iter_mut().for_each(|c| *c = 0);
-                col.iter().for_each(|&b| {
-                    if b != BASES_MAP[b'.' as usize] {
-                        counts[BASES_UPPER_COUNTER[b as usize]] += 1;
-                    }
-                });
-
-                // Get two most common bases and counts - (c, b)
-                let (mc0, mc1) = counts
-                    .iter()
-                    .enumerate()
-                    .sorted_by_key(|(_, c)| Reverse(*c))
-                    .take(2)
-                    .map(|(i, c)| (*c, BASES_UPPER[i]))
-                    .collect_tuple()
-                    .unwrap();
-                let tbase = BASES_UPPER[col[0] as usize];
-
-                let base = if mc0.0 < 2 || (mc0.0 == mc1.0 && (mc0.1 == tbase || mc1.1 == tbase)) {
-                    tbase
-                } else {
-                    mc0.1
-                };
-
-                /*println!(
-                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
-                    std::str::from_utf8(&read.id).unwrap(),
-                    wid,
-                    pos,
-                    ins,
-                    corrected_seqs.len(),
-                    corrected.len(),
-                    "N",
-                    base,
-                );*/
-                if base != b'*' {
-                    corrected.push(base);
-                }
-            }
-        }
+            None if window.wid == 0 => 0,
+            None => window_corrected.start_of(half_overlap),
+        };
+        pending = Some((window, window_corrected, from));
     }
 
+    if let Some((prev, prev_corrected, from)) = pending {
+        let end = window_end(prev, &prev_corrected);
+        add_window(
+            (&mut corrected_seqs, &mut corrected, &mut read_edits),
+            prev_corrected,
+            from..end,
+        );
+    }
     corrected_seqs.push(corrected);
+    if let Some(edits) = edits {
+        edits.extend(read_edits);
+    }
     Some(corrected_seqs)
 }
 
+/// Merges the windows of each read into the corrected sequences, which are
+/// empty for reads which cannot be corrected. Reports are sent to the given
+/// senders.
 pub(crate) fn consensus_worker(
     receiver: Receiver<ConsensusData>,
-    sender: Sender<(usize, Vec<Vec<u8>>)>,
+    sender: Sender<(usize, Vec<CorrectedSeq>)>,
+    reports: ReportSenders,
+    (window_size, window_stride): (u32, u32),
+    min_end_alns: u8,
 ) {
     let mut consensus_data = HashMap::default();
     let mut counts = [0u8; 5];
@@ -240,10 +520,35 @@ pub(crate) fn consensus_worker(
                 let mut windows = consensus_data.remove(&rid).unwrap();
                 windows.sort_by_key(|cw| cw.wid);
 
-                let seq = consensus(windows, &mut counts);
-
-                if let Some(s) = seq {
-                    sender.send((rid as usize, s)).unwrap();
+                if let Some(confidence_sender) = reports.confidences.as_ref() {
+                    let confidences: Vec<_> =
+                        windows.iter().filter_map(WindowConfidence::new).collect();
+                    if !confidences.is_empty() {
+                        confidence_sender.send((rid as usize, confidences)).unwrap();
+                    }
+                }
+                if let Some(haplotypes_sender) = reports.haplotypes.as_ref() {
+                    let groups: Vec<_> = windows.iter().filter_map(WindowGroups::new).collect();
+                    if !groups.is_empty() {
+                        haplotypes_sender.send((rid as usize, groups)).unwrap();
+                    }
+                }
+                let mut edits = Vec::new();
+                let seq = consensus(
+                    windows,
+                    (window_size, window_stride),
+                    min_end_alns,
+                    &mut counts,
+                    reports.edits.as_ref().map(|_| &mut edits),
+                );
+
+                // Uncorrected reads are sent without sequences, so the writer
+                // knows they are done
+                sender
+                    .send((rid as usize, seq.unwrap_or_default()))
+                    .unwrap();
+                if let Some(edits_sender) = reports.edits.as_ref().filter(|_| !edits.is_empty()) {
+                    edits_sender.send((rid as usize, edits)).unwrap();
                 }
             }
         }
@@ -251,3 +556,147 @@ pub(crate) fn consensus_worker(
         //println!("Consensus device: {}, in {}", device, receiver.len());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+
+    use super::{consensus, phred_quality, ConsensusWindow, MAX_QUAL};
+    use crate::inference::BASES_MAP;
+
+    #[test]
+    fn phred_qualities() {
+        assert_eq!(phred_quality(0.9), 10);
+        assert_eq!(phred_quality(0.999), 30);
+        assert_eq!(phred_quality(1.0), MAX_QUAL);
+        assert_eq!(phred_quality(0.0), 0);
+    }
+
+    #[test]
+    fn consensus_overlapping_windows() {
+        // Windows of size 8 with stride 4 cover the read of length 12
+        let window = |wid, base: u8| {
+            let bases = Array2::from_elem((8, 3), BASES_MAP[base as usize]);
+            let quals = Array2::zeros((8, 3));
+            let qids = vec![2, 3];
+            ConsensusWindow::new(
+                1,
+                wid,
+                2,
+                2,
+                bases,
+                quals,
+                vec![],
+                vec![],
+                qids,
+                None,
+                None,
+                None,
+            )
+        };
+
+        let data = vec![window(0, b'A'), window(1, b'C')];
+        let corrected = consensus(data, (8, 4), 2, &mut [0; 5], None).unwrap();
+        assert_eq!(corrected.len(), 1);
+        assert_eq!(corrected[0].seq, b"AAAAAACCCCCC");
+    }
+
+    /// Window whose target row and two supporting rows are given
+    fn aligned_window(
+        wid: u16,
+        n_total_wins: u16,
+        target: &[u8],
+        others: &[u8],
+    ) -> ConsensusWindow {
+        let mut bases = Array2::zeros((target.len(), 3));
+        for (i, (&t, &o)) in target.iter().zip(others.iter()).enumerate() {
+            bases[[i, 0]] = BASES_MAP[t as usize];
+            bases[[i, 1]] = BASES_MAP[o as usize];
+            bases[[i, 2]] = BASES_MAP[o as usize];
+        }
+        let quals = Array2::zeros((target.len(), 3));
+        ConsensusWindow::new(
+            1,
+            wid,
+            2,
+            n_total_wins,
+            bases,
+            quals,
+            vec![],
+            vec![],
+            vec![2, 3],
+            None,
+            None,
+            None,
+        )
+    }
+
+    #[test]
+    fn consensus_window_junction() {
+        // Read ACGTACGTACGT, only the first window has an insertion after the
+        // position 5, which is in the middle of the overlap
+        let data = vec![
+            aligned_window(0, 2, b"ACGTAC*GT", b"ACGTACGGT"),
+            aligned_window(1, 2, b"ACGTACGT", b"ACGTACGT"),
+        ];
+        let mut edits = Vec::new();
+        let corrected = consensus(data, (8, 4), 2, &mut [0; 5], Some(&mut edits)).unwrap();
+        assert_eq!(corrected.len(), 1);
+        assert_eq!(corrected[0].seq, b"ACGTACGTACGT");
+        // Insertion is not in the used part of the first window
+        assert!(edits.is_empty());
+    }
+
+    #[test]
+    fn consensus_edits() {
+        let data = vec![
+            aligned_window(0, 2, b"ACGT", b"AGGT"),
+            aligned_window(1, 2, b"AC*GTA", b"A*TGTA"),
+        ];
+        let mut edits = Vec::new();
+        let corrected = consensus(data, (4, 4), 2, &mut [0; 5], Some(&mut edits)).unwrap();
+        assert_eq!(corrected[0].seq, b"AGGTATGTA");
+
+        let edits: Vec<_> = edits
+            .iter()
+            .map(|e| (e.pos, e.tbases.as_slice(), e.bases.as_slice()))
+            .collect();
+        assert_eq!(
+            edits,
+            [(1, &b"C"[..], &b"G"[..]), (5, b"C", b""), (6, b"", b"T")]
+        );
+    }
+
+    #[test]
+    fn consensus_split() {
+        let mut second = aligned_window(1, 2, b"ACGTAC", b"ACGTAC");
+        second.breaks = vec![2];
+        let data = vec![aligned_window(0, 2, b"ACGT", b"ACGT"), second];
+        let corrected = consensus(data, (4, 4), 2, &mut [0; 5], None).unwrap();
+
+        let seqs: Vec<_> = corrected.iter().map(|c| c.seq.as_slice()).collect();
+        assert_eq!(seqs, [&b"ACGTAC"[..], b"GTAC"]);
+    }
+
+    #[test]
+    fn consensus_trim_ends() {
+        let data = || {
+            // Middle window is supported by three reads
+            let mut middle = aligned_window(1, 3, b"CCCC", b"CCCC");
+            middle.bases = Array2::from_elem((4, 4), BASES_MAP[b'C' as usize]);
+            middle.quals = Array2::zeros((4, 4));
+            middle.n_alns = 3;
+            vec![
+                aligned_window(0, 3, b"ACGT", b"ACGT"),
+                middle,
+                aligned_window(2, 3, b"GGTT", b"GGTT"),
+            ]
+        };
+
+        let corrected = consensus(data(), (4, 4), 2, &mut [0; 5], None).unwrap();
+        assert_eq!(corrected[0].seq, b"ACGTCCCCGGTT");
+        let corrected = consensus(data(), (4, 4), 3, &mut [0; 5], None).unwrap();
+        assert_eq!(corrected[0].seq, b"CCCC");
+        assert!(consensus(data(), (4, 4), 4, &mut [0; 5], None).is_none());
+    }
+}
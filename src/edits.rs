@@ -0,0 +1,123 @@
+use std::io::{self, prelude::*};
+
+use crossbeam_channel::Receiver;
+
+use crate::haec_io::HAECRecord;
+
+const EDITS_HEADER: &[u8] = b"read\tposition\tref\tcorrected\tprobability\n";
+
+/// Correction applied to the target read. Substituted and deleted bases are
+/// given at their positions in the target read, inserted bases at the position
+/// of the target base before which they are inserted.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Edit {
+    pub(crate) pos: usize,
+    /// Target bases, empty for insertions
+    pub(crate) tbases: Vec<u8>,
+    /// Corrected bases, empty for deletions
+    pub(crate) bases: Vec<u8>,
+    /// Probability of the corrected bases, the lowest one for multiple bases
+    pub(crate) prob: f32,
+    /// Index in the corrected window where the edit is applied
+    pub(crate) idx: usize,
+}
+
+/// Records the edit if the corrected base differs from the target base, `*`
+/// is a gap. Bases inserted before the same target base are merged.
+pub(crate) fn add_edit(
+    edits: &mut Vec<Edit>,
+    pos: usize,
+    (tbase, base): (u8, u8),
+    prob: f32,
+    idx: usize,
+) {
+    if tbase == base {
+        return;
+    }
+
+    let gap = |b: u8| match b {
+        b'*' => Vec::new(),
+        b => vec![b],
+    };
+    if let Some(last) = edits.last_mut() {
+        let adjacent = last.idx + last.bases.len() == idx;
+        if tbase == b'*' && last.tbases.is_empty() && last.pos == pos && adjacent {
+            last.bases.push(base);
+            last.prob = last.prob.min(prob);
+            return;
+        }
+    }
+
+    edits.push(Edit {
+        pos,
+        tbases: gap(tbase),
+        bases: gap(base),
+        prob,
+        idx,
+    });
+}
+
+/// Writes the edits of the corrected reads as TSV with a row per edit, gaps
+/// are written as `-`.
+pub(crate) fn edits_writer(
+    reads: &[HAECRecord],
+    mut writer: impl Write,
+    write_header: bool,
+    receiver: Receiver<(usize, Vec<Edit>)>,
+) -> io::Result<()> {
+    if write_header {
+        writer.write_all(EDITS_HEADER)?;
+    }
+
+    let or_gap = |bases: &[u8]| match bases.is_empty() {
+        true => "-".to_string(),
+        false => String::from_utf8_lossy(bases).into_owned(),
+    };
+    for (rid, edits) in receiver {
+        let id = &reads[rid].id;
+        for edit in edits {
+            writer.write_all(id)?;
+            writeln!(
+                writer,
+                "\t{}\t{}\t{}\t{:.4}",
+                edit.pos,
+                or_gap(&edit.tbases),
+                or_gap(&edit.bases),
+                edit.prob
+            )?;
+        }
+    }
+
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_edit, Edit};
+
+    #[test]
+    fn merge_insertions() {
+        let mut edits = Vec::new();
+        add_edit(&mut edits, 3, (b'A', b'A'), 0.9, 3);
+        add_edit(&mut edits, 4, (b'*', b'C'), 0.9, 4);
+        add_edit(&mut edits, 4, (b'*', b'G'), 0.8, 5);
+        add_edit(&mut edits, 4, (b'A', b'T'), 0.7, 6);
+        add_edit(&mut edits, 5, (b'C', b'*'), 0.6, 7);
+
+        let edit = |pos, tbases: &[u8], bases: &[u8], prob, idx| Edit {
+            pos,
+            tbases: tbases.to_vec(),
+            bases: bases.to_vec(),
+            prob,
+            idx,
+        };
+        assert_eq!(
+            edits,
+            [
+                edit(4, b"", b"CG", 0.8, 4),
+                edit(4, b"A", b"T", 0.7, 6),
+                edit(5, b"C", b"", 0.6, 7)
+            ]
+        );
+    }
+}
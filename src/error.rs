@@ -0,0 +1,61 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum HerroError {
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("Invalid reads: {0}")]
+    InvalidReads(String),
+
+    #[error("Invalid record at line {line}: {message}")]
+    InvalidLine { line: usize, message: String },
+
+    #[error("Invalid BAM record {record}: {message}")]
+    InvalidRecord { record: usize, message: String },
+
+    #[error("Cannot load model: {0}")]
+    Model(String),
+
+    #[error("HDF5 error: {0}")]
+    Hdf5(String),
+
+    #[error("Arrow error: {0}")]
+    Arrow(String),
+
+    #[error("{0} validation checks failed")]
+    Validation(usize),
+
+    #[error("Cannot download model: {0}")]
+    Download(String),
+
+    #[error("Cannot run minimap2: {0}")]
+    Minimap2(io::Error),
+
+    #[error("Correction panicked: {0}")]
+    Panic(String),
+
+    #[error("{}: {source}", path.display())]
+    File {
+        path: PathBuf,
+        source: Box<HerroError>,
+    },
+}
+
+impl HerroError {
+    /// Adds the path of the file which caused the error.
+    pub(crate) fn in_file<P: AsRef<Path>>(self, path: P) -> Self {
+        HerroError::File {
+            path: path.as_ref().to_owned(),
+            source: Box::new(self),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, HerroError>;
@@ -0,0 +1,355 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use flate2::bufread::MultiGzDecoder;
+
+use crate::error::{HerroError, Result};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Record of the samtools faidx index (.fai). Sequence and quality lines
+/// contain `line_bases` bases and take `line_width` bytes with the line ending.
+/// Quality offset is given only for FASTQ files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FaiEntry {
+    pub(crate) length: usize,
+    offset: u64,
+    line_bases: u64,
+    line_width: u64,
+    qual_offset: Option<u64>,
+}
+
+impl FaiEntry {
+    /// Byte range of the bases in the file, including line endings.
+    fn file_range(&self, start: u64, range: &Range<usize>) -> Range<u64> {
+        let pos = |i: u64| start + i / self.line_bases * self.line_width + i % self.line_bases;
+        pos(range.start as u64)..pos(range.end as u64 - 1) + 1
+    }
+}
+
+/// Read names with their index records, in the order of the file.
+pub(crate) type FaiIndex = Vec<(Vec<u8>, FaiEntry)>;
+
+pub(crate) fn fai_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".fai");
+    PathBuf::from(path)
+}
+
+fn gzi_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".gzi");
+    PathBuf::from(path)
+}
+
+/// Parses the index, returns the read names with their records.
+fn parse_fai(reader: impl BufRead) -> Result<FaiIndex> {
+    let mut entries = Vec::new();
+    for (i, line) in reader.split(b'\n').enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+
+        let invalid = |message: &str| HerroError::InvalidLine {
+            line: i + 1,
+            message: message.to_string(),
+        };
+        let mut fields = line.split(|c| *c == b'\t');
+        let name = fields.next().unwrap().to_vec();
+        let mut numbers = fields.map(|f| {
+            std::str::from_utf8(f)
+                .ok()
+                .and_then(|f| f.trim_end().parse::<u64>().ok())
+        });
+        let mut next = |field: &str| {
+            numbers
+                .next()
+                .flatten()
+                .ok_or_else(|| invalid(&format!("missing or invalid {}", field)))
+        };
+
+        let entry = FaiEntry {
+            length: next("length")? as usize,
+            offset: next("offset")?,
+            line_bases: next("line bases")?,
+            line_width: next("line width")?,
+            qual_offset: next("quality offset").ok(),
+        };
+        if entry.line_bases == 0 || entry.line_width < entry.line_bases {
+            return Err(invalid("invalid line length"));
+        }
+
+        entries.push((name, entry));
+    }
+
+    Ok(entries)
+}
+
+/// Parses the .gzi index of a bgzipped file. Block offsets (compressed and
+/// uncompressed) are returned with the first block, which is not stored.
+fn parse_gzi(mut reader: impl Read) -> io::Result<Vec<(u64, u64)>> {
+    let mut buffer = [0; 8];
+    reader.read_exact(&mut buffer)?;
+    let n_blocks = u64::from_le_bytes(buffer);
+
+    let mut blocks = vec![(0, 0)];
+    for _ in 0..n_blocks {
+        reader.read_exact(&mut buffer)?;
+        let compressed = u64::from_le_bytes(buffer);
+        reader.read_exact(&mut buffer)?;
+        let uncompressed = u64::from_le_bytes(buffer);
+
+        blocks.push((compressed, uncompressed));
+    }
+
+    Ok(blocks)
+}
+
+#[cfg(unix)]
+pub(crate) fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+/// Reader which starts at the given offset, without changing the position of
+/// the file, so the file can be shared by threads.
+struct PositionedReader<'a> {
+    file: &'a File,
+    position: u64,
+}
+
+impl Read for PositionedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = read_at(self.file, buf, self.position)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+/// FASTA/FASTQ file indexed with samtools faidx. Bgzipped files are read from
+/// the start of the BGZF block containing the requested offset, which is found
+/// with the .gzi index.
+#[derive(Debug)]
+pub(crate) struct IndexedFile {
+    path: PathBuf,
+    file: File,
+    blocks: Option<Vec<(u64, u64)>>,
+}
+
+impl IndexedFile {
+    /// Opens the file with its index, returns the read names with their records.
+    pub(crate) fn open(path: &Path) -> Result<(Arc<Self>, FaiIndex)> {
+        let fai = fai_path(path);
+        let entries = File::open(&fai)
+            .map_err(HerroError::from)
+            .and_then(|f| parse_fai(BufReader::new(f)))
+            .map_err(|e| e.in_file(&fai))?;
+
+        let file = File::open(path).map_err(|e| HerroError::from(e).in_file(path))?;
+        let mut magic = [0; 2];
+        let n = read_at(&file, &mut magic, 0).map_err(|e| HerroError::from(e).in_file(path))?;
+
+        let blocks = if n == magic.len() && magic == GZIP_MAGIC {
+            let gzi = gzi_path(path);
+            let blocks = File::open(&gzi)
+                .and_then(|f| parse_gzi(BufReader::new(f)))
+                .map_err(|e| HerroError::from(e).in_file(&gzi))?;
+            Some(blocks)
+        } else {
+            None
+        };
+
+        let file = Self {
+            path: path.to_owned(),
+            file,
+            blocks,
+        };
+        Ok((Arc::new(file), entries))
+    }
+
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let blocks = match self.blocks {
+            Some(ref blocks) => blocks,
+            None => {
+                let mut reader = PositionedReader {
+                    file: &self.file,
+                    position: offset,
+                };
+                return reader.read_exact(buf);
+            }
+        };
+
+        // Last block which starts before the offset
+        let idx = blocks.partition_point(|&(_, u)| u <= offset) - 1;
+        let (compressed, uncompressed) = blocks[idx];
+        let reader = PositionedReader {
+            file: &self.file,
+            position: compressed,
+        };
+
+        let mut decoder = MultiGzDecoder::new(BufReader::new(reader));
+        let skip = offset - uncompressed;
+        let skipped = io::copy(&mut (&mut decoder).take(skip), &mut io::sink())?;
+        if skipped < skip {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        decoder.read_exact(buf)
+    }
+
+    /// Reads the bases (or qualities) in the range, starting at `start`.
+    fn fetch(&self, entry: &FaiEntry, start: u64, range: Range<usize>) -> Result<Vec<u8>> {
+        if range.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let file_range = entry.file_range(start, &range);
+        let mut data = vec![0; (file_range.end - file_range.start) as usize];
+        self.read_exact_at(file_range.start, &mut data)
+            .map_err(|e| HerroError::from(e).in_file(&self.path))?;
+        data.retain(|&c| c != b'\n' && c != b'\r');
+
+        if data.len() != range.len() {
+            let e = io::Error::new(io::ErrorKind::InvalidData, "reads do not match their index");
+            return Err(HerroError::from(e).in_file(&self.path));
+        }
+
+        Ok(data)
+    }
+}
+
+/// Read whose bases and qualities are fetched from the indexed file.
+#[derive(Debug, Clone)]
+pub(crate) struct IndexedRead {
+    file: Arc<IndexedFile>,
+    entry: FaiEntry,
+}
+
+impl IndexedRead {
+    pub(crate) fn new(file: Arc<IndexedFile>, entry: FaiEntry) -> Self {
+        Self { file, entry }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entry.length
+    }
+
+    pub(crate) fn has_quals(&self) -> bool {
+        self.entry.qual_offset.is_some()
+    }
+
+    pub(crate) fn fetch_seq(&self, range: Range<usize>) -> Result<Vec<u8>> {
+        self.file.fetch(&self.entry, self.entry.offset, range)
+    }
+
+    /// Returns None for reads without qualities (FASTA).
+    pub(crate) fn fetch_qual(&self, range: Range<usize>) -> Result<Option<Vec<u8>>> {
+        match self.entry.qual_offset {
+            Some(offset) => self.file.fetch(&self.entry, offset, range).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl PartialEq for IndexedRead {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.file, &other.file) && self.entry == other.entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::Path;
+
+    use crate::bgzf::compress_block;
+
+    use super::{fai_path, gzi_path, parse_fai, IndexedFile, IndexedRead};
+
+    fn write_file(path: &Path, content: &[u8]) {
+        File::create(path).unwrap().write_all(content).unwrap();
+    }
+
+    #[test]
+    fn parse_index() {
+        let entries = parse_fai("r1\t10\t4\t10\t11\t19\nr2\t8\t35\t4\t5\n".as_bytes()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].0, b"r1");
+        assert_eq!(entries[0].1.qual_offset, Some(19));
+        assert_eq!(entries[1].1.length, 8);
+        assert_eq!(entries[1].1.qual_offset, None);
+
+        assert!(parse_fai("r1\t10\tx\t10\t11\n".as_bytes()).is_err());
+        assert!(parse_fai("r1\t10\t4\t0\t1\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn fetch_indexed_reads() {
+        let dir = std::env::temp_dir().join(format!("herro-faidx-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // Multi-line FASTA
+        let fasta = dir.join("reads.fa");
+        write_file(&fasta, b">r1\nACGTA\nCGTAC\nGT\n>r2\nTTTT\n");
+        write_file(&fai_path(&fasta), b"r1\t12\t4\t5\t6\nr2\t4\t23\t4\t5\n");
+
+        let (file, entries) = IndexedFile::open(&fasta).unwrap();
+        let r1 = IndexedRead::new(file.clone(), entries[0].1.clone());
+        assert_eq!(r1.fetch_seq(0..12).unwrap(), b"ACGTACGTACGT");
+        assert_eq!(r1.fetch_seq(3..9).unwrap(), b"TACGTA");
+        assert_eq!(r1.fetch_qual(0..12).unwrap(), None);
+        let r2 = IndexedRead::new(file, entries[1].1.clone());
+        assert_eq!(r2.fetch_seq(1..4).unwrap(), b"TTT");
+
+        // Reads which do not match their index are an error
+        let truncated = dir.join("truncated.fa");
+        write_file(&truncated, b">r1\nACGTA\n");
+        write_file(&fai_path(&truncated), b"r1\t12\t4\t5\t6\n");
+        let (file, entries) = IndexedFile::open(&truncated).unwrap();
+        let r1 = IndexedRead::new(file, entries[0].1.clone());
+        assert!(r1.fetch_seq(0..12).is_err());
+
+        // FASTQ, bgzipped in small blocks
+        let fastq = b"@r1\nACGTACGT\n+\n!!!!IIII\n@r2\nGGCC\n+\n#$%&\n";
+        let fai = b"r1\t8\t4\t8\t9\t15\nr2\t4\t28\t4\t5\t35\n";
+        let fastq_path = dir.join("reads.fq");
+        write_file(&fastq_path, fastq);
+        write_file(&fai_path(&fastq_path), fai);
+
+        let bgzf_path = dir.join("reads.fq.gz");
+        let (mut bgzf, mut gzi) = (Vec::new(), Vec::new());
+        for (i, chunk) in fastq.chunks(7).enumerate() {
+            if i > 0 {
+                gzi.extend_from_slice(&(bgzf.len() as u64).to_le_bytes());
+                gzi.extend_from_slice(&(i as u64 * 7).to_le_bytes());
+            }
+            bgzf.extend(compress_block(chunk).unwrap());
+        }
+        let n_blocks = (gzi.len() / 16) as u64;
+        gzi.splice(0..0, n_blocks.to_le_bytes());
+        write_file(&bgzf_path, &bgzf);
+        write_file(&fai_path(&bgzf_path), fai);
+        write_file(&gzi_path(&bgzf_path), &gzi);
+
+        for path in [fastq_path, bgzf_path] {
+            let (file, entries) = IndexedFile::open(&path).unwrap();
+            let r1 = IndexedRead::new(file.clone(), entries[0].1.clone());
+            assert_eq!(r1.fetch_seq(2..6).unwrap(), b"GTAC");
+            assert_eq!(r1.fetch_qual(2..6).unwrap().unwrap(), b"!!II");
+            let r2 = IndexedRead::new(file, entries[1].1.clone());
+            assert_eq!(r2.fetch_seq(0..4).unwrap(), b"GGCC");
+            assert_eq!(r2.fetch_qual(0..4).unwrap().unwrap(), b"#$%&");
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,23 +1,53 @@
-use npyz::WriterBuilder;
-use rustc_hash::FxHashMap as HashMap;
-use std::fs::{create_dir_all, File};
+use npyz::{NpyFile, WriterBuilder};
+use rustc_hash::{FxHashMap as HashMap, FxHashSet as HashSet};
+use std::cmp::Reverse;
+use std::fs::{self, create_dir_all, File};
 use std::io::prelude::*;
-use std::io::{BufWriter, Result};
-use std::path::Path;
+use std::io::{self, BufWriter, Cursor, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use crossbeam_channel::Sender;
+use itertools::Itertools;
 
-use ndarray::{s, stack, Array, Array2, ArrayBase, ArrayViewMut1, Axis, Data, Ix2};
+use ndarray::{s, stack, Array, Array2, Array3, ArrayBase, ArrayViewMut1, Axis, Data, Ix2};
 use ordered_float::OrderedFloat;
-
-use crate::aligners::CigarOp;
-use crate::haec_io::HAECRecord;
-use crate::inference::{prepare_examples, InferenceData, WindowExample};
-use crate::overlaps::{Alignment, Strand};
+use tracing::info;
+
+use crate::aligners::{CigarOp, PairwiseAligner};
+use crate::aln_cache::AlignmentCache;
+use crate::error::HerroError;
+use crate::haec_io::{open_text_file, HAECRecord};
+use crate::inference::{prepare_examples, FeatureEncoder, InferenceData, WindowExample};
+use crate::overlaps::{Alignment, Overlap, Strand};
 use crate::pbars::PBarNotification;
+use crate::spill::{features_memory, FeatureSpill, SpilledBatch};
 use crate::windowing::{extract_windows, OverlapWindow};
-
-pub(crate) const TOP_K: usize = 30;
+use crate::{FeatureFormat, WindowReadSelection};
+
+#[cfg(feature = "arrow")]
+use crate::columnar::{TableFormat, TableWriter};
+
+/// Supporting reads per window of the released models
+pub(crate) const DEFAULT_TOP_K: usize = 30;
+/// Support of a position, including the target, is counted in a byte
+pub(crate) const MAX_TOP_K: usize = 254;
+
+/// Windows into which a target read is split and the overlaps which support
+/// them.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct WindowParams {
+    pub(crate) size: u32,
+    /// Distance between the starts of adjacent windows, divides the size
+    pub(crate) stride: u32,
+    /// Maximum number of supporting reads of a window
+    pub(crate) top_k: usize,
+    /// Minimum accuracy of an overlap aligned to a window
+    pub(crate) min_accuracy: Option<f32>,
+    /// Order in which the overlaps of a window are chosen as its supporting
+    /// reads
+    pub(crate) selection: WindowReadSelection,
+}
 
 const BASE_LOWER: [u8; 128] = [
     255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
@@ -45,9 +75,10 @@ fn get_max_ins_for_window(
     tid: u32,
     tstart: usize,
     window_length: usize,
+    top_k: usize,
 ) -> Vec<u16> {
     let mut max_ins = vec![0; window_length];
-    for ow in overlaps.iter().take(TOP_K) {
+    for ow in overlaps.iter().take(top_k) {
         let mut tpos = ow.tstart as usize - tstart;
 
         // Handle cigar
@@ -102,7 +133,7 @@ fn get_features_for_ol_window(
     tid: u32,
     max_ins: &[u16],
     qbuffer: &mut [u8],
-) {
+) -> crate::error::Result<()> {
     // Handle query sequence
     let (qstart, qend) = if window.overlap.tid == tid {
         (window.overlap.qstart, window.overlap.qend)
@@ -110,28 +141,25 @@ fn get_features_for_ol_window(
         (window.overlap.tstart, window.overlap.tend)
     };
 
+    let range = match window.overlap.strand {
+        Strand::Forward => (qstart + window.qstart) as usize..(qstart + window.qend) as usize,
+        Strand::Reverse => (qend - window.qend) as usize..(qend - window.qstart) as usize,
+    };
+    let qlen = (window.qend - window.qstart) as usize;
+    let query_quals = query.qual.get(range.clone())?;
+
     let mut query_iter: Box<dyn DoubleEndedIterator<Item = (&u8, &u8)>> =
         match window.overlap.strand {
             Strand::Forward => {
-                let range = (qstart + window.qstart) as usize..(qstart + window.qend) as usize;
-                let qlen = (window.qend - window.qstart) as usize;
-
-                query.seq.get_subseq(range.clone(), qbuffer);
-                let quals = &query.qual[range];
-
-                Box::new(qbuffer[..qlen].iter().zip(quals))
+                query.seq.get_subseq(range, qbuffer)?;
+                Box::new(qbuffer[..qlen].iter().zip(query_quals.iter()))
             }
             Strand::Reverse => {
-                let range = (qend - window.qend) as usize..(qend - window.qstart) as usize;
-                let qlen = (window.qend - window.qstart) as usize;
-
-                query.seq.get_rc_subseq(range.clone(), qbuffer);
-                let quals = &query.qual[range];
-
+                query.seq.get_rc_subseq(range, qbuffer)?;
                 Box::new(
                     qbuffer[..qlen]
                         .iter()
-                        .zip(quals.iter().rev())
+                        .zip(query_quals.iter().rev())
                         .map(|(b, q)| (&BASE_LOWER[*b as usize], q)),
                 )
             }
@@ -230,6 +258,8 @@ fn get_features_for_ol_window(
         // No alignment at the end
         bases.slice_mut(s![idx..]).fill(b'.');
     }
+
+    Ok(())
 }
 
 fn write_target_for_window(
@@ -240,7 +270,7 @@ fn write_target_for_window(
     mut quals: ArrayViewMut1<'_, f32>,
     window_length: usize,
     tbuffer: &[u8],
-) {
+) -> crate::error::Result<()> {
     bases.fill(b'*'); // Fill like forward
 
     /*let tlen = tstart + window_length - tstart;
@@ -251,7 +281,7 @@ fn write_target_for_window(
     let mut tpos = 0;
     tbuffer[tstart..tstart + window_length]
         .iter()
-        .zip(target.qual[tstart..tstart + window_length].iter())
+        .zip(target.qual.get(tstart..tstart + window_length)?.iter())
         .enumerate()
         .for_each(|(i, (b, q))| {
             bases[tpos] = *b;
@@ -259,6 +289,8 @@ fn write_target_for_window(
 
             tpos += 1 + max_ins[i] as usize;
         });
+
+    Ok(())
 }
 
 fn get_features_for_window(
@@ -269,14 +301,15 @@ fn get_features_for_window(
     max_ins: &[u16],
     tstart: usize,
     window_length: usize, // Full window length
+    top_k: usize,
     tbuffer: &[u8],
     qbuffer: &mut [u8],
-) -> (Array2<u8>, Array2<f32>) {
+) -> crate::error::Result<(Array2<u8>, Array2<f32>)> {
     //Get features
     let length = max_ins.iter().map(|v| *v as usize).sum::<usize>() + max_ins.len();
 
-    let mut bases = Array::from_elem((length, 1 + TOP_K), b'.');
-    let mut quals = Array::from_elem((length, 1 + TOP_K), b'!' as f32);
+    let mut bases = Array::from_elem((length, 1 + top_k), b'.');
+    let mut quals = Array::from_elem((length, 1 + top_k), b'!' as f32);
 
     // First write the target
     write_target_for_window(
@@ -287,10 +320,10 @@ fn get_features_for_window(
         quals.index_axis_mut(Axis(1), 0),
         window_length,
         tbuffer,
-    );
+    )?;
 
     // Write top-k overlaps for the window
-    overlaps.iter().take(TOP_K).enumerate().for_each(|(i, ow)| {
+    for (i, ow) in overlaps.iter().take(top_k).enumerate() {
         let qid = ow.overlap.return_other_id(tid);
         get_features_for_ol_window(
             bases.index_axis_mut(Axis(1), i + 1),
@@ -302,10 +335,10 @@ fn get_features_for_window(
             tid,
             &max_ins,
             qbuffer,
-        )
-    });
+        )?;
+    }
 
-    (bases, quals)
+    Ok((bases, quals))
 }
 
 fn overlap_window_filter(cigar: &[CigarOp]) -> bool {
@@ -314,25 +347,162 @@ fn overlap_window_filter(cigar: &[CigarOp]) -> bool {
         _ => false,
     });
 
-    //accuracy >= 0.80 && !long_indel
-    //calculate_accuracy(cigar) >= 0.85 &&
     !long_indel
 }
 
+/// Writes the overlapping parts of the target and the query, reverse
+/// complemented for reverse overlaps, to the buffers. Returns their lengths.
+fn overlap_seqs(
+    overlap: &Overlap,
+    reads: &[HAECRecord],
+    (tbuf, qbuf): (&mut [u8], &mut [u8]),
+) -> crate::error::Result<(usize, usize)> {
+    let (tstart, tend) = (overlap.tstart as usize, overlap.tend as usize);
+    reads[overlap.tid as usize]
+        .seq
+        .get_subseq(tstart..tend, tbuf)?;
+
+    let (qstart, qend) = (overlap.qstart as usize, overlap.qend as usize);
+    match overlap.strand {
+        Strand::Forward => reads[overlap.qid as usize]
+            .seq
+            .get_subseq(qstart..qend, qbuf)?,
+        Strand::Reverse => reads[overlap.qid as usize]
+            .seq
+            .get_rc_subseq(qstart..qend, qbuf)?,
+    }
+
+    Ok((tend - tstart, qend - qstart))
+}
+
+/// Aligner of the overlaps without CIGARs with the optional alignment cache and
+/// the buffers of the target and query sequences
+pub(crate) type RealignmentContext<'a> = (
+    &'a mut dyn PairwiseAligner,
+    Option<&'a AlignmentCache>,
+    &'a mut [u8],
+    &'a mut [u8],
+);
+
+/// Aligns the overlaps without CIGARs. Aligners which align multiple pairs at
+/// once get up to their batch size of overlaps together. CIGARs found in the
+/// cache are not aligned again, and the computed ones are added to it.
+pub(crate) fn align_overlaps(
+    alignments: &mut [Alignment],
+    reads: &[HAECRecord],
+    (aligner, cache, tbuf, qbuf): RealignmentContext,
+) -> crate::error::Result<()> {
+    let mut unaligned: Vec<_> = alignments
+        .iter_mut()
+        .filter(|aln| aln.cigar.is_empty())
+        .collect();
+    if let Some(cache) = cache {
+        unaligned.retain_mut(|aln| match cache.get(&aln.overlap, reads) {
+            Some(cigar) => {
+                aln.cigar = cigar;
+                false
+            }
+            None => true,
+        });
+    }
+
+    let batch_size = aligner.batch_size();
+    if batch_size == 1 {
+        for alignment in unaligned.iter_mut() {
+            let (tlen, qlen) = overlap_seqs(&alignment.overlap, reads, (tbuf, qbuf))?;
+            let divergence = alignment.divergence();
+            alignment.cigar = aligner.align(&tbuf[..tlen], &qbuf[..qlen], divergence);
+        }
+    } else {
+        for batch in unaligned.chunks_mut(batch_size) {
+            let seqs: Vec<_> = batch
+                .iter()
+                .map(|aln| {
+                    let overlap = &aln.overlap;
+                    let mut target = vec![0; (overlap.tend - overlap.tstart) as usize];
+                    let mut query = vec![0; (overlap.qend - overlap.qstart) as usize];
+                    overlap_seqs(overlap, reads, (&mut target, &mut query))?;
+                    Ok((target, query))
+                })
+                .collect::<crate::error::Result<_>>()?;
+
+            let pairs: Vec<_> = batch
+                .iter()
+                .zip(seqs.iter())
+                .map(|(aln, (target, query))| (&target[..], &query[..], aln.divergence()))
+                .collect();
+            let cigars = aligner.align_batch(&pairs);
+            for (aln, cigar) in batch.iter_mut().zip(cigars) {
+                aln.cigar = cigar;
+            }
+        }
+    }
+
+    if let Some(cache) = cache {
+        cache.insert(unaligned.iter().map(|aln| &**aln), reads);
+    }
+
+    Ok(())
+}
+
+/// Extracts the features of the windows of the target read. Overlaps without
+/// CIGARs are realigned with the given aligner, or taken from the alignment
+/// cache, before the features are encoded with [`encode_features`].
 pub(crate) fn extract_features<'a, T: FeaturesOutput<'a>>(
+    rid: u32,
+    reads: &'a [HAECRecord],
+    mut overlaps: Vec<Alignment>,
+    windows: WindowParams,
+    junctions: &[u32],
+    (aligner, cache, tbuf, qbuf): RealignmentContext,
+    feats_output: &mut T,
+) -> crate::error::Result<()> {
+    align_overlaps(&mut overlaps, reads, (aligner, cache, tbuf, qbuf))?;
+    encode_features(
+        rid,
+        reads,
+        overlaps,
+        windows,
+        junctions,
+        (tbuf, qbuf),
+        feats_output,
+    )
+}
+
+/// Encodes the features of the windows of the target read from its aligned
+/// overlaps. Windows start every `stride` bases, which has to divide the
+/// window size, so adjacent windows overlap if the stride is smaller than the
+/// window size. Windows which are contained in the previous window are not
+/// used. Overlaps aligned to a window with an accuracy below `min_accuracy` do
+/// not support it, and the first `top_k` overlaps in the order of the
+/// `selection` are used as its supporting reads. Windows containing the given junctions, at which the
+/// corrected read is split, keep their positions relative to the window start.
+pub(crate) fn encode_features<'a, T: FeaturesOutput<'a>>(
     rid: u32,
     reads: &'a [HAECRecord],
     overlaps: Vec<Alignment>,
-    window_size: u32,
+    windows: WindowParams,
+    junctions: &[u32],
     (tbuf, qbuf): (&mut [u8], &mut [u8]),
     feats_output: &mut T,
-) {
+) -> crate::error::Result<()> {
+    let WindowParams {
+        size: window_size,
+        stride: window_stride,
+        top_k,
+        min_accuracy,
+        selection,
+    } = windows;
     let read = &reads[rid as usize];
-    reads[rid as usize].seq.get_sequence(tbuf);
+    reads[rid as usize].seq.get_subseq(.., tbuf)?;
 
-    // Get overlaps for windows
-    let n_windows = (read.seq.len() + window_size as usize - 1) / window_size as usize;
+    // Get overlaps for windows, the last window ends at the end of the read
+    let (read_len, stride) = (read.seq.len(), window_stride as usize);
+    let n_windows = 1 + read_len
+        .saturating_sub(window_size as usize)
+        .div_ceil(stride);
     let mut windows = vec![Vec::new(); n_windows];
+    let n_phases = window_size / window_stride;
 
     let mut ovlps_cigar_map = HashMap::default();
     for alignment in overlaps.iter() {
@@ -367,17 +537,32 @@ pub(crate) fn extract_features<'a, T: FeaturesOutput<'a>>(
 
         let (tshift, qshift) = (0, 0);
 
-        //Extract windows
+        //Extract windows, non-overlapping windows starting at the same phase of
+        // the stride are extracted together
         let is_target = alignment.overlap.tid == rid;
-        extract_windows(
-            &mut windows,
-            &alignment.overlap,
-            &alignment.cigar,
-            tshift,
-            qshift,
-            is_target,
-            window_size,
-        );
+        for phase in 0..n_phases {
+            let shift = (window_size - phase * window_stride) % window_size;
+            let n_tiles = (read_len + shift as usize).div_ceil(window_size as usize);
+            let mut tiles = vec![Vec::new(); n_tiles];
+            extract_windows(
+                &mut tiles,
+                &alignment.overlap,
+                &alignment.cigar,
+                (tshift, qshift),
+                is_target,
+                window_size,
+                shift,
+            );
+
+            // With a shift, the first tile ends at the start of the phase
+            let skipped = (shift > 0) as usize;
+            for (j, tile) in tiles.into_iter().enumerate().skip(skipped) {
+                let wid = (j - skipped) * n_phases as usize + phase as usize;
+                if let Some(window) = windows.get_mut(wid) {
+                    window.extend(tile);
+                }
+            }
+        }
 
         ovlps_cigar_map.insert(qid, &alignment.cigar);
     }
@@ -393,7 +578,7 @@ pub(crate) fn extract_features<'a, T: FeaturesOutput<'a>>(
         }*/
 
         let win_len = if i == n_windows - 1 {
-            read.seq.len() - i * window_size as usize
+            read_len - i * stride
         } else {
             window_size as usize
         };
@@ -408,38 +593,46 @@ pub(crate) fn extract_features<'a, T: FeaturesOutput<'a>>(
             overlap_window_filter(&cigar[ow.cigar_start_idx..cigar_end])
         });
 
-        // Sort window to take TOP-K
-        windows[i].sort_by_key(|ow| {
-            let cigar = ovlps_cigar_map
-                .get(&ow.overlap.return_other_id(rid))
-                .unwrap();
-
-            let tstart = ow.tstart as usize;
-            let tend = i * window_size as usize + win_len;
-            //reads[rid as usize].seq.get_subseq(tstart..tend, tbuf);
-
-            let qid = ow.overlap.return_other_id(rid);
-            let (qstart, qend) = get_query_region(ow, rid);
-            let qlen = (qend - qstart) as usize;
-            match ow.overlap.strand {
-                Strand::Forward => reads[qid as usize]
-                    .seq
-                    .get_subseq(qstart as usize..qend as usize, qbuf),
-                Strand::Reverse => reads[qid as usize]
-                    .seq
-                    .get_rc_subseq(qstart as usize..qend as usize, qbuf),
-            }
+        // Rank the overlaps to take the top-k, overlaps aligned to the window
+        // with a lower accuracy than the minimum are dropped
+        let mut scored: Vec<_> = windows[i]
+            .drain(..)
+            .map(|ow| {
+                let cigar = ovlps_cigar_map
+                    .get(&ow.overlap.return_other_id(rid))
+                    .unwrap();
+
+                let tstart = ow.tstart as usize;
+                let tend = i * stride + win_len;
+                //reads[rid as usize].seq.get_subseq(tstart..tend, tbuf);
+
+                let qid = ow.overlap.return_other_id(rid);
+                let (qstart, qend) = get_query_region(&ow, rid);
+                let qlen = (qend - qstart) as usize;
+                match ow.overlap.strand {
+                    Strand::Forward => reads[qid as usize]
+                        .seq
+                        .get_subseq(qstart as usize..qend as usize, qbuf)?,
+                    Strand::Reverse => reads[qid as usize]
+                        .seq
+                        .get_rc_subseq(qstart as usize..qend as usize, qbuf)?,
+                }
 
-            let acc = calculate_accuracy(ow, cigar, &tbuf[tstart..tend], &qbuf[..qlen]);
-            OrderedFloat(-acc)
-        });
+                let acc = calculate_accuracy(&ow, cigar, &tbuf[tstart..tend], &qbuf[..qlen]);
+                Ok((acc, ow))
+            })
+            .filter_ok(|&(acc, _)| min_accuracy.is_none_or(|min| acc >= min))
+            .collect::<crate::error::Result<_>>()?;
+        rank_window_reads(&mut scored, selection, (rid, i));
+        windows[i].extend(scored.into_iter().map(|(_, ow)| ow));
 
         let max_ins = get_max_ins_for_window(
             &windows[i],
             &ovlps_cigar_map,
             rid,
-            i * window_size as usize,
+            i * stride,
             win_len,
+            top_k,
         );
 
         let (bases, quals) = get_features_for_window(
@@ -448,20 +641,26 @@ pub(crate) fn extract_features<'a, T: FeaturesOutput<'a>>(
             rid,
             reads,
             &max_ins,
-            i * window_size as usize,
+            i * stride,
             win_len,
+            top_k,
             tbuf,
             qbuf,
-        );
+        )?;
 
-        let qids: Vec<&str> = windows[i]
+        let qids: Vec<u32> = windows[i]
             .iter()
-            .map(|ow| {
-                std::str::from_utf8(&reads[ow.overlap.return_other_id(rid) as usize].id).unwrap()
-            })
+            .map(|ow| ow.overlap.return_other_id(rid))
             .collect();
 
         let supported = get_supported(&bases);
+        let window_start = i * stride;
+        let breaks: Vec<u16> = junctions
+            .iter()
+            .map(|&j| j as usize)
+            .filter(|&j| j > window_start && j < window_start + win_len)
+            .map(|j| (j - window_start) as u16)
+            .collect();
 
         feats_output.update(
             rid,
@@ -470,11 +669,49 @@ pub(crate) fn extract_features<'a, T: FeaturesOutput<'a>>(
             quals,
             supported,
             qids,
+            breaks,
             n_windows as u16,
         );
     }
 
     feats_output.emit();
+    Ok(())
+}
+
+/// Orders the overlaps of the window, given with their alignment accuracy, by
+/// the selection. Overlaps which rank equally stay ordered by the accuracy.
+fn rank_window_reads(
+    overlaps: &mut Vec<(f32, OverlapWindow)>,
+    selection: WindowReadSelection,
+    (tid, wid): (u32, usize),
+) {
+    overlaps.sort_by_key(|&(acc, _)| OrderedFloat(-acc));
+    match selection {
+        WindowReadSelection::Identity => (),
+        WindowReadSelection::Span => overlaps.sort_by_key(|(_, ow)| Reverse(ow.qend - ow.qstart)),
+        WindowReadSelection::Stratified => {
+            let (forward, reverse): (Vec<_>, Vec<_>) = overlaps
+                .drain(..)
+                .partition(|(_, ow)| ow.overlap.strand == Strand::Forward);
+            overlaps.extend(forward.into_iter().interleave(reverse));
+        }
+        WindowReadSelection::Random(seed) => overlaps.sort_by_cached_key(|(_, ow)| {
+            random_rank(seed, (tid, wid), ow.overlap.return_other_id(tid))
+        }),
+    }
+}
+
+/// Pseudo-random rank of the query in the window of the target, which depends
+/// only on the seed and the reads, so the selection is reproducible regardless
+/// of the order in which the overlaps are given.
+fn random_rank(seed: u64, (tid, wid): (u32, usize), qid: u32) -> u64 {
+    // SplitMix64 finalizer
+    let mut x = seed
+        ^ (((tid as u64) << 32) | qid as u64).wrapping_mul(0x9e3779b97f4a7c15)
+        ^ (wid as u64).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
 }
 
 fn calculate_accuracy(window: &OverlapWindow, cigar: &[CigarOp], tseq: &[u8], qseq: &[u8]) -> f32 {
@@ -607,6 +844,25 @@ where
     supporeted
 }
 
+/// Compression level of the zstd-compressed npy files
+const NPY_ZSTD_LEVEL: i32 = 3;
+
+/// Path of the zstd-compressed version of the npy file.
+fn zst_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".zst");
+    PathBuf::from(path)
+}
+
+/// Writes the npy file, compressed with zstd into `<path>.zst` if `compress`
+/// is set. Features are highly compressible, mostly gaps and repeated bases.
+fn write_npy(path: &Path, npy: &[u8], compress: bool) -> Result<()> {
+    match compress {
+        true => zstd::stream::copy_encode(npy, File::create(zst_path(path))?, NPY_ZSTD_LEVEL),
+        false => fs::write(path, npy),
+    }
+}
+
 fn output_features<P: AsRef<Path>>(
     path: P,
     window_id: u16,
@@ -614,6 +870,7 @@ fn output_features<P: AsRef<Path>>(
     bases: Array2<u8>,
     quals: Array2<f32>,
     supported: impl IntoIterator<Item = SupportedPos>,
+    compress: bool,
 ) -> Result<()> {
     let ids_path = path.as_ref().join(format!("{}.ids.txt", window_id));
     let ids_file = File::create(ids_path)?;
@@ -628,37 +885,304 @@ fn output_features<P: AsRef<Path>>(
     let quals = quals.mapv(|q| q as u8);
     let features = stack![Axis(0), bases, quals];
 
-    // Write feats
+    // Write feats, npy files are written into memory and then to the file, so
+    // they can be compressed
+    let mut npy = Cursor::new(Vec::new());
     let shape: Vec<_> = features.shape().iter().map(|&s| s as u64).collect();
     let mut writer = npyz::WriteOptions::new()
         .default_dtype()
         .shape(&shape)
-        .writer(BufWriter::new(File::create(features_path)?))
+        .writer(&mut npy)
         .begin_nd()?;
     writer.extend(features.iter())?;
     writer.finish()?;
+    write_npy(&features_path, npy.get_ref(), compress)?;
 
     let quals_path = path.as_ref().join(format!("{}.quals.npy", window_id));
+    let mut npy = Cursor::new(Vec::new());
     let shape: Vec<_> = quals.shape().iter().map(|&s| s as u64).collect();
     let mut writer = npyz::WriteOptions::new()
         .default_dtype()
         .shape(&shape)
-        .writer(BufWriter::new(File::create(quals_path)?))
+        .writer(&mut npy)
         .begin_nd()?;
     writer.extend(quals.iter())?;
     writer.finish()?;
+    write_npy(&quals_path, npy.get_ref(), compress)?;
 
     let supported_path = path.as_ref().join(format!("{}.supported.npy", window_id));
+    let mut npy = Cursor::new(Vec::new());
     let mut writer = npyz::WriteOptions::new()
         .default_dtype()
-        .writer(BufWriter::new(File::create(supported_path)?))
+        .writer(&mut npy)
         .begin_1d()?;
     writer.extend(supported)?;
     writer.finish()?;
+    write_npy(&supported_path, npy.get_ref(), compress)?;
 
     Ok(())
 }
 
+/// Features of a window read by `input_features`: bases, quals, supported
+/// positions and supporting reads.
+pub(crate) type WindowFeatures = (Array2<u8>, Array2<f32>, Vec<SupportedPos>, Vec<u32>);
+
+/// Opens the npy file, or its zstd-compressed version if it exists.
+fn open_npy(path: &Path) -> crate::error::Result<NpyFile<Box<dyn BufRead + Send>>> {
+    let compressed = zst_path(path);
+    let path = match compressed.exists() {
+        true => &compressed,
+        false => path,
+    };
+    NpyFile::new(open_text_file(path)?).map_err(|e| HerroError::from(e).in_file(path))
+}
+
+/// Reads the features of a window written by `output_features`. Supporting
+/// reads are mapped to their ids with `name_to_id`.
+fn input_features<P: AsRef<Path>>(
+    path: P,
+    window_id: u16,
+    name_to_id: &HashMap<&[u8], u32>,
+) -> crate::error::Result<WindowFeatures> {
+    let ids_path = path.as_ref().join(format!("{}.ids.txt", window_id));
+    let ids = fs::read(&ids_path).map_err(|e| HerroError::from(e).in_file(&ids_path))?;
+    let qids = ids
+        .split(|&c| c == b'\n')
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            name_to_id.get(id).copied().ok_or_else(|| {
+                let message = format!(
+                    "supporting read {} is not in the reads",
+                    String::from_utf8_lossy(id)
+                );
+                HerroError::InvalidReads(message).in_file(&ids_path)
+            })
+        })
+        .collect::<crate::error::Result<Vec<_>>>()?;
+
+    // Features are the stacked bases and quals, [2, L, R]
+    let features_path = path.as_ref().join(format!("{}.features.npy", window_id));
+    let npy = open_npy(&features_path)?;
+    let shape: Vec<_> = npy.shape().iter().map(|&s| s as usize).collect();
+    let features = match shape[..] {
+        [2, length, n_rows] => npy
+            .into_vec::<u8>()
+            .and_then(|data| {
+                Array3::from_shape_vec((2, length, n_rows), data)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            })
+            .map_err(|e| HerroError::from(e).in_file(&features_path))?,
+        _ => {
+            let message = format!("features have shape {:?} instead of [2, L, R]", shape);
+            return Err(
+                HerroError::from(io::Error::new(io::ErrorKind::InvalidData, message))
+                    .in_file(&features_path),
+            );
+        }
+    };
+    let bases = features.index_axis(Axis(0), 0).to_owned();
+    let quals = features.index_axis(Axis(0), 1).mapv(|q| q as f32);
+
+    let supported_path = path.as_ref().join(format!("{}.supported.npy", window_id));
+    let supported = open_npy(&supported_path)?
+        .into_vec::<SupportedPos>()
+        .map_err(|e| HerroError::from(e).in_file(&supported_path))?;
+
+    Ok((bases, quals, supported, qids))
+}
+
+/// Directory of the precomputed features of the read.
+fn features_dir(dir: &Path, read: &HAECRecord) -> PathBuf {
+    dir.join(&*String::from_utf8_lossy(&read.id))
+}
+
+/// Target reads, in the order of the input, which have precomputed features
+/// in the directory.
+pub(crate) fn feature_targets(
+    reads: &[HAECRecord],
+    dir: &Path,
+    skipped: &HashSet<u32>,
+) -> Vec<u32> {
+    let targets: Vec<_> = (0..reads.len() as u32)
+        .filter(|rid| !skipped.contains(rid))
+        .filter(|&rid| features_dir(dir, &reads[rid as usize]).is_dir())
+        .collect();
+    info!(
+        "Found features of {} of {} target reads",
+        targets.len(),
+        reads.len() - skipped.len()
+    );
+    targets
+}
+
+/// Passes the features of the target reads written by the `features`
+/// subcommand in the npy format to the output, instead of generating them
+/// from the overlaps. Features are generated with non-overlapping windows.
+/// Target reads without features, see `feature_targets`, are not corrected.
+pub(crate) fn features_reader<'a, T: FeaturesOutput<'a>>(
+    reads: &'a [HAECRecord],
+    dir: &Path,
+    window_size: u32,
+    targets: &[u32],
+    feats_output: &mut T,
+    pbar_sender: Sender<PBarNotification>,
+) -> crate::error::Result<()> {
+    let name_to_id: HashMap<_, _> = reads
+        .iter()
+        .enumerate()
+        .map(|(i, e)| (&*e.id, i as u32))
+        .collect();
+
+    pbar_sender
+        .send(PBarNotification::BatchLen(targets.len() as u64))
+        .unwrap();
+
+    for &rid in targets {
+        let read = &reads[rid as usize];
+        let path = features_dir(dir, read);
+        let n_windows = 1 + read
+            .seq
+            .len()
+            .saturating_sub(window_size as usize)
+            .div_ceil(window_size as usize);
+
+        feats_output.init(rid, &read.id);
+        for wid in 0..n_windows as u16 {
+            let (bases, quals, supported, qids) = input_features(&path, wid, &name_to_id)?;
+            feats_output.update(
+                rid,
+                wid,
+                bases,
+                quals,
+                supported,
+                qids,
+                Vec::new(),
+                n_windows as u16,
+            );
+        }
+        feats_output.emit();
+    }
+
+    Ok(())
+}
+
+/// Writes the features of a window into the `{rname}/{window_id}` group, with
+/// `features` (u8, [2, L, R], bases and quals), `positions` (u16) and
+/// `insertions` (u8) of the supported columns and `ids` of the supporting reads.
+#[cfg(feature = "hdf5")]
+fn output_features_hdf5(
+    file: &hdf5::File,
+    rname: &str,
+    window_id: u16,
+    ids: &[&str],
+    bases: Array2<u8>,
+    quals: Array2<f32>,
+    supported: Vec<SupportedPos>,
+) -> hdf5::Result<()> {
+    use hdf5::types::VarLenUnicode;
+    use ndarray::Array1;
+
+    // Intermediate group of the read is created with the first window
+    let group = file.create_group(&format!("{}/{}", rname, window_id))?;
+
+    let quals = quals.mapv(|q| q as u8);
+    let features = stack![Axis(0), bases, quals];
+    group
+        .new_dataset_builder()
+        .with_data(&features)
+        .create("features")?;
+
+    let positions: Array1<u16> = supported.iter().map(|s| s.pos).collect();
+    group
+        .new_dataset_builder()
+        .with_data(&positions)
+        .create("positions")?;
+    let insertions: Array1<u8> = supported.iter().map(|s| s.ins).collect();
+    group
+        .new_dataset_builder()
+        .with_data(&insertions)
+        .create("insertions")?;
+
+    let ids: Array1<VarLenUnicode> = ids.iter().map(|id| id.parse().unwrap()).collect();
+    group.new_dataset_builder().with_data(&ids).create("ids")?;
+
+    Ok(())
+}
+
+/// Destination of the features generated by `FeatsGenOutput`.
+pub(crate) enum FeaturesSink<T> {
+    /// Directory with a subdirectory of numpy files for each target read,
+    /// compressed with zstd if `compress` is set
+    Npy { dir: T, compress: bool },
+    #[cfg(feature = "hdf5")]
+    Hdf5(hdf5::File),
+    /// Arrow IPC or parquet file written by a single thread
+    #[cfg(feature = "arrow")]
+    Table(TableWriter),
+}
+
+impl<T: AsRef<Path> + Clone> FeaturesSink<T> {
+    /// Opens one sink for each of the feature generation threads. Columnar
+    /// formats are written into `part-{i}` files of the output directory, which
+    /// are read together as a dataset.
+    pub(crate) fn open(
+        path: T,
+        format: FeatureFormat,
+        compress: bool,
+        threads: usize,
+    ) -> crate::error::Result<Vec<Self>> {
+        match format {
+            FeatureFormat::Npy => Ok((0..threads)
+                .map(|_| FeaturesSink::Npy {
+                    dir: path.clone(),
+                    compress,
+                })
+                .collect()),
+            #[cfg(feature = "hdf5")]
+            FeatureFormat::Hdf5 => {
+                let file = hdf5::File::create(path.as_ref())
+                    .map_err(|e| HerroError::Hdf5(e.to_string()).in_file(&path))?;
+                Ok((0..threads)
+                    .map(|_| FeaturesSink::Hdf5(file.clone()))
+                    .collect())
+            }
+            #[cfg(feature = "arrow")]
+            FeatureFormat::Arrow => Self::open_tables(path.as_ref(), TableFormat::Arrow, threads),
+            #[cfg(feature = "parquet")]
+            FeatureFormat::Parquet => {
+                Self::open_tables(path.as_ref(), TableFormat::Parquet, threads)
+            }
+            #[cfg(not(feature = "hdf5"))]
+            FeatureFormat::Hdf5 => Err(HerroError::InvalidConfig(
+                "herro was built without the hdf5 feature".to_string(),
+            )),
+            #[cfg(not(feature = "arrow"))]
+            FeatureFormat::Arrow => Err(HerroError::InvalidConfig(
+                "herro was built without the arrow feature".to_string(),
+            )),
+            #[cfg(not(feature = "parquet"))]
+            FeatureFormat::Parquet => Err(HerroError::InvalidConfig(
+                "herro was built without the parquet feature".to_string(),
+            )),
+        }
+    }
+
+    #[cfg(feature = "arrow")]
+    fn open_tables(
+        dir: &Path,
+        format: TableFormat,
+        threads: usize,
+    ) -> crate::error::Result<Vec<Self>> {
+        create_dir_all(dir).map_err(|e| HerroError::from(e).in_file(dir))?;
+        (0..threads)
+            .map(|i| {
+                let path = dir.join(format!("part-{}.{}", i, format.extension()));
+                TableWriter::create(&path, format).map(FeaturesSink::Table)
+            })
+            .collect()
+    }
+}
+
 pub(crate) trait FeaturesOutput<'a> {
     fn init<'b>(&mut self, rid: u32, rname: &'b [u8])
     where
@@ -670,33 +1194,51 @@ pub(crate) trait FeaturesOutput<'a> {
         bases: Array2<u8>,
         quals: Array2<f32>,
         supported: Vec<SupportedPos>,
-        ids: Vec<&str>,
+        qids: Vec<u32>,
+        breaks: Vec<u16>,
         n_wids: u16,
     );
     fn emit(&mut self);
 }
 
-#[derive(Clone)]
 pub(crate) struct FeatsGenOutput<'a, T>
 where
     T: AsRef<Path> + Clone,
 {
-    base_path: T,
+    sink: FeaturesSink<T>,
+    reads: &'a [HAECRecord],
     rname: Option<&'a [u8]>,
+    n_windows: u64,
     pbar_sender: Sender<PBarNotification>,
 }
 
-impl<T> FeatsGenOutput<'_, T>
+impl<'a, T> FeatsGenOutput<'a, T>
 where
     T: AsRef<Path> + Clone,
 {
-    pub(crate) fn new(path: T, pbar_sender: Sender<PBarNotification>) -> Self {
+    pub(crate) fn new(
+        sink: FeaturesSink<T>,
+        reads: &'a [HAECRecord],
+        pbar_sender: Sender<PBarNotification>,
+    ) -> Self {
         Self {
-            base_path: path,
+            sink,
+            reads,
             rname: None,
+            n_windows: 0,
             pbar_sender: pbar_sender,
         }
     }
+
+    /// Completes the output after all target reads were processed.
+    pub(crate) fn finish(self) -> crate::error::Result<()> {
+        #[cfg(feature = "arrow")]
+        if let FeaturesSink::Table(writer) = self.sink {
+            return writer.finish();
+        }
+
+        Ok(())
+    }
 }
 
 impl<'a, T> FeaturesOutput<'a> for FeatsGenOutput<'a, T>
@@ -717,36 +1259,136 @@ where
         bases: Array2<u8>,
         quals: Array2<f32>,
         supported: Vec<SupportedPos>,
-        ids: Vec<&str>,
+        qids: Vec<u32>,
+        _breaks: Vec<u16>,
         _n_wids: u16,
     ) {
         let rid = std::str::from_utf8(self.rname.unwrap()).unwrap();
-        let output_path = self.base_path.as_ref().join(rid);
-        create_dir_all(&output_path).expect("Cannot create directory");
+        let ids: Vec<&str> = qids
+            .iter()
+            .map(|&qid| std::str::from_utf8(&self.reads[qid as usize].id).unwrap())
+            .collect();
+        match self.sink {
+            FeaturesSink::Npy { ref dir, compress } => {
+                let output_path = dir.as_ref().join(rid);
+                create_dir_all(&output_path).expect("Cannot create directory");
 
-        output_features(&output_path, wid, &ids, bases, quals, supported.into_iter()).unwrap();
+                output_features(&output_path, wid, &ids, bases, quals, supported, compress)
+                    .unwrap();
+            }
+            #[cfg(feature = "hdf5")]
+            FeaturesSink::Hdf5(ref file) => {
+                output_features_hdf5(file, rid, wid, &ids, bases, quals, supported).unwrap()
+            }
+            #[cfg(feature = "arrow")]
+            FeaturesSink::Table(ref mut writer) => writer
+                .write(rid, wid, &ids, bases, quals, supported)
+                .unwrap(),
+        }
+        self.n_windows += 1;
     }
 
     fn emit(&mut self) {
+        self.pbar_sender
+            .send(PBarNotification::Windows(self.n_windows))
+            .unwrap();
         self.pbar_sender.send(PBarNotification::Inc).unwrap();
 
         self.rname = None;
+        self.n_windows = 0;
     }
 }
 
+/// Sends the windows to inference in batches. Batches are sent at the end of
+/// each read, so a batch only holds windows of a single read and its results
+/// do not depend on which reads are encoded together.
 pub(crate) struct InferenceOutput {
     sender: Sender<InferenceData>,
     features: Vec<WindowExample>,
     batch_size: usize,
+    /// Windows with fewer supporting reads are not corrected
+    min_coverage: usize,
+    top_k: usize,
+    encoder: Arc<dyn FeatureEncoder>,
+    pbar_sender: Sender<PBarNotification>,
+    /// Batches which exceed the memory budget of the spill are written to
+    /// disk and sent to the spill reader
+    spill: Option<(Arc<FeatureSpill>, Sender<SpilledBatch>)>,
+    /// First error while spilling, the features are dropped after it
+    error: Option<HerroError>,
 }
 
 impl InferenceOutput {
-    pub(crate) fn new(sender: Sender<InferenceData>, batch_size: usize) -> Self {
+    pub(crate) fn new(
+        sender: Sender<InferenceData>,
+        batch_size: usize,
+        (min_coverage, top_k): (usize, usize),
+        encoder: Arc<dyn FeatureEncoder>,
+        pbar_sender: Sender<PBarNotification>,
+    ) -> Self {
         Self {
             sender,
             features: Vec::with_capacity(batch_size),
             batch_size: batch_size,
+            min_coverage,
+            top_k,
+            encoder,
+            pbar_sender,
+            spill: None,
+            error: None,
+        }
+    }
+
+    pub(crate) fn with_spill(
+        mut self,
+        spill: Arc<FeatureSpill>,
+        spill_sender: Sender<SpilledBatch>,
+    ) -> Self {
+        self.spill = Some((spill, spill_sender));
+        self
+    }
+
+    /// Returns the first error while sending the features.
+    pub(crate) fn finish(self) -> crate::error::Result<()> {
+        self.error.map_or(Ok(()), Err)
+    }
+
+    fn send_features(&mut self) {
+        if self.error.is_some() {
+            self.features.clear();
+            return;
+        }
+
+        self.pbar_sender
+            .send(PBarNotification::Windows(self.features.len() as u64))
+            .unwrap();
+
+        let reservation = match self.spill {
+            Some((ref spill, ref spill_sender)) => {
+                let bytes = features_memory(&self.features);
+                match spill.try_reserve(bytes) {
+                    Some(reservation) => Some(reservation),
+                    None => {
+                        match spill.write(self.features.drain(..)) {
+                            Ok(path) => spill_sender.send((path, bytes)).unwrap(),
+                            Err(e) => self.error = Some(e),
+                        }
+                        return;
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let mut data = prepare_examples(
+            self.features.drain(..),
+            self.batch_size,
+            self.encoder.as_ref(),
+        );
+        if let Some(reservation) = reservation {
+            data = data.with_reservation(reservation);
         }
+        self.sender.send(data).unwrap();
     }
 }
 
@@ -763,33 +1405,34 @@ impl<'a> FeaturesOutput<'a> for InferenceOutput {
         wid: u16,
         bases: Array2<u8>,
         quals: Array2<f32>,
-        supported: Vec<SupportedPos>,
-        ids: Vec<&str>,
+        mut supported: Vec<SupportedPos>,
+        mut qids: Vec<u32>,
+        breaks: Vec<u16>,
         n_wids: u16,
     ) {
-        self.features.push(WindowExample::new(
-            rid,
-            wid,
-            ids.len().min(TOP_K) as u8,
-            bases,
-            quals,
-            supported,
-            n_wids,
-        ));
+        // Only the first top-k supporting reads are in the features
+        qids.truncate(self.top_k);
+        // Windows without enough coverage are neither inferred nor corrected
+        if qids.len() < self.min_coverage {
+            supported.clear();
+            qids.clear();
+        }
+        let example = WindowExample::new(rid, wid, bases, quals, supported, qids, n_wids);
+        self.features.push(example.with_breaks(breaks));
 
         if self.features.len() == self.batch_size {
-            let data = prepare_examples(self.features.drain(..), self.batch_size);
-            self.sender.send(data).unwrap();
+            self.send_features();
         }
     }
 
     fn emit(&mut self) {
-        let data = prepare_examples(self.features.drain(..), self.batch_size);
-        self.sender.send(data).unwrap();
+        self.send_features();
     }
 }
 
-#[derive(npyz::AutoSerialize, npyz::Serialize, PartialEq, Eq, Hash, Clone, Copy)]
+#[derive(
+    npyz::AutoSerialize, npyz::Serialize, npyz::Deserialize, Debug, PartialEq, Eq, Hash, Clone, Copy,
+)]
 pub(crate) struct SupportedPos {
     pub pos: u16,
     pub ins: u8,
@@ -800,3 +1443,91 @@ impl SupportedPos {
         SupportedPos { pos, ins }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use ndarray::Array2;
+    use rustc_hash::FxHashMap as HashMap;
+
+    use super::{input_features, output_features, rank_window_reads, SupportedPos};
+    use crate::overlaps::{Overlap, Strand};
+    use crate::windowing::OverlapWindow;
+    use crate::WindowReadSelection;
+
+    #[test]
+    fn window_features_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("herro-features-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let bases = Array2::from_shape_fn((5, 3), |(i, j)| b"ACGT*"[(i + j) % 5]);
+        let quals = Array2::from_shape_fn((5, 3), |(i, j)| (33 + i * 3 + j) as f32);
+        let supported = vec![SupportedPos::new(1, 0), SupportedPos::new(3, 2)];
+        for (window_id, compress) in [(7, false), (9, true)] {
+            output_features(
+                &dir,
+                window_id,
+                &["r2", "r0"],
+                bases.clone(),
+                quals.clone(),
+                supported.clone(),
+                compress,
+            )
+            .unwrap();
+        }
+        assert!(dir.join("9.features.npy.zst").exists());
+        assert!(!dir.join("9.features.npy").exists());
+
+        let name_to_id: HashMap<&[u8], u32> = [(&b"r0"[..], 0), (b"r1", 1), (b"r2", 2)]
+            .into_iter()
+            .collect();
+        let expected = (bases, quals, supported, vec![2, 0]);
+        assert_eq!(input_features(&dir, 7, &name_to_id).unwrap(), expected);
+        assert_eq!(input_features(&dir, 9, &name_to_id).unwrap(), expected);
+
+        // Supporting reads have to be in the reads
+        let name_to_id: HashMap<&[u8], u32> = [(&b"r0"[..], 0)].into_iter().collect();
+        assert!(input_features(&dir, 7, &name_to_id).is_err());
+        assert!(input_features(&dir, 8, &name_to_id).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn window_read_ranking() {
+        // Queries 1-3 of target 0 with their accuracy and span in the window
+        let overlaps: Vec<_> = [
+            (1, Strand::Forward),
+            (2, Strand::Forward),
+            (3, Strand::Reverse),
+        ]
+        .into_iter()
+        .map(|(qid, strand)| Overlap::new(qid, 1000, 0, 1000, strand, 0, 1000, 0, 1000))
+        .collect();
+        let windows: Vec<_> = [(0.99, 100), (0.95, 300), (0.9, 200)]
+            .into_iter()
+            .zip(overlaps.iter())
+            .map(|((acc, span), ovlp)| (acc, OverlapWindow::new(ovlp, 0, 0, span, 0, 0, 0, 0)))
+            .collect();
+        let rank = |selection, reversed: bool| {
+            let mut windows = windows.clone();
+            if reversed {
+                windows.reverse();
+            }
+            rank_window_reads(&mut windows, selection, (0, 2));
+            windows
+                .iter()
+                .map(|(_, ow)| ow.overlap.qid)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(rank(WindowReadSelection::Identity, true), [1, 2, 3]);
+        assert_eq!(rank(WindowReadSelection::Span, false), [2, 3, 1]);
+        assert_eq!(rank(WindowReadSelection::Stratified, false), [1, 3, 2]);
+        let mut random = rank(WindowReadSelection::Random(7), false);
+        assert_eq!(random, rank(WindowReadSelection::Random(7), true));
+        random.sort();
+        assert_eq!(random, [1, 2, 3]);
+    }
+}
@@ -0,0 +1,367 @@
+//! C interface for correcting reads from other programs, declared in
+//! `include/herro.h`. The library is built with
+//! `cargo rustc --release --lib --features ffi --crate-type cdylib` (or
+//! `staticlib`).
+//!
+//! Reads and overlaps are written into a temporary directory and corrected with
+//! `error_correction`, same as with the command line tool. Panics are reported
+//! as errors instead of unwinding into the caller, which requires building
+//! with unwinding, e.g. `--config profile.release.panic='unwind'`, since the
+//! release profile aborts on panic.
+
+use std::cell::RefCell;
+use std::env;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+use std::slice;
+
+use needletail::parse_fastx_file;
+
+use crate::config::CorrectionConfig;
+use crate::error::{HerroError, Result};
+use crate::tempdir::TempDir;
+use crate::{error_correction, AlnMode, Device};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Input read, quality is NULL for reads without qualities.
+#[repr(C)]
+pub struct HerroRead {
+    pub id: *const c_char,
+    pub seq: *const u8,
+    pub qual: *const u8,
+    pub length: usize,
+}
+
+/// Correction parameters, zero values select the defaults.
+#[repr(C)]
+pub struct HerroOptions {
+    /// Number of feature generation threads
+    pub threads: usize,
+    /// Cuda device id, or -1 for the CPU
+    pub device: c_int,
+    /// Number of windows per inference batch
+    pub batch_size: usize,
+    /// Window size, taken from the model spec if 0
+    pub window_size: u32,
+}
+
+#[repr(C)]
+pub struct HerroCorrectedRead {
+    pub id: *mut c_char,
+    pub seq: *mut u8,
+    pub length: usize,
+}
+
+/// Corrected reads, owned by herro and released with `herro_free_corrected`.
+#[repr(C)]
+pub struct HerroCorrectedReads {
+    pub reads: *mut HerroCorrectedRead,
+    pub n_reads: usize,
+}
+
+fn set_last_error(error: &HerroError) {
+    let message = CString::new(error.to_string().replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+unsafe fn c_str<'a>(ptr: *const c_char, name: &str) -> Result<&'a str> {
+    if ptr.is_null() {
+        return Err(HerroError::InvalidConfig(format!("{} is NULL", name)));
+    }
+
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map_err(|_| HerroError::InvalidConfig(format!("{} is not valid UTF-8", name)))
+}
+
+/// Writes the reads in FASTQ, or in FASTA if none of the reads has qualities.
+unsafe fn write_reads(reads: &[HerroRead], path: &Path) -> Result<()> {
+    let has_quals = reads.first().is_some_and(|r| !r.qual.is_null());
+    if reads.iter().any(|r| r.qual.is_null() == has_quals) {
+        return Err(HerroError::InvalidReads(
+            "either all or none of the reads should have qualities".to_string(),
+        ));
+    }
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    for (i, read) in reads.iter().enumerate() {
+        let id = c_str(read.id, &format!("id of read {}", i))?;
+        if read.seq.is_null() {
+            let message = format!("sequence of read {} is NULL", id);
+            return Err(HerroError::InvalidReads(message));
+        }
+
+        let seq = slice::from_raw_parts(read.seq, read.length);
+        match has_quals {
+            true => {
+                let qual = slice::from_raw_parts(read.qual, read.length);
+                writeln!(writer, "@{}", id)?;
+                writer.write_all(seq)?;
+                writer.write_all(b"\n+\n")?;
+                writer.write_all(qual)?;
+                writer.write_all(b"\n")?;
+            }
+            false => {
+                writeln!(writer, ">{}", id)?;
+                writer.write_all(seq)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+fn read_corrected(path: &Path) -> Result<Vec<HerroCorrectedRead>> {
+    let invalid_reads =
+        |e: needletail::errors::ParseError| HerroError::InvalidReads(e.to_string()).in_file(path);
+
+    // Nothing is written if no read was corrected
+    if !fs::metadata(path).is_ok_and(|m| m.len() > 0) {
+        return Ok(Vec::new());
+    }
+
+    let mut reader = parse_fastx_file(path).map_err(invalid_reads)?;
+    let mut corrected = Vec::new();
+    while let Some(record) = reader.next() {
+        let record = record.map_err(invalid_reads)?;
+        let id = CString::new(record.id()).expect("Read id contains NUL.");
+        let seq = record.seq().into_owned().into_boxed_slice();
+
+        corrected.push(HerroCorrectedRead {
+            id: id.into_raw(),
+            length: seq.len(),
+            seq: Box::into_raw(seq) as *mut u8,
+        });
+    }
+
+    Ok(corrected)
+}
+
+unsafe fn correct(
+    model_path: *const c_char,
+    options: *const HerroOptions,
+    reads: *const HerroRead,
+    n_reads: usize,
+    paf: *const u8,
+    paf_len: usize,
+) -> Result<Vec<HerroCorrectedRead>> {
+    let model_path = c_str(model_path, "model path")?;
+    if options.is_null() || (reads.is_null() && n_reads > 0) || (paf.is_null() && paf_len > 0) {
+        return Err(HerroError::InvalidConfig(
+            "options, reads or overlaps are NULL".to_string(),
+        ));
+    }
+    let options = &*options;
+
+    let mut builder = CorrectionConfig::builder().quiet(true);
+    if options.threads > 0 {
+        builder = builder.threads(options.threads);
+    }
+    builder = match options.device {
+        d if d < 0 => builder.devices(vec![Device::Cpu]),
+        d => builder.devices(vec![Device::Cuda(d as usize)]),
+    };
+    if options.batch_size > 0 {
+        builder = builder.batch_size(options.batch_size);
+    }
+    if options.window_size > 0 {
+        builder = builder.window_size(options.window_size);
+    }
+    let config = builder.build()?;
+
+    // Each call has its own directory, removed when the call finishes
+    let dir = TempDir::new_in(&env::temp_dir(), "herro")?;
+    let reads_path = dir.path().join("reads.fq");
+    let overlaps_path = dir.path().join("overlaps.paf");
+    let output_path = dir.path().join("corrected.fa");
+
+    let reads: &[HerroRead] = match n_reads {
+        0 => &[],
+        _ => slice::from_raw_parts(reads, n_reads),
+    };
+    write_reads(reads, &reads_path).map_err(|e| e.in_file(&reads_path))?;
+    let paf: &[u8] = match paf_len {
+        0 => &[],
+        _ => slice::from_raw_parts(paf, paf_len),
+    };
+    fs::write(&overlaps_path, paf).map_err(|e| HerroError::from(e).in_file(&overlaps_path))?;
+
+    error_correction(
+        &reads_path,
+        &[model_path],
+        &output_path,
+        AlnMode::Overlaps(vec![&overlaps_path]),
+        &config,
+    )?;
+
+    read_corrected(&output_path)
+}
+
+/// Message of the panic payload, which is usually a string.
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload
+            .downcast_ref::<String>()
+            .map_or("unknown panic", |m| m.as_str()),
+    }
+}
+
+/// Corrects the reads using their overlaps given in PAF (the same format as
+/// `--overlaps`). Corrected reads are stored into `corrected` and have to be
+/// released with `herro_free_corrected`. Returns 0 on success, or -1 on error
+/// (including a panic during the correction) with the message given by
+/// `herro_last_error`.
+///
+/// # Safety
+///
+/// Pointers have to be valid, with `n_reads` reads and `paf_len` bytes of
+/// overlaps, and read ids and the model path have to be NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn herro_correct(
+    model_path: *const c_char,
+    options: *const HerroOptions,
+    reads: *const HerroRead,
+    n_reads: usize,
+    paf: *const u8,
+    paf_len: usize,
+    corrected: *mut HerroCorrectedReads,
+) -> c_int {
+    if corrected.is_null() {
+        set_last_error(&HerroError::InvalidConfig("output is NULL".to_string()));
+        return -1;
+    }
+
+    // Panics cannot unwind into the caller
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        correct(model_path, options, reads, n_reads, paf, paf_len)
+    }))
+    .unwrap_or_else(|payload| {
+        Err(HerroError::Panic(
+            panic_message(payload.as_ref()).to_string(),
+        ))
+    });
+    match result {
+        Ok(reads) => {
+            let reads = reads.into_boxed_slice();
+            *corrected = HerroCorrectedReads {
+                n_reads: reads.len(),
+                reads: Box::into_raw(reads) as *mut HerroCorrectedRead,
+            };
+            0
+        }
+        Err(e) => {
+            set_last_error(&e);
+            *corrected = HerroCorrectedReads {
+                reads: ptr::null_mut(),
+                n_reads: 0,
+            };
+            -1
+        }
+    }
+}
+
+/// Releases the reads returned by `herro_correct`.
+///
+/// # Safety
+///
+/// Reads have to be returned by `herro_correct` and released only once.
+#[no_mangle]
+pub unsafe extern "C" fn herro_free_corrected(corrected: *mut HerroCorrectedReads) {
+    if corrected.is_null() || (*corrected).reads.is_null() {
+        return;
+    }
+
+    let corrected = &mut *corrected;
+    let reads = Box::from_raw(ptr::slice_from_raw_parts_mut(
+        corrected.reads,
+        corrected.n_reads,
+    ));
+    for read in reads.iter() {
+        drop(CString::from_raw(read.id));
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            read.seq,
+            read.length,
+        )));
+    }
+
+    corrected.reads = ptr::null_mut();
+    corrected.n_reads = 0;
+}
+
+/// Message of the last error on the calling thread, or NULL. The message is
+/// valid until the next call of `herro_correct` on the same thread.
+#[no_mangle]
+pub extern "C" fn herro_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |m| m.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{CStr, CString};
+    use std::fs;
+    use std::ptr;
+
+    use super::{
+        herro_free_corrected, panic_message, read_corrected, write_reads, HerroCorrectedReads,
+        HerroRead,
+    };
+    use crate::tempdir::TempDir;
+
+    #[test]
+    fn write_and_read_back() {
+        let dir = TempDir::new_in(&std::env::temp_dir(), "herro-ffi-test").unwrap();
+        let path = dir.path().join("reads.fq");
+
+        let ids = [CString::new("r0").unwrap(), CString::new("r1").unwrap()];
+        let read = |i: usize, qual: *const u8| HerroRead {
+            id: ids[i].as_ptr(),
+            seq: b"ACGT".as_ptr(),
+            qual,
+            length: 4,
+        };
+
+        let reads = [read(0, b"!!!!".as_ptr()), read(1, b"####".as_ptr())];
+        unsafe { write_reads(&reads, &path).unwrap() };
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "@r0\nACGT\n+\n!!!!\n@r1\nACGT\n+\n####\n"
+        );
+
+        let reads = [read(0, b"!!!!".as_ptr()), read(1, ptr::null())];
+        assert!(unsafe { write_reads(&reads, &path) }.is_err());
+
+        fs::write(&path, ">r0\nACGT\n>r1\nAC\n").unwrap();
+        let corrected = read_corrected(&path).unwrap().into_boxed_slice();
+        let mut corrected = HerroCorrectedReads {
+            n_reads: corrected.len(),
+            reads: Box::into_raw(corrected) as *mut _,
+        };
+
+        unsafe {
+            let read = &*corrected.reads.add(1);
+            assert_eq!(CStr::from_ptr(read.id).to_str().unwrap(), "r1");
+            assert_eq!(std::slice::from_raw_parts(read.seq, read.length), b"AC");
+
+            herro_free_corrected(&mut corrected);
+        }
+        assert!(corrected.reads.is_null());
+    }
+
+    #[test]
+    fn caught_panics() {
+        let payload = std::panic::catch_unwind(|| panic!("index {} out of range", 3)).unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "index 3 out of range");
+        let payload = std::panic::catch_unwind(|| std::panic::panic_any(1)).unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "unknown panic");
+    }
+}
@@ -1,7 +1,23 @@
 use core::panic;
-use std::{ops::RangeBounds, path::Path};
-
-use needletail::parse_fastx_file;
+use std::{
+    borrow::Cow,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    ops::{Bound, Deref, Range, RangeBounds},
+    path::Path,
+    sync::Arc,
+};
+
+use flate2::bufread::MultiGzDecoder;
+use needletail::{errors::ParseError, parse_fastx_file};
+use rustc_hash::FxHashSet as HashSet;
+use tracing::warn;
+
+use crate::error::{HerroError, Result};
+use crate::faidx::{IndexedFile, IndexedRead};
+use crate::sam;
+use crate::DuplicateIds;
 
 const BASE_ENCODING: [u64; 128] = [
     255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
@@ -14,16 +30,116 @@ const BASE_ENCODING: [u64; 128] = [
 ];
 
 const BASE_DECODING: [u8; 4] = [b'A', b'C', b'G', b'T'];
+/// Bases which cannot be 2-bit encoded (N and other IUPAC codes) are packed as
+/// this code, and their positions are stored separately.
+const N_CODE: u64 = 0;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+pub(crate) const STDIN_PATH: &str = "-";
+
+/// SAM tags written by dorado which remain valid for the corrected read: read
+/// group (run id and model), channel, read number, start time, file name,
+/// parent read, barcode and duplex status
+const DORADO_TAGS: [&[u8]; 8] = [b"RG", b"ch", b"rn", b"st", b"fn", b"pi", b"BC", b"dx"];
+/// Fields of the read headers written by MinKNOW and Guppy
+const MINKNOW_FIELDS: [&[u8]; 10] = [
+    b"runid",
+    b"read",
+    b"ch",
+    b"start_time",
+    b"flow_cell_id",
+    b"protocol_group_id",
+    b"sample_id",
+    b"barcode",
+    b"barcode_alias",
+    b"parent_read_id",
+];
 
 pub struct HAECRecord {
-    pub id: Vec<u8>,
+    pub id: ReadId,
     pub description: Option<Vec<u8>>,
     pub seq: HAECSeq,
-    pub qual: Vec<u8>,
+    pub qual: HAECQual,
+}
+
+/// Id of a read, stored in a buffer shared with the ids of the other reads
+/// parsed from the same file. Datasets have tens of millions of reads, and an
+/// allocation for each id would take more memory than the ids themselves.
+#[derive(Clone)]
+pub struct ReadId {
+    ids: Arc<Vec<u8>>,
+    range: Range<usize>,
+}
+
+impl Deref for ReadId {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.ids[self.range.clone()]
+    }
+}
+
+impl From<Vec<u8>> for ReadId {
+    fn from(id: Vec<u8>) -> Self {
+        let range = 0..id.len();
+        ReadId {
+            ids: Arc::new(id),
+            range,
+        }
+    }
+}
+
+impl From<&str> for ReadId {
+    fn from(id: &str) -> Self {
+        ReadId::from(id.as_bytes().to_vec())
+    }
+}
+
+impl fmt::Debug for ReadId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", String::from_utf8_lossy(self))
+    }
+}
+
+/// Collects the ids of the reads while they are parsed, which are then
+/// interned into one buffer.
+#[derive(Default)]
+struct ReadIds {
+    ids: Vec<u8>,
+    ends: Vec<usize>,
+}
+
+impl ReadIds {
+    fn push(&mut self, id: &[u8]) {
+        self.ids.extend_from_slice(id);
+        self.ends.push(self.ids.len());
+    }
+
+    /// Creates the records from the parts of the reads, in the order in which
+    /// their ids were pushed.
+    fn into_records(mut self, reads: Vec<(Option<Vec<u8>>, HAECSeq, HAECQual)>) -> Vec<HAECRecord> {
+        self.ids.shrink_to_fit();
+        let ids = Arc::new(self.ids);
+        let starts = std::iter::once(0).chain(self.ends.iter().copied());
+
+        starts
+            .zip(self.ends.iter().copied())
+            .zip(reads)
+            .map(|((start, end), (description, seq, qual))| {
+                let id = ReadId {
+                    ids: Arc::clone(&ids),
+                    range: start..end,
+                };
+                HAECRecord::new(id, description, seq, qual)
+            })
+            .collect()
+    }
 }
 
 impl HAECRecord {
-    fn new(id: Vec<u8>, description: Option<Vec<u8>>, seq: HAECSeq, qual: Vec<u8>) -> Self {
+    fn new(id: ReadId, description: Option<Vec<u8>>, seq: HAECSeq, qual: HAECQual) -> Self {
         HAECRecord {
             id,
             description,
@@ -31,85 +147,515 @@ impl HAECRecord {
             qual,
         }
     }
+
+    /// Appends the basecaller tags in the description to the header, SAM tags
+    /// (`ch:i:12`) separated by tabs and MinKNOW fields (`ch=12`) by spaces.
+    /// Tags which are not valid for the corrected read, like the mean quality
+    /// or the modified bases, are dropped.
+    pub(crate) fn push_basecaller_tags(&self, header: &mut Vec<u8>) {
+        let Some(description) = self.description.as_ref() else {
+            return;
+        };
+
+        for field in description.split(|c| *c == b' ' || *c == b'\t') {
+            let is_tag = field.len() > 5 && field[2] == b':' && field[4] == b':';
+            if is_tag && DORADO_TAGS.contains(&&field[..2]) {
+                header.push(b'\t');
+            } else if field
+                .split(|c| *c == b'=')
+                .next()
+                .is_some_and(|key| key.len() < field.len() && MINKNOW_FIELDS.contains(&key))
+            {
+                header.push(b' ');
+            } else {
+                continue;
+            }
+
+            header.extend_from_slice(field);
+        }
+    }
 }
 
-pub fn get_reads<P: AsRef<Path>>(path: P, min_length: u32) -> Vec<HAECRecord> {
-    let mut reader = parse_fastx_file(path).expect("Cannot open file containing reads.");
+/// Quality assigned to the bases of reads without qualities (FASTA)
+const MISSING_QUAL: u8 = b'!';
+
+/// Parses reads with at least `min_length` bases from FASTA/FASTQ or BAM.
+/// Qualities are required unless `require_quals` is false, in which case reads
+/// without them get the lowest quality for every base.
+pub fn get_reads<P: AsRef<Path>>(
+    path: P,
+    min_length: u32,
+    require_quals: bool,
+) -> Result<Vec<HAECRecord>> {
+    if is_bam(&path)? {
+        return get_bam_reads(path, min_length, require_quals);
+    }
+
+    let invalid_reads = |e: ParseError| HerroError::InvalidReads(e.to_string()).in_file(&path);
+    let mut reader = parse_fastx_file(&path).map_err(invalid_reads)?;
 
+    let mut ids = ReadIds::default();
     let mut reads = Vec::new();
     while let Some(record) = reader.next() {
-        let record = record.expect("Error parsing fastx file.");
+        let record = record.map_err(invalid_reads)?;
         if record.num_bases() < min_length as usize {
             continue;
         }
 
         let mut split = record.id().splitn(2, |c| *c == b' ' || *c == b'\t');
-        let id = split.next().expect("Invalid read id.").to_owned();
+        let id = split.next().expect("Invalid read id.");
         let description = split.next().map(|d| d.to_owned());
 
         let seq = HAECSeq::from(&*record.seq());
-        let qual = record
-            .qual()
-            .expect("Qualities should be present.")
-            .to_owned();
+        let qual = match record.qual() {
+            Some(qual) => HAECQual::from(qual.to_owned()),
+            None if !require_quals => HAECQual::missing(record.num_bases()),
+            None => {
+                let message = format!(
+                    "qualities should be present for read {}",
+                    String::from_utf8_lossy(id)
+                );
+                return Err(HerroError::InvalidReads(message).in_file(&path));
+            }
+        };
+
+        ids.push(id);
+        reads.push((description, seq, qual));
+    }
+
+    Ok(ids.into_records(reads))
+}
+
+/// Reads of an unmapped BAM file, e.g. written by dorado. Tags of the records
+/// are used as the descriptions of the reads, so they can be kept in the
+/// output.
+fn get_bam_reads<P: AsRef<Path>>(
+    path: P,
+    min_length: u32,
+    require_quals: bool,
+) -> Result<Vec<HAECRecord>> {
+    let mut ids = ReadIds::default();
+    let mut reads = Vec::new();
+    sam::parse_bam_reads(open_text_file(&path)?, |read| {
+        if read.seq.len() < min_length as usize {
+            return Ok(());
+        }
+
+        let qual = match read.qual {
+            Some(qual) => HAECQual::from(qual),
+            None if !require_quals => HAECQual::missing(read.seq.len()),
+            None => {
+                let message = format!(
+                    "qualities should be present for read {}",
+                    String::from_utf8_lossy(&read.name)
+                );
+                return Err(HerroError::InvalidReads(message));
+            }
+        };
+        let description = (!read.tags.is_empty()).then_some(read.tags);
+
+        ids.push(&read.name);
+        reads.push((description, HAECSeq::from(&*read.seq), qual));
+        Ok(())
+    })
+    .map_err(|e| e.in_file(&path))?;
+
+    Ok(ids.into_records(reads))
+}
+
+/// Returns true if the reads file is in BAM format. Reads from stdin are
+/// parsed as FASTA/FASTQ.
+fn is_bam<P: AsRef<Path>>(path: P) -> Result<bool> {
+    if path.as_ref() == Path::new(STDIN_PATH) {
+        return Ok(false);
+    }
 
-        reads.push(HAECRecord::new(id, description, seq, qual));
+    let mut reader = open_text_file(&path)?;
+    let magic = reader
+        .fill_buf()
+        .map_err(|e| HerroError::from(e).in_file(&path))?;
+    Ok(magic.starts_with(sam::BAM_MAGIC))
+}
+
+/// Parses only the ids and lengths of the reads.
+pub(crate) fn read_lengths<P: AsRef<Path>>(path: P) -> Result<Vec<(Vec<u8>, u32)>> {
+    if is_bam(&path)? {
+        let mut lengths = Vec::new();
+        sam::parse_bam_reads(open_text_file(&path)?, |read| {
+            lengths.push((read.name, read.seq.len() as u32));
+            Ok(())
+        })
+        .map_err(|e| e.in_file(&path))?;
+        return Ok(lengths);
+    }
+
+    let invalid_reads = |e: ParseError| HerroError::InvalidReads(e.to_string()).in_file(&path);
+    let mut reader = parse_fastx_file(&path).map_err(invalid_reads)?;
+
+    let mut lengths = Vec::new();
+    while let Some(record) = reader.next() {
+        let record = record.map_err(invalid_reads)?;
+        let id = record
+            .id()
+            .split(|c| *c == b' ' || *c == b'\t')
+            .next()
+            .expect("Invalid read id.");
+
+        lengths.push((id.to_owned(), record.num_bases() as u32));
+    }
+
+    Ok(lengths)
+}
+
+/// Loads only the ids and lengths of the reads indexed with samtools faidx
+/// (`<path>.fai`, and `<path>.gzi` for bgzipped files). Bases and qualities
+/// are read from the file when they are needed.
+pub fn get_indexed_reads<P: AsRef<Path>>(
+    path: P,
+    min_length: u32,
+    require_quals: bool,
+) -> Result<Vec<HAECRecord>> {
+    let (file, entries) = IndexedFile::open(path.as_ref())?;
+
+    let mut ids = ReadIds::default();
+    let mut reads = Vec::new();
+    for (id, entry) in entries {
+        if entry.length < min_length as usize {
+            continue;
+        }
+
+        let read = IndexedRead::new(file.clone(), entry);
+        let qual = match read.has_quals() {
+            true => HAECQual {
+                data: QualData::Indexed(read.clone()),
+            },
+            false if !require_quals => HAECQual::missing(read.len()),
+            false => {
+                let message = format!(
+                    "qualities should be present for read {}",
+                    String::from_utf8_lossy(&id)
+                );
+                return Err(HerroError::InvalidReads(message).in_file(&path));
+            }
+        };
+        let seq = HAECSeq {
+            data: SeqData::Indexed(read),
+        };
+
+        ids.push(&id);
+        reads.push((None, seq, qual));
+    }
+
+    Ok(ids.into_records(reads))
+}
+
+/// Renames or removes the reads whose ids were already used by the earlier
+/// reads, depending on the policy. Error contains the first duplicate id.
+pub(crate) fn resolve_duplicate_ids(
+    reads: &mut Vec<HAECRecord>,
+    policy: DuplicateIds,
+) -> std::result::Result<(), String> {
+    // Ids are borrowed from the reads, so they are not copied for the check
+    let mut ids: HashSet<&[u8]> = HashSet::default();
+    let duplicates: Vec<_> = reads
+        .iter()
+        .enumerate()
+        .filter(|(_, r)| !ids.insert(&r.id))
+        .map(|(i, _)| i)
+        .collect();
+    let Some(&first) = duplicates.first() else {
+        return Ok(());
+    };
+
+    match policy {
+        DuplicateIds::Error => {
+            return Err(format!(
+                "duplicate read id {}",
+                String::from_utf8_lossy(&reads[first].id)
+            ))
+        }
+        DuplicateIds::Rename => {
+            let mut renamed_ids: HashSet<Vec<u8>> = HashSet::default();
+            let renamed: Vec<_> = duplicates
+                .iter()
+                .map(|&i| {
+                    let id = &reads[i].id;
+                    let renamed = (1..)
+                        .map(|n| [&id[..], format!("_{}", n).as_bytes()].concat())
+                        .find(|r| !ids.contains(&r[..]) && !renamed_ids.contains(r))
+                        .unwrap();
+
+                    renamed_ids.insert(renamed.clone());
+                    renamed
+                })
+                .collect();
+
+            for (&i, id) in duplicates.iter().zip(renamed) {
+                reads[i].id = ReadId::from(id);
+            }
+        }
+        DuplicateIds::Skip => {
+            let mut skipped = duplicates.iter().peekable();
+            let mut i = 0;
+            reads.retain(|_| {
+                let keep = skipped.next_if_eq(&&i).is_none();
+                i += 1;
+                keep
+            });
+        }
     }
 
-    reads.shrink_to_fit();
-    reads
+    let action = match policy {
+        DuplicateIds::Rename => "renamed",
+        _ => "skipped",
+    };
+    warn!(
+        "{} reads with duplicate ids were {}",
+        duplicates.len(),
+        action
+    );
+    Ok(())
+}
+
+/// Opens a text file which can be plain, gzip/bgzip or zstd compressed.
+/// Compression is detected from the magic bytes at the start of the file.
+/// Path "-" is used for reading from the standard input.
+pub(crate) fn open_text_file<P: AsRef<Path>>(path: P) -> Result<Box<dyn BufRead + Send>> {
+    let in_file = |e: io::Error| HerroError::from(e).in_file(&path);
+
+    let source: Box<dyn Read + Send> = if path.as_ref() == Path::new(STDIN_PATH) {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(&path).map_err(in_file)?)
+    };
+    let mut reader = BufReader::new(source);
+
+    let magic = reader.fill_buf().map_err(in_file)?;
+    let reader: Box<dyn BufRead + Send> = if magic.starts_with(&GZIP_MAGIC) {
+        Box::new(BufReader::new(MultiGzDecoder::new(reader)))
+    } else if magic.starts_with(&ZSTD_MAGIC) {
+        let decoder = zstd::Decoder::with_buffer(reader).map_err(in_file)?;
+        Box::new(BufReader::new(decoder))
+    } else {
+        Box::new(reader)
+    };
+
+    Ok(reader)
+}
+
+/// Reads the list of read ids, one id per line. Only the first word of the line
+/// is used, so the ids can be followed by other columns.
+pub(crate) fn read_ids<P: AsRef<Path>>(path: P) -> Result<HashSet<Vec<u8>>> {
+    let reader = open_text_file(&path)?;
+    parse_ids(reader).map_err(|e| HerroError::from(e).in_file(&path))
+}
+
+fn parse_ids(reader: impl BufRead) -> io::Result<HashSet<Vec<u8>>> {
+    let mut ids = HashSet::default();
+    for line in reader.split(b'\n') {
+        let line = line?;
+        if let Some(id) = line
+            .split(|c| c.is_ascii_whitespace())
+            .find(|w| !w.is_empty())
+        {
+            ids.insert(id.to_vec());
+        }
+    }
+
+    Ok(ids)
+}
+
+#[derive(PartialEq, Debug)]
+enum SeqData {
+    /// 2-bit encoded bases, with the runs of Ns as exceptions
+    Packed {
+        data: Vec<u64>,
+        length: usize,
+        ns: Vec<Range<u32>>,
+    },
+    Indexed(IndexedRead),
 }
 
 #[derive(PartialEq, Debug)]
 pub struct HAECSeq {
-    data: Vec<u64>,
-    length: usize,
+    data: SeqData,
 }
 
 impl HAECSeq {
     pub fn new(data: Vec<u64>, length: usize) -> Self {
-        HAECSeq { data, length }
+        HAECSeq {
+            data: SeqData::Packed {
+                data,
+                length,
+                ns: Vec::new(),
+            },
+        }
     }
 
     pub fn len(&self) -> usize {
-        return self.length;
+        match self.data {
+            SeqData::Packed { length, .. } => length,
+            SeqData::Indexed(ref read) => read.len(),
+        }
     }
 
-    pub fn get_sequence(&self, buffer: &mut [u8]) {
-        decode(&self.data, self.length, .., false, buffer)
+    /// Decodes the whole read, including its Ns. Indexed reads are fetched
+    /// from the file, which fails if it cannot be read.
+    pub fn get_sequence(&self, buffer: &mut [u8]) -> Result<()> {
+        match self.data {
+            SeqData::Packed { ref ns, .. } => {
+                self.get_subseq(.., buffer)?;
+                ns.iter()
+                    .for_each(|r| buffer[r.start as usize..r.end as usize].fill(b'N'));
+            }
+            SeqData::Indexed(ref read) => {
+                let seq = read.fetch_seq(0..read.len())?;
+                buffer[..seq.len()].iter_mut().zip(seq).for_each(|(d, b)| {
+                    match BASE_ENCODING[b as usize] {
+                        c if c < 4 => *d = BASE_DECODING[c as usize],
+                        _ => *d = b'N',
+                    }
+                });
+            }
+        }
+
+        Ok(())
     }
 
-    pub fn get_subseq<R: RangeBounds<usize>>(&self, range: R, buffer: &mut [u8]) {
-        decode(&self.data, self.length, range, false, buffer)
+    // Subsequences are used for alignment and features, which only support
+    // ACGT, so Ns are decoded as their packed code.
+    pub fn get_subseq<R: RangeBounds<usize>>(&self, range: R, buffer: &mut [u8]) -> Result<()> {
+        self.decode(range, false, buffer)
     }
 
-    pub fn get_rc_subseq<R: RangeBounds<usize>>(&self, range: R, buffer: &mut [u8]) {
-        decode(&self.data, self.length, range, true, buffer)
+    pub fn get_rc_subseq<R: RangeBounds<usize>>(&self, range: R, buffer: &mut [u8]) -> Result<()> {
+        self.decode(range, true, buffer)
+    }
+
+    fn decode<R: RangeBounds<usize>>(
+        &self,
+        range: R,
+        is_reversed: bool,
+        buffer: &mut [u8],
+    ) -> Result<()> {
+        match self.data {
+            SeqData::Packed {
+                ref data, length, ..
+            } => decode(data, length, range, is_reversed, buffer),
+            SeqData::Indexed(ref read) => {
+                // Bases are encoded in the same way as the packed ones
+                let seq = read.fetch_seq(to_range(range, read.len()))?;
+                let (data, length) = encode(&seq);
+                decode(&data, length, .., is_reversed, buffer)
+            }
+        }
+
+        Ok(())
     }
 }
 
 impl From<&[u8]> for HAECSeq {
     fn from(value: &[u8]) -> Self {
         let (data, length) = encode(value);
-        HAECSeq::new(data, length)
+        HAECSeq {
+            data: SeqData::Packed {
+                data,
+                length,
+                ns: n_runs(value),
+            },
+        }
     }
 }
 
-impl From<&HAECSeq> for Vec<u8> {
-    fn from(value: &HAECSeq) -> Self {
-        let mut seq = vec![0; value.length];
-        decode(&value.data, value.length, .., false, &mut seq);
+impl TryFrom<&HAECSeq> for Vec<u8> {
+    type Error = HerroError;
+
+    fn try_from(value: &HAECSeq) -> Result<Self> {
+        let mut seq = vec![0; value.len()];
+        value.get_sequence(&mut seq)?;
+
+        Ok(seq)
+    }
+}
+
+#[derive(Debug)]
+enum QualData {
+    Stored(Vec<u8>),
+    Indexed(IndexedRead),
+    /// Reads without qualities
+    Missing(usize),
+}
+
+/// Base qualities of the read (phred+33).
+#[derive(Debug)]
+pub struct HAECQual {
+    data: QualData,
+}
+
+impl HAECQual {
+    fn missing(length: usize) -> Self {
+        HAECQual {
+            data: QualData::Missing(length),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self.data {
+            QualData::Stored(ref qual) => qual.len(),
+            QualData::Indexed(ref read) => read.len(),
+            QualData::Missing(length) => length,
+        }
+    }
+
+    /// Qualities in the range, indexed reads are fetched from the file.
+    pub fn get<R: RangeBounds<usize>>(&self, range: R) -> Result<Cow<'_, [u8]>> {
+        let range = to_range(range, self.len());
+        let qual = match self.data {
+            QualData::Stored(ref qual) => Cow::Borrowed(&qual[range]),
+            QualData::Indexed(ref read) => match read.fetch_qual(range.clone())? {
+                Some(qual) => Cow::Owned(qual),
+                None => Cow::Owned(vec![MISSING_QUAL; range.len()]),
+            },
+            QualData::Missing(_) => Cow::Owned(vec![MISSING_QUAL; range.len()]),
+        };
+
+        Ok(qual)
+    }
+}
 
-        seq
+impl From<Vec<u8>> for HAECQual {
+    fn from(value: Vec<u8>) -> Self {
+        HAECQual {
+            data: QualData::Stored(value),
+        }
     }
 }
 
+fn to_range<R: RangeBounds<usize>>(range: R, length: usize) -> Range<usize> {
+    let start = match range.start_bound() {
+        Bound::Unbounded => 0,
+        Bound::Included(s) => *s,
+        Bound::Excluded(s) => *s + 1,
+    };
+
+    let end = match range.end_bound() {
+        Bound::Unbounded => length,
+        Bound::Included(e) => *e + 1,
+        Bound::Excluded(e) => *e,
+    };
+
+    start..end
+}
+
 fn encode(sequence: &[u8]) -> (Vec<u64>, usize) {
     let mut data = Vec::with_capacity((sequence.len() + 31) / 32);
     let mut block = 0;
 
     for (i, b) in sequence.iter().enumerate() {
-        let c = BASE_ENCODING[*b as usize];
+        let c = match BASE_ENCODING[*b as usize] {
+            c if c < 4 => c,
+            _ => N_CODE,
+        };
 
         block |= c << ((i << 1) & 63);
         if (i + 1) & 31 == 0 || i == sequence.len() - 1 {
@@ -121,6 +667,25 @@ fn encode(sequence: &[u8]) -> (Vec<u64>, usize) {
     (data, sequence.len())
 }
 
+/// Runs of the bases which cannot be 2-bit encoded.
+fn n_runs(sequence: &[u8]) -> Vec<Range<u32>> {
+    let mut runs: Vec<Range<u32>> = Vec::new();
+    for (i, b) in sequence.iter().enumerate() {
+        if BASE_ENCODING[*b as usize] < 4 {
+            continue;
+        }
+
+        let i = i as u32;
+        match runs.last_mut() {
+            Some(run) if run.end == i => run.end += 1,
+            _ => runs.push(i..i + 1),
+        }
+    }
+
+    runs.shrink_to_fit();
+    runs
+}
+
 fn decode<R: RangeBounds<usize>>(
     sequence: &[u64],
     length: usize,
@@ -128,18 +693,7 @@ fn decode<R: RangeBounds<usize>>(
     is_reversed: bool,
     buffer: &mut [u8],
 ) {
-    let start = match range.start_bound() {
-        std::ops::Bound::Unbounded => 0,
-        std::ops::Bound::Included(s) => *s,
-        std::ops::Bound::Excluded(s) => *s + 1,
-    };
-
-    let end = match range.end_bound() {
-        std::ops::Bound::Unbounded => length,
-        std::ops::Bound::Included(e) => *e + 1,
-        std::ops::Bound::Excluded(e) => *e,
-    };
-
+    let Range { start, end } = to_range(range, length);
     if end > length {
         panic!("Out of bounds for 2-bit sequence decoding.")
     }
@@ -160,21 +714,63 @@ fn decode<R: RangeBounds<usize>>(
     });
 }
 
-pub(crate) fn bytes_to_u32(bytes: &[u8]) -> u32 {
-    bytes.iter().fold(0, |acc, &d| {
+/// Parses an unsigned integer, returns None for empty or invalid numbers.
+pub(crate) fn bytes_to_u32(bytes: &[u8]) -> Option<u32> {
+    if bytes.is_empty() {
+        return None;
+    }
+
+    bytes.iter().try_fold(0u32, |acc, &d| {
         if d.is_ascii_digit() {
-            acc * 10 + (d - b'0') as u32
+            acc.checked_mul(10)?.checked_add((d - b'0') as u32)
         } else {
-            panic!("Character is not a valid digit");
+            None
         }
     })
 }
 
+pub(crate) fn bytes_to_f32(bytes: &[u8]) -> Option<f32> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
 #[cfg(test)]
 mod tests {
+    use std::{fs::File, io::prelude::*};
+
+    use flate2::{write::GzEncoder, Compression};
+
     use crate::haec_io::HAECSeq;
+    use crate::DuplicateIds;
+
+    use super::{
+        decode, encode, n_runs, open_text_file, parse_ids, resolve_duplicate_ids, HAECQual,
+        HAECRecord, ReadIds,
+    };
 
-    use super::{decode, encode};
+    #[test]
+    fn basecaller_tags() {
+        let tags = |description: &str| {
+            let read = HAECRecord::new(
+                "r0".into(),
+                Some(description.as_bytes().to_vec()),
+                HAECSeq::from(&b"ACGT"[..]),
+                HAECQual::missing(4),
+            );
+            let mut header = Vec::new();
+            read.push_basecaller_tags(&mut header);
+            String::from_utf8(header).unwrap()
+        };
+
+        assert_eq!(
+            tags("qs:f:12.5\tdu:f:1.2\tch:i:132\trn:i:981\tRG:Z:abc_dna_r10.4.1\tMM:Z:C+m?,1;"),
+            "\tch:i:132\trn:i:981\tRG:Z:abc_dna_r10.4.1"
+        );
+        assert_eq!(
+            tags("runid=abc read=981 ch=132 start_time=2024-01-01T00:00:00Z model=x ch"),
+            " runid=abc read=981 ch=132 start_time=2024-01-01T00:00:00Z"
+        );
+        assert_eq!(tags("comment"), "");
+    }
 
     #[test]
     fn encode_sequence1() {
@@ -203,7 +799,22 @@ mod tests {
     #[test]
     fn decode_sequence2() {
         let haec_seq = HAECSeq::new(vec![0b10010011100100], 7);
-        assert_eq!(Vec::from(&haec_seq), "ACGTACG".as_bytes());
+        assert_eq!(Vec::try_from(&haec_seq).unwrap(), "ACGTACG".as_bytes());
+    }
+
+    #[test]
+    fn decode_sequence_with_ns() {
+        let sequence = "NNACGNTNNACGT".as_bytes();
+        assert_eq!(n_runs(sequence), vec![0..2, 5..6, 7..9]);
+
+        let haec_seq = HAECSeq::from(sequence);
+        assert_eq!(Vec::try_from(&haec_seq).unwrap(), sequence);
+
+        let mut buffer = vec![0; 4];
+        haec_seq.get_subseq(4..8, &mut buffer).unwrap();
+        assert_eq!(&buffer, "GATA".as_bytes());
+        haec_seq.get_rc_subseq(4..8, &mut buffer).unwrap();
+        assert_eq!(&buffer, "TATC".as_bytes());
     }
 
     #[test]
@@ -211,7 +822,7 @@ mod tests {
         let haec_seq = HAECSeq::from("ACGTACGTACGT".as_bytes());
 
         let mut buffer = vec![0; 100];
-        haec_seq.get_subseq(3..10, &mut buffer);
+        haec_seq.get_subseq(3..10, &mut buffer).unwrap();
 
         assert_eq!(&buffer[..7], "TACGTAC".as_bytes())
     }
@@ -221,7 +832,7 @@ mod tests {
         let haec_seq = HAECSeq::from("ACGTACGTACGT".as_bytes());
 
         let mut buffer = vec![0; 100];
-        haec_seq.get_subseq(3.., &mut buffer);
+        haec_seq.get_subseq(3.., &mut buffer).unwrap();
 
         assert_eq!(&buffer[..9], "TACGTACGT".as_bytes())
     }
@@ -231,7 +842,7 @@ mod tests {
         let haec_seq = HAECSeq::from("ACGTACGTACGT".as_bytes());
 
         let mut buffer = vec![0; 100];
-        haec_seq.get_subseq(3..haec_seq.len(), &mut buffer);
+        haec_seq.get_subseq(3..haec_seq.len(), &mut buffer).unwrap();
 
         assert_eq!(&buffer[..9], "TACGTACGT".as_bytes())
     }
@@ -241,7 +852,7 @@ mod tests {
         let haec_seq = HAECSeq::from("ACGTACGTACGT".as_bytes());
 
         let mut buffer = vec![0; 100];
-        haec_seq.get_subseq(.., &mut buffer);
+        haec_seq.get_subseq(.., &mut buffer).unwrap();
 
         assert_eq!(&buffer[..12], "ACGTACGTACGT".as_bytes())
     }
@@ -251,7 +862,7 @@ mod tests {
         let haec_seq = HAECSeq::from("ACGTACGTACGT".as_bytes());
 
         let mut buffer = vec![0; 100];
-        haec_seq.get_subseq(8..11, &mut buffer);
+        haec_seq.get_subseq(8..11, &mut buffer).unwrap();
 
         assert_eq!(&buffer[..3], "ACG".as_bytes())
     }
@@ -261,7 +872,7 @@ mod tests {
         let haec_seq = HAECSeq::from("ATCGATCGATCG".as_bytes());
 
         let mut buffer = vec![0; 100];
-        haec_seq.get_rc_subseq(.., &mut buffer);
+        haec_seq.get_rc_subseq(.., &mut buffer).unwrap();
 
         assert_eq!(&buffer[..12], "CGATCGATCGAT".as_bytes())
     }
@@ -271,7 +882,7 @@ mod tests {
         let haec_seq = HAECSeq::from("ATCGATCGATCG".as_bytes());
 
         let mut buffer = vec![0; 100];
-        haec_seq.get_rc_subseq(3.., &mut buffer);
+        haec_seq.get_rc_subseq(3.., &mut buffer).unwrap();
 
         assert_eq!(&buffer[..9], "CGATCGATC".as_bytes())
     }
@@ -281,8 +892,105 @@ mod tests {
         let haec_seq = HAECSeq::from("ATCGATCGATCG".as_bytes());
 
         let mut buffer = vec![0; 100];
-        haec_seq.get_rc_subseq(..9, &mut buffer);
+        haec_seq.get_rc_subseq(..9, &mut buffer).unwrap();
 
         assert_eq!(&buffer[..9], "TCGATCGAT".as_bytes())
     }
+
+    #[test]
+    fn open_compressed_files() {
+        let content = "r1\t100\t0\t100\t+\tr2\t100\t0\t100\n".as_bytes();
+        let dir = std::env::temp_dir().join(format!("herro-open-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let plain_path = dir.join("alns.paf");
+        File::create(&plain_path)
+            .unwrap()
+            .write_all(content)
+            .unwrap();
+
+        let gz_path = dir.join("alns.paf.gz");
+        let mut gz = GzEncoder::new(File::create(&gz_path).unwrap(), Compression::default());
+        gz.write_all(content).unwrap();
+        gz.finish().unwrap();
+
+        let zst_path = dir.join("alns.paf.zst");
+        let mut zst = zstd::Encoder::new(File::create(&zst_path).unwrap(), 0).unwrap();
+        zst.write_all(content).unwrap();
+        zst.finish().unwrap();
+
+        for path in [plain_path, gz_path, zst_path] {
+            let mut buffer = Vec::new();
+            open_text_file(&path)
+                .unwrap()
+                .read_to_end(&mut buffer)
+                .unwrap();
+            assert_eq!(buffer, content);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_read_ids() {
+        let ids = parse_ids("r1\n\nr2 length=100\r\n  r3\n".as_bytes()).unwrap();
+
+        let mut ids: Vec<_> = ids.into_iter().collect();
+        ids.sort();
+        assert_eq!(ids, [b"r1", b"r2", b"r3"]);
+    }
+
+    #[test]
+    fn interned_read_ids() {
+        let mut ids = ReadIds::default();
+        let mut parts = Vec::new();
+        for (i, id) in ["r1", "", "read_2"].into_iter().enumerate() {
+            ids.push(id.as_bytes());
+            parts.push((
+                None,
+                HAECSeq::from(&b"ACG"[..i + 1]),
+                HAECQual::missing(i + 1),
+            ));
+        }
+
+        let reads = ids.into_records(parts);
+        let ids: Vec<_> = reads.iter().map(|r| (&*r.id, r.seq.len())).collect();
+        assert_eq!(ids, [(&b"r1"[..], 1), (b"", 2), (b"read_2", 3)]);
+        assert!(std::sync::Arc::ptr_eq(&reads[0].id.ids, &reads[2].id.ids));
+    }
+
+    #[test]
+    fn duplicate_read_ids() {
+        let reads = || -> Vec<HAECRecord> {
+            ["r1", "r2", "r1", "r1_1", "r1"]
+                .into_iter()
+                .enumerate()
+                .map(|(i, id)| {
+                    let seq = HAECSeq::from(&b"ACGTA"[..i + 1]);
+                    HAECRecord::new(id.into(), None, seq, HAECQual::missing(i + 1))
+                })
+                .collect()
+        };
+        let ids = |reads: &[HAECRecord]| -> Vec<_> {
+            reads
+                .iter()
+                .map(|r| (String::from_utf8(r.id.to_vec()).unwrap(), r.seq.len()))
+                .collect()
+        };
+
+        let err = resolve_duplicate_ids(&mut reads(), DuplicateIds::Error).unwrap_err();
+        assert_eq!(err, "duplicate read id r1");
+
+        let mut renamed = reads();
+        resolve_duplicate_ids(&mut renamed, DuplicateIds::Rename).unwrap();
+        let expected = [("r1", 1), ("r2", 2), ("r1_2", 3), ("r1_1", 4), ("r1_3", 5)];
+        let expected: Vec<_> = expected.iter().map(|&(i, l)| (i.to_string(), l)).collect();
+        assert_eq!(ids(&renamed), expected);
+
+        let mut skipped = reads();
+        resolve_duplicate_ids(&mut skipped, DuplicateIds::Skip).unwrap();
+        let expected = [("r1", 1), ("r2", 2), ("r1_1", 4)];
+        let expected: Vec<_> = expected.iter().map(|&(i, l)| (i.to_string(), l)).collect();
+        assert_eq!(ids(&skipped), expected);
+    }
 }
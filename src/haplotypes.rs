@@ -0,0 +1,187 @@
+use std::io::{self, prelude::*};
+
+use crossbeam_channel::Receiver;
+
+use crate::consensus::{ConsensusWindow, BASES_UPPER_COUNTER};
+use crate::haec_io::HAECRecord;
+use crate::inference::BASES_MAP;
+
+const HAPLOTYPES_HEADER: &[u8] =
+    b"read\tstart\tend\tsupporting_read\tgroup\tagreeing\tdisagreeing\n";
+
+/// Supporting read of a window with the number of informative positions at
+/// which it agrees or disagrees with the corrected target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ReadGroup {
+    pub(crate) qid: u32,
+    pub(crate) n_agreeing: u16,
+    pub(crate) n_disagreeing: u16,
+}
+
+impl ReadGroup {
+    /// HP-style group: 1 for reads on the haplotype of the target, 2 for the
+    /// other reads and 0 if it cannot be decided.
+    pub(crate) fn group(&self) -> u8 {
+        match (self.n_agreeing, self.n_disagreeing) {
+            (a, d) if a > d => 1,
+            (a, d) if a < d => 2,
+            _ => 0,
+        }
+    }
+}
+
+/// Groups of the supporting reads of a corrected window. Positions whose info
+/// logit predicted by the model is positive are informative (e.g. heterozygous
+/// sites), and reads are grouped by their bases at these positions compared to
+/// the corrected bases.
+pub(crate) struct WindowGroups {
+    pub(crate) wid: u16,
+    pub(crate) n_total_wins: u16,
+    pub(crate) reads: Vec<ReadGroup>,
+}
+
+impl WindowGroups {
+    /// Returns None for windows which are not corrected or have no positions
+    /// predicted by the model.
+    pub(crate) fn new(window: &ConsensusWindow) -> Option<Self> {
+        if window.n_alns < 2 || window.supported.is_empty() {
+            return None;
+        }
+        let info_logits = window.info_logits.as_ref()?;
+        let predicted = window.bases_logits.as_ref()?;
+
+        let mut reads: Vec<_> = window
+            .qids
+            .iter()
+            .map(|&qid| ReadGroup {
+                qid,
+                n_agreeing: 0,
+                n_disagreeing: 0,
+            })
+            .collect();
+
+        let informative = window
+            .supported
+            .iter()
+            .zip(info_logits.iter().zip(predicted.iter()))
+            .filter(|(_, (&logit, _))| logit > 0.);
+        for (supported, (_, &base)) in informative {
+            let col = window.indices[supported.pos as usize] + supported.ins as usize;
+            let row = window.bases.row(col);
+
+            // First row is the target
+            for (read, &b) in reads.iter_mut().zip(row.iter().skip(1)) {
+                if b == BASES_MAP[b'.' as usize] {
+                    continue;
+                }
+
+                match BASES_UPPER_COUNTER[b as usize] == base as usize {
+                    true => read.n_agreeing += 1,
+                    false => read.n_disagreeing += 1,
+                }
+            }
+        }
+
+        Some(WindowGroups {
+            wid: window.wid,
+            n_total_wins: window.n_total_wins,
+            reads,
+        })
+    }
+}
+
+/// Writes the groups of the supporting reads as TSV with a row per window and
+/// supporting read. Windows are given by their span in the target read.
+pub(crate) fn haplotypes_writer(
+    reads: &[HAECRecord],
+    mut writer: impl Write,
+    write_header: bool,
+    (window_size, window_stride): (u32, u32),
+    receiver: Receiver<(usize, Vec<WindowGroups>)>,
+) -> io::Result<()> {
+    if write_header {
+        writer.write_all(HAPLOTYPES_HEADER)?;
+    }
+
+    for (rid, windows) in receiver {
+        let target = &reads[rid];
+        for window in windows {
+            let start = window.wid as usize * window_stride as usize;
+            let end = match window.wid + 1 == window.n_total_wins {
+                true => target.seq.len(),
+                false => (start + window_size as usize).min(target.seq.len()),
+            };
+
+            for read in window.reads {
+                writer.write_all(&target.id)?;
+                write!(writer, "\t{}\t{}\t", start, end)?;
+                writer.write_all(&reads[read.qid as usize].id)?;
+                writeln!(
+                    writer,
+                    "\t{}\t{}\t{}",
+                    read.group(),
+                    read.n_agreeing,
+                    read.n_disagreeing
+                )?;
+            }
+        }
+    }
+
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+
+    use super::{ReadGroup, WindowGroups};
+    use crate::consensus::ConsensusWindow;
+    use crate::features::SupportedPos;
+    use crate::inference::BASES_MAP;
+
+    #[test]
+    fn group_supporting_reads() {
+        // Target and four supporting reads, C/T at position 1 and A/G at
+        // position 3 are informative, read 4 does not cover them
+        let rows: [&[u8]; 5] = [b"ACGAT", b"ACGAT", b"ATGGT", b"AtGgT", b"A.G.T"];
+        let mut bases = Array2::zeros((5, 5));
+        for (r, row) in rows.iter().enumerate() {
+            for (c, &b) in row.iter().enumerate() {
+                bases[[c, r]] = BASES_MAP[b as usize];
+            }
+        }
+
+        let supported = vec![
+            SupportedPos::new(1, 0),
+            SupportedPos::new(2, 0),
+            SupportedPos::new(3, 0),
+        ];
+        let window = ConsensusWindow::new(
+            0,
+            0,
+            4,
+            1,
+            bases,
+            Array2::zeros((5, 5)),
+            vec![0, 1, 2, 3, 4],
+            supported,
+            vec![1, 2, 3, 4],
+            Some(vec![2.5, -3., 1.2]),
+            Some(vec![1, 2, 0]),
+            Some(vec![0.9, 0.99, 0.8]),
+        );
+
+        let groups = WindowGroups::new(&window).unwrap();
+        let read = |qid, n_agreeing, n_disagreeing| ReadGroup {
+            qid,
+            n_agreeing,
+            n_disagreeing,
+        };
+        assert_eq!(
+            groups.reads,
+            [read(1, 2, 0), read(2, 0, 2), read(3, 0, 2), read(4, 0, 0)]
+        );
+        let hp: Vec<_> = groups.reads.iter().map(|r| r.group()).collect();
+        assert_eq!(hp, [1, 2, 2, 0]);
+    }
+}
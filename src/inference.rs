@@ -1,20 +1,42 @@
+use std::fmt;
 use std::path::Path;
+use std::str::FromStr;
+use std::time::Instant;
 
 use crossbeam_channel::{Receiver, Sender};
 use itertools::Itertools;
 
-use ndarray::{s, Array2, ArrayBase, Axis, Data, Ix2};
-
-use tch::{CModule, IValue, IndexOp, Tensor};
+use ndarray::{s, Array2, Array3, ArrayBase, ArrayView2, Axis, Data, Ix2};
 
 use crate::{
     consensus::{ConsensusData, ConsensusWindow},
+    error::{HerroError, Result},
     features::SupportedPos,
+    pbars::PBarNotification,
+    spill::Reservation,
+    telemetry::DeviceStats,
+    Backend, CorrectionConfig,
+};
+
+#[cfg(feature = "candle")]
+mod candle;
+mod encoders;
+mod ensemble;
+#[cfg(feature = "onnx")]
+mod onnx;
+mod spec;
+#[cfg(feature = "tch")]
+mod torch;
+
+pub(crate) use encoders::{feature_encoder, FeatureEncoder};
+pub(crate) use spec::{
+    check_quantization, resolve_ensemble_window_size, resolve_feature_layout, resolve_feature_set,
+    resolve_top_k, ModelSpec,
 };
 
 const BASE_PADDING: u8 = 11;
-const QUAL_MIN_VAL: f32 = 33.;
-const QUAL_MAX_VAL: f32 = 126.;
+/// Base classes predicted by the model: A, C, G, T and gap
+const N_BASE_CLASSES: u8 = 5;
 
 pub(crate) const BASES_MAP: [u8; 128] = [
     255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
@@ -26,21 +48,73 @@ pub(crate) const BASES_MAP: [u8; 128] = [
     8, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
 ];
 
+/// Device used for inference, given as `cpu`, `mps` (Apple GPU), `cuda:<id>`
+/// or `hip:<id>` (AMD GPU with ROCm).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Device {
+    Cpu,
+    Mps,
+    Cuda(usize),
+    Hip(usize),
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Device::Cpu => write!(f, "cpu"),
+            Device::Mps => write!(f, "mps"),
+            Device::Cuda(id) => write!(f, "cuda:{}", id),
+            Device::Hip(id) => write!(f, "hip:{}", id),
+        }
+    }
+}
+
+impl FromStr for Device {
+    type Err = HerroError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "cpu" => Ok(Device::Cpu),
+            "mps" => Ok(Device::Mps),
+            _ => {
+                let parse = |prefix| s.strip_prefix(prefix).and_then(|id| id.parse().ok());
+                parse("cuda:")
+                    .map(Device::Cuda)
+                    .or_else(|| parse("hip:").map(Device::Hip))
+                    .ok_or_else(|| {
+                        HerroError::InvalidConfig(format!(
+                            "invalid device {}, expected cpu, mps, cuda:<id> or hip:<id>",
+                            s
+                        ))
+                    })
+            }
+        }
+    }
+}
+
+/// Batch of windows in the model input format, independent of the backend.
 pub(crate) struct InferenceBatch {
     wids: Vec<u32>,
-    bases: Tensor,
-    quals: Tensor,
-    lens: Tensor,
-    indices: Vec<Tensor>,
+    /// Encoded bases, [B, L, R]
+    bases: Array3<i32>,
+    /// Normalized qualities, [B, L, R]
+    quals: Array3<f32>,
+    /// Number of target positions in each window, [B]
+    lens: Vec<i32>,
+    /// Concatenated target positions of all windows, [sum(lens)]
+    indices: Vec<i32>,
+    /// Optional channels of the feature layout by their input names, [B, L, R]
+    /// each, given to the model after the other inputs
+    channels: Vec<(&'static str, Array3<f32>)>,
 }
 
 impl InferenceBatch {
     fn new(
         wids: Vec<u32>,
-        bases: Tensor,
-        quals: Tensor,
-        lens: Tensor,
-        indices: Vec<Tensor>,
+        bases: Array3<i32>,
+        quals: Array3<f32>,
+        lens: Vec<i32>,
+        indices: Vec<i32>,
     ) -> Self {
         Self {
             wids,
@@ -48,13 +122,196 @@ impl InferenceBatch {
             quals,
             lens,
             indices,
+            channels: Vec::new(),
         }
     }
 }
 
+/// Model output for all target positions in the batch, concatenated over windows.
+pub(crate) struct BatchPrediction {
+    pub(crate) info_logits: Vec<f32>,
+    /// Predicted base class for each position
+    pub(crate) bases: Vec<u8>,
+    /// Probability of the predicted base
+    pub(crate) bases_probs: Vec<f32>,
+}
+
+/// Model which predicts corrected bases for a batch of windows. Models are
+/// loaded on a single device and used by a single inference worker.
+pub(crate) trait InferenceBackend: Send {
+    /// Returns the informative logits, [N], and the base logits, [N, C], of
+    /// the target positions in the batch.
+    fn logits(&self, batch: &InferenceBatch) -> Result<(Vec<f32>, Array2<f32>)>;
+
+    fn predict(&self, batch: &InferenceBatch) -> Result<BatchPrediction> {
+        let (info_logits, bases_logits) = self.logits(batch)?;
+        if info_logits.len() != batch.indices.len() || bases_logits.nrows() != batch.indices.len() {
+            return Err(HerroError::Model(format!(
+                "model returns {} info and {} base logits for {} target positions",
+                info_logits.len(),
+                bases_logits.nrows(),
+                batch.indices.len()
+            )));
+        }
+        let (bases, bases_probs) = predict_bases(bases_logits.view());
+
+        Ok(BatchPrediction {
+            info_logits,
+            bases,
+            bases_probs,
+        })
+    }
+}
+
+/// Loads the model for the configured backend on the given device.
+pub(crate) fn load_model<P: AsRef<Path>>(
+    config: &CorrectionConfig,
+    model_path: P,
+    device: Device,
+) -> Result<Box<dyn InferenceBackend>> {
+    let model_path = model_path.as_ref();
+    let model: Box<dyn InferenceBackend> = match config.backend {
+        #[cfg(feature = "tch")]
+        Backend::Tch => Box::new(torch::TorchModel::load(model_path, device, config)?),
+        #[cfg(not(feature = "tch"))]
+        Backend::Tch => {
+            return Err(HerroError::InvalidConfig(
+                "herro was built without the tch feature".to_string(),
+            ))
+        }
+        #[cfg(feature = "onnx")]
+        Backend::Onnx(provider) => {
+            Box::new(onnx::OnnxModel::load(model_path, provider, device, config)?)
+        }
+        #[cfg(not(feature = "onnx"))]
+        Backend::Onnx(_) => {
+            return Err(HerroError::InvalidConfig(
+                "herro was built without the onnx feature".to_string(),
+            ))
+        }
+        #[cfg(feature = "candle")]
+        Backend::Candle => Box::new(candle::CandleModel::load(model_path, device)?),
+        #[cfg(not(feature = "candle"))]
+        Backend::Candle => {
+            return Err(HerroError::InvalidConfig(
+                "herro was built without the candle feature".to_string(),
+            ))
+        }
+    };
+
+    Ok(model)
+}
+
+/// Loads the models on the device, models are combined into an ensemble if
+/// there are more of them.
+pub(crate) fn load_models<P: AsRef<Path>>(
+    config: &CorrectionConfig,
+    model_paths: &[P],
+    device: Device,
+) -> Result<Box<dyn InferenceBackend>> {
+    let mut models = model_paths
+        .iter()
+        .map(|path| load_model(config, path, device))
+        .collect::<Result<Vec<_>>>()?;
+
+    match models.len() {
+        1 => Ok(models.pop().unwrap()),
+        _ => Ok(Box::new(ensemble::EnsembleModel::new(
+            models,
+            config.ensemble,
+        ))),
+    }
+}
+
+/// Batch with a full-length and a shorter (last) window of dummy features with
+/// `top_k` supporting reads, encoded like the windows of the reads.
+fn dummy_batch(
+    (window_size, top_k): (usize, usize),
+    encoder: &dyn FeatureEncoder,
+) -> InferenceBatch {
+    let examples = [window_size, window_size.div_ceil(2)]
+        .into_iter()
+        .enumerate()
+        .map(|(wid, length)| {
+            let bases = Array2::from_shape_fn((length, 1 + top_k), |(i, j)| b"ACGT"[(i + j) % 4]);
+            let quals = Array2::from_elem((length, 1 + top_k), b'?' as f32);
+            let supported = (0..length)
+                .step_by(length.div_ceil(16))
+                .map(|pos| SupportedPos::new(pos as u16, 0))
+                .collect();
+            let qids = (1..=top_k as u32).collect();
+            WindowExample::new(0, wid as u16, bases, quals, supported, qids, 2)
+        });
+
+    prepare_examples(examples, 2, encoder)
+        .batches
+        .pop()
+        .unwrap()
+}
+
+/// Runs a dummy batch through the model and checks that its outputs match
+/// the window size, the number of supporting reads and the features of the
+/// encoder, so a model which does not match
+/// them fails before the reads are processed instead of producing wrong
+/// corrections.
+pub(crate) fn verify_model(
+    model: &dyn InferenceBackend,
+    (window_size, top_k): (u32, usize),
+    encoder: &dyn FeatureEncoder,
+) -> Result<()> {
+    let batch = dummy_batch((window_size as usize, top_k), encoder);
+    let (n_windows, length, n_rows) = batch.bases.dim();
+    let n_supported = batch.indices.len();
+    let layout = format!(
+        "{} windows of {} positions with {} reads (target and top {} supporting reads)",
+        n_windows, length, n_rows, top_k
+    );
+
+    let prediction = model.predict(&batch).map_err(|e| {
+        HerroError::Model(format!(
+            "model failed on a dummy batch of {}: {}. Check that the model was trained with window size {} (-w or the model spec), {} supporting reads (--top-k or the model spec) and the features of this version of herro",
+            layout, e, window_size, top_k
+        ))
+    })?;
+
+    let BatchPrediction {
+        info_logits,
+        bases,
+        bases_probs,
+    } = prediction;
+    if [info_logits.len(), bases.len(), bases_probs.len()] != [n_supported; 3] {
+        return Err(HerroError::Model(format!(
+            "model returned {} informative logits, {} bases and {} probabilities for {} supported positions of a dummy batch of {}, the model outputs do not match the features of this version of herro",
+            info_logits.len(),
+            bases.len(),
+            bases_probs.len(),
+            n_supported,
+            layout
+        )));
+    }
+    if let Some(base) = bases.iter().find(|&&b| b >= N_BASE_CLASSES) {
+        return Err(HerroError::Model(format!(
+            "model predicted base class {}, herro decodes {} classes (A, C, G, T and gap)",
+            base, N_BASE_CLASSES
+        )));
+    }
+    let valid_prob = |p: &f32| (0. ..=1.).contains(p);
+    if !info_logits.iter().all(|l| l.is_finite()) || !bases_probs.iter().all(valid_prob) {
+        return Err(HerroError::Model(
+            "model returned NaN or infinite outputs for a dummy batch, check the model weights and --precision"
+                .to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
 pub(crate) struct InferenceData {
     consensus_data: ConsensusData,
     batches: Vec<InferenceBatch>,
+    /// Memory of the features in the budget of the feature spill, released
+    /// when the batches are decoded
+    reservation: Option<Reservation>,
 }
 
 impl InferenceData {
@@ -62,186 +319,178 @@ impl InferenceData {
         Self {
             consensus_data,
             batches,
+            reservation: None,
         }
     }
+
+    pub(crate) fn with_reservation(mut self, reservation: Reservation) -> Self {
+        self.reservation = Some(reservation);
+        self
+    }
+
+    /// Number of windows in the batches, windows without enough supporting
+    /// reads are not inferred
+    pub(crate) fn n_windows(&self) -> usize {
+        self.batches.iter().map(|b| b.wids.len()).sum()
+    }
 }
 
-fn collate<'a>(batch: &[(u32, &ConsensusWindow)]) -> InferenceBatch {
+fn collate(batch: &[(u32, &ConsensusWindow)], encoder: &dyn FeatureEncoder) -> InferenceBatch {
     // Get longest sequence
     let length = batch
         .iter()
         .map(|(_, f)| f.bases.len_of(Axis(0)))
         .max()
         .unwrap();
-    let size = [
-        batch.len() as i64,
-        length as i64,
-        batch[0].1.bases.len_of(Axis(1)) as i64,
-    ]; // [B, L, R]
-
-    let bases = Tensor::full(
-        &size,
-        BASE_PADDING as i64,
-        (tch::Kind::Int, tch::Device::Cpu),
-    );
+    let size = (batch.len(), length, batch[0].1.bases.len_of(Axis(1))); // [B, L, R]
 
-    let quals = Tensor::ones(&size, (tch::Kind::Float, tch::Device::Cpu));
+    let mut bases = Array3::from_elem(size, BASE_PADDING as i32);
+    let mut quals = Array3::ones(size);
 
     let mut lens = Vec::with_capacity(batch.len());
-    let mut indices = Vec::with_capacity(batch.len());
+    let mut indices = Vec::new();
     let mut wids = Vec::with_capacity(batch.len());
 
     for (idx, (wid, f)) in batch.iter().enumerate() {
         wids.push(*wid);
         let l = f.bases.len_of(Axis(0));
 
-        let bt = unsafe {
-            let shape: Vec<_> = f.bases.shape().iter().map(|s| *s as i64).collect();
-            Tensor::from_blob(
-                f.bases.as_ptr(),
-                &shape,
-                &[shape[shape.len() - 1], 1],
-                tch::Kind::Uint8,
-                tch::Device::Cpu,
-            )
-        };
-
-        let qt = unsafe {
-            let shape: Vec<_> = f.quals.shape().iter().map(|s| *s as i64).collect();
-            Tensor::from_blob(
-                f.quals.as_ptr() as *const u8,
-                &shape,
-                &[shape[shape.len() - 1], 1],
-                tch::Kind::Float,
-                tch::Device::Cpu,
-            )
-        };
-
-        bases.i((idx as i64, ..l as i64, ..)).copy_(&bt);
-        quals.i((idx as i64, ..l as i64, ..)).copy_(&qt);
-
-        /*for p in 0..l {
-            for r in 0..f.bases.len_of(Axis(1)) {
-                let _ = bases
-                    .i((idx as i64, p as i64, r as i64))
-                    .fill_(f.bases[[p, r]] as i64);
-
-                let _ = quals
-                    .i((idx as i64, p as i64, r as i64))
-                    .fill_(f.quals[[p, r]] as f64);
-            }
-        }*/
-
-        //println!("Bases shape: {:?}", f.bases.shape());
-        //println!("Quals shape: {:?}", f.quals.shape());
+        bases
+            .slice_mut(s![idx, ..l, ..])
+            .zip_mut_with(&f.bases, |b, &fb| *b = fb as i32);
+        quals.slice_mut(s![idx, ..l, ..]).assign(&f.quals);
 
         lens.push(f.supported.len() as i32);
-
-        let tidx: Vec<_> = f
-            .supported
-            .iter()
-            .map(|&sp| (f.indices[sp.pos as usize] + sp.ins as usize) as i32)
-            .collect();
-        indices.push(Tensor::try_from(tidx).unwrap());
+        indices.extend(
+            f.supported
+                .iter()
+                .map(|&sp| (f.indices[sp.pos as usize] + sp.ins as usize) as i32),
+        );
     }
 
-    /*if batch[0].1.supported.contains(&SupportedPos::new(837, 0))
-        && batch[0].1.supported.contains(&SupportedPos::new(1157, 0))
-    {
-        bases.save("bases_to_test.tmp2.pt").unwrap();
-        quals.save("quals_to_test.tmp2.pt").unwrap();
-        indices[0].save("indices_to_test.tmp2.pt").unwrap();
-    }*/
+    let channels = encoder.channels(&bases);
+    encoder.encode_bases(&mut bases);
 
-    InferenceBatch::new(wids, bases, quals, Tensor::try_from(lens).unwrap(), indices)
+    let mut batch = InferenceBatch::new(wids, bases, quals, lens, indices);
+    batch.channels = channels;
+    batch
 }
 
-fn inference(
-    batch: InferenceBatch,
-    model: &CModule,
-    device: tch::Device,
-) -> (Vec<u32>, Vec<Tensor>, Vec<Tensor>) {
-    let inputs = [
-        IValue::Tensor(batch.bases.to(device)),
-        IValue::Tensor(batch.quals.to(device)),
-        IValue::Tensor(batch.lens),
-        IValue::TensorList(batch.indices),
-    ];
-
-    let (info_logits, bases_logits) =
-        <(Tensor, Tensor)>::try_from(model.forward_is(&inputs).unwrap()).unwrap();
-
-    // Get number of target positions for each window
-    let lens: Vec<i64> = match inputs[2] {
-        IValue::Tensor(ref t) => Vec::try_from(t).unwrap(),
-        _ => unreachable!(),
-    };
-
-    let info_logits = info_logits.to(tch::Device::Cpu).split_with_sizes(&lens, 0);
-    let bases_logits = bases_logits
-        .argmax(1, false)
-        .to(tch::Device::Cpu)
-        .split_with_sizes(&lens, 0);
-
-    (batch.wids, info_logits, bases_logits)
+/// Computes the predicted base and its probability from the base logits, [N, C].
+pub(crate) fn predict_bases(logits: ArrayView2<f32>) -> (Vec<u8>, Vec<f32>) {
+    logits
+        .rows()
+        .into_iter()
+        .map(|row| {
+            let (base, max) =
+                row.iter()
+                    .enumerate()
+                    .fold((0, f32::NEG_INFINITY), |(bi, bm), (i, &l)| {
+                        if l > bm {
+                            (i, l)
+                        } else {
+                            (bi, bm)
+                        }
+                    });
+
+            // Softmax probability of the maximum logit
+            let sum: f32 = row.iter().map(|&l| (l - max).exp()).sum();
+            (base as u8, 1. / sum)
+        })
+        .unzip()
 }
 
-pub(crate) fn inference_worker<P: AsRef<Path>>(
-    model_path: P,
-    device: tch::Device,
+/// Inference data with the predictions of its batches, decoded by `decode_worker`.
+pub(crate) type InferredData = (InferenceData, Vec<BatchPrediction>);
+
+/// Infers the batches from the queue shared by all devices. Predictions are
+/// decoded by a separate worker, so the next batch is taken from the queue as
+/// soon as the device is done with the current one.
+pub(crate) fn inference_worker(
+    model: Box<dyn InferenceBackend>,
     input_channel: Receiver<InferenceData>,
-    output_channel: Sender<ConsensusData>,
+    output_channel: Sender<InferredData>,
+    pbar_sender: Sender<PBarNotification>,
+    stats: &DeviceStats,
 ) {
-    let _no_grad = tch::no_grad_guard();
+    loop {
+        let data = match input_channel.recv() {
+            Ok(data) => data,
+            Err(_) => break,
+        };
 
-    let mut model = tch::CModule::load_on_device(model_path, device).expect("Cannot load model.");
-    model.set_eval();
+        let predictions = data
+            .batches
+            .iter()
+            .map(|batch| {
+                let start = Instant::now();
+                let prediction = model
+                    .predict(batch)
+                    .unwrap_or_else(|e| panic!("Inference failed: {}", e));
+                stats.add_batch(batch.wids.len(), start.elapsed());
+                pbar_sender.send(PBarNotification::InferredBatch).unwrap();
+                prediction
+            })
+            .collect();
 
+        output_channel.send((data, predictions)).unwrap();
+    }
+}
+
+/// Splits the predictions into their windows. Windows are sent to the consensus
+/// worker selected by their read id, so all windows of a read are merged by the
+/// same worker.
+pub(crate) fn decode_worker(
+    input_channel: Receiver<InferredData>,
+    output_channels: Vec<Sender<ConsensusData>>,
+) {
     loop {
-        let mut data = match input_channel.recv() {
+        let (mut data, predictions) = match input_channel.recv() {
             Ok(data) => data,
             Err(_) => break,
         };
 
-        for batch in data.batches {
-            let (wids, info_logits, bases_logits) = inference(batch, &model, device);
-            wids.into_iter()
-                .zip(info_logits.into_iter())
-                .zip(bases_logits.into_iter())
-                .for_each(|((wid, il), bl)| {
-                    data.consensus_data[wid as usize]
-                        .info_logits
-                        .replace(Vec::try_from(il).unwrap());
-
-                    data.consensus_data[wid as usize]
-                        .bases_logits
-                        .replace(Vec::try_from(bl).unwrap());
-                });
+        for (batch, mut prediction) in data.batches.iter().zip(predictions) {
+            // Split the output into windows, starting from the last one
+            for (&wid, &len) in batch.wids.iter().zip(batch.lens.iter()).rev() {
+                let start = prediction.info_logits.len() - len as usize;
+                let window = &mut data.consensus_data[wid as usize];
+
+                window
+                    .info_logits
+                    .replace(prediction.info_logits.split_off(start));
+                window
+                    .bases_logits
+                    .replace(prediction.bases.split_off(start));
+                window
+                    .bases_probs
+                    .replace(prediction.bases_probs.split_off(start));
+            }
         }
 
-        /*println!(
-            "Device {}, in: {}, out: {}",
-            d,
-            input_channel.len(),
-            output_channel.len()
-        );*/
-
-        output_channel.send(data.consensus_data).unwrap();
+        let mut outputs: Vec<ConsensusData> = output_channels.iter().map(|_| Vec::new()).collect();
+        for window in data.consensus_data {
+            outputs[window.rid as usize % output_channels.len()].push(window);
+        }
+        for (output, channel) in outputs.into_iter().zip(output_channels.iter()) {
+            if !output.is_empty() {
+                channel.send(output).unwrap();
+            }
+        }
     }
 }
 
 pub(crate) fn prepare_examples(
     features: impl IntoIterator<Item = WindowExample>,
     batch_size: usize,
+    encoder: &dyn FeatureEncoder,
 ) -> InferenceData {
     let windows: Vec<_> = features
         .into_iter()
         .map(|mut example| {
             // Transform bases (encode) and quals (normalize)
             example.bases.mapv_inplace(|b| BASES_MAP[b as usize]);
-            example
-                .quals
-                .mapv_inplace(|q| 2. * (q - QUAL_MIN_VAL) / (QUAL_MAX_VAL - QUAL_MIN_VAL) - 1.);
+            encoder.encode_quals(&mut example.quals);
 
             // Transpose: [R, L] -> [L, R]
             //bases.swap_axes(1, 0);
@@ -250,7 +499,7 @@ pub(crate) fn prepare_examples(
             let tidx = get_target_indices(&example.bases);
 
             //TODO: Start here.
-            ConsensusWindow::new(
+            let mut window = ConsensusWindow::new(
                 example.rid,
                 example.wid,
                 example.n_alns,
@@ -259,9 +508,13 @@ pub(crate) fn prepare_examples(
                 example.quals,
                 tidx,
                 example.supported,
+                example.qids,
                 None,
                 None,
-            )
+                None,
+            );
+            window.breaks = example.breaks;
+            window
         })
         .collect();
 
@@ -272,7 +525,7 @@ pub(crate) fn prepare_examples(
         .into_iter()
         .map(|v| {
             let batch = v.collect::<Vec<_>>();
-            collate(&batch)
+            collate(&batch, encoder)
         })
         .collect();
 
@@ -295,35 +548,191 @@ fn get_target_indices<S: Data<Elem = u8>>(bases: &ArrayBase<S, Ix2>) -> Vec<usiz
 }
 
 pub(crate) struct WindowExample {
-    rid: u32,
-    wid: u16,
-    n_alns: u8,
-    bases: Array2<u8>,
-    quals: Array2<f32>,
-    supported: Vec<SupportedPos>,
-    n_total_wins: u16,
+    pub(crate) rid: u32,
+    pub(crate) wid: u16,
+    pub(crate) n_alns: u8,
+    pub(crate) bases: Array2<u8>,
+    pub(crate) quals: Array2<f32>,
+    pub(crate) supported: Vec<SupportedPos>,
+    /// Ids of the supporting reads, in the order of their rows
+    pub(crate) qids: Vec<u32>,
+    /// Positions in the window at which the corrected read is split
+    pub(crate) breaks: Vec<u16>,
+    pub(crate) n_total_wins: u16,
 }
 
 impl WindowExample {
     pub(crate) fn new(
         rid: u32,
         wid: u16,
-        n_alns: u8,
         bases: Array2<u8>,
         quals: Array2<f32>,
         supported: Vec<SupportedPos>,
+        qids: Vec<u32>,
         n_total_wins: u16,
     ) -> Self {
         Self {
             rid,
             wid,
-            n_alns,
+            n_alns: qids.len() as u8,
             bases,
             quals,
             supported,
+            qids,
+            breaks: Vec::new(),
             n_total_wins,
         }
     }
+
+    pub(crate) fn with_breaks(mut self, breaks: Vec<u16>) -> Self {
+        self.breaks = breaks;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use crossbeam_channel::{bounded, unbounded};
+    use ndarray::{array, Array2, Array3};
+
+    use super::{
+        decode_worker, feature_encoder, predict_bases, verify_model, BatchPrediction, Device,
+        InferenceBackend, InferenceBatch, InferenceData,
+    };
+    use crate::consensus::ConsensusWindow;
+    use crate::error::{HerroError, Result};
+    use crate::features::DEFAULT_TOP_K;
+    use crate::inference::spec::FeatureLayout;
+    use crate::{FeatureSet, QualEncoding};
+
+    #[test]
+    fn parse_devices() {
+        assert_eq!("cpu".parse::<Device>().unwrap(), Device::Cpu);
+        assert_eq!("mps".parse::<Device>().unwrap(), Device::Mps);
+        assert_eq!("cuda:3".parse::<Device>().unwrap(), Device::Cuda(3));
+        assert_eq!(Device::Cuda(3).to_string(), "cuda:3");
+        assert_eq!("hip:1".parse::<Device>().unwrap(), Device::Hip(1));
+        assert_eq!(Device::Hip(1).to_string(), "hip:1");
+
+        for invalid in ["0", "cuda", "cuda:", "cuda:-1", "gpu:0", "hip", "hip:x"] {
+            assert!(invalid.parse::<Device>().is_err());
+        }
+    }
+
+    #[test]
+    fn decode_predictions() {
+        let window = |rid| {
+            let (bases, quals) = (Array2::zeros((1, 1)), Array2::zeros((1, 1)));
+            ConsensusWindow::new(
+                rid,
+                0,
+                1,
+                1,
+                bases,
+                quals,
+                vec![],
+                vec![],
+                vec![0],
+                None,
+                None,
+                None,
+            )
+        };
+        let batch = InferenceBatch::new(
+            vec![0, 1],
+            Array3::zeros((2, 1, 1)),
+            Array3::zeros((2, 1, 1)),
+            vec![2, 1],
+            vec![0, 1, 0],
+        );
+        let data = InferenceData::new(vec![window(4), window(7)], vec![batch]);
+        let prediction = BatchPrediction {
+            info_logits: vec![0.1, 0.2, 0.3],
+            bases: vec![1, 2, 3],
+            bases_probs: vec![0.5, 0.6, 0.7],
+        };
+
+        let (input_sender, input_recv) = bounded(1);
+        let (outputs, output_recvs): (Vec<_>, Vec<_>) = (0..2).map(|_| unbounded()).unzip();
+        input_sender.send((data, vec![prediction])).unwrap();
+        drop(input_sender);
+        decode_worker(input_recv, outputs);
+
+        // Windows are routed by their read ids
+        let even = output_recvs[0].recv().unwrap();
+        assert_eq!(even[0].rid, 4);
+        assert_eq!(even[0].bases_logits, Some(vec![1, 2]));
+        let odd = output_recvs[1].recv().unwrap();
+        assert_eq!(odd[0].rid, 7);
+        assert_eq!(odd[0].bases_probs, Some(vec![0.7]));
+    }
+
+    /// Model predicting the given base for each supported position, with
+    /// `extra` additional outputs
+    struct MockModel {
+        base: u8,
+        extra: usize,
+        fail: bool,
+    }
+
+    impl InferenceBackend for MockModel {
+        fn logits(&self, batch: &InferenceBatch) -> Result<(Vec<f32>, Array2<f32>)> {
+            if self.fail || batch.bases.dim().2 != DEFAULT_TOP_K + 1 {
+                return Err(HerroError::Model("shape mismatch".to_string()));
+            }
+
+            let n = batch.indices.len() + self.extra;
+            let n_classes = (self.base as usize + 1).max(5);
+            let logits = Array2::from_shape_fn((n, n_classes), |(_, c)| {
+                if c == self.base as usize {
+                    3.
+                } else {
+                    0.
+                }
+            });
+            Ok((vec![0.; n], logits))
+        }
+    }
+
+    #[test]
+    fn verify_models() {
+        let model = |base, extra, fail| MockModel { base, extra, fail };
+        let encoder = feature_encoder(FeatureSet::Standard, FeatureLayout::default());
+        let encoder = encoder.as_ref();
+        assert!(verify_model(&model(2, 0, false), (4096, DEFAULT_TOP_K), encoder).is_ok());
+        assert!(verify_model(&model(2, 0, false), (1000, DEFAULT_TOP_K), encoder).is_ok());
+        let weights = FeatureLayout {
+            qual_encoding: QualEncoding::Weights,
+            homopolymers: true,
+            context: true,
+        };
+        let strandless = feature_encoder(FeatureSet::Strandless, weights);
+        assert!(verify_model(
+            &model(2, 0, false),
+            (4096, DEFAULT_TOP_K),
+            strandless.as_ref()
+        )
+        .is_ok());
+        assert!(verify_model(&model(2, 1, false), (4096, DEFAULT_TOP_K), encoder).is_err());
+        assert!(verify_model(&model(5, 0, false), (4096, DEFAULT_TOP_K), encoder).is_err());
+        assert!(verify_model(&model(2, 0, true), (4096, DEFAULT_TOP_K), encoder).is_err());
+        assert!(verify_model(&model(2, 0, false), (4096, 20), encoder).is_err());
+    }
+
+    #[test]
+    fn predict_bases_from_logits() {
+        let logits = array![[0., 2., 0., 0., 0.], [1., 1., 1., 1., 3.]];
+        let (bases, probs) = predict_bases(logits.view());
+
+        assert_eq!(bases, [1, 4]);
+        assert_relative_eq!(probs[0], 2f32.exp() / (2f32.exp() + 4.), epsilon = 1e-6);
+        assert_relative_eq!(
+            probs[1],
+            3f32.exp() / (3f32.exp() + 4. * 1f32.exp()),
+            epsilon = 1e-6
+        );
+    }
 }
 
 /*#[cfg(test)]
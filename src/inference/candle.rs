@@ -0,0 +1,351 @@
+use std::path::Path;
+
+use candle_core::{DType, Device, Module, ModuleT, Tensor, D};
+use candle_nn::{BatchNorm, Conv1d, Conv1dConfig, Conv2d, Conv2dConfig, Embedding, Linear};
+use ndarray::{Array2, Array3};
+use rustc_hash::FxHashMap as HashMap;
+
+use super::{InferenceBackend, InferenceBatch};
+use crate::error::{HerroError, Result};
+
+const BATCH_NORM_EPS: f64 = 1e-5;
+
+/// Model weights stored in a safetensors file, keyed by the parameter names
+/// of the PyTorch model (state dict).
+struct Weights {
+    tensors: HashMap<String, Tensor>,
+}
+
+impl Weights {
+    fn load(path: &Path, device: &Device) -> candle_core::Result<Self> {
+        let tensors = candle_core::safetensors::load(path, device)?;
+        Ok(Self {
+            tensors: tensors.into_iter().collect(),
+        })
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.tensors.contains_key(name)
+    }
+
+    fn get(&self, name: &str) -> candle_core::Result<Tensor> {
+        match self.tensors.get(name) {
+            Some(t) => t.to_dtype(DType::F32),
+            None => candle_core::bail!("missing tensor {}", name),
+        }
+    }
+
+    fn get_optional(&self, name: &str) -> candle_core::Result<Option<Tensor>> {
+        match self.contains(name) {
+            true => self.get(name).map(Some),
+            false => Ok(None),
+        }
+    }
+
+    fn embedding(&self, prefix: &str) -> candle_core::Result<Embedding> {
+        let weight = self.get(&format!("{prefix}.weight"))?;
+        let hidden_size = weight.dim(1)?;
+        Ok(Embedding::new(weight, hidden_size))
+    }
+
+    /// Convolution with zero padding which keeps the length of the input
+    fn conv1d(&self, prefix: &str, groups: usize) -> candle_core::Result<Conv1d> {
+        let weight = self.get(&format!("{prefix}.weight"))?;
+        let bias = self.get_optional(&format!("{prefix}.bias"))?;
+        let config = Conv1dConfig {
+            padding: weight.dim(2)? / 2,
+            groups,
+            ..Default::default()
+        };
+
+        Ok(Conv1d::new(weight, bias, config))
+    }
+
+    fn conv2d(&self, prefix: &str) -> candle_core::Result<Conv2d> {
+        let weight = self.get(&format!("{prefix}.weight"))?;
+        let bias = self.get_optional(&format!("{prefix}.bias"))?;
+        Ok(Conv2d::new(weight, bias, Conv2dConfig::default()))
+    }
+
+    fn batch_norm(&self, prefix: &str) -> candle_core::Result<BatchNorm> {
+        let weight = self.get(&format!("{prefix}.weight"))?;
+        BatchNorm::new(
+            weight.dim(0)?,
+            self.get(&format!("{prefix}.running_mean"))?,
+            self.get(&format!("{prefix}.running_var"))?,
+            weight,
+            self.get(&format!("{prefix}.bias"))?,
+            BATCH_NORM_EPS,
+        )
+    }
+
+    fn linear(&self, prefix: &str) -> candle_core::Result<Linear> {
+        let weight = self.get(&format!("{prefix}.weight"))?;
+        let bias = self.get_optional(&format!("{prefix}.bias"))?;
+        Ok(Linear::new(weight, bias))
+    }
+}
+
+/// Depthwise separable convolution followed by batch norm and ReLU, which is
+/// left out in the last layer of a block.
+struct SeparableConv {
+    depthwise: Conv1d,
+    pointwise: Conv1d,
+    bn: BatchNorm,
+    relu: bool,
+}
+
+impl SeparableConv {
+    fn load(weights: &Weights, prefix: &str, in_channels: usize) -> candle_core::Result<Self> {
+        Ok(Self {
+            depthwise: weights.conv1d(&format!("{prefix}.depthwise"), in_channels)?,
+            pointwise: weights.conv1d(&format!("{prefix}.pointwise"), 1)?,
+            bn: weights.batch_norm(&format!("{prefix}.bn"))?,
+            relu: true,
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let x = self.pointwise.forward(&self.depthwise.forward(x)?)?;
+        let x = self.bn.forward_t(&x, false)?;
+        match self.relu {
+            true => x.relu(),
+            false => Ok(x),
+        }
+    }
+}
+
+/// Residual block of separable convolutions.
+struct ConvBlock {
+    layers: Vec<SeparableConv>,
+    pointwise_residual: Conv1d,
+    bn_residual: BatchNorm,
+}
+
+impl ConvBlock {
+    fn load(weights: &Weights, prefix: &str) -> candle_core::Result<Self> {
+        let pointwise_residual = weights.conv1d(&format!("{prefix}.pointwise_residual"), 1)?;
+        let mut in_channels = pointwise_residual.weight().dim(1)?;
+
+        let mut layers = Vec::new();
+        while weights.contains(&format!(
+            "{prefix}.layers.{}.depthwise.weight",
+            layers.len()
+        )) {
+            let layer_prefix = format!("{prefix}.layers.{}", layers.len());
+            let layer = SeparableConv::load(weights, &layer_prefix, in_channels)?;
+            in_channels = layer.pointwise.weight().dim(0)?;
+
+            layers.push(layer);
+        }
+        // ReLU is applied after the residual is added
+        if let Some(last) = layers.last_mut() {
+            last.relu = false;
+        }
+
+        Ok(Self {
+            layers,
+            pointwise_residual,
+            bn_residual: weights.batch_norm(&format!("{prefix}.bn_residual"))?,
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let mut output = x.clone();
+        for layer in self.layers.iter() {
+            output = layer.forward(&output)?;
+        }
+
+        let residual = self.pointwise_residual.forward(x)?;
+        let residual = self.bn_residual.forward_t(&residual, false)?;
+        (residual + output)?.relu()
+    }
+}
+
+/// Model reimplemented with candle, so no C++ library is required. Weights
+/// are loaded from the state dict of the TorchScript model saved in the
+/// safetensors format by `scripts/export_safetensors.py`, which checks that
+/// the model has the parameters used here.
+/// Reads are encoded with `embedding` and convolved with `qn.context_read`
+/// (over positions) and `qn.context_pos` (over all reads). The residual blocks
+/// in `qn.backbone` are followed by the `fc_info` and `fc_bases` heads, which
+/// are applied to the target positions only.
+pub(crate) struct CandleModel {
+    device: Device,
+    embedding: Embedding,
+    context_read: (Conv2d, BatchNorm),
+    context_pos: (Conv2d, BatchNorm),
+    backbone: Vec<ConvBlock>,
+    fc_info: Linear,
+    fc_bases: Linear,
+}
+
+impl CandleModel {
+    pub(crate) fn load(path: &Path, device: crate::Device) -> Result<Self> {
+        let device =
+            match device {
+                crate::Device::Cpu => Device::Cpu,
+                crate::Device::Mps => Device::new_metal(0)
+                    .map_err(|e| HerroError::Model(e.to_string()).in_file(path))?,
+                crate::Device::Cuda(id) => Device::new_cuda(id)
+                    .map_err(|e| HerroError::Model(e.to_string()).in_file(path))?,
+                crate::Device::Hip(_) => {
+                    return Err(HerroError::InvalidConfig(
+                        "HIP devices are not supported by the candle backend".to_string(),
+                    ))
+                }
+            };
+        Self::load_on_device(path, device)
+            .map_err(|e| HerroError::Model(e.to_string()).in_file(path))
+    }
+
+    fn load_on_device(path: &Path, device: Device) -> candle_core::Result<Self> {
+        let weights = Weights::load(path, &device)?;
+
+        let mut backbone = Vec::new();
+        while weights.contains(&format!(
+            "qn.backbone.{}.pointwise_residual.weight",
+            backbone.len()
+        )) {
+            let prefix = format!("qn.backbone.{}", backbone.len());
+            backbone.push(ConvBlock::load(&weights, &prefix)?);
+        }
+
+        Ok(Self {
+            embedding: weights.embedding("embedding")?,
+            context_read: (
+                weights.conv2d("qn.context_read.0")?,
+                weights.batch_norm("qn.context_read.1")?,
+            ),
+            context_pos: (
+                weights.conv2d("qn.context_pos.0")?,
+                weights.batch_norm("qn.context_pos.1")?,
+            ),
+            backbone,
+            fc_info: weights.linear("fc_info")?,
+            fc_bases: weights.linear("fc_bases")?,
+            device,
+        })
+    }
+
+    /// Returns info logits, [N], and bases logits, [N, C].
+    fn forward(&self, batch: &InferenceBatch) -> candle_core::Result<(Tensor, Tensor)> {
+        let (b, l, r) = batch.bases.dim();
+        let bases: Vec<_> = batch.bases.iter().map(|&b| b as u32).collect();
+        let bases = Tensor::from_slice(&bases, (b, l, r), &self.device)?;
+        let quals = input_tensor(&batch.quals, &self.device)?;
+
+        // [B, L, R, E + 1 + channels] -> [B, E + 1 + channels, L, R]
+        let mut inputs = vec![self.embedding.forward(&bases)?, quals.unsqueeze(D::Minus1)?];
+        for (_, channel) in batch.channels.iter() {
+            let channel = input_tensor(channel, &self.device)?;
+            inputs.push(channel.unsqueeze(D::Minus1)?);
+        }
+        let x = Tensor::cat(&inputs, 3)?;
+        let x = x.permute((0, 3, 1, 2))?;
+
+        // Positions are padded, so the read context keeps the length of the window
+        let (conv, bn) = &self.context_read;
+        let padding = conv.weight().dim(2)? / 2;
+        let x = conv.forward(&x.pad_with_zeros(2, padding, padding)?)?;
+        let x = bn.forward_t(&x, false)?.relu()?;
+
+        // Reads are merged into a single channel, [B, C, L, 1] -> [B, C, L]
+        let (conv, bn) = &self.context_pos;
+        let x = bn.forward_t(&conv.forward(&x)?, false)?.relu()?;
+        let mut x = x.squeeze(D::Minus1)?;
+
+        for block in self.backbone.iter() {
+            x = block.forward(&x)?;
+        }
+        let x = x.transpose(1, 2)?; // [B, L, C]
+
+        let mut start = 0;
+        let mut selected = Vec::with_capacity(b);
+        for (i, &len) in batch.lens.iter().enumerate() {
+            let end = start + len as usize;
+            let indices: Vec<_> = batch.indices[start..end]
+                .iter()
+                .map(|&i| i as u32)
+                .collect();
+            let indices = Tensor::from_slice(&indices, end - start, &self.device)?;
+            selected.push(x.get(i)?.index_select(&indices, 0)?);
+
+            start = end;
+        }
+        let x = Tensor::cat(&selected, 0)?; // [N, C]
+
+        let info_logits = self.fc_info.forward(&x)?.squeeze(D::Minus1)?;
+        let bases_logits = self.fc_bases.forward(&x)?;
+
+        Ok((info_logits, bases_logits))
+    }
+}
+
+/// Tensor of the input of the batch, [B, L, R]
+fn input_tensor(input: &Array3<f32>, device: &Device) -> candle_core::Result<Tensor> {
+    match input.as_slice() {
+        Some(data) => Tensor::from_slice(data, input.dim(), device),
+        None => candle_core::bail!("batch input is not contiguous"),
+    }
+}
+
+impl InferenceBackend for CandleModel {
+    fn logits(&self, batch: &InferenceBatch) -> Result<(Vec<f32>, Array2<f32>)> {
+        let model_error = |e: candle_core::Error| HerroError::Model(e.to_string());
+        let (info_logits, bases_logits) = self.forward(batch).map_err(model_error)?;
+
+        let info_logits = info_logits.to_vec1::<f32>().map_err(model_error)?;
+        let shape = bases_logits.dims2().map_err(model_error)?;
+        let bases_logits = bases_logits
+            .flatten_all()
+            .and_then(|logits| logits.to_vec1::<f32>())
+            .map_err(model_error)?;
+        let bases_logits = Array2::from_shape_vec(shape, bases_logits)
+            .map_err(|e| HerroError::Model(format!("bases logits: {}", e)))?;
+
+        Ok((info_logits, bases_logits))
+    }
+}
+
+#[cfg(all(test, feature = "tch"))]
+mod tests {
+    use std::env;
+    use std::path::PathBuf;
+
+    use approx::assert_abs_diff_eq;
+    use ndarray::Array1;
+
+    use super::CandleModel;
+    use crate::features::DEFAULT_TOP_K;
+    use crate::inference::spec::FeatureLayout;
+    use crate::inference::torch::TorchModel;
+    use crate::inference::{dummy_batch, feature_encoder, InferenceBackend};
+    use crate::{CorrectionConfig, Device, FeatureSet};
+
+    /// TorchScript model, its weights are exported next to it with
+    /// `scripts/export_safetensors.py`
+    const MODEL_VAR: &str = "HERRO_TEST_TORCHSCRIPT";
+
+    #[test]
+    #[ignore = "requires a TorchScript model given by HERRO_TEST_TORCHSCRIPT"]
+    fn same_logits_as_tch() {
+        let path =
+            PathBuf::from(env::var_os(MODEL_VAR).expect("HERRO_TEST_TORCHSCRIPT is not set"));
+        let config = CorrectionConfig::builder().build().unwrap();
+        let torch = TorchModel::load(&path, Device::Cpu, &config).unwrap();
+        let candle = CandleModel::load(&path.with_extension("safetensors"), Device::Cpu).unwrap();
+
+        let encoder = feature_encoder(FeatureSet::Standard, FeatureLayout::default());
+        let batch = dummy_batch((4096, DEFAULT_TOP_K), encoder.as_ref());
+        let (expected_info, expected_bases) = torch.logits(&batch).unwrap();
+        let (info, bases) = candle.logits(&batch).unwrap();
+
+        assert_abs_diff_eq!(
+            Array1::from(info),
+            Array1::from(expected_info),
+            epsilon = 1e-3
+        );
+        assert_abs_diff_eq!(bases, expected_bases, epsilon = 1e-3);
+    }
+}
@@ -0,0 +1,280 @@
+use std::sync::Arc;
+
+use ndarray::{Array2, Array3, Axis};
+
+use super::spec::FeatureLayout;
+use super::BASES_MAP;
+use crate::{FeatureSet, QualEncoding};
+
+const QUAL_MIN_VAL: f32 = 33.;
+const QUAL_MAX_VAL: f32 = 126.;
+/// Homopolymer runs are counted up to this length
+const MAX_HOMOPOLYMER_LEN: usize = 16;
+
+/// Encoding of the windows into the model input. Windows keep the bases
+/// encoded with `BASES_MAP` for the consensus, the encoder transforms their
+/// qualities and, when the windows are collated into a batch, the bases of the
+/// batch and the additional channels of the feature layout.
+pub(crate) trait FeatureEncoder: Send + Sync {
+    /// Name of the feature set, as given with `--feature-set`
+    fn name(&self) -> &'static str;
+
+    /// Input channels of the model, in order, as listed in the model spec
+    fn features(&self) -> Vec<&'static str>;
+
+    /// Encodes the Phred+33 qualities of a window, [L, R].
+    fn encode_quals(&self, quals: &mut Array2<f32>);
+
+    /// Additional channels of a batch by their input names, [B, L, R] each,
+    /// computed from the bases before they are encoded.
+    fn channels(&self, _bases: &Array3<i32>) -> Vec<(&'static str, Array3<f32>)> {
+        Vec::new()
+    }
+
+    /// Encodes the bases of a batch, [B, L, R].
+    fn encode_bases(&self, _bases: &mut Array3<i32>) {}
+}
+
+/// Bases with the strand of their read and the channels of the feature layout.
+pub(crate) struct StandardEncoder {
+    layout: FeatureLayout,
+}
+
+impl FeatureEncoder for StandardEncoder {
+    fn name(&self) -> &'static str {
+        "standard"
+    }
+
+    fn features(&self) -> Vec<&'static str> {
+        let quals = match self.layout.qual_encoding {
+            QualEncoding::Phred => "quals",
+            QualEncoding::Weights => "qual_weights",
+        };
+        let mut features = vec!["bases", quals];
+        if self.layout.homopolymers {
+            features.push("homopolymers");
+        }
+        if self.layout.context {
+            features.push("context");
+        }
+        features
+    }
+
+    fn encode_quals(&self, quals: &mut Array2<f32>) {
+        let qual_encoding = self.layout.qual_encoding;
+        quals.mapv_inplace(|q| encode_qual(q, qual_encoding));
+    }
+
+    fn channels(&self, bases: &Array3<i32>) -> Vec<(&'static str, Array3<f32>)> {
+        let mut channels = Vec::new();
+        if self.layout.homopolymers {
+            channels.push(("homopolymers", homopolymer_lengths(bases)));
+        }
+        if self.layout.context {
+            channels.push(("context", dinucleotide_context(bases)));
+        }
+        channels
+    }
+}
+
+/// Reduced alphabet without the strand: bases and gaps of the reads on the
+/// reverse strand are encoded as the forward ones, for models trained without
+/// the strand of the supporting reads. Qualities and channels are encoded as
+/// in the standard feature set.
+pub(crate) struct StrandlessEncoder(StandardEncoder);
+
+impl FeatureEncoder for StrandlessEncoder {
+    fn name(&self) -> &'static str {
+        "strandless"
+    }
+
+    fn features(&self) -> Vec<&'static str> {
+        self.0.features()
+    }
+
+    fn encode_quals(&self, quals: &mut Array2<f32>) {
+        self.0.encode_quals(quals)
+    }
+
+    fn channels(&self, bases: &Array3<i32>) -> Vec<(&'static str, Array3<f32>)> {
+        self.0.channels(bases)
+    }
+
+    fn encode_bases(&self, bases: &mut Array3<i32>) {
+        // Reverse bases are encoded as 5-8 and reverse gaps as 9
+        bases.mapv_inplace(|b| if (5..=9).contains(&b) { b - 5 } else { b });
+    }
+}
+
+/// Encoder of the feature set with the channels of the feature layout.
+pub(crate) fn feature_encoder(
+    feature_set: FeatureSet,
+    layout: FeatureLayout,
+) -> Arc<dyn FeatureEncoder> {
+    let standard = StandardEncoder { layout };
+    match feature_set {
+        FeatureSet::Standard => Arc::new(standard),
+        FeatureSet::Strandless => Arc::new(StrandlessEncoder(standard)),
+    }
+}
+
+/// Class of the two bases preceding each base in its read, in the orientation
+/// of the target, `1 + 4 * first + second` for bases A, C, G, T (0-3), [B, L, R].
+/// Gaps do not interrupt the context and have class 0, as have the positions
+/// without alignment and padding, which reset it, and the first two bases of
+/// each aligned part of a read in the window.
+fn dinucleotide_context(bases: &Array3<i32>) -> Array3<f32> {
+    let gaps = [BASES_MAP[b'*' as usize], BASES_MAP[b'#' as usize]].map(|g| g as i32);
+    let mut context = Array3::zeros(bases.dim());
+
+    for (read, mut read_context) in bases
+        .lanes(Axis(1))
+        .into_iter()
+        .zip(context.lanes_mut(Axis(1)))
+    {
+        // Forward bases are encoded as 0-3 and reverse bases as 5-8
+        let mut preceding = (None, None);
+        for (i, &b) in read.iter().enumerate() {
+            if gaps.contains(&b) {
+                continue;
+            }
+
+            let base = (0..=8).contains(&b).then_some(b % 5);
+            if let (Some(first), Some(second), Some(_)) = (preceding.0, preceding.1, base) {
+                read_context[i] = (1 + 4 * first + second) as f32;
+            }
+            preceding = match base {
+                Some(_) => (preceding.1, base),
+                None => (None, None),
+            };
+        }
+    }
+
+    context
+}
+
+/// Length of the homopolymer run of each base in its read, capped at
+/// `MAX_HOMOPOLYMER_LEN`, [B, L, R]. Gaps do not interrupt a run and have
+/// length 0, as have the positions without alignment and padding, which end
+/// it. Runs are cut at the window boundaries.
+fn homopolymer_lengths(bases: &Array3<i32>) -> Array3<f32> {
+    let gaps = [BASES_MAP[b'*' as usize], BASES_MAP[b'#' as usize]].map(|g| g as i32);
+    let mut lengths = Array3::zeros(bases.dim());
+
+    let mut run = Vec::new();
+    for (read, mut read_lengths) in bases
+        .lanes(Axis(1))
+        .into_iter()
+        .zip(lengths.lanes_mut(Axis(1)))
+    {
+        let mut fill_run = |run: &mut Vec<usize>| {
+            let len = run.len().min(MAX_HOMOPOLYMER_LEN) as f32;
+            run.drain(..).for_each(|i| read_lengths[i] = len);
+        };
+
+        // Forward bases are encoded as 0-3 and reverse bases as 5-8
+        let mut run_base = None;
+        for (i, &b) in read.iter().enumerate() {
+            if gaps.contains(&b) {
+                continue;
+            }
+
+            let base = (0..=8).contains(&b).then_some(b % 5);
+            if base != run_base {
+                fill_run(&mut run);
+                run_base = base;
+            }
+            if base.is_some() {
+                run.push(i);
+            }
+        }
+        fill_run(&mut run);
+    }
+
+    lengths
+}
+
+/// Encodes the Phred+33 quality for the model input.
+fn encode_qual(qual: f32, qual_encoding: QualEncoding) -> f32 {
+    match qual_encoding {
+        QualEncoding::Phred => 2. * (qual - QUAL_MIN_VAL) / (QUAL_MAX_VAL - QUAL_MIN_VAL) - 1.,
+        QualEncoding::Weights => 1. - 10f32.powf(-(qual - QUAL_MIN_VAL) / 10.),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use ndarray::{array, s, Array2, Array3};
+
+    use super::{
+        dinucleotide_context, encode_qual, feature_encoder, homopolymer_lengths, FeatureLayout,
+    };
+    use crate::inference::{BASES_MAP, BASE_PADDING};
+    use crate::{FeatureSet, QualEncoding};
+
+    #[test]
+    fn context_channel() {
+        // Target and a supporting read on the reverse strand, which is padded
+        let encode = |row: &[u8]| row.iter().map(|&b| BASES_MAP[b as usize] as i32).collect();
+        let mut read: Vec<_> = encode(b"ca#gt");
+        read.push(BASE_PADDING as i32);
+        let rows = [encode(b"AC*GT."), read];
+        let bases = Array3::from_shape_fn((1, 6, 2), |(_, l, r)| rows[r][l]);
+
+        // AC -> 1 + 4 * 0 + 1, CG -> 1 + 4 * 1 + 2, CA -> 1 + 4 * 1 + 0
+        let context = dinucleotide_context(&bases);
+        assert_eq!(context.slice(s![0, .., 0]), array![0., 0., 0., 2., 7., 0.]);
+        assert_eq!(context.slice(s![0, .., 1]), array![0., 0., 0., 5., 3., 0.]);
+    }
+
+    #[test]
+    fn homopolymer_channel() {
+        // Target and a supporting read on the reverse strand, which is padded
+        let encode = |row: &[u8]| row.iter().map(|&b| BASES_MAP[b as usize] as i32).collect();
+        let mut read: Vec<_> = encode(b"aa#ac");
+        read.push(BASE_PADDING as i32);
+        let rows = [encode(b"AAC*G."), read];
+        let bases = Array3::from_shape_fn((1, 6, 2), |(_, l, r)| rows[r][l]);
+
+        let lengths = homopolymer_lengths(&bases);
+        assert_eq!(lengths.slice(s![0, .., 0]), array![2., 2., 1., 0., 1., 0.]);
+        assert_eq!(lengths.slice(s![0, .., 1]), array![3., 3., 0., 3., 1., 0.]);
+    }
+
+    #[test]
+    fn encode_quals() {
+        assert_relative_eq!(encode_qual(b'!' as f32, QualEncoding::Phred), -1.);
+        assert_relative_eq!(encode_qual(b'~' as f32, QualEncoding::Phred), 1.);
+        assert_relative_eq!(encode_qual(b'!' as f32, QualEncoding::Weights), 0.);
+        assert_relative_eq!(encode_qual(b'+' as f32, QualEncoding::Weights), 0.9);
+        assert_relative_eq!(
+            encode_qual(b'5' as f32, QualEncoding::Weights),
+            0.99,
+            epsilon = 1e-6
+        );
+    }
+
+    #[test]
+    fn strandless_features() {
+        let layout = FeatureLayout {
+            homopolymers: true,
+            ..Default::default()
+        };
+        let encoder = feature_encoder(FeatureSet::Strandless, layout);
+        assert_eq!(encoder.name(), "strandless");
+        assert_eq!(encoder.features(), ["bases", "quals", "homopolymers"]);
+
+        let encode = |row: &[u8]| row.iter().map(|&b| BASES_MAP[b as usize] as i32).collect();
+        let rows: [Vec<_>; 2] = [encode(b"AC*GT."), encode(b"ac#gt.")];
+        let mut bases = Array3::from_shape_fn((1, 6, 2), |(_, l, r)| rows[r][l]);
+        let channels = encoder.channels(&bases);
+        encoder.encode_bases(&mut bases);
+        assert_eq!(bases.slice(s![0, .., 0]), bases.slice(s![0, .., 1]));
+        assert_eq!(channels[0].0, "homopolymers");
+
+        let mut quals = Array2::from_elem((1, 2), b'!' as f32);
+        encoder.encode_quals(&mut quals);
+        assert_eq!(quals, array![[-1., -1.]]);
+    }
+}
@@ -0,0 +1,168 @@
+use ndarray::{Array2, Axis};
+
+use super::{BatchPrediction, InferenceBackend, InferenceBatch};
+use crate::error::{HerroError, Result};
+use crate::Ensemble;
+
+/// Models whose predictions are combined into a single one, all loaded on the
+/// same device. Base probabilities of the models are averaged, or the base
+/// predicted by most models is taken, with ties broken by the mean
+/// probability. Informative logits are averaged in both cases.
+pub(crate) struct EnsembleModel {
+    models: Vec<Box<dyn InferenceBackend>>,
+    mode: Ensemble,
+}
+
+/// Converts the logits of each row into probabilities.
+fn softmax(logits: &mut Array2<f32>) {
+    for mut row in logits.rows_mut() {
+        let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        row.mapv_inplace(|l| (l - max).exp());
+        let sum = row.sum();
+        row /= sum;
+    }
+}
+
+/// Returns the class with the highest score in each row and its probability.
+fn select(scores: &Array2<f32>, probs: &Array2<f32>) -> (Vec<u8>, Vec<f32>) {
+    scores
+        .axis_iter(Axis(0))
+        .zip(probs.axis_iter(Axis(0)))
+        .map(|(scores, probs)| {
+            let (base, _) =
+                scores
+                    .iter()
+                    .enumerate()
+                    .fold((0, f32::NEG_INFINITY), |(bi, bs), (i, &s)| match s > bs {
+                        true => (i, s),
+                        false => (bi, bs),
+                    });
+            (base as u8, probs[base])
+        })
+        .unzip()
+}
+
+impl EnsembleModel {
+    pub(crate) fn new(models: Vec<Box<dyn InferenceBackend>>, mode: Ensemble) -> Self {
+        Self { models, mode }
+    }
+
+    /// Returns the mean informative logits, the mean base probabilities and
+    /// the votes of the models for each base, [N, C].
+    fn combine(&self, batch: &InferenceBatch) -> Result<(Vec<f32>, Array2<f32>, Array2<f32>)> {
+        let mut combined: Option<(Vec<f32>, Array2<f32>, Array2<f32>)> = None;
+        for model in self.models.iter() {
+            let (info_logits, mut probs) = model.logits(batch)?;
+            softmax(&mut probs);
+
+            let (sum_info, sum_probs, votes) = combined.get_or_insert_with(|| {
+                let shape = probs.dim();
+                (
+                    vec![0.; info_logits.len()],
+                    Array2::zeros(shape),
+                    Array2::zeros(shape),
+                )
+            });
+            if info_logits.len() != sum_info.len() || probs.dim() != sum_probs.dim() {
+                return Err(HerroError::Model(
+                    "models of the ensemble return outputs of different shapes".to_string(),
+                ));
+            }
+
+            sum_info
+                .iter_mut()
+                .zip(info_logits)
+                .for_each(|(s, l)| *s += l);
+            *sum_probs += &probs;
+            let (bases, _) = select(&probs, &probs);
+            for (i, base) in bases.into_iter().enumerate() {
+                votes[[i, base as usize]] += 1.;
+            }
+        }
+
+        let n_models = self.models.len() as f32;
+        let (mut info_logits, mut probs, votes) = combined.unwrap();
+        info_logits.iter_mut().for_each(|l| *l /= n_models);
+        probs /= n_models;
+        Ok((info_logits, probs, votes))
+    }
+}
+
+impl InferenceBackend for EnsembleModel {
+    /// Base logits are the log of the mean probabilities.
+    fn logits(&self, batch: &InferenceBatch) -> Result<(Vec<f32>, Array2<f32>)> {
+        let (info_logits, probs, _) = self.combine(batch)?;
+        Ok((info_logits, probs.mapv(f32::ln)))
+    }
+
+    fn predict(&self, batch: &InferenceBatch) -> Result<BatchPrediction> {
+        let (info_logits, probs, votes) = self.combine(batch)?;
+        let (bases, bases_probs) = match self.mode {
+            Ensemble::Mean => select(&probs, &probs),
+            // Mean probabilities are at most one, so they only break ties
+            Ensemble::Vote => select(&(votes + &probs), &probs),
+        };
+
+        Ok(BatchPrediction {
+            info_logits,
+            bases,
+            bases_probs,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use ndarray::{array, Array2, Array3};
+
+    use super::EnsembleModel;
+    use crate::error::Result;
+    use crate::inference::{InferenceBackend, InferenceBatch};
+    use crate::Ensemble;
+
+    /// Model predicting the given probabilities for every batch
+    struct FixedModel(Vec<f32>, Array2<f32>);
+
+    impl InferenceBackend for FixedModel {
+        fn logits(&self, _batch: &InferenceBatch) -> Result<(Vec<f32>, Array2<f32>)> {
+            Ok((self.0.clone(), self.1.mapv(f32::ln)))
+        }
+    }
+
+    #[test]
+    fn combine_predictions() {
+        let models = || -> Vec<Box<dyn InferenceBackend>> {
+            vec![
+                Box::new(FixedModel(vec![1.], array![[0.9, 0.1, 0., 0., 0.]])),
+                Box::new(FixedModel(vec![2.], array![[0.4, 0.6, 0., 0., 0.]])),
+                Box::new(FixedModel(vec![3.], array![[0.4, 0.6, 0., 0., 0.]])),
+            ]
+        };
+        let batch = InferenceBatch::new(
+            vec![0],
+            Array3::zeros((1, 1, 1)),
+            Array3::zeros((1, 1, 1)),
+            vec![1],
+            vec![0],
+        );
+
+        // Mean probabilities prefer the confident model
+        let mean = EnsembleModel::new(models(), Ensemble::Mean);
+        let prediction = mean.predict(&batch).unwrap();
+        assert_eq!(prediction.bases, [0]);
+        assert_relative_eq!(prediction.bases_probs[0], 1.7 / 3., epsilon = 1e-6);
+        assert_relative_eq!(prediction.info_logits[0], 2., epsilon = 1e-6);
+
+        // Default prediction from the logits matches the mean
+        let (_, logits) = mean.logits(&batch).unwrap();
+        let (bases, _) = crate::inference::predict_bases(logits.view());
+        assert_eq!(bases, [0]);
+
+        // Majority of the models wins the vote
+        let vote = EnsembleModel::new(models(), Ensemble::Vote);
+        let prediction = vote.predict(&batch).unwrap();
+        assert_eq!(prediction.bases, [1]);
+        assert_relative_eq!(prediction.bases_probs[0], 1.3 / 3., epsilon = 1e-6);
+    }
+}
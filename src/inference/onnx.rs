@@ -0,0 +1,111 @@
+use std::path::Path;
+
+use ndarray::{Array1, Array2, Ix2};
+use ort::{
+    CPUExecutionProvider, CUDAExecutionProvider, DirectMLExecutionProvider,
+    ExecutionProviderDispatch, ROCmExecutionProvider, Session, TensorRTExecutionProvider, Value,
+};
+
+use super::{InferenceBackend, InferenceBatch};
+use crate::error::{HerroError, Result};
+use crate::{CorrectionConfig, Device, OnnxProvider, Precision};
+
+/// Model exported to ONNX, run with ONNX Runtime. Model inputs are `bases`
+/// (int32, [B, L, R]), `quals` (float32, [B, L, R]), `lens` (int32, [B]) and
+/// `indices` (int32, target positions of all windows concatenated), followed by
+/// the optional channels of the feature layout (e.g. `homopolymers`, float32,
+/// [B, L, R]). Outputs are `info_logits` (float32, [N]) and `bases_logits`
+/// (float32, [N, C]).
+/// On the CPU device, the CPU execution provider is always used. TensorRT
+/// builds an engine for each input shape, which takes a while, so the engines
+/// can be cached in a directory and reused by later runs.
+pub(crate) struct OnnxModel {
+    session: Session,
+}
+
+impl OnnxModel {
+    pub(crate) fn load(
+        path: &Path,
+        provider: OnnxProvider,
+        device: Device,
+        config: &CorrectionConfig,
+    ) -> Result<Self> {
+        let (provider, device) = match device {
+            Device::Cpu => (OnnxProvider::Cpu, 0),
+            Device::Cuda(id) => (provider, id as i32),
+            Device::Hip(id) => (OnnxProvider::Rocm, id as i32),
+            Device::Mps => {
+                return Err(HerroError::InvalidConfig(
+                    "MPS is not supported by the onnx backend".to_string(),
+                ))
+            }
+        };
+        let provider: ExecutionProviderDispatch = match provider {
+            OnnxProvider::Cpu => CPUExecutionProvider::default().build(),
+            OnnxProvider::Cuda => CUDAExecutionProvider::default()
+                .with_device_id(device)
+                .build(),
+            OnnxProvider::Rocm => ROCmExecutionProvider::default()
+                .with_device_id(device)
+                .build(),
+            OnnxProvider::DirectMl => DirectMLExecutionProvider::default()
+                .with_device_id(device)
+                .build(),
+            OnnxProvider::TensorRt => {
+                let mut trt = TensorRTExecutionProvider::default()
+                    .with_device_id(device)
+                    .with_fp16(config.precision == Precision::Fp16);
+                if let Some(ref cache) = config.trt_engine_cache {
+                    trt = trt
+                        .with_engine_cache(true)
+                        .with_engine_cache_path(cache.display());
+                }
+
+                trt.build()
+            }
+        };
+
+        let session = Session::builder()
+            .and_then(|b| b.with_execution_providers([provider.error_on_failure()]))
+            .and_then(|b| b.with_intra_threads(config.intra_op_threads))
+            .and_then(|b| b.commit_from_file(path))
+            .map_err(|e| HerroError::Model(e.to_string()).in_file(path))?;
+
+        Ok(Self { session })
+    }
+}
+
+impl InferenceBackend for OnnxModel {
+    fn logits(&self, batch: &InferenceBatch) -> Result<(Vec<f32>, Array2<f32>)> {
+        let model_error = |e: ort::Error| HerroError::Model(e.to_string());
+
+        let mut inputs = ort::inputs![
+            "bases" => Value::from_array(batch.bases.clone())?,
+            "quals" => Value::from_array(batch.quals.clone())?,
+            "lens" => Value::from_array(Array1::from_vec(batch.lens.clone()))?,
+            "indices" => Value::from_array(Array1::from_vec(batch.indices.clone()))?,
+        ]
+        .map_err(model_error)?;
+        for &(name, ref channel) in batch.channels.iter() {
+            let channel = Value::from_array(channel.clone()).map_err(model_error)?;
+            inputs.push((name.into(), channel.into()));
+        }
+        let outputs = self.session.run(inputs).map_err(model_error)?;
+
+        let info_logits = outputs["info_logits"]
+            .try_extract_tensor::<f32>()
+            .map_err(model_error)?
+            .iter()
+            .copied()
+            .collect();
+
+        let bases_logits = outputs["bases_logits"]
+            .try_extract_tensor::<f32>()
+            .map_err(model_error)?;
+        let bases_logits = bases_logits
+            .into_dimensionality::<Ix2>()
+            .map_err(|e| HerroError::Model(format!("bases_logits: {}", e)))?;
+
+        Ok((info_logits, bases_logits.to_owned()))
+    }
+}
@@ -0,0 +1,543 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use itertools::Itertools;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::config::DEFAULT_WINDOW_SIZE;
+use crate::error::{HerroError, Result};
+use crate::features::{DEFAULT_TOP_K, MAX_TOP_K};
+use crate::{FeatureSet, Precision, QualEncoding};
+
+/// Version of the model spec format supported by herro
+const SPEC_VERSION: u32 = 1;
+/// Feature channels generated for each window, in order. Models which list
+/// only `bases` do not use base qualities, so their reads can be given in FASTA.
+const FEATURES: [&str; 2] = ["bases", "quals"];
+/// Quality channel of models which take the probability that each base is
+/// correct instead of its normalized quality
+const QUAL_WEIGHTS: &str = "qual_weights";
+/// Optional channel with the homopolymer run length of each base, follows the
+/// bases and qualities
+const HOMOPOLYMERS: &str = "homopolymers";
+/// Channel with the dinucleotide preceding each base, the last channel of
+/// feature layout version 2
+const CONTEXT: &str = "context";
+/// Latest feature layout version. Version 1 has the bases and the optional
+/// quality and homopolymer channels, version 2 adds the context channel.
+const LAYOUT_VERSION: u32 = 2;
+
+/// Feature channels given to the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct FeatureLayout {
+    pub(crate) qual_encoding: QualEncoding,
+    /// Homopolymer run length of each base in its read
+    pub(crate) homopolymers: bool,
+    /// Dinucleotide preceding each base in its read, feature layout version 2
+    pub(crate) context: bool,
+}
+
+/// Input specification of the model, stored in a JSON file next to the model
+/// (model path with the `.json` extension), e.g.
+/// `{"version": 1, "window_size": 4096, "top_k": 30, "features": ["bases", "quals"]}`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub(crate) struct ModelSpec {
+    pub(crate) version: u32,
+    /// Size of the window the model was trained on
+    pub(crate) window_size: u32,
+    /// Maximum number of supporting reads in a window
+    pub(crate) top_k: usize,
+    pub(crate) features: Vec<String>,
+    /// Version of the feature layout, 1 for specs without it
+    #[serde(default = "default_layout_version")]
+    pub(crate) layout_version: u32,
+    /// Encoder of the features, the standard feature set for specs without it
+    #[serde(default)]
+    pub(crate) feature_set: FeatureSet,
+    /// Weights quantized to int8 by `scripts/quantize_model.py`
+    #[serde(default)]
+    pub(crate) quantized: bool,
+}
+
+fn default_layout_version() -> u32 {
+    1
+}
+
+impl ModelSpec {
+    pub(crate) fn path(model_path: &Path) -> PathBuf {
+        model_path.with_extension("json")
+    }
+
+    /// Returns the validated spec of the model, or None if the model has no
+    /// spec file.
+    pub(crate) fn load(model_path: &Path) -> Result<Option<Self>> {
+        let path = Self::path(model_path);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(HerroError::from(e).in_file(&path)),
+        };
+
+        Self::parse(BufReader::new(file))
+            .map(Some)
+            .map_err(|e| e.in_file(&path))
+    }
+
+    fn parse<R: Read>(reader: R) -> Result<Self> {
+        let spec: Self = serde_json::from_reader(reader)
+            .map_err(|e| HerroError::Model(format!("invalid model spec: {}", e)))?;
+
+        if spec.version != SPEC_VERSION {
+            return Err(HerroError::Model(format!(
+                "unsupported model spec version {} (expected {})",
+                spec.version, SPEC_VERSION
+            )));
+        }
+        if !(1..=MAX_TOP_K).contains(&spec.top_k) {
+            return Err(HerroError::Model(format!(
+                "model expects {} supporting reads per window, herro generates 1 to {}",
+                spec.top_k, MAX_TOP_K
+            )));
+        }
+        if !(1..=LAYOUT_VERSION).contains(&spec.layout_version) {
+            return Err(HerroError::Model(format!(
+                "unsupported feature layout version {} (herro supports 1 to {})",
+                spec.layout_version, LAYOUT_VERSION
+            )));
+        }
+        let features = match spec.features.split_last() {
+            Some((last, features)) if last == CONTEXT => features,
+            _ => &spec.features[..],
+        };
+        if (features.len() < spec.features.len()) != (spec.layout_version >= 2) {
+            return Err(HerroError::Model(format!(
+                "model expects features {:?}, the \"{}\" channel is the last channel of feature layout version 2 and only of it",
+                spec.features, CONTEXT
+            )));
+        }
+        if !is_generated(features) {
+            return Err(HerroError::Model(format!(
+                "model expects features {:?}, herro generates \"{}\", optionally followed by \"{}\" or \"{}\" and \"{}\"",
+                spec.features, FEATURES[0], FEATURES[1], QUAL_WEIGHTS, HOMOPOLYMERS
+            )));
+        }
+
+        Ok(spec)
+    }
+
+    /// Model uses base qualities of the target and supporting reads
+    pub(crate) fn uses_quals(&self) -> bool {
+        self.qual_encoding().is_some()
+    }
+
+    /// Encoding of the qualities expected by the model, None if the model
+    /// does not use them.
+    pub(crate) fn qual_encoding(&self) -> Option<QualEncoding> {
+        self.features.iter().find_map(|f| match f.as_str() {
+            "quals" => Some(QualEncoding::Phred),
+            QUAL_WEIGHTS => Some(QualEncoding::Weights),
+            _ => None,
+        })
+    }
+
+    /// Model uses the homopolymer channel
+    pub(crate) fn homopolymers(&self) -> bool {
+        self.features.iter().any(|f| f == HOMOPOLYMERS)
+    }
+}
+
+/// Feature layout version of the models without a spec, or of all models if
+/// given by the user, which has to match the specs. Models of an ensemble have
+/// to use the same version.
+fn resolve_layout_version(layout_version: Option<u32>, specs: &[Option<ModelSpec>]) -> Result<u32> {
+    if let Some(version) = layout_version.filter(|v| !(1..=LAYOUT_VERSION).contains(v)) {
+        return Err(HerroError::InvalidConfig(format!(
+            "unsupported feature layout version {} (herro supports 1 to {})",
+            version, LAYOUT_VERSION
+        )));
+    }
+
+    let layout_version = specs
+        .iter()
+        .flatten()
+        .try_fold(layout_version, |version, spec| match version {
+            Some(version) if version != spec.layout_version => {
+                Err(HerroError::InvalidConfig(format!(
+                    "feature layout version {} does not match the version of the model ({})",
+                    version, spec.layout_version
+                )))
+            }
+            _ => Ok(Some(spec.layout_version)),
+        })?;
+    Ok(layout_version.unwrap_or(1))
+}
+
+/// Features start with the bases, followed by the optional quality and
+/// homopolymer channels.
+fn is_generated(features: &[String]) -> bool {
+    let mut features = features.iter().map(|f| f.as_str()).peekable();
+    if features.next() != Some(FEATURES[0]) {
+        return false;
+    }
+    features.next_if(|&f| f == FEATURES[1] || f == QUAL_WEIGHTS);
+    features.next_if_eq(&HOMOPOLYMERS);
+    features.next().is_none()
+}
+
+/// Resolves the feature layout of the models of an ensemble, which share the
+/// batches. The quality encoding given by the user has to match the models
+/// with a spec, models without a spec use the given encoding or Phred
+/// qualities. The homopolymer channel is used if the user or the specs
+/// request it, and models with a spec have to agree on it, as on the layout
+/// version.
+pub(crate) fn resolve_feature_layout(
+    qual_encoding: Option<QualEncoding>,
+    homopolymers: bool,
+    layout_version: Option<u32>,
+    specs: &[Option<ModelSpec>],
+) -> Result<FeatureLayout> {
+    let layout_version = resolve_layout_version(layout_version, specs)?;
+    let qual_encoding = specs
+        .iter()
+        .flatten()
+        .filter_map(|spec| spec.qual_encoding())
+        .try_fold(qual_encoding, |encoding, spec_encoding| match encoding {
+            Some(encoding) if encoding != spec_encoding => Err(HerroError::InvalidConfig(format!(
+                "quality encoding {:?} does not match the quality encoding of the model ({:?})",
+                encoding, spec_encoding
+            ))),
+            _ => Ok(Some(spec_encoding)),
+        })?;
+
+    let spec_homopolymers: Vec<_> = specs.iter().flatten().map(|s| s.homopolymers()).collect();
+    if !spec_homopolymers.iter().all_equal() {
+        return Err(HerroError::InvalidConfig(
+            "models of the ensemble differ in the homopolymer channel".to_string(),
+        ));
+    }
+    if homopolymers && spec_homopolymers.contains(&false) {
+        return Err(HerroError::InvalidConfig(
+            "homopolymer channel is not used by the model spec".to_string(),
+        ));
+    }
+
+    Ok(FeatureLayout {
+        qual_encoding: qual_encoding.unwrap_or_default(),
+        homopolymers: homopolymers || spec_homopolymers.contains(&true),
+        context: layout_version >= 2,
+    })
+}
+
+/// Feature set of the models without a spec, or of all models if given by the
+/// user, which has to match the specs. Models of an ensemble share the batches,
+/// so they have to use the same feature set.
+pub(crate) fn resolve_feature_set(
+    feature_set: Option<FeatureSet>,
+    specs: &[Option<ModelSpec>],
+) -> Result<FeatureSet> {
+    let feature_set = specs
+        .iter()
+        .flatten()
+        .try_fold(feature_set, |set, spec| match set {
+            Some(set) if set != spec.feature_set => Err(HerroError::InvalidConfig(format!(
+                "feature set {} does not match the feature set of the model ({})",
+                set.name(),
+                spec.feature_set.name()
+            ))),
+            _ => Ok(Some(spec.feature_set)),
+        })?;
+    Ok(feature_set.unwrap_or_default())
+}
+
+/// Number of supporting reads per window of the models without a spec, or of
+/// all models if given by the user, which has to match the specs. Models of an
+/// ensemble share the batches, so they have to agree on it.
+pub(crate) fn resolve_top_k(top_k: Option<usize>, specs: &[Option<ModelSpec>]) -> Result<usize> {
+    let top_k = specs
+        .iter()
+        .flatten()
+        .try_fold(top_k, |top_k, spec| match top_k {
+            Some(top_k) if top_k != spec.top_k => Err(HerroError::InvalidConfig(format!(
+                "{} supporting reads per window do not match the model ({})",
+                top_k, spec.top_k
+            ))),
+            _ => Ok(Some(spec.top_k)),
+        })?;
+    Ok(top_k.unwrap_or(DEFAULT_TOP_K))
+}
+
+/// Int8 precision runs only models quantized to int8 weights, which are not
+/// run with other precisions. Models without a spec cannot be checked.
+pub(crate) fn check_quantization(precision: Precision, specs: &[Option<ModelSpec>]) -> Result<()> {
+    let int8 = precision == Precision::Int8;
+    match specs.iter().flatten().find(|spec| spec.quantized != int8) {
+        Some(_) if int8 => Err(HerroError::InvalidConfig(
+            "int8 precision requires a model quantized with scripts/quantize_model.py".to_string(),
+        )),
+        Some(_) => Err(HerroError::InvalidConfig(format!(
+            "model is quantized to int8 and cannot be run with {:?} precision, use --precision int8",
+            precision
+        ))),
+        None => {
+            if int8 && specs.iter().any(Option::is_none) {
+                warn!("Model has no spec, so it cannot be checked to be quantized to int8");
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Window size given by the user has to match the model, otherwise the window
+/// size of the model (or the default one, for models without a spec) is used.
+fn resolve_window_size(window_size: Option<u32>, spec: Option<&ModelSpec>) -> Result<u32> {
+    match (window_size, spec) {
+        (Some(window_size), Some(spec)) if window_size != spec.window_size => {
+            Err(HerroError::InvalidConfig(format!(
+                "window size {} does not match the window size of the model ({})",
+                window_size, spec.window_size
+            )))
+        }
+        (Some(window_size), _) => Ok(window_size),
+        (None, Some(spec)) => Ok(spec.window_size),
+        (None, None) => Ok(DEFAULT_WINDOW_SIZE),
+    }
+}
+
+/// Resolves the window size of the models of an ensemble, models with a spec
+/// have to agree on it.
+pub(crate) fn resolve_ensemble_window_size(
+    window_size: Option<u32>,
+    specs: &[Option<ModelSpec>],
+) -> Result<u32> {
+    let window_size = specs.iter().flatten().try_fold(window_size, |ws, spec| {
+        resolve_window_size(ws, Some(spec)).map(Some)
+    })?;
+    resolve_window_size(window_size, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_quantization, resolve_ensemble_window_size, resolve_feature_layout,
+        resolve_feature_set, resolve_top_k, resolve_window_size, FeatureLayout, ModelSpec,
+    };
+    use crate::{FeatureSet, Precision, QualEncoding};
+
+    #[test]
+    fn parse_spec() {
+        let json =
+            r#"{"version": 1, "window_size": 2048, "top_k": 30, "features": ["bases", "quals"]}"#;
+        let spec = ModelSpec::parse(json.as_bytes()).unwrap();
+        assert_eq!(spec.window_size, 2048);
+
+        let json =
+            r#"{"version": 2, "window_size": 2048, "top_k": 30, "features": ["bases", "quals"]}"#;
+        assert!(ModelSpec::parse(json.as_bytes()).is_err());
+        let json =
+            r#"{"version": 1, "window_size": 2048, "top_k": 0, "features": ["bases", "quals"]}"#;
+        assert!(ModelSpec::parse(json.as_bytes()).is_err());
+        let json = r#"{"version": 1, "window_size": 2048, "top_k": 30, "features": ["bases"]}"#;
+        assert!(!ModelSpec::parse(json.as_bytes()).unwrap().uses_quals());
+        let json = r#"{"version": 1, "window_size": 2048, "top_k": 30, "features": ["quals"]}"#;
+        assert!(ModelSpec::parse(json.as_bytes()).is_err());
+        let json = r#"{"version": 1, "window_size": 2048, "top_k": 30, "features": ["bases", "qual_weights"]}"#;
+        let spec = ModelSpec::parse(json.as_bytes()).unwrap();
+        assert!(spec.uses_quals());
+        assert_eq!(spec.qual_encoding(), Some(QualEncoding::Weights));
+        let json = r#"{"version": 1, "window_size": 2048, "top_k": 30, "features": ["bases", "quals", "homopolymers"]}"#;
+        assert!(ModelSpec::parse(json.as_bytes()).unwrap().homopolymers());
+        let json = r#"{"version": 1, "window_size": 2048, "top_k": 30, "features": ["bases", "homopolymers", "quals"]}"#;
+        assert!(ModelSpec::parse(json.as_bytes()).is_err());
+        assert!(ModelSpec::parse("{}".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn window_size_from_spec() {
+        let spec = ModelSpec {
+            version: 1,
+            window_size: 2048,
+            top_k: 30,
+            features: vec!["bases".to_string(), "quals".to_string()],
+            layout_version: 1,
+            feature_set: FeatureSet::Standard,
+            quantized: false,
+        };
+
+        assert_eq!(resolve_window_size(None, Some(&spec)).unwrap(), 2048);
+        assert_eq!(resolve_window_size(Some(2048), Some(&spec)).unwrap(), 2048);
+        assert!(resolve_window_size(Some(4096), Some(&spec)).is_err());
+        assert_eq!(resolve_window_size(None, None).unwrap(), 4096);
+        assert_eq!(resolve_window_size(Some(1024), None).unwrap(), 1024);
+    }
+
+    #[test]
+    fn quantized_models() {
+        let json =
+            r#"{"version": 1, "window_size": 4096, "top_k": 30, "features": ["bases", "quals"]}"#;
+        let spec = ModelSpec::parse(json.as_bytes()).unwrap();
+        assert!(!spec.quantized);
+        let quantized = ModelSpec {
+            quantized: true,
+            ..spec.clone()
+        };
+
+        assert!(check_quantization(Precision::Int8, &[Some(quantized.clone()), None]).is_ok());
+        assert!(check_quantization(Precision::Int8, &[Some(spec.clone())]).is_err());
+        assert!(check_quantization(Precision::Fp32, &[Some(spec), None]).is_ok());
+        assert!(check_quantization(Precision::Fp16, &[Some(quantized)]).is_err());
+    }
+
+    #[test]
+    fn ensemble_window_size() {
+        let spec = |window_size| ModelSpec {
+            version: 1,
+            window_size,
+            top_k: 30,
+            features: vec!["bases".to_string(), "quals".to_string()],
+            layout_version: 1,
+            feature_set: FeatureSet::Standard,
+            quantized: false,
+        };
+
+        let specs = [Some(spec(2048)), None, Some(spec(2048))];
+        assert_eq!(resolve_ensemble_window_size(None, &specs).unwrap(), 2048);
+        assert!(resolve_ensemble_window_size(Some(4096), &specs).is_err());
+        assert!(resolve_ensemble_window_size(None, &[Some(spec(2048)), Some(spec(4096))]).is_err());
+        assert_eq!(
+            resolve_ensemble_window_size(Some(1024), &[None, None]).unwrap(),
+            1024
+        );
+    }
+
+    #[test]
+    fn feature_layout_from_spec() {
+        let spec = |features: &[&str]| ModelSpec {
+            version: 1,
+            window_size: 4096,
+            top_k: 30,
+            features: features.iter().map(|f| f.to_string()).collect(),
+            layout_version: 1,
+            feature_set: FeatureSet::Standard,
+            quantized: false,
+        };
+        let phred = Some(spec(&["bases", "quals"]));
+        let weights = Some(spec(&["bases", "qual_weights"]));
+        let bases = Some(spec(&["bases"]));
+        let homopolymers = Some(spec(&["bases", "quals", "homopolymers"]));
+        let layout = |qual_encoding, homopolymers| FeatureLayout {
+            qual_encoding,
+            homopolymers,
+            context: false,
+        };
+
+        assert_eq!(
+            resolve_feature_layout(None, false, None, &[None]).unwrap(),
+            FeatureLayout::default()
+        );
+        assert_eq!(
+            resolve_feature_layout(Some(QualEncoding::Weights), true, None, &[None]).unwrap(),
+            layout(QualEncoding::Weights, true)
+        );
+        assert_eq!(
+            resolve_feature_layout(None, false, None, &[bases, weights.clone()]).unwrap(),
+            layout(QualEncoding::Weights, false)
+        );
+        assert_eq!(
+            resolve_feature_layout(None, false, None, &[None, homopolymers.clone()]).unwrap(),
+            layout(QualEncoding::Phred, true)
+        );
+        assert!(resolve_feature_layout(
+            Some(QualEncoding::Phred),
+            false,
+            None,
+            &[None, weights.clone()]
+        )
+        .is_err());
+        assert!(resolve_feature_layout(None, false, None, &[phred.clone(), weights]).is_err());
+        assert!(resolve_feature_layout(None, true, None, &[phred.clone(), None]).is_err());
+        assert!(resolve_feature_layout(None, false, None, &[phred, homopolymers]).is_err());
+    }
+
+    #[test]
+    fn layout_version() {
+        let json = r#"{"version": 1, "window_size": 2048, "top_k": 30, "features": ["bases", "quals", "context"], "layout_version": 2}"#;
+        let spec = ModelSpec::parse(json.as_bytes()).unwrap();
+        assert_eq!(spec.layout_version, 2);
+        let json = r#"{"version": 1, "window_size": 2048, "top_k": 30, "features": ["bases", "quals", "context"]}"#;
+        assert!(ModelSpec::parse(json.as_bytes()).is_err());
+        let json = r#"{"version": 1, "window_size": 2048, "top_k": 30, "features": ["bases", "quals"], "layout_version": 2}"#;
+        assert!(ModelSpec::parse(json.as_bytes()).is_err());
+        let json = r#"{"version": 1, "window_size": 2048, "top_k": 30, "features": ["bases", "context", "quals"], "layout_version": 2}"#;
+        assert!(ModelSpec::parse(json.as_bytes()).is_err());
+        let json = r#"{"version": 1, "window_size": 2048, "top_k": 30, "features": ["bases", "context"], "layout_version": 3}"#;
+        assert!(ModelSpec::parse(json.as_bytes()).is_err());
+
+        let v2 = Some(spec);
+        assert!(
+            resolve_feature_layout(None, false, None, &[v2.clone(), None])
+                .unwrap()
+                .context
+        );
+        assert!(
+            resolve_feature_layout(None, false, Some(2), &[None])
+                .unwrap()
+                .context
+        );
+        assert!(
+            !resolve_feature_layout(None, false, None, &[None])
+                .unwrap()
+                .context
+        );
+        assert!(resolve_feature_layout(None, false, Some(1), &[v2]).is_err());
+        assert!(resolve_feature_layout(None, false, Some(3), &[None]).is_err());
+    }
+
+    #[test]
+    fn feature_set_from_spec() {
+        let json = r#"{"version": 1, "window_size": 2048, "top_k": 30, "features": ["bases", "quals"], "feature_set": "strandless"}"#;
+        let strandless = ModelSpec::parse(json.as_bytes()).unwrap();
+        assert_eq!(strandless.feature_set, FeatureSet::Strandless);
+        let json = r#"{"version": 1, "window_size": 2048, "top_k": 30, "features": ["bases", "quals"], "feature_set": "bases_only"}"#;
+        assert!(ModelSpec::parse(json.as_bytes()).is_err());
+        let json =
+            r#"{"version": 1, "window_size": 2048, "top_k": 30, "features": ["bases", "quals"]}"#;
+        let standard = ModelSpec::parse(json.as_bytes()).unwrap();
+        assert_eq!(standard.feature_set, FeatureSet::Standard);
+
+        let strandless = Some(strandless);
+        assert_eq!(
+            resolve_feature_set(None, &[None, strandless.clone()]).unwrap(),
+            FeatureSet::Strandless
+        );
+        assert_eq!(
+            resolve_feature_set(Some(FeatureSet::Strandless), &[None]).unwrap(),
+            FeatureSet::Strandless
+        );
+        assert_eq!(
+            resolve_feature_set(None, &[None]).unwrap(),
+            FeatureSet::Standard
+        );
+        assert!(
+            resolve_feature_set(Some(FeatureSet::Standard), &[None, strandless.clone()]).is_err()
+        );
+        assert!(resolve_feature_set(None, &[strandless, Some(standard)]).is_err());
+    }
+
+    #[test]
+    fn top_k_from_spec() {
+        let json =
+            r#"{"version": 1, "window_size": 2048, "top_k": 50, "features": ["bases", "quals"]}"#;
+        let wide = Some(ModelSpec::parse(json.as_bytes()).unwrap());
+        let json =
+            r#"{"version": 1, "window_size": 2048, "top_k": 300, "features": ["bases", "quals"]}"#;
+        assert!(ModelSpec::parse(json.as_bytes()).is_err());
+
+        assert_eq!(resolve_top_k(None, &[None]).unwrap(), 30);
+        assert_eq!(resolve_top_k(Some(40), &[None]).unwrap(), 40);
+        assert_eq!(resolve_top_k(None, &[None, wide.clone()]).unwrap(), 50);
+        assert_eq!(resolve_top_k(Some(50), &[None, wide.clone()]).unwrap(), 50);
+        assert!(resolve_top_k(Some(30), &[None, wide]).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use std::env;
+use std::path::Path;
+
+use tch::{CModule, Device, IValue, Kind, Tensor};
+
+use ndarray::{Array2, Array3};
+use tracing::warn;
+
+use super::{InferenceBackend, InferenceBatch};
+use crate::error::{HerroError, Result};
+use crate::{CorrectionConfig, Precision};
+
+const CUBLAS_WORKSPACE_CONFIG: &str = "CUBLAS_WORKSPACE_CONFIG";
+
+/// TorchScript model run with libtorch on the CPU, a CUDA device or an Apple
+/// GPU (MPS). With half precision, weights and qualities are cast to the half
+/// precision type, while the outputs are converted back to single precision.
+/// Int8 models are quantized with `scripts/quantize_model.py` and take single
+/// precision inputs. Optional channels of the feature layout are passed after
+/// the target indices, in their order.
+pub(crate) struct TorchModel {
+    model: CModule,
+    device: Device,
+    kind: Kind,
+}
+
+impl TorchModel {
+    pub(crate) fn load(
+        path: &Path,
+        device: crate::Device,
+        config: &CorrectionConfig,
+    ) -> Result<Self> {
+        // Number of threads is global in libtorch, but it is the same for all models
+        tch::set_num_threads(config.intra_op_threads as i32);
+        if config.deterministic {
+            tch::manual_seed(0);
+            tch::Cuda::cudnn_set_benchmark(false);
+        }
+
+        let device = match device {
+            crate::Device::Cpu => Device::Cpu,
+            crate::Device::Mps if tch::utils::has_mps() => Device::Mps,
+            crate::Device::Mps => {
+                return Err(HerroError::InvalidConfig(
+                    "MPS is not available, it requires an Apple Silicon GPU with macOS 12.3 or newer and libtorch built with MPS support".to_string(),
+                ))
+            }
+            // ROCm builds of libtorch expose HIP devices through the CUDA API
+            crate::Device::Cuda(id) | crate::Device::Hip(id) => Device::Cuda(id),
+        };
+        // cuBLAS needs a fixed workspace for reproducible results, which is
+        // read from the environment when its first handle is created
+        if config.deterministic
+            && matches!(device, Device::Cuda(_))
+            && env::var_os(CUBLAS_WORKSPACE_CONFIG).is_none()
+        {
+            warn!(
+                "{} is not set, cuBLAS results may differ between runs, set it to :4096:8 for reproducible results",
+                CUBLAS_WORKSPACE_CONFIG
+            );
+        }
+        let mut model = CModule::load_on_device(path, device)
+            .map_err(|e| HerroError::Model(e.to_string()).in_file(path))?;
+        model.set_eval();
+
+        let kind = match config.precision {
+            Precision::Fp32 | Precision::Int8 => Kind::Float,
+            Precision::Fp16 => Kind::Half,
+            Precision::Bf16 => Kind::BFloat16,
+        };
+        if kind != Kind::Float {
+            model.to(device, kind, false);
+        }
+
+        Ok(Self {
+            model,
+            device,
+            kind,
+        })
+    }
+}
+
+/// Tensor of the input of the batch, [B, L, R]
+fn input_tensor<T: tch::kind::Element>(input: &Array3<T>, shape: [i64; 3]) -> Result<Tensor> {
+    match input.as_slice() {
+        Some(data) => Ok(Tensor::from_slice(data).view(shape)),
+        None => Err(HerroError::Model(
+            "batch input is not contiguous".to_string(),
+        )),
+    }
+}
+
+impl InferenceBackend for TorchModel {
+    fn logits(&self, batch: &InferenceBatch) -> Result<(Vec<f32>, Array2<f32>)> {
+        let _guard = tch::no_grad_guard();
+        let model_error = |e: tch::TchError| HerroError::Model(e.to_string());
+
+        let (b, l, r) = batch.bases.dim();
+        let shape = [b as i64, l as i64, r as i64];
+        let bases = input_tensor(&batch.bases, shape)?;
+        let quals = input_tensor(&batch.quals, shape)?;
+
+        let mut start = 0;
+        let indices = batch
+            .lens
+            .iter()
+            .map(|&len| {
+                let end = start + len as usize;
+                let window_indices = Tensor::from_slice(&batch.indices[start..end]);
+                start = end;
+
+                window_indices
+            })
+            .collect();
+
+        let mut inputs = vec![
+            IValue::Tensor(bases.to(self.device)),
+            IValue::Tensor(quals.to(self.device).to_kind(self.kind)),
+            IValue::Tensor(Tensor::from_slice(&batch.lens)),
+            IValue::TensorList(indices),
+        ];
+        for (_, channel) in batch.channels.iter() {
+            let channel = input_tensor(channel, shape)?;
+            inputs.push(IValue::Tensor(channel.to(self.device).to_kind(self.kind)));
+        }
+
+        let outputs = self.model.forward_is(&inputs).map_err(model_error)?;
+        let (info_logits, bases_logits) =
+            <(Tensor, Tensor)>::try_from(outputs).map_err(model_error)?;
+
+        let info_logits = info_logits.to_kind(Kind::Float).to(Device::Cpu);
+        let bases_logits = bases_logits.to_kind(Kind::Float).to(Device::Cpu);
+        let (n, n_classes) = bases_logits.size2().map_err(model_error)?;
+        let bases_logits = Vec::try_from(bases_logits.flatten(0, -1)).map_err(model_error)?;
+        let bases_logits = Array2::from_shape_vec((n as usize, n_classes as usize), bases_logits)
+            .map_err(|e| HerroError::Model(format!("bases logits: {}", e)))?;
+
+        Ok((
+            Vec::try_from(info_logits).map_err(model_error)?,
+            bases_logits,
+        ))
+    }
+}
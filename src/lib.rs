@@ -1,222 +1,1451 @@
 use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
-use features::extract_features;
+use features::{align_overlaps, encode_features, extract_features};
+use rustc_hash::{FxHashMap as HashMap, FxHashSet as HashSet};
 
 use haec_io::HAECRecord;
 
 use pbars::{
     get_parse_reads_spinner, set_parse_reads_spinner_finish, track_progress, PBarNotification,
+    ProgressJson,
 };
 
 use std::{
-    fs::File,
-    io::{prelude::*, BufWriter},
-    path::Path,
+    env,
+    fs::{self, File},
+    io::{self, prelude::*, BufWriter},
+    iter,
+    path::{Path, PathBuf},
     thread::{self},
+    time::{Duration, Instant},
 };
 
+use clap::ValueEnum;
+use serde::Deserialize;
+use tracing::{info, warn};
+
 use crate::{
-    consensus::consensus_worker,
-    features::{FeatsGenOutput, InferenceOutput},
-    inference::inference_worker,
-    overlaps::alignment_reader,
+    aligners::{aligner_settings, pairwise_aligner},
+    aln_cache::AlignmentCache,
+    bgzf::BgzfWriter,
+    checkpoint::CheckpointWriter,
+    chimeras::resolve_chimeras,
+    confidence::confidence_writer,
+    config::{validate_min_coverage, validate_window_stride},
+    consensus::{consensus_worker, CorrectedSeq, ReportSenders},
+    edits::edits_writer,
+    features::{
+        feature_targets, features_reader, FeatsGenOutput, FeaturesSink, InferenceOutput,
+        WindowParams,
+    },
+    haplotypes::haplotypes_writer,
+    inference::{
+        check_quantization, decode_worker, feature_encoder, inference_worker, load_models,
+        resolve_ensemble_window_size, resolve_feature_layout, resolve_feature_set, resolve_top_k,
+        verify_model, ModelSpec,
+    },
+    memory::{MemoryPlan, PipelineSizes},
+    overlaps::{alignment_reader, OverlapCounts},
+    provenance::Provenance,
+    run_report::RunReport,
+    spill::{spill_reader, FeatureSpill},
+    telemetry::{monitor_devices, process_memory, summarize_devices, DeviceStats},
 };
 
 mod aligners;
+mod aln_cache;
+mod bench;
+mod bgzf;
+mod checkpoint;
+mod chimeras;
+#[cfg(feature = "arrow")]
+mod columnar;
+mod confidence;
+mod config;
 mod consensus;
+mod edits;
+mod error;
+mod faidx;
 mod features;
+#[cfg(feature = "ffi")]
+mod ffi;
 mod haec_io;
+mod haplotypes;
 mod inference;
+mod m4;
+mod memory;
 mod mm2;
+mod models;
+mod overlapper;
 mod overlaps;
 mod pbars;
+mod provenance;
+mod run_report;
+mod sam;
+mod spill;
+mod stats;
+mod telemetry;
+mod tempdir;
+mod validate;
 mod windowing;
 
+pub use bench::{bench, BenchReport};
+pub use config::{
+    CorrectionConfig, CorrectionConfigBuilder, FeatureGenConfig, FeatureGenConfigBuilder, ReadShard,
+};
+pub use error::{HerroError, Result};
+pub use inference::Device;
+pub use models::fetch_model;
+pub use overlaps::{OverlapFilter, OverlapsFormat};
+pub use stats::{overlap_stats, Stats};
+pub use validate::{validate_inputs, ValidationReport};
+
 pub(crate) const READS_BATCH_SIZE: usize = 100_000;
 pub(crate) const ALN_CHANNEL_CAPACITY: usize = 50_000;
 pub(crate) const LINE_ENDING: u8 = b'\n';
 pub(crate) const INFER_CHANNEL_CAP_FACTOR: usize = 2;
+/// Inferred data waiting to be decoded, per device
+pub(crate) const DECODE_CHANNEL_CAPACITY: usize = 2;
+pub(crate) const PHRED_OFFSET: u8 = 33;
 
 pub enum AlnMode<V: AsRef<Path>> {
     None,
     Read(V),
     Write(V),
+    /// Overlaps from one or more files, merged keeping one overlap per read
+    /// pair
+    Overlaps(Vec<V>),
+    Internal,
 }
 
-pub fn generate_features<T, U, V>(
-    reads_path: T,
-    output_path: U,
-    threads: usize,
-    window_size: u32,
-    aln_mode: AlnMode<V>,
-) where
-    T: AsRef<Path> + Send + Sync,
-    U: AsRef<Path> + Send + Sync + Clone,
-    V: AsRef<Path> + Send,
-{
-    // Get fastq reads
-    let reads = parse_reads(&reads_path, window_size);
-    let max_len = reads.iter().map(|r| r.seq.len()).max().unwrap();
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Fasta,
+    Fastq,
+}
 
-    let (alns_sender, alns_receiver) = bounded(ALN_CHANNEL_CAPACITY);
-    let (pbar_sender, pbar_receiver) = unbounded();
-    thread::scope(|s| {
-        let pbar_s = pbar_sender.clone();
-        s.spawn(|| alignment_reader(&reads, &reads_path, aln_mode, threads, alns_sender, pbar_s));
+/// Format of the features written by `generate_features`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum FeatureFormat {
+    /// Directory per target read with numpy files for each window
+    #[default]
+    Npy,
+    /// Single HDF5 file with a group per target read and window (requires the
+    /// hdf5 feature)
+    Hdf5,
+    /// Directory of Arrow IPC files with a row per window (requires the arrow
+    /// feature)
+    Arrow,
+    /// Directory of parquet files with a row per window (requires the parquet
+    /// feature)
+    Parquet,
+}
 
-        for _ in 0..threads {
-            let pbar_s = pbar_sender.clone();
+/// Size of the batches of target reads whose overlaps are loaded and processed
+/// together, which bounds the memory used by the overlaps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetBatch {
+    /// Number of target reads in a batch
+    Reads(usize),
+    /// Maximum number of bases of the target reads in a batch (batches contain
+    /// at least one read)
+    Bases(u64),
+}
 
-            s.spawn(|| {
-                let mut feats_output = FeatsGenOutput::new(&output_path, pbar_s);
-                let mut tbuf = vec![0; max_len];
-                let mut qbuf = vec![0; max_len];
+impl Default for TargetBatch {
+    fn default() -> Self {
+        TargetBatch::Reads(READS_BATCH_SIZE)
+    }
+}
 
-                loop {
-                    let (rid, alns) = match alns_receiver.recv() {
-                        Ok(out) => out,
-                        Err(_) => break,
-                    };
+/// Handling of reads with the same id, which make the overlaps of the reads
+/// ambiguous.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum DuplicateIds {
+    /// Stop with an error
+    #[default]
+    Error,
+    /// Append `_<n>` to the ids of the later reads, so their overlaps are not
+    /// used
+    Rename,
+    /// Keep only the first read with the id
+    Skip,
+}
 
-                    extract_features(
-                        rid,
-                        &reads,
-                        alns,
-                        window_size,
-                        (&mut tbuf, &mut qbuf),
-                        &mut feats_output,
-                    );
-                }
-            });
+/// Order in which the overlaps of a window are chosen as its supporting reads,
+/// the first top-k of which are used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WindowReadSelection {
+    /// Overlaps with the most accurate alignment to the window
+    #[default]
+    Identity,
+    /// Overlaps covering the most bases of the window, ties are broken by the
+    /// alignment accuracy
+    Span,
+    /// Overlaps of both strands in turn, each ranked by the alignment accuracy
+    Stratified,
+    /// Random overlaps, chosen reproducibly for the seed
+    Random(u64),
+}
+
+/// Handling of the chimeric junctions detected from the overlaps of the target
+/// reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ChimeraMode {
+    /// Remove the overlaps spanning the junctions
+    Detect,
+    /// Also split the corrected reads at the junctions
+    Split,
+}
+
+/// Pairwise aligner used to realign overlaps without CIGARs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Aligner {
+    /// Banded global alignment with unit costs around the overlap diagonal
+    #[default]
+    Banded,
+    /// Banded alignment with the band derived from the overlap divergence and
+    /// widened where the alignment reaches its edge
+    Adaptive,
+    /// Banded alignment of many overlaps at once with SIMD instructions
+    Simd,
+    /// Gap-affine wavefront alignment with WFA2 (requires the wfa feature)
+    Wfa,
+    /// Edit distance alignment with edlib (requires the edlib feature)
+    Edlib,
+    /// Adaptive block alignment with block-aligner, using AVX2 (requires the
+    /// block-aligner feature)
+    BlockAligner,
+}
+
+/// Library used to run the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// TorchScript model run with libtorch
+    Tch,
+    /// ONNX model run with ONNX Runtime (requires the onnx feature)
+    Onnx(OnnxProvider),
+    /// Model weights in the safetensors format run with candle (requires the
+    /// candle feature)
+    Candle,
+}
+
+impl Default for Backend {
+    /// libtorch is used if herro is built with it, otherwise candle
+    fn default() -> Self {
+        if cfg!(feature = "candle") && !cfg!(feature = "tch") {
+            Backend::Candle
+        } else {
+            Backend::Tch
         }
+    }
+}
 
-        drop(pbar_sender);
+/// Floating point precision of the model weights and inputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Precision {
+    #[default]
+    Fp32,
+    Fp16,
+    Bf16,
+    /// Model quantized to int8 weights for the CPU, inputs stay in single
+    /// precision
+    Int8,
+}
 
-        track_progress(pbar_receiver);
-    });
+/// How the predictions of multiple models are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Ensemble {
+    /// Average the base probabilities of the models
+    #[default]
+    Mean,
+    /// Take the base predicted by most models
+    Vote,
 }
 
-pub fn error_correction<T, U, V>(
+/// Encoding of the windows into the model input, selected with `--feature-set`
+/// or by the `feature_set` of the model spec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum FeatureSet {
+    /// Bases with the strand of their read, qualities and the channels of the
+    /// feature layout
+    #[default]
+    Standard,
+    /// Bases without the strand of their read, otherwise as the standard set
+    Strandless,
+}
+
+impl FeatureSet {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FeatureSet::Standard => "standard",
+            FeatureSet::Strandless => "strandless",
+        }
+    }
+}
+
+/// Encoding of the base qualities in the model input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum QualEncoding {
+    /// Phred qualities scaled to [-1, 1]
+    #[default]
+    Phred,
+    /// Probability that the base is correct, `1 - 10^(-Q/10)`, which the model
+    /// can use to weight the contribution of each supporting base
+    Weights,
+}
+
+/// ONNX Runtime execution provider.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OnnxProvider {
+    Cpu,
+    Cuda,
+    Rocm,
+    #[value(name = "directml")]
+    DirectMl,
+    /// TensorRT with engines built for the model on the first use
+    #[value(name = "tensorrt")]
+    TensorRt,
+}
+
+pub fn generate_features<T, U, V>(
     reads_path: T,
-    model_path: &str,
     output_path: U,
-    threads: usize,
-    window_size: u32,
-    devices: Vec<usize>,
-    batch_size: usize,
     aln_mode: AlnMode<V>,
-) where
+    config: &FeatureGenConfig,
+) -> Result<()>
+where
     T: AsRef<Path> + Send + Sync,
-    U: AsRef<Path> + Send + Sync,
+    U: AsRef<Path> + Send + Sync + Clone,
     V: AsRef<Path> + Send,
 {
-    tch::set_num_threads(1);
+    let FeatureGenConfig {
+        threads,
+        window_size,
+        top_k,
+        overlap_filter,
+        trust_cigar,
+        aligner,
+        ref align_cache,
+        quiet,
+        shard,
+        ref read_ids,
+        feature_format,
+        compress_features,
+        indexed_reads,
+        duplicate_ids,
+        target_batch,
+        overlaps_format,
+        ref progress_json,
+    } = *config;
+
+    let start = Instant::now();
+    let mut progress_json = progress_json
+        .as_deref()
+        .map(ProgressJson::open)
+        .transpose()?;
 
-    let reads = parse_reads(&reads_path, window_size);
+    // Get fastq reads
+    let reads = parse_reads(
+        &reads_path,
+        window_size,
+        true,
+        indexed_reads,
+        duplicate_ids,
+        quiet,
+    )?;
     let max_len = reads.iter().map(|r| r.seq.len()).max().unwrap();
+    if let Some(json) = progress_json.as_mut() {
+        json.emit("parse_reads", reads.len() as u64, Some(reads.len() as u64));
+    }
+    info!("Generating features using {} threads", threads);
+
+    let read_ids = read_ids.as_ref().map(haec_io::read_ids).transpose()?;
+    let skipped = skipped_targets(&reads, shard, read_ids.as_ref(), &HashSet::default());
 
+    let sinks = FeaturesSink::open(&output_path, feature_format, compress_features, threads)?;
+    let aligners = (0..threads)
+        .map(|_| pairwise_aligner(aligner))
+        .collect::<Result<Vec<_>>>()?;
+    let align_cache = align_cache
+        .as_ref()
+        .map(|dir| {
+            let settings = aligner_settings(aligner);
+            AlignmentCache::open(dir, &settings, &reads, threads)
+        })
+        .transpose()?;
+    let cache = align_cache.as_ref();
+    let window_params = WindowParams {
+        size: window_size,
+        stride: window_size,
+        top_k,
+        min_accuracy: overlap_filter.min_window_accuracy,
+        selection: overlap_filter.window_reads,
+    };
+
+    let overlap_counts = OverlapCounts::default();
     let (alns_sender, alns_receiver) = bounded(ALN_CHANNEL_CAPACITY);
-    let (writer_sender, writer_receiver) = unbounded();
     let (pbar_sender, pbar_receiver) = unbounded();
-    thread::scope(|s| {
+    let result = thread::scope(|s| {
         let pbar_s = pbar_sender.clone();
-        s.spawn(|| alignment_reader(&reads, &reads_path, aln_mode, threads, alns_sender, pbar_s));
-        s.spawn(|| correction_writer(&reads, output_path, writer_receiver, pbar_sender));
-
-        for device in devices {
-            let (infer_sender, infer_recv) = bounded(INFER_CHANNEL_CAP_FACTOR * threads);
-            let (cons_sender, cons_recv) = unbounded();
-            let writer_s = writer_sender.clone();
+        let reader_handle = s.spawn(|| {
+            alignment_reader(
+                &reads,
+                &reads_path,
+                aln_mode,
+                overlaps_format,
+                threads,
+                overlap_filter,
+                trust_cigar,
+                target_batch,
+                &skipped,
+                false,
+                alns_sender,
+                pbar_s,
+                &overlap_counts,
+            )
+        });
 
-            for _ in 0..threads {
+        let ref_reads = &reads;
+        let feats_handles: Vec<_> = sinks
+            .into_iter()
+            .zip(aligners)
+            .map(|(sink, mut aligner)| {
                 let alns_r = alns_receiver.clone();
-                let infer_s = infer_sender.clone();
+                let pbar_s = pbar_sender.clone();
 
-                let ref_reads = &reads;
                 s.spawn(move || {
-                    let _guard = tch::no_grad_guard();
-
-                    let mut feats_output = InferenceOutput::new(infer_s, batch_size);
+                    let mut feats_output = FeatsGenOutput::new(sink, ref_reads, pbar_s);
                     let mut tbuf = vec![0; max_len];
                     let mut qbuf = vec![0; max_len];
 
+                    // Reads are still received after an error, so the reader
+                    // is not blocked
+                    let mut result = Ok(());
                     loop {
                         let (rid, alns) = match alns_r.recv() {
                             Ok(out) => out,
                             Err(_) => break,
                         };
+                        if result.is_err() {
+                            continue;
+                        }
 
-                        extract_features(
+                        result = extract_features(
                             rid,
                             ref_reads,
                             alns,
-                            window_size,
-                            (&mut tbuf, &mut qbuf),
+                            window_params,
+                            &[],
+                            (aligner.as_mut(), cache, &mut tbuf, &mut qbuf),
                             &mut feats_output,
                         );
                     }
-                });
+
+                    result.and(feats_output.finish())
+                })
+            })
+            .collect();
+
+        drop(pbar_sender);
+
+        track_progress(pbar_receiver, quiet, progress_json.as_mut());
+
+        let feats_result = feats_handles
+            .into_iter()
+            .try_for_each(|h| h.join().unwrap());
+        reader_handle.join().unwrap().and(feats_result)
+    });
+    result?;
+    if let Some(cache) = cache {
+        cache.flush()?;
+    }
+
+    info!("Feature generation finished in {:.2?}", start.elapsed());
+    Ok(())
+}
+
+pub fn error_correction<T, U, V>(
+    reads_path: T,
+    model_paths: &[&str],
+    output_path: U,
+    aln_mode: AlnMode<V>,
+    config: &CorrectionConfig,
+) -> Result<()>
+where
+    T: AsRef<Path> + Send + Sync,
+    U: AsRef<Path> + Send + Sync,
+    V: AsRef<Path> + Send,
+{
+    let CorrectionConfig {
+        threads,
+        align_threads,
+        encode_threads,
+        writer_threads,
+        window_size,
+        window_stride,
+        top_k,
+        min_coverage,
+        trim_ends,
+        overlap_filter,
+        trust_cigar,
+        aligner,
+        ref align_cache,
+        ref from_features,
+        ref devices,
+        batch_size,
+        workers_per_device,
+        telemetry_interval,
+        output_format,
+        sharded_output,
+        quiet,
+        resume,
+        checkpoint,
+        deterministic,
+        shard,
+        ref read_ids,
+        keep_uncorrected,
+        tag_uncorrected,
+        keep_descriptions,
+        keep_tags,
+        indexed_reads,
+        duplicate_ids,
+        target_batch,
+        overlaps_format,
+        aln_capacity,
+        features_capacity,
+        consensus_capacity,
+        writer_capacity,
+        spill_memory,
+        ref spill_dir,
+        max_memory,
+        ref confidence_bed,
+        ref edits_tsv,
+        ref haplotypes_tsv,
+        chimeras,
+        qual_encoding,
+        homopolymer_channel,
+        feature_layout,
+        feature_set,
+        ref run_report,
+        ref progress_json,
+        ..
+    } = *config;
+
+    let start = Instant::now();
+    let mut progress_json = progress_json
+        .as_deref()
+        .map(ProgressJson::open)
+        .transpose()?;
+    let mut report = RunReport::default();
+
+    // Each device has its own copy of the models
+    let specs = model_paths
+        .iter()
+        .map(|&path| {
+            File::open(path).map_err(|e| HerroError::from(e).in_file(path))?;
+            ModelSpec::load(path.as_ref())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    check_quantization(config.precision, &specs)?;
+    if config.precision == Precision::Int8 {
+        warn!("Int8 quantization slightly reduces the accuracy of the correction, compare it with fp32 on a subset of the reads");
+    }
+    let window_size = resolve_ensemble_window_size(window_size, &specs)?;
+    match specs.iter().any(|s| s.is_some()) {
+        true => info!("Using window size {} from the model spec", window_size),
+        false => info!("Model has no spec, using window size {}", window_size),
+    }
+    if model_paths.len() > 1 {
+        info!(
+            "Combining the predictions of {} models ({:?})",
+            model_paths.len(),
+            config.ensemble
+        );
+    }
+    let window_stride = window_stride.unwrap_or(window_size);
+    validate_window_stride(window_size, window_stride)?;
+    let top_k = resolve_top_k(top_k, &specs)?;
+    validate_min_coverage((min_coverage, trim_ends), top_k)?;
+    let window_params = WindowParams {
+        size: window_size,
+        stride: window_stride,
+        top_k,
+        min_accuracy: overlap_filter.min_window_accuracy,
+        selection: overlap_filter.window_reads,
+    };
+    let layout =
+        resolve_feature_layout(qual_encoding, homopolymer_channel, feature_layout, &specs)?;
+    let encoder = feature_encoder(resolve_feature_set(feature_set, &specs)?, layout);
+    info!(
+        "Using feature set {} with features {:?}",
+        encoder.name(),
+        encoder.features()
+    );
+
+    // Models are checked with a dummy batch before the reads are processed,
+    // each inference worker has its own copy
+    let models = devices
+        .iter()
+        .flat_map(|&d| iter::repeat_n(d, workers_per_device))
+        .map(|d| {
+            let model = load_models(config, model_paths, d)?;
+            verify_model(model.as_ref(), (window_size, top_k), encoder.as_ref())?;
+            Ok(model)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    report.add_stage("load_models", start.elapsed());
+
+    // Precomputed features are read by a single thread without realignment
+    let align_threads = align_threads.unwrap_or(threads);
+    let encode_threads = encode_threads.unwrap_or(threads);
+    let (n_aligners, n_encoders) = match from_features {
+        Some(_) => (0, 0),
+        None => (
+            align_threads * devices.len(),
+            encode_threads * devices.len(),
+        ),
+    };
+    let aligners = (0..n_aligners)
+        .map(|_| pairwise_aligner(aligner))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Reads without qualities can be corrected by models which do not use them
+    let require_quals = specs
+        .iter()
+        .any(|s| s.as_ref().is_none_or(|s| s.uses_quals()));
+    let stage_start = Instant::now();
+    let reads = parse_reads(
+        &reads_path,
+        window_size,
+        require_quals,
+        indexed_reads,
+        duplicate_ids,
+        quiet,
+    )?;
+    let max_len = reads.iter().map(|r| r.seq.len()).max().unwrap();
+    report.add_stage("parse_reads", stage_start.elapsed());
+    if let Some(json) = progress_json.as_mut() {
+        json.emit("parse_reads", reads.len() as u64, Some(reads.len() as u64));
+    }
+
+    let align_cache = align_cache
+        .as_ref()
+        .map(|dir| {
+            let settings = aligner_settings(aligner);
+            AlignmentCache::open(dir, &settings, &reads, threads)
+        })
+        .transpose()?;
+    let cache = align_cache.as_ref();
+
+    // Settings which are not given are sized from the memory left after
+    // loading the reads and models
+    let n_bases: u64 = reads.iter().map(|r| r.seq.len() as u64).sum();
+    let n_feat_threads = encode_threads * devices.len();
+    let features_default = INFER_CHANNEL_CAP_FACTOR * n_feat_threads;
+    let plan = max_memory.map(|max_memory| {
+        let used = process_memory("VmRSS").unwrap_or(2 * n_bases);
+        let sizes = PipelineSizes {
+            mean_read_length: n_bases / reads.len() as u64,
+            window_size,
+            top_k,
+            batch_size,
+            features_capacity: features_default,
+            max_coverage: overlap_filter.max_coverage,
+            computes_overlaps: matches!(aln_mode, AlnMode::None | AlnMode::Write(_)),
+        };
+        MemoryPlan::new(max_memory, used, sizes)
+    });
+    if let Some(plan) = plan {
+        info!("Using {}", plan);
+        if plan.is_exceeded() {
+            warn!("Reads and models use most of the memory budget, consider --indexed-reads");
+        }
+    }
+    let target_batch = target_batch
+        .or(plan.map(|p| p.target_batch))
+        .unwrap_or_default();
+    let aln_capacity = aln_capacity
+        .or(plan.map(|p| p.aln_capacity))
+        .unwrap_or(ALN_CHANNEL_CAPACITY);
+    let features_capacity = features_capacity
+        .or(plan.map(|p| p.features_capacity))
+        .unwrap_or(features_default);
+    let spill_memory = spill_memory.or(plan.map(|p| p.spill_memory));
+    let mut overlap_filter = overlap_filter;
+    if let Some(plan) = plan {
+        overlap_filter.max_coverage = plan.max_coverage;
+    }
+
+    // With sharded output, each device has its own writer and shards are merged at the end
+    let is_compressed = is_compressed_output(&output_path);
+    let header_fields = match (keep_descriptions, keep_tags) {
+        (true, _) => HeaderFields::Description,
+        (false, true) => HeaderFields::BasecallerTags,
+        (false, false) => HeaderFields::Id,
+    };
+    let shard_paths: Vec<_> = match sharded_output {
+        true => devices
+            .iter()
+            .map(|&d| shard_path(&output_path, d))
+            .collect(),
+        false => Vec::new(),
+    };
+
+    let writer_paths: Vec<&Path> = if sharded_output {
+        shard_paths.iter().map(|p| p.as_path()).collect()
+    } else {
+        vec![output_path.as_ref()]
+    };
+
+    // Reads which were corrected before the interrupted run was stopped are skipped
+    let mut writer_outputs = Vec::with_capacity(writer_paths.len());
+    let mut completed_ids = HashSet::default();
+    for path in writer_paths.iter() {
+        let (file, checkpoint, completed) = checkpoint::open_output(path, resume, checkpoint)?;
+        writer_outputs.push((file, checkpoint));
+        completed_ids.extend(completed);
+    }
+    Provenance::new(
+        &reads_path,
+        model_paths,
+        config,
+        window_params,
+        encoder.as_ref(),
+    )?
+    .write(&output_path)?;
+
+    if resume {
+        info!(
+            "Resuming correction, {} reads already corrected",
+            completed_ids.len()
+        );
+    }
+    let read_ids = read_ids.as_ref().map(haec_io::read_ids).transpose()?;
+    let skipped = skipped_targets(&reads, shard, read_ids.as_ref(), &completed_ids);
+    report.input.reads = reads.len() as u64;
+    report.input.bases = n_bases;
+    report.input.target_reads = (reads.len() - skipped.len()) as u64;
+
+    // Resumed runs append the reports of the remaining reads
+    let open_report = |path: &PathBuf| {
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(resume)
+            .truncate(!resume)
+            .open(path)
+            .map_err(|e| HerroError::from(e).in_file(path))
+    };
+    let confidence_file = confidence_bed.as_ref().map(open_report).transpose()?;
+    let edits_file = edits_tsv.as_ref().map(open_report).transpose()?;
+    let haplotypes_file = haplotypes_tsv.as_ref().map(open_report).transpose()?;
+
+    let stage_start = Instant::now();
+    let overlap_counts = OverlapCounts::default();
+    let device_stats: Vec<_> = devices
+        .iter()
+        .map(|&d| DeviceStats::new(d, workers_per_device))
+        .collect();
+    let spill = match spill_memory {
+        Some(budget) => Some(FeatureSpill::new(
+            spill_dir.clone().unwrap_or_else(env::temp_dir),
+            budget,
+        )?),
+        None => None,
+    };
+    let feature_targets = from_features
+        .as_ref()
+        .map(|dir| feature_targets(&reads, dir, &skipped));
+
+    // Windows of a read are merged by a single consensus worker, selected by
+    // the read id, and consensus workers are assigned to the writers in turn
+    let n_consensus = writer_threads.unwrap_or(1) * devices.len();
+    let n_writers = writer_outputs.len();
+    let writer_orders: Vec<Option<Vec<u32>>> = (0..n_writers)
+        .map(|w| {
+            deterministic.then(|| {
+                let targets = match feature_targets {
+                    Some(ref targets) => targets.clone(),
+                    None => (0..reads.len() as u32)
+                        .filter(|rid| !skipped.contains(rid))
+                        .collect(),
+                };
+                targets
+                    .into_iter()
+                    .filter(|&rid| (rid as usize % n_consensus) % n_writers == w)
+                    .collect()
+            })
+        })
+        .collect();
+
+    let (alns_sender, alns_receiver) = bounded(aln_capacity);
+    let (pbar_sender, pbar_receiver) = unbounded();
+    let (reader_result, spill_result, report_results, writers) = thread::scope(|s| {
+        // Batches are shared by all devices, so faster devices infer more of them
+        let (infer_sender, infer_recv) = bounded(features_capacity);
+
+        // Batches over the memory budget are spilled by the feature generation
+        // and read back by their own thread
+        let (spill_sender, spill_receiver) = unbounded();
+        let spill_handle = spill.clone().map(|spill| {
+            let infer_s = infer_sender.clone();
+            let encoder = encoder.clone();
+            s.spawn(move || {
+                spill_reader(spill, spill_receiver, infer_s, batch_size, encoder.as_ref())
+            })
+        });
+        let feats_output = |pbar_s| {
+            let output = InferenceOutput::new(
+                infer_sender.clone(),
+                batch_size,
+                (min_coverage as usize, top_k),
+                encoder.clone(),
+                pbar_s,
+            );
+            match spill {
+                Some(ref spill) => output.with_spill(spill.clone(), spill_sender.clone()),
+                None => output,
             }
+        };
 
+        let pbar_s = pbar_sender.clone();
+        let mut reader_output = feats_output(pbar_s.clone());
+        let reader_handle = s.spawn(|| match (from_features, feature_targets.as_ref()) {
+            (Some(dir), Some(targets)) => {
+                features_reader(
+                    &reads,
+                    dir,
+                    window_size,
+                    targets,
+                    &mut reader_output,
+                    pbar_s,
+                )?;
+                reader_output.finish()
+            }
+            _ => alignment_reader(
+                &reads,
+                &reads_path,
+                aln_mode,
+                overlaps_format,
+                threads,
+                overlap_filter,
+                trust_cigar,
+                target_batch,
+                &skipped,
+                deterministic,
+                alns_sender,
+                pbar_s,
+                &overlap_counts,
+            ),
+        });
+
+        let ref_reads = &reads;
+        let (writer_senders, writer_handles): (Vec<_>, Vec<_>) = writer_outputs
+            .into_iter()
+            .zip(writer_orders)
+            .map(|((file, checkpoint), order)| {
+                let (writer_sender, writer_receiver) = channel(writer_capacity);
+                let pbar_s = pbar_sender.clone();
+                let handle = s.spawn(move || {
+                    correction_writer(
+                        ref_reads,
+                        file,
+                        checkpoint,
+                        (output_format, header_fields),
+                        is_compressed,
+                        writer_threads.unwrap_or(threads),
+                        order,
+                        writer_receiver,
+                        pbar_s,
+                    )
+                });
+
+                (writer_sender, handle)
+            })
+            .unzip();
+
+        // Reports are written by their own threads, their headers are not
+        // repeated when they are appended
+        let windows = (window_size, window_stride);
+        let mut reports = ReportSenders::default();
+        let mut report_handles = Vec::new();
+        if let (Some(file), Some(path)) = (confidence_file, confidence_bed.as_ref()) {
+            let (sender, receiver) = channel(writer_capacity);
+            let handle = s.spawn(move || {
+                confidence_writer(ref_reads, BufWriter::new(file), windows, receiver)
+            });
+            reports.confidences = Some(sender);
+            report_handles.push((handle, path));
+        }
+        if let (Some(file), Some(path)) = (edits_file, edits_tsv.as_ref()) {
+            let (sender, receiver) = channel(writer_capacity);
+            let handle = s.spawn(move || {
+                let write_header = file.metadata()?.len() == 0;
+                edits_writer(ref_reads, BufWriter::new(file), write_header, receiver)
+            });
+            reports.edits = Some(sender);
+            report_handles.push((handle, path));
+        }
+        if let (Some(file), Some(path)) = (haplotypes_file, haplotypes_tsv.as_ref()) {
+            let (sender, receiver) = channel(writer_capacity);
+            let handle = s.spawn(move || {
+                let write_header = file.metadata()?.len() == 0;
+                let writer = BufWriter::new(file);
+                haplotypes_writer(ref_reads, writer, write_header, windows, receiver)
+            });
+            reports.haplotypes = Some(sender);
+            report_handles.push((handle, path));
+        }
+
+        let (cons_senders, cons_receivers): (Vec<_>, Vec<_>) = (0..n_consensus)
+            .map(|_| channel(consensus_capacity))
+            .unzip();
+        for (i, cons_recv) in cons_receivers.into_iter().enumerate() {
+            let writer_s = writer_senders[i % writer_senders.len()].clone();
+            let reports_s = reports.clone();
+            let min_end_alns = trim_ends.unwrap_or(0);
             s.spawn(move || {
-                inference_worker(
-                    model_path,
-                    tch::Device::Cuda(device),
-                    infer_recv,
-                    cons_sender,
-                )
+                consensus_worker(cons_recv, writer_s, reports_s, windows, min_end_alns)
             });
+        }
+        drop(reports);
+
+        for device in devices.iter() {
+            info!(
+                "Device {}: {} alignment threads, {} feature encoding threads, {} inference workers, batch size {}",
+                device, align_threads, encode_threads, workers_per_device, batch_size
+            );
+        }
+        for (i, model) in models.into_iter().enumerate() {
+            // Worker infers the next batch while the previous one is decoded
+            let (decode_sender, decode_recv) = bounded(DECODE_CHANNEL_CAPACITY);
+            let infer_r = infer_recv.clone();
+            let pbar_s = pbar_sender.clone();
+            let stats = &device_stats[i / workers_per_device];
+            s.spawn(move || inference_worker(model, infer_r, decode_sender, pbar_s, stats));
+
+            let cons_s = cons_senders.clone();
+            s.spawn(move || decode_worker(decode_recv, cons_s));
+        }
+        drop((infer_recv, cons_senders));
+
+        // Overlaps are realigned and their features encoded by separate threads
+        // Reads are still received after an error, so the previous stage is not
+        // blocked, and the first error of each thread is returned at the end
+        let (aligned_sender, aligned_receiver) = bounded(aln_capacity);
+        let mut aligner_handles = Vec::with_capacity(aligners.len());
+        for mut aligner in aligners {
+            let alns_r = alns_receiver.clone();
+            let aligned_s = aligned_sender.clone();
+
+            aligner_handles.push(s.spawn(move || {
+                let mut tbuf = vec![0; max_len];
+                let mut qbuf = vec![0; max_len];
+
+                let mut result = Ok(());
+                loop {
+                    let (rid, mut alns) = match alns_r.recv() {
+                        Ok(out) => out,
+                        Err(_) => break,
+                    };
+                    if result.is_err() {
+                        continue;
+                    }
+
+                    let junctions = match chimeras {
+                        Some(mode) => resolve_chimeras(rid, ref_reads, &mut alns, mode),
+                        None => Vec::new(),
+                    };
+                    result = align_overlaps(
+                        &mut alns,
+                        ref_reads,
+                        (aligner.as_mut(), cache, &mut tbuf, &mut qbuf),
+                    );
+                    if result.is_ok() {
+                        aligned_s.send((rid, alns, junctions)).unwrap();
+                    }
+                }
+
+                result
+            }));
+        }
+        drop(aligned_sender);
+
+        let mut encoder_handles = Vec::with_capacity(n_encoders);
+        for _ in 0..n_encoders {
+            let aligned_r = aligned_receiver.clone();
+            let mut feats_output = feats_output(pbar_sender.clone());
+
+            encoder_handles.push(s.spawn(move || {
+                let mut tbuf = vec![0; max_len];
+                let mut qbuf = vec![0; max_len];
+
+                let mut result = Ok(());
+                while let Ok((rid, alns, junctions)) = aligned_r.recv() {
+                    if result.is_err() {
+                        continue;
+                    }
+
+                    result = encode_features(
+                        rid,
+                        ref_reads,
+                        alns,
+                        window_params,
+                        &junctions,
+                        (&mut tbuf, &mut qbuf),
+                        &mut feats_output,
+                    );
+                }
+                result.and(feats_output.finish())
+            }));
+        }
+        drop(aligned_receiver);
+        drop((infer_sender, spill_sender));
 
-            s.spawn(move || consensus_worker(cons_recv, writer_s));
+        drop(writer_senders);
+        drop(pbar_sender);
+
+        // Monitor stops when the workers are done
+        let (stop_sender, stop_receiver) = bounded(0);
+        if telemetry_interval > 0 {
+            let interval = Duration::from_secs(telemetry_interval);
+            let stats = &device_stats;
+            s.spawn(move || monitor_devices(stats, interval, stop_receiver));
         }
 
-        drop(writer_sender);
+        report.set_progress(track_progress(pbar_receiver, quiet, progress_json.as_mut()));
+        drop(stop_sender);
 
-        track_progress(pbar_receiver);
+        let writers: Vec<_> = writer_handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect();
+        let report_results: Vec<_> = report_handles
+            .into_iter()
+            .map(|(h, path)| (h.join().unwrap(), path))
+            .collect();
+        let spill_result = spill_handle.map(|h| h.join().unwrap());
+        let encoder_result = aligner_handles
+            .into_iter()
+            .chain(encoder_handles)
+            .try_for_each(|h| h.join().unwrap());
+        (
+            reader_handle.join().unwrap().and(encoder_result),
+            spill_result,
+            report_results,
+            writers,
+        )
     });
+    reader_result?;
+    spill_result.transpose()?;
+    if let Some(cache) = cache {
+        cache.flush()?;
+    }
+    for (result, path) in report_results {
+        result.map_err(|e| HerroError::from(e).in_file(path))?;
+    }
+    let mut writers: Vec<_> = writers
+        .into_iter()
+        .zip(writer_paths.iter())
+        .map(|(result, path)| result.map_err(|e| HerroError::from(e).in_file(path)))
+        .collect::<Result<_>>()?;
+    report.add_stage("correction", stage_start.elapsed());
+    report.devices = summarize_devices(&device_stats, stage_start.elapsed());
+    report.set_overlaps(&overlap_counts);
+
+    // Target reads which were not corrected are written to the first output
+    let stage_start = Instant::now();
+    let written: HashSet<u32> = writers
+        .iter()
+        .flat_map(|w| w.written.iter().copied())
+        .collect();
+    report.reads_corrected = written.len() as u64;
+    if keep_uncorrected {
+        let uncorrected: Vec<_> = (0..reads.len() as u32)
+            .filter(|rid| !skipped.contains(rid) && !written.contains(rid))
+            .collect();
+        info!("Writing {} uncorrected reads", uncorrected.len());
+
+        let output = (&mut writers[0], writer_paths[0]);
+        write_uncorrected(&reads, &uncorrected, output, tag_uncorrected)?;
+    }
+    for (writer, path) in writers.iter_mut().zip(writer_paths.iter()) {
+        writer
+            .writer
+            .finish()
+            .map_err(|e| HerroError::from(e).in_file(path))?;
+    }
+
+    if sharded_output {
+        info!("Merging {} output shards", shard_paths.len());
+        merge_shards(&shard_paths, &output_path)?;
+    }
+    for path in writer_paths {
+        checkpoint::remove_checkpoint(path)?;
+    }
+    report.add_stage("finish", stage_start.elapsed());
+    report.add_stage("total", start.elapsed());
+    if let Some(path) = run_report {
+        report.write(path)?;
+    }
+
+    info!("Error correction finished in {:.2?}", start.elapsed());
+    Ok(())
+}
+
+/// Returns the target reads which are not processed, since they belong to other
+/// shards, are not in the list of read ids or were already corrected. Skipped
+/// reads are still used as the supporting reads.
+fn skipped_targets(
+    reads: &[HAECRecord],
+    shard: Option<ReadShard>,
+    read_ids: Option<&HashSet<Vec<u8>>>,
+    completed_ids: &HashSet<Vec<u8>>,
+) -> HashSet<u32> {
+    let is_target = |id: &[u8]| {
+        shard.is_none_or(|s| s.contains(id)) && read_ids.is_none_or(|ids| ids.contains(id))
+    };
+
+    let skipped: HashSet<u32> = reads
+        .iter()
+        .enumerate()
+        .filter(|(_, r)| completed_ids.contains(&*r.id) || !is_target(&r.id))
+        .map(|(i, _)| i as u32)
+        .collect();
+
+    if let Some(ids) = read_ids {
+        let n_found = reads.iter().filter(|r| ids.contains(&*r.id)).count();
+        if n_found < ids.len() {
+            warn!(
+                "{} listed read ids were not found in the reads",
+                ids.len() - n_found
+            );
+        }
+    }
+    if shard.is_some() || read_ids.is_some() {
+        info!(
+            "Processing {} of {} target reads",
+            reads.len() - skipped.len(),
+            reads.len()
+        );
+    }
+
+    skipped
+}
+
+fn is_compressed_output<P: AsRef<Path>>(output_path: P) -> bool {
+    // BGZF output is compatible with gzip
+    matches!(
+        output_path.as_ref().extension().and_then(|e| e.to_str()),
+        Some("gz" | "bgz")
+    )
+}
+
+/// Bounded channel with the given capacity, or unbounded if it is not given.
+fn channel<T>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    match capacity {
+        Some(capacity) => bounded(capacity),
+        None => unbounded(),
+    }
+}
+
+fn shard_path<P: AsRef<Path>>(output_path: P, device: Device) -> PathBuf {
+    let mut path = output_path.as_ref().as_os_str().to_owned();
+    match device {
+        Device::Cpu => path.push(".shard_cpu"),
+        Device::Mps => path.push(".shard_mps"),
+        Device::Cuda(id) => path.push(format!(".shard{}", id)),
+        Device::Hip(id) => path.push(format!(".shard_hip{}", id)),
+    }
+    PathBuf::from(path)
 }
 
-fn parse_reads<P: AsRef<Path>>(reads_path: P, window_size: u32) -> Vec<HAECRecord> {
+/// Concatenates the shards into the output file and removes them. Concatenated
+/// BGZF (and gzip) files are valid compressed files. Shards are removed only
+/// after the output is written, so an interrupted merge can be resumed.
+fn merge_shards<P: AsRef<Path>>(shard_paths: &[PathBuf], output_path: P) -> Result<()> {
+    let output_file =
+        File::create(&output_path).map_err(|e| HerroError::from(e).in_file(&output_path))?;
+    let mut output = BufWriter::new(output_file);
+    for path in shard_paths {
+        let mut shard = File::open(path).map_err(|e| HerroError::from(e).in_file(path))?;
+        io::copy(&mut shard, &mut output).map_err(|e| HerroError::from(e).in_file(path))?;
+    }
+    output
+        .flush()
+        .map_err(|e| HerroError::from(e).in_file(&output_path))?;
+
+    for path in shard_paths {
+        fs::remove_file(path).map_err(|e| HerroError::from(e).in_file(path))?;
+    }
+
+    Ok(())
+}
+
+fn parse_reads<P: AsRef<Path>>(
+    reads_path: P,
+    window_size: u32,
+    require_quals: bool,
+    indexed: bool,
+    duplicate_ids: DuplicateIds,
+    quiet: bool,
+) -> Result<Vec<HAECRecord>> {
     // Get fastq reads
-    let spinner = get_parse_reads_spinner(None);
-    let reads = haec_io::get_reads(&reads_path, window_size);
+    let spinner = get_parse_reads_spinner(None, quiet);
+    let mut reads = match indexed {
+        true => haec_io::get_indexed_reads(&reads_path, window_size, require_quals)?,
+        false => haec_io::get_reads(&reads_path, window_size, require_quals)?,
+    };
     set_parse_reads_spinner_finish(reads.len(), spinner);
+    info!(
+        "Parsed {} reads with at least {} bases from {}",
+        reads.len(),
+        window_size,
+        reads_path.as_ref().display()
+    );
+
+    if reads.is_empty() {
+        let message = format!("no reads with at least {} bases", window_size);
+        return Err(HerroError::InvalidReads(message).in_file(&reads_path));
+    }
+
+    haec_io::resolve_duplicate_ids(&mut reads, duplicate_ids)
+        .map_err(|message| HerroError::InvalidReads(message).in_file(&reads_path))?;
+    Ok(reads)
+}
+
+/// Fields of the input read headers copied to the corrected reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HeaderFields {
+    Id,
+    Description,
+    BasecallerTags,
+}
+
+/// File of the corrected reads, BGZF compressed or not.
+enum OutputWriter {
+    Plain(BufWriter<File>),
+    Bgzf(BgzfWriter<File>),
+}
+
+impl OutputWriter {
+    /// Writes the remaining data, and the EOF block of BGZF.
+    fn finish(&mut self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(writer) => writer.flush(),
+            OutputWriter::Bgzf(writer) => writer.finish(),
+        }
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputWriter::Plain(writer) => writer.write(buf),
+            OutputWriter::Bgzf(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(writer) => writer.flush(),
+            OutputWriter::Bgzf(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Output of the corrected reads. Ids of the reads written by the writer are
+/// kept, so the uncorrected reads can be written at the end.
+struct CorrectionWriter {
+    writer: OutputWriter,
+    checkpoint: CheckpointWriter,
+    output_format: OutputFormat,
+    header_fields: HeaderFields,
+    written: Vec<u32>,
+}
+
+impl CorrectionWriter {
+    fn write_record(
+        &mut self,
+        id: &[u8],
+        comment: &[u8],
+        seq: &[u8],
+        qual: &[u8],
+    ) -> io::Result<()> {
+        let writer = &mut self.writer;
+        match self.output_format {
+            OutputFormat::Fasta => writer.write_all(b">")?,
+            OutputFormat::Fastq => writer.write_all(b"@")?,
+        }
+        writer.write_all(id)?;
+        writer.write_all(comment)?;
+        writer.write_all(&[LINE_ENDING])?;
+
+        writer.write_all(seq)?;
+        writer.write_all(&[LINE_ENDING])?;
+
+        if self.output_format == OutputFormat::Fastq {
+            writer.write_all(b"+\n")?;
+            writer.write_all(qual)?;
+            writer.write_all(&[LINE_ENDING])?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the corrected sequences of the read, parts of a split read are
+    /// numbered in their ids.
+    fn write_corrected(&mut self, read: &HAECRecord, seqs: Vec<CorrectedSeq>) -> io::Result<()> {
+        let n_seqs = seqs.len();
+        for (i, mut corrected) in seqs.into_iter().enumerate() {
+            let mut comment = match n_seqs {
+                1 => Vec::new(),
+                _ => format!(":{}", i).into_bytes(),
+            };
+            self.push_description(&mut comment, read);
+            corrected.qual.iter_mut().for_each(|q| *q += PHRED_OFFSET);
 
-    reads
+            self.write_record(&read.id, &comment, &corrected.seq, &corrected.qual)?;
+        }
+
+        self.complete_read(&read.id)
+    }
+
+    /// Appends the kept fields of the input read header to the comment.
+    fn push_description(&self, comment: &mut Vec<u8>, read: &HAECRecord) {
+        match (self.header_fields, read.description.as_ref()) {
+            (HeaderFields::Description, Some(description)) => {
+                comment.push(b' ');
+                comment.extend_from_slice(description);
+            }
+            (HeaderFields::BasecallerTags, _) => read.push_basecaller_tags(comment),
+            _ => (),
+        }
+    }
+
+    /// Writes the corrected sequences of the read, reads without them are
+    /// written with the uncorrected reads at the end.
+    fn write_read(
+        &mut self,
+        reads: &[HAECRecord],
+        rid: u32,
+        seqs: Vec<CorrectedSeq>,
+    ) -> io::Result<()> {
+        if seqs.is_empty() {
+            return Ok(());
+        }
+
+        self.write_corrected(&reads[rid as usize], seqs)?;
+        self.written.push(rid);
+        Ok(())
+    }
+
+    /// Records the written read in the checkpoint and saves it periodically.
+    fn complete_read(&mut self, id: &[u8]) -> io::Result<()> {
+        if self.checkpoint.add(id)? {
+            self.checkpoint.save(&mut self.writer)?;
+        }
+
+        Ok(())
+    }
 }
 
-fn correction_writer<U: AsRef<Path>>(
+fn correction_writer(
     reads: &[HAECRecord],
-    output_path: U,
-    consensus_recv: Receiver<(usize, Vec<Vec<u8>>)>,
+    file: File,
+    checkpoint: CheckpointWriter,
+    (output_format, header_fields): (OutputFormat, HeaderFields),
+    is_compressed: bool,
+    threads: usize,
+    order: Option<Vec<u32>>,
+    consensus_recv: Receiver<(usize, Vec<CorrectedSeq>)>,
     pbar_sender: Sender<PBarNotification>,
-) {
-    let file = File::create(output_path).unwrap();
-    let mut writer = BufWriter::new(file);
-
-    loop {
-        let (rid, seqs) = match consensus_recv.recv() {
-            Ok(out) => out,
-            Err(_) => break,
+) -> io::Result<CorrectionWriter> {
+    let writer = if is_compressed {
+        OutputWriter::Bgzf(BgzfWriter::new(file, threads))
+    } else {
+        OutputWriter::Plain(BufWriter::new(file))
+    };
+    let mut output = CorrectionWriter {
+        writer,
+        checkpoint,
+        output_format,
+        header_fields,
+        written: Vec::new(),
+    };
+
+    // With the order of the reads, a read is kept until the reads before it
+    // are done, otherwise reads are written in the order of completion.
+    // Uncorrected reads arrive without sequences and are not written here.
+    // Reads are still received after an error, so the consensus workers are
+    // not blocked, and the first error is returned at the end.
+    let mut order = order.map(|order| order.into_iter().peekable());
+    let mut pending = HashMap::default();
+    let mut result = Ok(());
+    while let Ok((rid, seqs)) = consensus_recv.recv() {
+        pbar_sender.send(PBarNotification::Inc).unwrap();
+        if result.is_err() {
+            continue;
+        }
+
+        result = match order {
+            Some(ref mut order) => {
+                pending.insert(rid as u32, seqs);
+                let mut written = Ok(());
+                while let Some(seqs) = order.peek().and_then(|rid| pending.remove(rid)) {
+                    let rid = order.next().unwrap();
+                    written = output.write_read(reads, rid, seqs);
+                    if written.is_err() {
+                        break;
+                    }
+                }
+                written
+            }
+            None => output.write_read(reads, rid as u32, seqs),
         };
+    }
+    result?;
 
-        if seqs.len() == 1 {
-            write!(&mut writer, ">").unwrap();
-            writer.write_all(&reads[rid].id).unwrap();
-            write!(&mut writer, "\n").unwrap();
+    // Reads which are not in the order are written at the end
+    let mut pending: Vec<_> = pending.into_iter().collect();
+    pending.sort_unstable_by_key(|(rid, _)| *rid);
+    for (rid, seqs) in pending {
+        output.write_read(reads, rid, seqs)?;
+    }
 
-            writer.write_all(&seqs[0]).unwrap();
-            write!(&mut writer, "\n").unwrap();
-        } else {
-            for (i, seq) in seqs.into_iter().enumerate() {
-                write!(&mut writer, ">").unwrap();
-                writer.write_all(&reads[rid].id).unwrap();
-                write!(&mut writer, ":{}\n", i).unwrap();
+    output.checkpoint.save(&mut output.writer)?;
+    Ok(output)
+}
 
-                writer.write_all(&seq).unwrap();
-                write!(&mut writer, "\n").unwrap();
-            }
+/// Writes the target reads without corrections, optionally marked with the
+/// `uncorrected` comment in the header after their description. Indexed reads
+/// are fetched from their file, errors of the output are given with its path.
+fn write_uncorrected(
+    reads: &[HAECRecord],
+    rids: &[u32],
+    (output, path): (&mut CorrectionWriter, &Path),
+    tag: bool,
+) -> Result<()> {
+    let in_output = |e: io::Error| HerroError::from(e).in_file(path);
+    for &rid in rids {
+        let read = &reads[rid as usize];
+        let mut comment = Vec::new();
+        output.push_description(&mut comment, read);
+        if tag {
+            comment.extend_from_slice(b" uncorrected");
         }
 
-        pbar_sender.send(PBarNotification::Inc).unwrap();
+        let seq = Vec::try_from(&read.seq)?;
+        let qual = read.qual.get(..)?;
+        output
+            .write_record(&read.id, &comment, &seq, &qual)
+            .map_err(in_output)?;
+        output.complete_read(&read.id).map_err(in_output)?;
     }
+
+    output
+        .checkpoint
+        .save(&mut output.writer)
+        .map_err(in_output)
 }
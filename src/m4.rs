@@ -0,0 +1,158 @@
+use rustc_hash::FxHashMap as HashMap;
+
+use std::io::prelude::*;
+use std::ops::Range;
+
+use crate::error::Result;
+use crate::overlaps::{
+    check_overlap, next_field, next_u32, Alignment, MalformedLines, Overlap, OverlapFilter,
+    OverlapSink, Strand,
+};
+use crate::LINE_ENDING;
+
+/// Parses overlaps in the M4 format written by MHAP and BLASR (`-m 4`), with
+/// whitespace-separated columns `qname tname score identity qstrand qstart qend
+/// qlen tstrand tstart tend tlen` and optional columns after them. Reads are
+/// given by their names (e.g. MHAP with `--store-full-id`) and coordinates are
+/// on the forward strand of both reads. M4 has no CIGARs, so overlaps are
+/// aligned during feature generation, and overlaps of the same read pair are
+/// ranked by their length. Malformed lines are handled as in `parse_paf`.
+pub(crate) fn parse_m4(
+    mut reader: impl BufRead,
+    name_to_id: &HashMap<&[u8], u32>,
+    overlap_filter: OverlapFilter,
+    targets: Option<&Range<u32>>,
+    malformed: &mut MalformedLines,
+    selector: &mut impl OverlapSink,
+) -> Result<()> {
+    let mut buffer = Vec::new();
+    for line_idx in 1.. {
+        buffer.clear();
+        if reader.read_until(LINE_ENDING, &mut buffer)? == 0 {
+            break;
+        }
+
+        let line = buffer.strip_suffix(&[LINE_ENDING]).unwrap_or(&buffer);
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+
+        match parse_m4_record(line, name_to_id, targets) {
+            Ok(Some(aln)) => match check_overlap(&aln.overlap, &overlap_filter) {
+                Ok(()) => {
+                    let overlap = &aln.overlap;
+                    let (qspan, tspan) =
+                        (overlap.qend - overlap.qstart, overlap.tend - overlap.tstart);
+                    let score = (qspan.min(tspan), qspan.max(tspan));
+                    selector.insert(aln, score);
+                }
+                Err(rejection) => selector.reject(rejection),
+            },
+            Ok(None) => (),
+            Err(message) => malformed.add(line_idx, message, overlap_filter.strict)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses a single M4 line. Returns None if any of the reads is not in the
+/// reads set or the target is not in `targets`. Error contains the description
+/// of the invalid field.
+fn parse_m4_record(
+    line: &[u8],
+    name_to_id: &HashMap<&[u8], u32>,
+    targets: Option<&Range<u32>>,
+) -> std::result::Result<Option<Alignment>, String> {
+    let mut data = line
+        .split(|c| c.is_ascii_whitespace())
+        .filter(|f| !f.is_empty());
+
+    let qname = next_field(&mut data, "query name")?;
+    let tname = next_field(&mut data, "target name")?;
+    next_field(&mut data, "score")?;
+    next_field(&mut data, "identity")?;
+
+    let qreverse = next_strand(&mut data, "query strand")?;
+    let qstart = next_u32(&mut data, "query start")?;
+    let qend = next_u32(&mut data, "query end")?;
+    let qlen = next_u32(&mut data, "query length")?;
+    let treverse = next_strand(&mut data, "target strand")?;
+    let tstart = next_u32(&mut data, "target start")?;
+    let tend = next_u32(&mut data, "target end")?;
+    let tlen = next_u32(&mut data, "target length")?;
+
+    if qstart >= qend || qend > qlen || tstart >= tend || tend > tlen {
+        return Err("invalid overlap coordinates".to_string());
+    }
+
+    let (Some(&qid), Some(&tid)) = (name_to_id.get(qname), name_to_id.get(tname)) else {
+        return Ok(None);
+    };
+    if targets.is_some_and(|t| !t.contains(&tid) && !t.contains(&qid)) {
+        return Ok(None);
+    }
+
+    let strand = match qreverse == treverse {
+        true => Strand::Forward,
+        false => Strand::Reverse,
+    };
+    let overlap = Overlap::new(qid, qlen, qstart, qend, strand, tid, tlen, tstart, tend);
+    Ok(Some(Alignment::new(overlap, Vec::new())))
+}
+
+/// Returns true for the reverse strand (1), false for the forward strand (0).
+fn next_strand<'a>(
+    data: &mut impl Iterator<Item = &'a [u8]>,
+    name: &str,
+) -> std::result::Result<bool, String> {
+    match next_field(data, name)? {
+        b"0" => Ok(false),
+        b"1" => Ok(true),
+        s => Err(format!("invalid {} '{}'", name, String::from_utf8_lossy(s))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_hash::FxHashMap as HashMap;
+
+    use crate::overlaps::{MalformedLines, OverlapFilter, PairSelector, Strand};
+
+    use super::parse_m4;
+
+    #[test]
+    fn parse_m4_records() {
+        let name_to_id: HashMap<&[u8], u32> =
+            [&b"r0"[..], b"r1", b"r2"].into_iter().zip(0..).collect();
+        let m4 = "r1 r0 -3000 85.0 0 0 6000 8000 0 4000 10000 10000 254\n\
+                  r1 r0 -2000 85.0 0 0 5000 8000 0 5000 10000 10000\n\
+                  r2 r0 0.12 120 0 0 6000 10000 1 0 6000 10000\n\
+                  r3 r0 0.12 120 0 0 6000 10000 0 4000 10000 10000\n\
+                  r2 r0 0.12 120 0 0 6000 10000 2 4000 10000 10000";
+
+        let mut malformed = MalformedLines::default();
+        let mut selector = PairSelector::new(false);
+        parse_m4(
+            m4.as_bytes(),
+            &name_to_id,
+            OverlapFilter::default(),
+            None,
+            &mut malformed,
+            &mut selector,
+        )
+        .unwrap();
+        let alns = selector.into_alignments();
+
+        let overlaps: Vec<_> = alns[&0]
+            .iter()
+            .map(|a| (a.overlap.qid, a.overlap.qend, a.overlap.strand))
+            .collect();
+        assert_eq!(
+            overlaps,
+            [(1, 6000, Strand::Forward), (2, 6000, Strand::Reverse)]
+        );
+        assert!(alns[&0].iter().all(|a| a.cigar.is_empty()));
+        assert_eq!(malformed.count(), 1);
+    }
+}
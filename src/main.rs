@@ -1,25 +1,127 @@
-use clap::{Args, Parser, Subcommand};
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::{env, fs, iter};
 
-use herro::{error_correction, generate_features, AlnMode};
+use clap::{Arg, ArgAction, Args, Command, CommandFactory, Parser, Subcommand, ValueEnum};
+
+use herro::{
+    bench, error_correction, fetch_model, generate_features, overlap_stats, validate_inputs,
+    Aligner, AlnMode, Backend, ChimeraMode, CorrectionConfig, Device, DuplicateIds, Ensemble,
+    FeatureFormat, FeatureGenConfig, FeatureSet, HerroError, OnnxProvider, OutputFormat,
+    OverlapFilter, OverlapsFormat, Precision, QualEncoding, ReadShard, WindowReadSelection,
+};
 
 use jemallocator::Jemalloc;
+use tracing::level_filters::LevelFilter;
 
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
+/// Macs have no CUDA devices, but Apple Silicon GPUs can be used through MPS
+const DEFAULT_DEVICE: &str = if cfg!(target_os = "macos") {
+    "mps"
+} else {
+    "cuda:0"
+};
+
 #[derive(Parser)]
 #[command(author, version, about)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    #[arg(
+        short = 'v',
+        long,
+        action = ArgAction::Count,
+        global = true,
+        help = "Increase logging verbosity (-v for info, -vv for debug messages)"
+    )]
+    verbose: u8,
+
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        help = "Logging level, overrides -v (default warn)"
+    )]
+    log_level: Option<LogLevel>,
+
+    #[arg(
+        long,
+        global = true,
+        env = "HERRO_THREADS",
+        help = "Maximum total number of threads, which caps the feature generation threads (divided between the devices) and the intra-op threads of the model (default all cores)"
+    )]
+    threads: Option<usize>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "TOML file setting the options by their long names, e.g. batch-size = 32. Top-level options apply to every subcommand which has them, options in a table named after a subcommand (e.g. [inference]) only to it. Options given on the command line override the file"
+    )]
+    config: Option<PathBuf>,
+}
+
+impl Cli {
+    fn log_level(&self) -> LevelFilter {
+        match (self.log_level, self.verbose) {
+            (Some(LogLevel::Error), _) => LevelFilter::ERROR,
+            (Some(LogLevel::Warn), _) => LevelFilter::WARN,
+            (Some(LogLevel::Info), _) => LevelFilter::INFO,
+            (Some(LogLevel::Debug), _) => LevelFilter::DEBUG,
+            (Some(LogLevel::Trace), _) => LevelFilter::TRACE,
+            (None, 0) => LevelFilter::WARN,
+            (None, 1) => LevelFilter::INFO,
+            (None, _) => LevelFilter::DEBUG,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// Order of the supporting reads of a window, the seed of the random order is
+/// given separately.
+#[derive(Clone, Copy, ValueEnum)]
+enum WindowReads {
+    Identity,
+    Span,
+    Stratified,
+    Random,
+}
+
+/// Inference backend, the ONNX Runtime execution provider is given separately.
+#[derive(Clone, Copy, ValueEnum)]
+enum BackendName {
+    Tch,
+    Onnx,
+    Candle,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     #[command(about = "Subcommand used for generating features")]
-    Features(FeatGenArgs),
+    Features(Box<FeatGenArgs>),
     #[command(about = "Subcommand used for error-correcting reads")]
-    Inference(InferenceArgs),
+    Inference(Box<InferenceArgs>),
+    #[command(about = "Subcommand used for reporting read and overlap statistics")]
+    Stats(Box<StatsArgs>),
+    #[command(
+        about = "Subcommand used for checking the inputs of inference without running it, takes the same arguments"
+    )]
+    Validate(Box<InferenceArgs>),
+    #[command(
+        about = "Subcommand used for measuring the throughput of alignment, feature generation and inference on a small dataset"
+    )]
+    Bench(Box<BenchArgs>),
 }
 
 #[derive(Args)]
@@ -33,6 +135,243 @@ struct AlignmentsIO {
         help = "Path to the folder where *.oec.zst alignments will be saved"
     )]
     write_alns: Option<String>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Paths to the all-vs-all alignments in PAF, SAM, BAM or M4 format (PAF/SAM/M4 can be gzip/bgzip/zstd compressed), comma-separated or given multiple times to merge them. Use - to read PAF from stdin"
+    )]
+    overlaps: Option<Vec<String>>,
+
+    #[arg(
+        long,
+        help = "Compute overlaps with the built-in overlapper instead of minimap2"
+    )]
+    internal_overlapper: bool,
+}
+
+impl AlignmentsIO {
+    fn mode(self) -> AlnMode<String> {
+        if self.internal_overlapper {
+            return AlnMode::Internal;
+        }
+
+        match (self.read_alns, self.write_alns, self.overlaps) {
+            (None, None, None) => AlnMode::None,
+            (Some(p), None, None) => AlnMode::Read(p),
+            (None, Some(p), None) => AlnMode::Write(p),
+            (None, None, Some(p)) => AlnMode::Overlaps(p),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(Args)]
+struct OverlapFilterArgs {
+    #[arg(
+        long,
+        default_value = "2500",
+        help = "Maximum unaligned overhang at the overlap ends, larger overhangs are treated as internal overlaps and discarded (default 2500)"
+    )]
+    overlap_threshold: u32,
+
+    #[arg(
+        long,
+        default_value = "1.111",
+        help = "Maximum ratio between the target and query overlap spans (and vice versa), overlaps outside [1/R, R] are discarded (default 1.111)"
+    )]
+    max_span_ratio: f32,
+
+    #[arg(
+        long,
+        help = "Keep the first overlap for each read pair instead of the one with the most residue matches"
+    )]
+    first_overlap: bool,
+
+    #[arg(
+        long,
+        help = "Maximum overlap divergence given by the de:f (or dv:f) PAF tag, overlaps without the tag are kept"
+    )]
+    max_divergence: Option<f32>,
+
+    #[arg(
+        long,
+        help = "Maximum number of overlaps per target read, only the longest overlaps are kept"
+    )]
+    max_overlaps_per_read: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Maximum coverage of each target read by its overlaps, overlaps with the most residue matches (long, high-identity overlaps) are kept until every position is covered this many times"
+    )]
+    max_coverage: Option<u32>,
+
+    #[arg(
+        long,
+        help = "Minimum mapping quality of the overlaps (PAF column 12), overlaps with mapping quality 255 (missing) are kept"
+    )]
+    min_mapq: Option<u8>,
+
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Minimum length of the target and query spans of the overlaps, shorter overlaps are discarded before alignment (default 0)"
+    )]
+    min_overlap_len: u32,
+
+    #[arg(
+        long,
+        help = "Keep only primary overlaps (tp:A:P PAF tag, not secondary in SAM/BAM), overlaps without the tag are kept"
+    )]
+    primary_only: bool,
+
+    #[arg(
+        long,
+        help = "Stop at the first malformed overlap line instead of skipping it"
+    )]
+    strict_paf: bool,
+
+    #[arg(
+        long,
+        help = "Number of bases by which overlaps without CIGARs are extended at each end before realignment, 0 disables the extension (default: the overlap threshold)"
+    )]
+    extend: Option<u32>,
+
+    #[arg(
+        long,
+        help = "Minimum accuracy (0-1) of an overlap aligned to a window, less accurately aligned overlaps do not support the window, e.g. 0.85 for R9 or 0.9 for R10 and duplex reads (default: no minimum)"
+    )]
+    min_window_accuracy: Option<f32>,
+
+    #[arg(
+        long,
+        default_value = "identity",
+        value_enum,
+        help = "Order in which the overlaps of a window are chosen as its supporting reads: identity (most accurately aligned), span (covering the most of the window), stratified (both strands in turn, each by identity) or random (default identity)"
+    )]
+    window_read_selection: WindowReads,
+
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Seed of the random selection of the supporting reads of a window (default 0)"
+    )]
+    window_read_seed: u64,
+}
+
+impl OverlapFilterArgs {
+    fn filter(&self) -> OverlapFilter {
+        OverlapFilter {
+            ol_threshold: self.overlap_threshold,
+            max_span_ratio: self.max_span_ratio,
+            keep_first: self.first_overlap,
+            max_divergence: self.max_divergence,
+            max_overlaps: self.max_overlaps_per_read,
+            max_coverage: self.max_coverage,
+            min_mapq: self.min_mapq,
+            min_overlap_len: self.min_overlap_len,
+            primary_only: self.primary_only,
+            strict: self.strict_paf,
+            extend: self.extend.unwrap_or(self.overlap_threshold),
+            min_window_accuracy: self.min_window_accuracy,
+            window_reads: match self.window_read_selection {
+                WindowReads::Identity => WindowReadSelection::Identity,
+                WindowReads::Span => WindowReadSelection::Span,
+                WindowReads::Stratified => WindowReadSelection::Stratified,
+                WindowReads::Random => WindowReadSelection::Random(self.window_read_seed),
+            },
+        }
+    }
+}
+
+#[derive(Args)]
+#[group(required = false, multiple = false)]
+struct TargetBatchArgs {
+    #[arg(
+        long,
+        help = "Number of target reads whose overlaps are loaded and processed together, lower values reduce the memory usage (default 100000)"
+    )]
+    batch_reads: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Maximum number of bases of the target reads processed together, instead of a fixed number of reads"
+    )]
+    batch_bases: Option<u64>,
+}
+
+#[derive(Args)]
+struct AlignmentArgs {
+    #[arg(
+        long,
+        requires = "overlaps",
+        value_enum,
+        help = "Format of the overlaps given with --overlaps (default: detected from the extension, .sam, .bam, .m4 or .mhap, otherwise PAF)"
+    )]
+    overlap_format: Option<OverlapsFormat>,
+
+    #[arg(
+        long,
+        default_value_t = true,
+        action = ArgAction::Set,
+        help = "Use CIGARs from the alignments (cg:Z tag for PAF) instead of realigning overlaps (default true)"
+    )]
+    trust_cigar: bool,
+
+    #[arg(
+        long,
+        default_value = "banded",
+        value_enum,
+        help = "Aligner used for realigning overlaps without CIGARs, wfa, edlib and block-aligner require herro built with the feature of the same name (default banded)"
+    )]
+    aligner: Aligner,
+
+    #[arg(
+        long,
+        help = "Directory in which the CIGARs of realigned overlaps are cached, so later runs on the same reads and overlaps reuse them"
+    )]
+    align_cache: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct ReadsArgs {
+    #[arg(
+        long,
+        help = "Fetch reads from the reads file on demand using its samtools faidx index (.fai, and .gzi for bgzipped reads) instead of loading them into memory"
+    )]
+    indexed_reads: bool,
+
+    #[arg(
+        long,
+        default_value = "error",
+        value_enum,
+        help = "Handling of reads with duplicate ids: stop with an error, rename the later reads (<id>_<n>) or skip them (default error)"
+    )]
+    on_duplicate: DuplicateIds,
+
+    #[arg(
+        long,
+        help = "Process only the target reads in shard i of N (e.g. 2/8), assigned by the hash of read ids. All reads are still used as supporting reads"
+    )]
+    shard: Option<ReadShard>,
+
+    #[arg(
+        long,
+        help = "Process only the target reads whose ids are listed in the file, one id per line. All reads are still used as supporting reads"
+    )]
+    read_ids: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct ProgressArgs {
+    #[arg(short = 'q', long, help = "Do not show progress bars")]
+    quiet: bool,
+
+    #[arg(
+        long,
+        help = "Write the progress as newline-delimited JSON events (stage, done, total, rate) into the file, or to stderr for -"
+    )]
+    progress_json: Option<PathBuf>,
 }
 
 #[derive(Args)]
@@ -40,6 +379,9 @@ struct FeatGenArgs {
     #[command(flatten)]
     alns: AlignmentsIO,
 
+    #[command(flatten)]
+    alignment: AlignmentArgs,
+
     #[arg(
         short = 'w',
         default_value = "4096",
@@ -47,17 +389,46 @@ struct FeatGenArgs {
     )]
     window_size: u32,
 
+    #[arg(
+        long,
+        default_value = "30",
+        help = "Maximum number of supporting reads of a window, the most accurately aligned overlaps are used (default 30)"
+    )]
+    top_k: usize,
+
     #[arg(
         short = 't',
-        default_value = "1",
-        help = "Number of feature generation threads (default 1)"
+        help = "Number of feature generation threads (default all cores)"
+    )]
+    feat_gen_threads: Option<usize>,
+
+    #[command(flatten)]
+    filter: OverlapFilterArgs,
+
+    #[command(flatten)]
+    batch: TargetBatchArgs,
+
+    #[arg(
+        long,
+        default_value = "npy",
+        value_enum,
+        help = "Format of the features, npy writes a folder per target read, hdf5 a single file with a group per read and window, and arrow or parquet a folder of files with a row per window (default npy)"
     )]
-    feat_gen_threads: usize,
+    feature_format: FeatureFormat,
+
+    #[arg(long, help = "Compress the npy features with zstd (.npy.zst files)")]
+    compress_features: bool,
+
+    #[command(flatten)]
+    input: ReadsArgs,
+
+    #[command(flatten)]
+    progress: ProgressArgs,
 
     #[arg(help = "Path to the fastq reads (can be gzipped)")]
     reads: String,
 
-    #[arg(help = "Path to the folder where features will be stored")]
+    #[arg(help = "Path to the folder (file for hdf5) where features will be stored")]
     output: String,
 }
 
@@ -66,82 +437,1086 @@ struct InferenceArgs {
     #[command(flatten)]
     alns: AlignmentsIO,
 
+    #[command(flatten)]
+    alignment: AlignmentArgs,
+
     #[arg(
         short = 'w',
-        default_value = "4096",
-        help = "Size of the window used for target chunking (default 4096)"
+        help = "Size of the window used for target chunking, checked against the model spec (default from the model spec, or 4096)"
     )]
-    window_size: u32,
+    window_size: Option<u32>,
+
+    #[arg(
+        long,
+        help = "Maximum number of supporting reads of a window, checked against the model spec (default from the model spec, or 30)"
+    )]
+    top_k: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Distance between the starts of adjacent windows. Windows overlap if it is smaller than the window size, which it has to divide (default: window size)"
+    )]
+    window_stride: Option<u32>,
+
+    #[arg(
+        long,
+        help = "Minimum number of supporting reads (2 to --top-k) of the leading and trailing windows, windows below it are trimmed from the corrected reads (default: only uncorrected windows are trimmed)"
+    )]
+    trim_ends: Option<u8>,
+
+    #[arg(
+        long,
+        help = "Minimum number of supporting reads (2 to --top-k) of a window, windows with fewer supporting reads are not corrected (default 2)"
+    )]
+    min_coverage: Option<u8>,
 
     #[arg(
         short = 't',
-        default_value = "1",
-        help = "Number of feature generation threads per device (default 1)"
+        help = "Number of feature generation threads per device (default all cores divided between the devices)"
     )]
-    feat_gen_threads: usize,
+    feat_gen_threads: Option<usize>,
 
-    #[arg(short = 'm', help = "Path to the model file")]
-    model: String,
+    #[arg(
+        long,
+        help = "Number of threads per device realigning overlaps without CIGARs (default -t)"
+    )]
+    align_threads: Option<usize>,
 
     #[arg(
-        short = 'd',
+        long,
+        help = "Number of threads per device encoding the features of the aligned overlaps (default -t)"
+    )]
+    encode_threads: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Number of consensus workers per device and compression threads of each output (default 1 consensus worker and -t compression threads)"
+    )]
+    writer_threads: Option<usize>,
+
+    #[command(flatten)]
+    filter: OverlapFilterArgs,
+
+    #[command(flatten)]
+    batch: TargetBatchArgs,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["read_alns", "write_alns", "overlaps", "internal_overlapper"],
+        help = "Directory with the features written by the features subcommand in the npy format, which are corrected instead of generating features from the overlaps"
+    )]
+    from_features: Option<PathBuf>,
+
+    #[arg(
+        short = 'm',
+        required = true,
         value_delimiter = ',',
-        default_value = "0",
-        help = "List of cuda devices in format d0,d1... (e.g 0,1,3) (default 0)"
+        env = "HERRO_MODEL",
+        help = "Path to the model file or URL (http, https or s3). Models given by URL are downloaded to $HERRO_MODELS (default $XDG_CACHE_HOME/herro/models). Multiple models, comma-separated or given multiple times, are combined into an ensemble"
     )]
-    devices: Vec<usize>,
+    models: Vec<String>,
 
     #[arg(
-        short = 'b',
-        help = "Batch size per device. B=64 recommended for 40 GB GPU cards."
+        long,
+        value_delimiter = ',',
+        help = "SHA-256 checksums of the models in the order of -m, the run fails if a model does not match its checksum"
     )]
-    batch_size: usize,
+    model_sha256: Vec<String>,
 
-    #[arg(help = "Path to the fastq reads (can be gzipped)")]
-    reads: String,
+    #[arg(
+        long,
+        default_value = "mean",
+        value_enum,
+        help = "How the predictions of multiple models are combined: mean averages the base probabilities, vote takes the base predicted by most models (default mean)"
+    )]
+    ensemble: Ensemble,
 
-    #[arg(help = "Path to the corrected reads")]
-    output: String,
-}
+    #[arg(
+        long,
+        value_enum,
+        help = "Encoding of the base qualities expected by the model: phred (Phred qualities scaled to [-1, 1]) or weights (probability that each base is correct, for models which weight the supporting bases by it). Taken from the model spec if present (default phred)"
+    )]
+    qual_encoding: Option<QualEncoding>,
 
-fn main() {
-    let cli = Cli::parse();
+    #[arg(
+        long,
+        help = "Give the homopolymer run length of each base to the model as an additional channel, for models without a spec which were trained with it"
+    )]
+    homopolymer_channel: bool,
 
-    match cli.command {
-        Commands::Features(args) => {
-            let mode = match (args.alns.read_alns, args.alns.write_alns) {
-                (None, None) => AlnMode::None,
-                (Some(p), None) => AlnMode::Read(p),
-                (None, Some(p)) => AlnMode::Write(p),
-                _ => unreachable!(),
-            };
+    #[arg(
+        long,
+        help = "Feature layout version expected by the model: 1, or 2 which adds a channel with the dinucleotide preceding each base. Taken from the model spec if present (default 1)"
+    )]
+    feature_layout: Option<u32>,
 
-            generate_features(
-                args.reads,
-                args.output,
-                args.feat_gen_threads,
-                args.window_size,
-                mode,
-            );
-        }
-        Commands::Inference(args) => {
-            let mode = match (args.alns.read_alns, args.alns.write_alns) {
-                (None, None) => AlnMode::None,
-                (Some(p), None) => AlnMode::Read(p),
-                (None, Some(p)) => AlnMode::Write(p),
-                _ => unreachable!(),
-            };
+    #[arg(
+        long,
+        value_enum,
+        help = "Encoding of the features expected by the model: standard, or strandless which encodes the bases of the supporting reads without their strand. Taken from the model spec if present (default standard)"
+    )]
+    feature_set: Option<FeatureSet>,
 
-            error_correction(
-                args.reads,
-                &args.model,
-                args.output,
-                args.feat_gen_threads,
+    #[arg(
+        short = 'd',
+        value_delimiter = ',',
+        default_value = DEFAULT_DEVICE,
+        env = "HERRO_DEVICES",
+        help = "Comma-separated list of devices: cuda:<id> (e.g. cuda:0,cuda:3), hip:<id> for AMD GPUs, mps for Apple GPUs or cpu (default mps on macOS, cuda:0 otherwise)"
+    )]
+    devices: Vec<Device>,
+
+    #[arg(long, help = "Run inference on the CPU, same as -d cpu")]
+    cpu: bool,
+
+    #[arg(
+        short = 'b',
+        long,
+        env = "HERRO_BATCH_SIZE",
+        help = "Number of windows per inference batch on each device. B=64 recommended for 40 GB GPU cards (default 64, or 8 on the CPU)"
+    )]
+    batch_size: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Number of threads used by the model for each batch (default 1, or the cores divided between the inference workers on the CPU)"
+    )]
+    intra_op_threads: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Number of inference workers on each device, each with its own copy of the model and batch queue. More workers improve the utilization of large GPUs with small batches, at the cost of the memory of another model copy (default 1, or one per 4 cores on the CPU)"
+    )]
+    workers_per_device: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Seconds between the logs of the batch throughput, utilization and memory usage of each device, 0 disables them (default 60)"
+    )]
+    telemetry_interval: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Number of reads with overlaps queued for feature generation (default 50000)"
+    )]
+    aln_queue: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Number of feature batches queued for inference (default two per feature generation thread)"
+    )]
+    feature_queue: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Number of inferred batches queued for each consensus worker (default unbounded)"
+    )]
+    consensus_queue: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Number of corrected reads queued for each output writer (default unbounded)"
+    )]
+    writer_queue: Option<usize>,
+
+    #[arg(
+        long,
+        value_parser = memory_size,
+        help = "Memory budget of the feature batches queued for inference (e.g. 16G), batches over the budget are spilled to disk and read back when the inference catches up (default unlimited)"
+    )]
+    spill_memory: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Directory of the features spilled to disk (default the temporary directory)"
+    )]
+    spill_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_parser = memory_size,
+        help = "Memory budget of the whole run (e.g. 64G), target read batches, queues and the spill budget which are not given are sized to stay under it, and overlaps are pruned to twice the top-k coverage if the batches would be too small"
+    )]
+    max_memory: Option<u64>,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "Inference backend, tch runs TorchScript models with libtorch, onnx runs ONNX models with ONNX Runtime and candle runs safetensors weights with candle (default tch, candle if built without libtorch)"
+    )]
+    backend: Option<BackendName>,
+
+    #[arg(
+        long,
+        default_value = "fp32",
+        value_enum,
+        help = "Precision of the model weights and inputs, half precision (fp16 or bf16) is faster on recent GPUs and requires the tch backend. int8 runs a model quantized with scripts/quantize_model.py on the CPU, at the cost of slightly lower accuracy (default fp32)"
+    )]
+    precision: Precision,
+
+    #[arg(
+        long,
+        default_value = "cuda",
+        value_enum,
+        help = "ONNX Runtime execution provider used with --backend onnx (default cuda)"
+    )]
+    onnx_provider: OnnxProvider,
+
+    #[arg(
+        long,
+        help = "Directory where TensorRT engines are cached, so they are built only once (--onnx-provider tensorrt)"
+    )]
+    trt_engine_cache: Option<PathBuf>,
+
+    #[arg(
+        long,
+        default_value = "fasta",
+        value_enum,
+        help = "Format of the corrected reads, FASTQ contains base qualities predicted by the model (default fasta)"
+    )]
+    output_format: OutputFormat,
+
+    #[arg(
+        long,
+        help = "Write corrected reads into one shard per device and merge them at the end"
+    )]
+    sharded_output: bool,
+
+    #[arg(
+        long,
+        help = "Write the mean model confidence of each corrected window as a bedGraph track (read id, start, end, confidence) in the coordinates of the target reads"
+    )]
+    confidence_bed: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Write the corrections applied to the target reads as TSV (read id, position in the target read, target bases, corrected bases, probability)"
+    )]
+    edits_tsv: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Write the HP-style groups of the supporting reads in each corrected window as TSV, reads on the haplotype of the target read are in group 1, other reads in group 2 and undecided reads in group 0"
+    )]
+    haplotypes_tsv: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "Detect chimeric target reads from coverage discontinuities of their overlaps: detect removes the overlaps spanning the junctions, split also writes the corrected parts as separate reads (<id>:<n>)"
+    )]
+    chimeras: Option<ChimeraMode>,
+
+    #[arg(
+        long,
+        help = "Write a JSON report of the run: input counts, overlaps kept and dropped by each filter, windows processed, reads corrected, wall time of each stage and peak memory"
+    )]
+    report: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Continue the interrupted run from its checkpoint, reads which were already corrected are skipped"
+    )]
+    resume: bool,
+
+    #[arg(
+        long,
+        help = "Record the corrected reads in <output>.checkpoint, so an interrupted run can be continued with --resume (always enabled with --resume)"
+    )]
+    checkpoint: bool,
+
+    #[arg(
+        long,
+        help = "Write the corrected reads in the order of the input and use a fixed seed, so runs on the same input are reproducible on the CPU (GPUs also need CUBLAS_WORKSPACE_CONFIG=:4096:8 and may still differ)"
+    )]
+    deterministic: bool,
+
+    #[arg(
+        long,
+        help = "Write target reads without usable overlaps unchanged, so the output contains all target reads"
+    )]
+    keep_uncorrected: bool,
+
+    #[arg(
+        long,
+        requires = "keep_uncorrected",
+        help = "Append \"uncorrected\" to the headers of the reads written unchanged"
+    )]
+    tag_uncorrected: bool,
+
+    #[arg(
+        long,
+        help = "Copy the descriptions of the input reads (everything after the read id in the header) to the headers of the corrected reads"
+    )]
+    keep_descriptions: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "keep_descriptions",
+        help = "Copy the basecaller tags of the input reads which remain valid after correction (dorado RG, ch, rn, st, fn, pi, BC and dx tags, MinKNOW runid, read, ch, start_time, ... fields) to the headers of the corrected reads"
+    )]
+    keep_tags: bool,
+
+    #[command(flatten)]
+    input: ReadsArgs,
+
+    #[command(flatten)]
+    progress: ProgressArgs,
+
+    #[arg(help = "Path to the fastq reads (can be gzipped)")]
+    reads: String,
+
+    #[arg(help = "Path to the corrected reads")]
+    output: String,
+}
+
+#[derive(Args)]
+struct StatsArgs {
+    #[arg(
+        short = 'w',
+        default_value = "4096",
+        help = "Size of the window used for target chunking, shorter reads are not used as targets (default 4096)"
+    )]
+    window_size: u32,
+
+    #[command(flatten)]
+    filter: OverlapFilterArgs,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "Format of the overlaps (default: detected from the extension, .sam, .bam, .m4 or .mhap, otherwise PAF)"
+    )]
+    overlap_format: Option<OverlapsFormat>,
+
+    #[arg(help = "Path to the reads (can be gzipped)")]
+    reads: String,
+
+    #[arg(
+        required = true,
+        help = "Paths to the all-vs-all overlaps in PAF, SAM, BAM or M4 format (PAF/SAM/M4 can be gzip/bgzip/zstd compressed), merged as for correction. Use - to read PAF from stdin"
+    )]
+    overlaps: Vec<String>,
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    #[arg(
+        long,
+        requires = "overlaps",
+        help = "Path to the reads of the benchmark (can be gzipped), a synthetic dataset is generated if not given"
+    )]
+    reads: Option<String>,
+
+    #[arg(
+        long,
+        requires = "reads",
+        help = "Path to the all-vs-all overlaps of the reads in PAF, SAM, BAM or M4 format"
+    )]
+    overlaps: Option<String>,
+
+    #[arg(
+        long,
+        requires = "overlaps",
+        value_enum,
+        help = "Format of the overlaps given with --overlaps (default: detected from the extension, .sam, .bam, .m4 or .mhap, otherwise PAF)"
+    )]
+    overlap_format: Option<OverlapsFormat>,
+
+    #[arg(
+        long,
+        default_value = "100",
+        help = "Number of target reads, the first reads of --reads or generated synthetic reads (default 100)"
+    )]
+    n_reads: usize,
+
+    #[arg(
+        short = 'w',
+        help = "Size of the window used for target chunking, checked against the model spec (default from the model spec, or 4096)"
+    )]
+    window_size: Option<u32>,
+
+    #[arg(
+        short = 't',
+        help = "Number of alignment and feature generation threads per device (default all cores divided between the devices)"
+    )]
+    feat_gen_threads: Option<usize>,
+
+    #[arg(
+        long,
+        default_value = "banded",
+        value_enum,
+        help = "Aligner used for realigning overlaps without CIGARs (default banded)"
+    )]
+    aligner: Aligner,
+
+    #[arg(
+        short = 'm',
+        required = true,
+        value_delimiter = ',',
+        env = "HERRO_MODEL",
+        help = "Path to the model file or URL (http, https or s3). Multiple models are combined into an ensemble"
+    )]
+    models: Vec<String>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "SHA-256 checksums of the models in the order of -m"
+    )]
+    model_sha256: Vec<String>,
+
+    #[arg(
+        short = 'd',
+        value_delimiter = ',',
+        default_value = DEFAULT_DEVICE,
+        env = "HERRO_DEVICES",
+        help = "Comma-separated list of devices: cuda:<id>, hip:<id>, mps or cpu (default mps on macOS, cuda:0 otherwise)"
+    )]
+    devices: Vec<Device>,
+
+    #[arg(long, help = "Run inference on the CPU, same as -d cpu")]
+    cpu: bool,
+
+    #[arg(
+        short = 'b',
+        long,
+        help = "Number of windows per inference batch on each device (default 64, or 8 on the CPU)"
+    )]
+    batch_size: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Number of threads used by the model for each batch (default 1, or the cores divided between the inference workers on the CPU)"
+    )]
+    intra_op_threads: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Number of inference workers on each device (default 1, or one per 4 cores on the CPU)"
+    )]
+    workers_per_device: Option<usize>,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "Inference backend (default tch, candle if built without libtorch)"
+    )]
+    backend: Option<BackendName>,
+
+    #[arg(
+        long,
+        default_value = "fp32",
+        value_enum,
+        help = "Precision of the model weights and inputs (default fp32)"
+    )]
+    precision: Precision,
+
+    #[arg(
+        long,
+        default_value = "cuda",
+        value_enum,
+        help = "ONNX Runtime execution provider used with --backend onnx (default cuda)"
+    )]
+    onnx_provider: OnnxProvider,
+}
+
+/// Configuration of the inference, also used to validate its inputs.
+fn correction_config(
+    args: &InferenceArgs,
+    max_threads: Option<usize>,
+) -> herro::Result<CorrectionConfig> {
+    let devices = match args.cpu {
+        true => vec![Device::Cpu],
+        false => args.devices.clone(),
+    };
+
+    let mut builder = CorrectionConfig::builder()
+        .overlap_filter(args.filter.filter())
+        .trust_cigar(args.alignment.trust_cigar)
+        .aligner(args.alignment.aligner)
+        .devices(devices);
+    if let Some(threads) = args.feat_gen_threads {
+        builder = builder.threads(threads);
+    }
+    if let Some(max_threads) = max_threads {
+        builder = builder.max_threads(max_threads);
+    }
+    if let Some(window_size) = args.window_size {
+        builder = builder.window_size(window_size);
+    }
+    if let Some(top_k) = args.top_k {
+        builder = builder.top_k(top_k);
+    }
+    if let Some(batch_size) = args.batch_size {
+        builder = builder.batch_size(batch_size);
+    }
+    if let Some(threads) = args.intra_op_threads {
+        builder = builder.intra_op_threads(threads);
+    }
+    if let Some(workers) = args.workers_per_device {
+        builder = builder.workers_per_device(workers);
+    }
+    if let Some(seconds) = args.telemetry_interval {
+        builder = builder.telemetry_interval(seconds);
+    }
+    if let Some(threads) = args.align_threads {
+        builder = builder.align_threads(threads);
+    }
+    if let Some(threads) = args.encode_threads {
+        builder = builder.encode_threads(threads);
+    }
+    if let Some(threads) = args.writer_threads {
+        builder = builder.writer_threads(threads);
+    }
+    if let Some(stride) = args.window_stride {
+        builder = builder.window_stride(stride);
+    }
+    if let Some(min_coverage) = args.min_coverage {
+        builder = builder.min_coverage(min_coverage);
+    }
+    if let Some(min_cov) = args.trim_ends {
+        builder = builder.trim_ends(min_cov);
+    }
+    if let Some(capacity) = args.aln_queue {
+        builder = builder.aln_capacity(capacity);
+    }
+    if let Some(capacity) = args.feature_queue {
+        builder = builder.features_capacity(capacity);
+    }
+    if let Some(capacity) = args.consensus_queue {
+        builder = builder.consensus_capacity(capacity);
+    }
+    if let Some(capacity) = args.writer_queue {
+        builder = builder.writer_capacity(capacity);
+    }
+    if let Some(bytes) = args.spill_memory {
+        builder = builder.spill_memory(bytes);
+    }
+    if let Some(dir) = args.spill_dir.clone() {
+        builder = builder.spill_dir(dir);
+    }
+    if let Some(bytes) = args.max_memory {
+        builder = builder.max_memory(bytes);
+    }
+    if let Some(cache) = args.trt_engine_cache.clone() {
+        builder = builder.trt_engine_cache(cache);
+    }
+    if let Some(shard) = args.input.shard {
+        builder = builder.shard(shard);
+    }
+    if let Some(read_ids) = args.input.read_ids.clone() {
+        builder = builder.read_ids(read_ids);
+    }
+    if let Some(dir) = args.alignment.align_cache.clone() {
+        builder = builder.align_cache(dir);
+    }
+    if let Some(dir) = args.from_features.clone() {
+        builder = builder.from_features(dir);
+    }
+    if let Some(path) = args.confidence_bed.clone() {
+        builder = builder.confidence_bed(path);
+    }
+    if let Some(path) = args.edits_tsv.clone() {
+        builder = builder.edits_tsv(path);
+    }
+    if let Some(path) = args.haplotypes_tsv.clone() {
+        builder = builder.haplotypes_tsv(path);
+    }
+    if let Some(version) = args.feature_layout {
+        builder = builder.feature_layout(version);
+    }
+    if let Some(encoding) = args.qual_encoding {
+        builder = builder.qual_encoding(encoding);
+    }
+    if let Some(feature_set) = args.feature_set {
+        builder = builder.feature_set(feature_set);
+    }
+    if let Some(mode) = args.chimeras {
+        builder = builder.chimeras(mode);
+    }
+    if let Some(path) = args.report.clone() {
+        builder = builder.run_report(path);
+    }
+    if let Some(n_reads) = args.batch.batch_reads {
+        builder = builder.batch_reads(n_reads);
+    }
+    if let Some(n_bases) = args.batch.batch_bases {
+        builder = builder.batch_bases(n_bases);
+    }
+    if let Some(format) = args.alignment.overlap_format {
+        builder = builder.overlaps_format(format);
+    }
+    if let Some(path) = args.progress.progress_json.clone() {
+        builder = builder.progress_json(path);
+    }
+
+    builder
+        .output_format(args.output_format)
+        .sharded_output(args.sharded_output)
+        .resume(args.resume)
+        .checkpoint(args.checkpoint)
+        .deterministic(args.deterministic)
+        .keep_uncorrected(args.keep_uncorrected)
+        .tag_uncorrected(args.tag_uncorrected)
+        .keep_descriptions(args.keep_descriptions)
+        .keep_tags(args.keep_tags)
+        .indexed_reads(args.input.indexed_reads)
+        .duplicate_ids(args.input.on_duplicate)
+        .backend(backend(args.backend, args.onnx_provider))
+        .precision(args.precision)
+        .ensemble(args.ensemble)
+        .homopolymer_channel(args.homopolymer_channel)
+        .quiet(args.progress.quiet)
+        .build()
+}
+
+/// Configuration of the benchmark, the stages are run as in the inference.
+fn bench_config(args: &BenchArgs, max_threads: Option<usize>) -> herro::Result<CorrectionConfig> {
+    let devices = match args.cpu {
+        true => vec![Device::Cpu],
+        false => args.devices.clone(),
+    };
+
+    let mut builder = CorrectionConfig::builder()
+        .aligner(args.aligner)
+        .devices(devices)
+        .backend(backend(args.backend, args.onnx_provider))
+        .precision(args.precision);
+    if let Some(threads) = args.feat_gen_threads {
+        builder = builder.threads(threads);
+    }
+    if let Some(max_threads) = max_threads {
+        builder = builder.max_threads(max_threads);
+    }
+    if let Some(window_size) = args.window_size {
+        builder = builder.window_size(window_size);
+    }
+    if let Some(batch_size) = args.batch_size {
+        builder = builder.batch_size(batch_size);
+    }
+    if let Some(threads) = args.intra_op_threads {
+        builder = builder.intra_op_threads(threads);
+    }
+    if let Some(workers) = args.workers_per_device {
+        builder = builder.workers_per_device(workers);
+    }
+    if let Some(format) = args.overlap_format {
+        builder = builder.overlaps_format(format);
+    }
+
+    builder.quiet(true).build()
+}
+
+/// Resolves the models given with `-m`, downloading them if needed.
+fn fetch_models(models: &[String], model_sha256: &[String]) -> herro::Result<Vec<String>> {
+    if !model_sha256.is_empty() && model_sha256.len() != models.len() {
+        return Err(HerroError::InvalidConfig(format!(
+            "{} checksums given for {} models",
+            model_sha256.len(),
+            models.len()
+        )));
+    }
+
+    models
+        .iter()
+        .enumerate()
+        .map(|(i, model)| fetch_model(model, model_sha256.get(i).map(|s| s.as_str())))
+        .collect()
+}
+
+fn backend(name: Option<BackendName>, onnx_provider: OnnxProvider) -> Backend {
+    match name {
+        None => Backend::default(),
+        Some(BackendName::Tch) => Backend::Tch,
+        Some(BackendName::Onnx) => Backend::Onnx(onnx_provider),
+        Some(BackendName::Candle) => Backend::Candle,
+    }
+}
+
+/// Parses a memory size in bytes, with an optional K, M, G or T suffix for
+/// binary multiples (e.g. 64G).
+fn memory_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (number, shift) = match size.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let shift = match c.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(format!("unknown size suffix {}, expected K, M, G or T", c)),
+            };
+            (&size[..i], shift)
+        }
+        _ => (size, 0),
+    };
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid memory size {}", size))?;
+    if !number.is_finite() || number < 0. {
+        return Err(format!("invalid memory size {}", size));
+    }
+    Ok((number * (1u64 << shift) as f64) as u64)
+}
+
+/// Returns the path given with `--config`.
+fn config_path<'a>(args: &'a [Cow<str>]) -> Option<&'a str> {
+    args.iter().enumerate().find_map(|(i, arg)| match &**arg {
+        "--config" => args.get(i + 1).map(|path| &**path),
+        arg => arg.strip_prefix("--config="),
+    })
+}
+
+/// Checks if the argument is given in the command line arguments.
+fn is_given(arg: &Arg, args: &[Cow<str>]) -> bool {
+    args.iter().any(|a| {
+        let long = arg.get_long().is_some_and(|long| {
+            a.strip_prefix("--")
+                .is_some_and(|a| a == long || a.starts_with(&format!("{}=", long)))
+        });
+        let short = arg.get_short().is_some_and(|short| {
+            !a.starts_with("--") && a.strip_prefix('-').is_some_and(|a| a.starts_with(short))
+        });
+        long || short
+    })
+}
+
+/// Converts the options in the config file to the arguments of the
+/// subcommand. Options given in `args` or by their environment variables are
+/// skipped, so both override the config file.
+fn config_args(
+    config: &toml::Table,
+    command: &Command,
+    subcommand: &str,
+    args: &[Cow<str>],
+) -> herro::Result<Vec<String>> {
+    let invalid = |msg: String| HerroError::InvalidConfig(format!("config file: {}", msg));
+
+    // Top-level options of other subcommands are skipped, options in the
+    // subcommand's table have to be known
+    let mut options = Vec::new();
+    for (key, value) in config.iter() {
+        match value {
+            toml::Value::Table(table) if key == subcommand => {
+                options.extend(table.iter().map(|(k, v)| (k, v, true)))
+            }
+            toml::Value::Table(_) if command.find_subcommand(key).is_some() => (),
+            _ => options.push((key, value, false)),
+        }
+    }
+
+    let find = |cmd: &Command, key: &str| {
+        let id = key.replace('-', "_");
+        cmd.get_arguments()
+            .find(|a| {
+                let short = a
+                    .get_short()
+                    .is_some_and(|s| key.len() == 1 && key.starts_with(s));
+                a.get_long() == Some(key) || short || a.get_id() == id.as_str()
+            })
+            .cloned()
+    };
+    let subcommand = command.find_subcommand(subcommand).unwrap();
+    let mut config_args = Vec::new();
+    for (key, value, is_strict) in options {
+        let Some(arg) = find(subcommand, key).or_else(|| find(command, key)) else {
+            if !is_strict && command.get_subcommands().any(|c| find(c, key).is_some()) {
+                continue;
+            }
+            return Err(invalid(format!("unknown option {}", key)));
+        };
+        if arg.is_positional() || arg.get_id() == "config" {
+            return Err(invalid(format!("{} cannot be set in the config file", key)));
+        }
+        // Environment variables override the config file as well
+        let env_set = arg
+            .get_env()
+            .is_some_and(|name| env::var_os(name).is_some());
+        if env_set || is_given(&arg, args) {
+            continue;
+        }
+
+        let flag = match (arg.get_long(), arg.get_short()) {
+            (Some(long), _) => format!("--{}", long),
+            (None, Some(short)) => format!("-{}", short),
+            (None, None) => unreachable!("options have a long or short name"),
+        };
+        let values = match value {
+            toml::Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            match (arg.get_action(), value) {
+                (ArgAction::SetTrue, toml::Value::Boolean(true)) => config_args.push(flag.clone()),
+                (ArgAction::SetTrue, toml::Value::Boolean(false)) => (),
+                // Counts are at most 255 in clap
+                (ArgAction::Count, toml::Value::Integer(n)) if (0..=255).contains(n) => {
+                    config_args.extend(iter::repeat_n(flag.clone(), *n as usize))
+                }
+                (ArgAction::Set | ArgAction::Append, toml::Value::String(s)) => {
+                    config_args.extend([flag.clone(), s.clone()])
+                }
+                (
+                    ArgAction::Set | ArgAction::Append,
+                    toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_),
+                ) => config_args.extend([flag.clone(), value.to_string()]),
+                _ => return Err(invalid(format!("invalid value {} of {}", value, key))),
+            }
+        }
+    }
+
+    Ok(config_args)
+}
+
+/// Inserts the options from the config file given with `--config` after the
+/// subcommand.
+fn with_config_file(args: Vec<OsString>) -> herro::Result<Vec<OsString>> {
+    let strs: Vec<_> = args.iter().map(|a| a.to_string_lossy()).collect();
+    let Some(path) = config_path(&strs) else {
+        return Ok(args);
+    };
+    // Missing subcommand is reported by clap
+    let command = Cli::command();
+    let Some(pos) = strs
+        .iter()
+        .skip(1)
+        .position(|a| command.find_subcommand(&**a).is_some())
+        .map(|i| i + 1)
+    else {
+        return Ok(args);
+    };
+
+    let text = fs::read_to_string(path)
+        .map_err(|e| HerroError::InvalidConfig(format!("cannot read {}: {}", path, e)))?;
+    let config: toml::Table = text
+        .parse()
+        .map_err(|e| HerroError::InvalidConfig(format!("cannot parse {}: {}", path, e)))?;
+    let config_args = config_args(&config, &command, &strs[pos], &strs[1..])?;
+
+    let mut args = args;
+    args.splice(
+        pos + 1..pos + 1,
+        config_args.into_iter().map(OsString::from),
+    );
+    Ok(args)
+}
+
+fn main() {
+    let args = with_config_file(env::args_os().collect()).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    });
+    let cli = Cli::parse_from(args);
+
+    tracing_subscriber::fmt()
+        .with_max_level(cli.log_level())
+        .with_writer(std::io::stderr)
+        .with_target(false)
+        .init();
+
+    let result = match cli.command {
+        Commands::Features(args) => {
+            let args = *args;
+            let mode = args.alns.mode();
+            let mut builder = FeatureGenConfig::builder()
+                .window_size(args.window_size)
+                .top_k(args.top_k)
+                .overlap_filter(args.filter.filter())
+                .trust_cigar(args.alignment.trust_cigar)
+                .aligner(args.alignment.aligner)
+                .feature_format(args.feature_format)
+                .compress_features(args.compress_features)
+                .indexed_reads(args.input.indexed_reads)
+                .duplicate_ids(args.input.on_duplicate)
+                .quiet(args.progress.quiet);
+            if let Some(path) = args.progress.progress_json.clone() {
+                builder = builder.progress_json(path);
+            }
+            if let Some(threads) = args.feat_gen_threads {
+                builder = builder.threads(threads);
+            }
+            if let Some(max_threads) = cli.threads {
+                builder = builder.max_threads(max_threads);
+            }
+            if let Some(shard) = args.input.shard {
+                builder = builder.shard(shard);
+            }
+            if let Some(read_ids) = args.input.read_ids {
+                builder = builder.read_ids(read_ids);
+            }
+            if let Some(dir) = args.alignment.align_cache {
+                builder = builder.align_cache(dir);
+            }
+            if let Some(n_reads) = args.batch.batch_reads {
+                builder = builder.batch_reads(n_reads);
+            }
+            if let Some(n_bases) = args.batch.batch_bases {
+                builder = builder.batch_bases(n_bases);
+            }
+            if let Some(format) = args.alignment.overlap_format {
+                builder = builder.overlaps_format(format);
+            }
+
+            builder
+                .build()
+                .and_then(|config| generate_features(args.reads, args.output, mode, &config))
+        }
+        Commands::Inference(args) => {
+            let args = *args;
+            correction_config(&args, cli.threads).and_then(|config| {
+                let models = fetch_models(&args.models, &args.model_sha256)?;
+                let models: Vec<_> = models.iter().map(|m| m.as_str()).collect();
+                let mode = args.alns.mode();
+                error_correction(args.reads, &models, args.output, mode, &config)
+            })
+        }
+        Commands::Validate(args) => {
+            let args = *args;
+            correction_config(&args, cli.threads).and_then(|config| {
+                let models = fetch_models(&args.models, &args.model_sha256)?;
+                let models: Vec<_> = models.iter().map(|m| m.as_str()).collect();
+                let mode = args.alns.mode();
+                let report = validate_inputs(args.reads, &models, args.output, mode, &config);
+                print!("{}", report);
+                report.result()
+            })
+        }
+        Commands::Bench(args) => {
+            let args = *args;
+            bench_config(&args, cli.threads).and_then(|config| {
+                let models = fetch_models(&args.models, &args.model_sha256)?;
+                let models: Vec<_> = models.iter().map(|m| m.as_str()).collect();
+                let dataset = args.reads.zip(args.overlaps);
+                bench(&models, dataset, args.n_reads, &config).map(|report| print!("{}", report))
+            })
+        }
+        Commands::Stats(args) => {
+            let args = *args;
+            overlap_stats(
+                args.reads,
+                args.overlaps,
+                args.overlap_format,
                 args.window_size,
-                args.devices,
-                args.batch_size,
-                mode,
-            );
+                args.filter.filter(),
+            )
+            .map(|stats| print!("{}", stats))
         }
+    };
+
+    if let Err(e) = result {
+        tracing::error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::env;
+
+    use clap::{CommandFactory, Parser};
+
+    use super::{config_args, config_path, memory_size, Cli, Commands};
+
+    #[test]
+    fn memory_sizes() {
+        assert_eq!(memory_size("1024"), Ok(1024));
+        assert_eq!(memory_size("64G"), Ok(64 << 30));
+        assert_eq!(memory_size("1.5k"), Ok(1536));
+        assert!(memory_size("16X").is_err());
+        assert!(memory_size("G").is_err());
+    }
+
+    #[test]
+    fn config_file_args() {
+        let config: toml::Table = r#"
+            threads = 8
+            feature-format = "npy"
+
+            [inference]
+            m = ["a.pt", "b.pt"]
+            batch-size = 32
+            keep-uncorrected = true
+            trust-cigar = false
+        "#
+        .parse()
+        .unwrap();
+
+        let cmd_args = ["herro", "inference", "-b", "16", "reads.fastq", "out.fasta"];
+        let cmd_args: Vec<_> = cmd_args.iter().map(|&a| Cow::from(a)).collect();
+        let args = config_args(&config, &Cli::command(), "inference", &cmd_args[1..]).unwrap();
+        assert_eq!(
+            args,
+            [
+                "--keep-uncorrected",
+                "-m",
+                "a.pt",
+                "-m",
+                "b.pt",
+                "--trust-cigar",
+                "false",
+                "--threads",
+                "8"
+            ]
+        );
+
+        // Options given on the command line override the config file
+        let mut full_args: Vec<_> = cmd_args.iter().map(|a| a.to_string()).collect();
+        full_args.splice(2..2, args);
+        let cli = Cli::try_parse_from(full_args).unwrap();
+        let Commands::Inference(args) = cli.command else {
+            panic!("expected the inference subcommand");
+        };
+        assert_eq!(args.batch_size, Some(16));
+        assert_eq!(args.models, ["a.pt", "b.pt"]);
+        assert_eq!(cli.threads, Some(8));
+
+        let config: toml::Table = "[inference]\nbatch-sise = 32".parse().unwrap();
+        assert!(config_args(&config, &Cli::command(), "inference", &[]).is_err());
+
+        let config: toml::Table = "verbose = 2".parse().unwrap();
+        let args = config_args(&config, &Cli::command(), "inference", &[]).unwrap();
+        assert_eq!(args, ["--verbose", "--verbose"]);
+        let config: toml::Table = "verbose = -1".parse().unwrap();
+        assert!(config_args(&config, &Cli::command(), "inference", &[]).is_err());
+
+        let args = ["herro", "--config=herro.toml", "inference"].map(Cow::from);
+        assert_eq!(config_path(&args), Some("herro.toml"));
+
+        // Environment variables override the config file, but not the command line
+        env::set_var("HERRO_BATCH_SIZE", "24");
+        let config: toml::Table = "[inference]\nbatch-size = 32".parse().unwrap();
+        assert!(config_args(&config, &Cli::command(), "inference", &[])
+            .unwrap()
+            .is_empty());
+        let args = [
+            "herro",
+            "inference",
+            "-m",
+            "a.pt",
+            "reads.fastq",
+            "out.fasta",
+        ];
+        let Commands::Inference(args) = Cli::try_parse_from(args).unwrap().command else {
+            panic!("expected the inference subcommand");
+        };
+        assert_eq!(args.batch_size, Some(24));
+        let args = [
+            "herro",
+            "inference",
+            "-b",
+            "8",
+            "-m",
+            "a.pt",
+            "r.fastq",
+            "o.fasta",
+        ];
+        let Commands::Inference(args) = Cli::try_parse_from(args).unwrap().command else {
+            panic!("expected the inference subcommand");
+        };
+        assert_eq!(args.batch_size, Some(8));
+        env::remove_var("HERRO_BATCH_SIZE");
     }
 }
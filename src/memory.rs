@@ -0,0 +1,186 @@
+use std::fmt;
+
+use crate::{TargetBatch, ALN_CHANNEL_CAPACITY};
+
+/// Memory of the overlaps of a target read per base of the read and overlap
+/// covering it, mostly their CIGARs and alignment buffers
+const OVERLAP_BYTES_PER_BASE: u64 = 2;
+/// Coverage of the target reads by their overlaps, assumed when it is not
+/// bounded by the maximum coverage
+const ASSUMED_COVERAGE: u64 = 100;
+/// Overlaps of a batch are loaded by a single thread while the previous batch
+/// is processed, so the overlaps are pruned instead of using smaller batches
+/// which would keep the alignment threads waiting. Overlaps computed by
+/// minimap2 take a pass over all reads per batch, so their batches are never
+/// smaller.
+const MIN_BATCH_BASES: u64 = 100_000_000;
+/// Encoded batches take about as much memory as their features
+const ENCODED_BATCH_FACTOR: u64 = 2;
+
+const GIB: f64 = (1u64 << 30) as f64;
+
+/// Settings of the pipeline derived from the memory budget, used for the
+/// settings which are not given explicitly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct MemoryPlan {
+    pub(crate) max_memory: u64,
+    /// Memory of the reads and models
+    pub(crate) used: u64,
+    pub(crate) target_batch: TargetBatch,
+    pub(crate) aln_capacity: usize,
+    pub(crate) features_capacity: usize,
+    pub(crate) spill_memory: u64,
+    /// Overlaps are pruned to this coverage if the target batches would be
+    /// too small otherwise
+    pub(crate) max_coverage: Option<u32>,
+}
+
+/// Sizes of the data flowing through the pipeline, which determine how much
+/// memory its queues take.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PipelineSizes {
+    pub(crate) mean_read_length: u64,
+    pub(crate) window_size: u32,
+    pub(crate) top_k: usize,
+    pub(crate) batch_size: usize,
+    /// Default capacity of the queue of feature batches
+    pub(crate) features_capacity: usize,
+    /// Maximum coverage given by the user
+    pub(crate) max_coverage: Option<u32>,
+    /// Overlaps are computed by minimap2 for each batch of target reads
+    pub(crate) computes_overlaps: bool,
+}
+
+impl MemoryPlan {
+    /// Splits the memory left after loading the reads and models (`used`)
+    /// between the stages: a quarter for the overlaps of the current batch of
+    /// target reads, a quarter for the reads with overlaps queued for feature
+    /// generation, a quarter for the feature batches queued for inference and
+    /// the rest for consensus and writing. Batches hold at least a read, or
+    /// `MIN_BATCH_BASES` if minimap2 computes the overlaps, even if they
+    /// exceed the budget.
+    pub(crate) fn new(max_memory: u64, used: u64, sizes: PipelineSizes) -> Self {
+        let stage = max_memory.saturating_sub(used) / 4;
+        let coverage = |max_coverage: Option<u32>| max_coverage.map_or(ASSUMED_COVERAGE, u64::from);
+
+        let mut max_coverage = sizes.max_coverage;
+        let mut batch_bases = stage / (OVERLAP_BYTES_PER_BASE * coverage(max_coverage));
+        if batch_bases < MIN_BATCH_BASES && max_coverage.is_none() {
+            // Windows use at most top-k overlaps, the others are spares
+            max_coverage = Some(2 * sizes.top_k as u32);
+            batch_bases = stage / (OVERLAP_BYTES_PER_BASE * coverage(max_coverage));
+        }
+
+        let min_batch_bases = match sizes.computes_overlaps {
+            true => MIN_BATCH_BASES,
+            false => sizes.mean_read_length,
+        };
+        let batch_bases = batch_bases.max(min_batch_bases).max(1);
+
+        let read_memory = sizes.mean_read_length * OVERLAP_BYTES_PER_BASE * coverage(max_coverage);
+        let aln_capacity = (stage / read_memory.max(1)).clamp(1, ALN_CHANNEL_CAPACITY as u64);
+        // Features of a window are bases and quals of the target and top-k reads
+        let batch_memory = ENCODED_BATCH_FACTOR
+            * (sizes.batch_size * sizes.window_size as usize * (1 + sizes.top_k) * 5) as u64;
+        let features_capacity =
+            (stage / batch_memory.max(1)).clamp(1, sizes.features_capacity as u64);
+
+        MemoryPlan {
+            max_memory,
+            used,
+            target_batch: TargetBatch::Bases(batch_bases),
+            aln_capacity: aln_capacity as usize,
+            features_capacity: features_capacity as usize,
+            spill_memory: stage.max(1),
+            max_coverage,
+        }
+    }
+
+    /// Reads and models take most of the budget, so the pipeline runs with
+    /// minimal batches and queues and may still exceed it.
+    pub(crate) fn is_exceeded(&self) -> bool {
+        4 * self.used > 3 * self.max_memory
+    }
+}
+
+impl fmt::Display for MemoryPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let batch = match self.target_batch {
+            TargetBatch::Reads(n) => format!("{} reads", n),
+            TargetBatch::Bases(n) => format!("{} bases", n),
+        };
+        write!(
+            f,
+            "memory budget {:.1} GiB, reads and models use {:.1} GiB: target batches of {}, {} queued reads with overlaps, {} queued feature batches, {:.1} GiB of queued features before spilling",
+            self.max_memory as f64 / GIB,
+            self.used as f64 / GIB,
+            batch,
+            self.aln_capacity,
+            self.features_capacity,
+            self.spill_memory as f64 / GIB
+        )?;
+        if let Some(max_coverage) = self.max_coverage {
+            write!(f, ", overlaps pruned to coverage {}", max_coverage)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryPlan, PipelineSizes, MIN_BATCH_BASES};
+    use crate::TargetBatch;
+
+    const GB: u64 = 1 << 30;
+
+    fn sizes(max_coverage: Option<u32>) -> PipelineSizes {
+        PipelineSizes {
+            mean_read_length: 50_000,
+            window_size: 4096,
+            top_k: 30,
+            batch_size: 64,
+            features_capacity: 16,
+            max_coverage,
+            computes_overlaps: false,
+        }
+    }
+
+    #[test]
+    fn memory_plan() {
+        // 128 GB budget with 16 GB of reads leaves 28 GB for each stage
+        let plan = MemoryPlan::new(128 * GB, 16 * GB, sizes(None));
+        assert_eq!(plan.target_batch, TargetBatch::Bases(28 * GB / 200));
+        assert_eq!(plan.max_coverage, None);
+        assert_eq!(plan.aln_capacity, (28 * GB / 10_000_000) as usize);
+        assert_eq!(plan.features_capacity, 16);
+        assert_eq!(plan.spill_memory, 28 * GB);
+        assert!(!plan.is_exceeded());
+
+        // Overlaps are pruned instead of using tiny batches
+        let plan = MemoryPlan::new(8 * GB, 7 * GB, sizes(None));
+        assert_eq!(plan.max_coverage, Some(60));
+        assert_eq!(plan.target_batch, TargetBatch::Bases(GB / 4 / 120));
+        assert!(plan.features_capacity < 16);
+        assert!(plan.is_exceeded());
+
+        // Given maximum coverage is kept
+        let plan = MemoryPlan::new(8 * GB, 7 * GB, sizes(Some(30)));
+        assert_eq!(plan.max_coverage, Some(30));
+
+        // Exceeded budget still runs with batches of a read and the smallest
+        // queues
+        let plan = MemoryPlan::new(GB, 2 * GB, sizes(None));
+        assert_eq!(plan.target_batch, TargetBatch::Bases(50_000));
+        assert_eq!((plan.aln_capacity, plan.features_capacity), (1, 1));
+
+        // Minimap2 runs over all reads for each batch, so batches stay large
+        let minimap2 = PipelineSizes {
+            computes_overlaps: true,
+            ..sizes(Some(30))
+        };
+        let plan = MemoryPlan::new(8 * GB, 7 * GB, minimap2);
+        assert_eq!(plan.target_batch, TargetBatch::Bases(MIN_BATCH_BASES));
+        let plan = MemoryPlan::new(GB, 2 * GB, minimap2);
+        assert_eq!(plan.target_batch, TargetBatch::Bases(MIN_BATCH_BASES));
+    }
+}
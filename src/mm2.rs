@@ -1,17 +1,33 @@
 use std::{
-    io::Write,
+    io::{self, Write},
     path::Path,
     process::{ChildStdout, Command, Stdio},
     thread,
 };
 
+use tracing::debug;
+
+use crate::error::{HerroError, Result};
 use crate::haec_io::HAECRecord;
 
+/// Version of minimap2 in PATH, fails if minimap2 cannot be run.
+pub(crate) fn minimap2_version() -> Result<String> {
+    let output = Command::new("minimap2")
+        .arg("--version")
+        .stderr(Stdio::null())
+        .output()
+        .map_err(HerroError::Minimap2)?;
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
 pub(crate) fn call_mm2<P: AsRef<Path>>(
     target: &[HAECRecord],
     query: P,
     threads: usize,
-) -> ChildStdout {
+) -> Result<ChildStdout> {
+    debug!("Running minimap2 for {} target reads", target.len());
+
     let mut child = Command::new("minimap2")
         .args([
             "-t",
@@ -27,35 +43,42 @@ pub(crate) fn call_mm2<P: AsRef<Path>>(
             "-z200",
             "--dual=yes",
             "-",
-            query.as_ref().to_str().unwrap(),
         ])
+        .arg(query.as_ref())
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::null())
         .spawn()
-        .expect("Failed to start echo process");
+        .map_err(HerroError::Minimap2)?;
 
     let mut stdin = child.stdin.take().expect("Failed to get minimap2 stdin");
 
+    // Only errors while writing to minimap2 are minimap2 errors, not the ones
+    // of the indexed reads
     thread::scope(|s| {
-        s.spawn(move || {
-            let mut buffer = vec![0u8; target.iter().map(|r| r.seq.len()).max().unwrap()];
+        s.spawn(move || -> Result<()> {
+            let max_len = target.iter().map(|r| r.seq.len()).max().unwrap_or(0);
+            let mut buffer = vec![0u8; max_len];
 
             for read in target {
-                write!(stdin, ">").unwrap();
-                stdin.write_all(&read.id).unwrap();
-                writeln!(stdin, "\n").unwrap();
+                read.seq.get_sequence(&mut buffer)?;
 
-                read.seq.get_sequence(&mut buffer);
-                stdin
-                    .write_all(&buffer[..read.seq.len()])
-                    .expect("Failed to write to minimap2 stdin");
-
-                writeln!(stdin, "").expect("Failed to write to minimap2 stdin");
+                let mut write = || -> io::Result<()> {
+                    write!(stdin, ">")?;
+                    stdin.write_all(&read.id)?;
+                    writeln!(stdin, "\n")?;
+                    stdin.write_all(&buffer[..read.seq.len()])?;
+                    writeln!(stdin, "")
+                };
+                write().map_err(HerroError::Minimap2)?;
             }
-        });
-    });
+
+            Ok(())
+        })
+        .join()
+        .unwrap()
+    })?;
 
     let stdout = child.stdout.take().expect("Failed to get minimap2 stdout");
-    stdout
+    Ok(stdout)
 }
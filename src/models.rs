@@ -0,0 +1,227 @@
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use sha2::{Digest, Sha256};
+use tracing::{debug, info};
+
+use crate::error::{HerroError, Result};
+use crate::inference::ModelSpec;
+
+/// Extension of the file with the checksum of a cached model
+const CHECKSUM_EXTENSION: &str = "sha256";
+/// Hex digits of the hash of the URL in the names of cached models
+const URL_HASH_LEN: usize = 16;
+
+/// Returns whether the model is given by a URL instead of a path.
+fn is_url(model: &str) -> bool {
+    ["http://", "https://", "s3://"]
+        .iter()
+        .any(|scheme| model.starts_with(scheme))
+}
+
+/// Directory of the downloaded models, `HERRO_MODELS` if it is set,
+/// otherwise `herro/models` in the XDG cache directory.
+fn cache_dir() -> Result<PathBuf> {
+    if let Some(dir) = env::var_os("HERRO_MODELS") {
+        return Ok(PathBuf::from(dir));
+    }
+
+    let cache = env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .ok_or_else(|| {
+            HerroError::Download(
+                "cannot find the cache directory, set HERRO_MODELS or XDG_CACHE_HOME".to_string(),
+            )
+        })?;
+    Ok(cache.join("herro").join("models"))
+}
+
+pub(crate) fn sha256<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Path of the model downloaded from the URL in the cache directory. Its name
+/// starts with a hash of the full URL, so models with the same file name from
+/// different URLs do not replace each other.
+fn cache_path(url: &str, dir: &Path) -> Result<PathBuf> {
+    let name = url
+        .rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| HerroError::Download(format!("no file name in {}", url)))?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(url.as_bytes());
+    let url_hash = format!("{:x}", hasher.finalize());
+    Ok(dir.join(format!("{}-{}", &url_hash[..URL_HASH_LEN], name)))
+}
+
+fn check_sha256(path: &Path, expected: Option<&str>) -> Result<String> {
+    let checksum = sha256(path).map_err(|e| HerroError::from(e).in_file(path))?;
+    if let Some(expected) = expected.filter(|e| !e.eq_ignore_ascii_case(&checksum)) {
+        return Err(HerroError::Download(format!(
+            "checksum {} of {} does not match {}",
+            checksum,
+            path.display(),
+            expected
+        )));
+    }
+
+    Ok(checksum)
+}
+
+/// Downloads the URL to the path with curl, or with the AWS CLI for S3 URLs.
+fn download(url: &str, path: &Path) -> Result<()> {
+    let mut command = match url.starts_with("s3://") {
+        true => {
+            let mut command = Command::new("aws");
+            command.args(["s3", "cp", "--quiet", url]).arg(path);
+            command
+        }
+        false => {
+            let mut command = Command::new("curl");
+            command.args(["-fsSL", "-o"]).arg(path).arg(url);
+            command
+        }
+    };
+
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| HerroError::Download(format!("cannot run {}: {}", program, e)))?;
+    if !output.status.success() {
+        let _ = fs::remove_file(path);
+        return Err(HerroError::Download(format!(
+            "{} failed for {}: {}",
+            program,
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Returns the path of the model in the cache directory, downloading it from
+/// the URL if it is not cached yet. Downloads are checked against the
+/// expected checksum before they are moved into the cache. The checksum is
+/// stored next to the model and checked on every use.
+fn cached_model(url: &str, dir: &Path, expected: Option<&str>) -> Result<PathBuf> {
+    let path = cache_path(url, dir)?;
+    let checksum_path = path.with_extension(CHECKSUM_EXTENSION);
+
+    if !path.exists() {
+        fs::create_dir_all(dir).map_err(|e| HerroError::from(e).in_file(dir))?;
+        info!("Downloading model {} to {}", url, path.display());
+
+        // Models are moved to the cache only when they are complete and verified
+        let partial = path.with_extension(format!("{}.part", std::process::id()));
+        download(url, &partial)?;
+        let checksum = match check_sha256(&partial, expected) {
+            Ok(checksum) => checksum,
+            Err(e) => {
+                let _ = fs::remove_file(&partial);
+                return Err(e);
+            }
+        };
+        fs::write(&checksum_path, format!("{}\n", checksum))
+            .map_err(|e| HerroError::from(e).in_file(&checksum_path))?;
+        fs::rename(&partial, &path).map_err(|e| HerroError::from(e).in_file(&path))?;
+
+        // Spec of the model is optional
+        let name = &url[url.rfind('/').map_or(0, |i| i + 1)..];
+        let spec_name = Path::new(name).with_extension("json");
+        let spec_url = format!("{}{}", &url[..url.len() - name.len()], spec_name.display());
+        if let Err(e) = download(&spec_url, &ModelSpec::path(&path)) {
+            debug!("Model has no spec: {}", e);
+        }
+        return Ok(path);
+    }
+
+    let stored = fs::read_to_string(&checksum_path).ok();
+    let expected = expected.or(stored.as_deref().map(str::trim));
+    check_sha256(&path, expected).map_err(|e| match e {
+        HerroError::Download(message) => {
+            HerroError::Download(format!("{}, remove the file to download it again", message))
+        }
+        e => e,
+    })?;
+
+    Ok(path)
+}
+
+/// Resolves the model given with `-m`: a path or a URL (http, https or s3).
+/// Models given by URL are downloaded to the model cache on first use. If a
+/// SHA-256 checksum is given, the model has to match it, model files are not
+/// hashed otherwise.
+pub fn fetch_model(model: &str, sha256_hex: Option<&str>) -> Result<String> {
+    if !is_url(model) {
+        if sha256_hex.is_some() {
+            check_sha256(Path::new(model), sha256_hex)?;
+        }
+        return Ok(model.to_string());
+    }
+
+    let path = cached_model(model, &cache_dir()?, sha256_hex)?;
+    Ok(path.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{cache_path, cached_model, fetch_model, is_url, sha256};
+
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn model_urls() {
+        assert!(is_url("https://example.org/model.pt"));
+        assert!(is_url("s3://herro.store.genome.sg/models/model_v0.1.pt"));
+        assert!(!is_url("models/model_v0.1.pt"));
+
+        // Model files are only hashed with a checksum
+        let path = std::env::temp_dir().join(format!("herro-model-{}.pt", std::process::id()));
+        let model = path.to_string_lossy();
+        assert_eq!(fetch_model(&model, None).unwrap(), model);
+        fs::write(&path, "abc").unwrap();
+        assert!(fetch_model(&model, Some(ABC_SHA256)).is_ok());
+        assert!(fetch_model(&model, Some(&ABC_SHA256.replace('b', "c"))).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cached_models() {
+        let dir = std::env::temp_dir().join(format!("herro-models-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let url = "https://example.org/models/model.pt";
+        let path = cache_path(url, &dir).unwrap();
+        fs::write(&path, "abc").unwrap();
+        assert_eq!(sha256(&path).unwrap(), ABC_SHA256);
+
+        // Models with the same name from other URLs are cached separately
+        let other = cache_path("https://example.com/models/model.pt", &dir).unwrap();
+        assert_ne!(other, path);
+        assert!(other.to_string_lossy().ends_with("-model.pt"));
+
+        // Cached models are not downloaded again
+        assert_eq!(cached_model(url, &dir, Some(ABC_SHA256)).unwrap(), path);
+        assert_eq!(cached_model(url, &dir, None).unwrap(), path);
+        assert!(cached_model(url, &dir, Some(&ABC_SHA256.replace('b', "c"))).is_err());
+
+        // Checksum stored with the model is checked when none is given
+        fs::write(path.with_extension("sha256"), "0000\n").unwrap();
+        assert!(cached_model(url, &dir, None).is_err());
+        assert!(cached_model(url, &dir, Some(ABC_SHA256)).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,475 @@
+use rustc_hash::FxHashMap as HashMap;
+
+use std::ops::Range;
+use std::thread;
+
+use itertools::Itertools;
+
+use crate::error::Result;
+use crate::haec_io::HAECRecord;
+use crate::overlaps::{check_overlap, Alignment, Overlap, OverlapFilter, PairSelector, Strand};
+
+// Same seeding and chaining parameters as the minimap2 call in mm2.rs
+const KMER_SIZE: usize = 25;
+const MINIMIZER_WINDOW: usize = 17;
+const MAX_OCCURRENCE_FRACTION: f64 = 2e-4;
+const MIN_CHAIN_SCORE: i32 = 4_000;
+const MAX_GAP: i64 = 5_000;
+const CHAIN_BAND_WIDTH: i64 = 500;
+const CHAIN_LOOKBACK: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Minimizer {
+    hash: u64,
+    pos: u32,
+    is_reversed: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Hit {
+    tid: u32,
+    pos: u32,
+    is_reversed: bool,
+}
+
+/// Seed match between the query and the target. Query position is given on
+/// the reverse complement of the query for reverse strand matches, so the
+/// positions of co-linear anchors increase on both reads.
+#[derive(Debug, Clone, Copy)]
+struct Anchor {
+    tid: u32,
+    is_reversed: bool,
+    tpos: u32,
+    qpos: u32,
+}
+
+/// Invertible integer hash used by minimap2, so that minimizers are not biased
+/// towards poly-A k-mers.
+fn hash64(mut key: u64, mask: u64) -> u64 {
+    key = (!key).wrapping_add(key << 21) & mask;
+    key ^= key >> 24;
+    key = (key.wrapping_add(key << 3).wrapping_add(key << 8)) & mask;
+    key ^= key >> 14;
+    key = (key.wrapping_add(key << 2).wrapping_add(key << 4)) & mask;
+    key ^= key >> 28;
+    key = key.wrapping_add(key << 31) & mask;
+    key
+}
+
+/// Computes (w, k)-minimizers of the canonical k-mers. K-mers equal to their
+/// reverse complement are skipped since their strand is ambiguous.
+fn minimizers(seq: &[u8], k: usize, w: usize) -> Vec<Minimizer> {
+    if seq.len() < k {
+        return Vec::new();
+    }
+
+    let mask = (1u64 << (2 * k)) - 1;
+    let shift = 2 * (k - 1);
+    let (mut fwd, mut rev) = (0u64, 0u64);
+
+    let kmers: Vec<_> = seq
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &b)| {
+            let c = match b {
+                b'C' | b'c' => 1,
+                b'G' | b'g' => 2,
+                b'T' | b't' => 3,
+                _ => 0,
+            };
+            fwd = ((fwd << 2) | c) & mask;
+            rev = (rev >> 2) | ((3 ^ c) << shift);
+
+            if i + 1 < k {
+                return None;
+            }
+
+            let pos = (i + 1 - k) as u32;
+            let kmer = match fwd.cmp(&rev) {
+                std::cmp::Ordering::Less => Minimizer {
+                    hash: hash64(fwd, mask),
+                    pos,
+                    is_reversed: false,
+                },
+                std::cmp::Ordering::Greater => Minimizer {
+                    hash: hash64(rev, mask),
+                    pos,
+                    is_reversed: true,
+                },
+                std::cmp::Ordering::Equal => Minimizer {
+                    hash: u64::MAX,
+                    pos,
+                    is_reversed: false,
+                },
+            };
+            Some(kmer)
+        })
+        .collect();
+
+    let mut result: Vec<Minimizer> = Vec::new();
+    for window in kmers.windows(w.min(kmers.len())) {
+        let min = window.iter().min_by_key(|m| m.hash).unwrap();
+        if min.hash == u64::MAX {
+            continue;
+        }
+
+        match result.last() {
+            Some(last) if last.pos == min.pos => (),
+            _ => result.push(*min),
+        }
+    }
+
+    result
+}
+
+/// Minimizer index of the target reads in the batch. Hits are sorted by the
+/// minimizer hash and each hash points to its range of hits.
+struct MinimizerIndex {
+    hits: Vec<Hit>,
+    buckets: HashMap<u64, (usize, usize)>,
+}
+
+impl MinimizerIndex {
+    fn new(targets: &[HAECRecord], offset: u32, threads: usize) -> Result<Self> {
+        let chunk_size = targets.len().div_ceil(threads).max(1);
+        let mut entries: Vec<(u64, Hit)> = thread::scope(|s| {
+            let handles: Vec<_> = targets
+                .chunks(chunk_size)
+                .enumerate()
+                .map(|(chunk_idx, chunk)| {
+                    s.spawn(move || {
+                        let mut buffer = Vec::new();
+                        let mut entries = Vec::new();
+                        for (i, read) in chunk.iter().enumerate() {
+                            let tid = offset + (chunk_idx * chunk_size + i) as u32;
+
+                            buffer.resize(read.seq.len(), 0);
+                            read.seq.get_sequence(&mut buffer)?;
+                            minimizers(&buffer, KMER_SIZE, MINIMIZER_WINDOW)
+                                .into_iter()
+                                .for_each(|m| {
+                                    let hit = Hit {
+                                        tid,
+                                        pos: m.pos,
+                                        is_reversed: m.is_reversed,
+                                    };
+                                    entries.push((m.hash, hit));
+                                });
+                        }
+
+                        Ok(entries)
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .flatten_ok()
+                .collect::<Result<_>>()
+        })?;
+        entries.sort_unstable_by_key(|(hash, _)| *hash);
+
+        let mut buckets = HashMap::default();
+        let mut start = 0;
+        for i in 1..=entries.len() {
+            if i == entries.len() || entries[i].0 != entries[start].0 {
+                buckets.insert(entries[start].0, (start, i));
+                start = i;
+            }
+        }
+
+        // Ignore the most frequent minimizers, they are usually repeats
+        let mut counts: Vec<_> = buckets.values().map(|(s, e)| e - s).collect();
+        counts.sort_unstable_by(|a, b| b.cmp(a));
+        if let Some(&max_occ) = counts.get((counts.len() as f64 * MAX_OCCURRENCE_FRACTION) as usize)
+        {
+            buckets.retain(|_, (s, e)| *e - *s <= max_occ);
+        }
+
+        let hits = entries.into_iter().map(|(_, hit)| hit).collect();
+        Ok(MinimizerIndex { hits, buckets })
+    }
+
+    fn get(&self, hash: u64) -> &[Hit] {
+        match self.buckets.get(&hash) {
+            Some(&(start, end)) => &self.hits[start..end],
+            None => &[],
+        }
+    }
+}
+
+fn gap_cost(gap: i64) -> i32 {
+    if gap == 0 {
+        return 0;
+    }
+
+    (0.01 * KMER_SIZE as f64 * gap as f64 + 0.5 * (gap as f64).log2()) as i32
+}
+
+/// Finds the best chain of anchors which all share the same target and strand.
+/// Anchors are sorted by the target position. Returns the chain score and the
+/// indices of the first and the last anchor in the chain.
+fn chain_anchors(anchors: &[Anchor]) -> (i32, usize, usize) {
+    let mut scores = vec![KMER_SIZE as i32; anchors.len()];
+    let mut starts: Vec<usize> = (0..anchors.len()).collect();
+
+    for i in 0..anchors.len() {
+        let (ti, qi) = (anchors[i].tpos as i64, anchors[i].qpos as i64);
+        for j in (i.saturating_sub(CHAIN_LOOKBACK)..i).rev() {
+            let dt = ti - anchors[j].tpos as i64;
+            let dq = qi - anchors[j].qpos as i64;
+            if dt <= 0 || dq <= 0 || dt > MAX_GAP || dq > MAX_GAP {
+                continue;
+            }
+
+            let gap = (dt - dq).abs();
+            if gap > CHAIN_BAND_WIDTH {
+                continue;
+            }
+
+            let score = scores[j] + dt.min(dq).min(KMER_SIZE as i64) as i32 - gap_cost(gap);
+            if score > scores[i] {
+                scores[i] = score;
+                starts[i] = starts[j];
+            }
+        }
+    }
+
+    let (end, &score) = scores.iter().enumerate().max_by_key(|(_, &s)| s).unwrap();
+    (score, starts[end], end)
+}
+
+/// Finds the overlaps between the query and the indexed targets. At most one
+/// overlap per target and strand is reported, together with its score given
+/// as (chain score, alignment block length).
+fn overlap_query(index: &MinimizerIndex, qid: u32, query: &[u8]) -> Vec<(Alignment, (u32, u32))> {
+    let qlen = query.len() as u32;
+
+    let mut anchors = Vec::new();
+    for m in minimizers(query, KMER_SIZE, MINIMIZER_WINDOW) {
+        for hit in index.get(m.hash) {
+            if hit.tid == qid {
+                continue;
+            }
+
+            let is_reversed = hit.is_reversed != m.is_reversed;
+            let qpos = if is_reversed {
+                qlen - m.pos - KMER_SIZE as u32
+            } else {
+                m.pos
+            };
+
+            anchors.push(Anchor {
+                tid: hit.tid,
+                is_reversed,
+                tpos: hit.pos,
+                qpos,
+            });
+        }
+    }
+    anchors.sort_unstable_by_key(|a| (a.tid, a.is_reversed, a.tpos, a.qpos));
+
+    let mut overlaps = Vec::new();
+    let mut group_start = 0;
+    for i in 1..=anchors.len() {
+        if i < anchors.len()
+            && anchors[i].tid == anchors[group_start].tid
+            && anchors[i].is_reversed == anchors[group_start].is_reversed
+        {
+            continue;
+        }
+
+        let group = &anchors[group_start..i];
+        group_start = i;
+
+        let (score, first, last) = chain_anchors(group);
+        if score < MIN_CHAIN_SCORE {
+            continue;
+        }
+
+        let (first, last) = (group[first], group[last]);
+        let (tstart, tend) = (first.tpos, last.tpos + KMER_SIZE as u32);
+        let (qstart, qend) = (first.qpos, last.qpos + KMER_SIZE as u32);
+        let (strand, qstart, qend) = if first.is_reversed {
+            (Strand::Reverse, qlen - qend, qlen - qstart)
+        } else {
+            (Strand::Forward, qstart, qend)
+        };
+
+        let block_len = (tend - tstart).max(qend - qstart);
+        let overlap = Overlap::new(
+            qid, qlen, qstart, qend, strand, first.tid, 0, // Set by the caller
+            tstart, tend,
+        );
+        overlaps.push((
+            Alignment::new(overlap, Vec::new()),
+            (score as u32, block_len),
+        ));
+    }
+
+    overlaps
+}
+
+/// Built-in all-vs-all overlapper, used as an alternative to minimap2. Reads
+/// are indexed in batches, same as with minimap2, and all reads are mapped
+/// against every batch. Overlaps don't contain CIGARs, so they are aligned
+/// during feature generation.
+pub(crate) fn overlap_batches<'a>(
+    reads: &'a [HAECRecord],
+    targets: Vec<Range<usize>>,
+    threads: usize,
+    overlap_filter: OverlapFilter,
+) -> impl Iterator<Item = Result<PairSelector>> + 'a {
+    targets.into_iter().map(move |targets| {
+        let offset = targets.start as u32;
+        let batch_ids = offset..targets.end as u32;
+        let index = MinimizerIndex::new(&reads[targets], offset, threads)?;
+
+        let chunk_size = reads.len().div_ceil(threads).max(1);
+        let overlaps: Vec<_> = thread::scope(|s| {
+            let handles: Vec<_> = reads
+                .chunks(chunk_size)
+                .enumerate()
+                .map(|(chunk_idx, chunk)| {
+                    let index = &index;
+                    s.spawn(move || {
+                        let mut buffer = Vec::new();
+                        let mut overlaps = Vec::new();
+                        for (i, read) in chunk.iter().enumerate() {
+                            let qid = (chunk_idx * chunk_size + i) as u32;
+
+                            buffer.resize(read.seq.len(), 0);
+                            read.seq.get_sequence(&mut buffer)?;
+                            overlaps.extend(overlap_query(index, qid, &buffer));
+                        }
+
+                        Ok(overlaps)
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .flatten_ok()
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        let mut selector = PairSelector::new(overlap_filter.keep_first).with_targets(batch_ids);
+        for (mut alignment, score) in overlaps {
+            alignment.overlap.tlen = reads[alignment.overlap.tid as usize].seq.len() as u32;
+            match check_overlap(&alignment.overlap, &overlap_filter) {
+                Ok(()) => selector.insert(alignment, score),
+                Err(rejection) => selector.reject(rejection),
+            }
+        }
+
+        Ok(selector)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::haec_io::{HAECRecord, HAECSeq};
+    use crate::overlaps::{target_batches, OverlapFilter, Strand};
+    use crate::TargetBatch;
+
+    use super::{minimizers, overlap_batches, KMER_SIZE, MINIMIZER_WINDOW};
+
+    fn random_sequence(length: usize) -> Vec<u8> {
+        let mut state = 42u64;
+        (0..length)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                b"ACGT"[(state >> 62) as usize]
+            })
+            .collect()
+    }
+
+    fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+        seq.iter()
+            .rev()
+            .map(|b| match b {
+                b'A' => b'T',
+                b'C' => b'G',
+                b'G' => b'C',
+                _ => b'A',
+            })
+            .collect()
+    }
+
+    fn record(id: &str, seq: &[u8]) -> HAECRecord {
+        HAECRecord {
+            id: id.into(),
+            description: None,
+            seq: HAECSeq::from(seq),
+            qual: vec![b'!'; seq.len()].into(),
+        }
+    }
+
+    #[test]
+    fn minimizers_are_canonical() {
+        let seq = random_sequence(1_000);
+        let fwd = minimizers(&seq, KMER_SIZE, MINIMIZER_WINDOW);
+        let rev = minimizers(&reverse_complement(&seq), KMER_SIZE, MINIMIZER_WINDOW);
+
+        assert!(!fwd.is_empty());
+        assert_eq!(fwd.len(), rev.len());
+        fwd.iter().zip(rev.iter().rev()).for_each(|(f, r)| {
+            assert_eq!(f.hash, r.hash);
+            assert_eq!(f.pos as usize, seq.len() - KMER_SIZE - r.pos as usize);
+            assert_ne!(f.is_reversed, r.is_reversed);
+        });
+    }
+
+    #[test]
+    fn overlap_reads() {
+        let genome = random_sequence(20_000);
+        let reads = vec![
+            record("r0", &genome[..12_000]),
+            record("r1", &genome[6_000..18_000]),
+            record("r2", &reverse_complement(&genome[3_000..15_000])),
+        ];
+
+        let targets = target_batches(reads.iter().map(|r| r.seq.len()), TargetBatch::default());
+        let batches: Vec<_> = overlap_batches(&reads, targets, 2, OverlapFilter::default())
+            .map(|selector| selector.unwrap().into_alignments())
+            .collect();
+        assert_eq!(batches.len(), 1);
+
+        let alns = &batches[0][&0];
+        assert_eq!(alns.len(), 2);
+        assert_eq!(batches[0][&1].len(), 2);
+        assert!(alns
+            .iter()
+            .all(|a| (a.overlap.tlen, a.overlap.qlen) == (12_000, 12_000)));
+
+        // Each pair is kept once, in either orientation, so the spans are
+        // given from the side of r0
+        let spans = |qid| {
+            let aln = alns
+                .iter()
+                .find(|a| a.overlap.return_other_id(0) == qid)
+                .unwrap();
+            let ovlp = &aln.overlap;
+            let other = match ovlp.tid == 0 {
+                true => (ovlp.qstart, ovlp.qend),
+                false => (ovlp.tstart, ovlp.tend),
+            };
+            (ovlp.strand, aln.target_span(0), other)
+        };
+
+        let (strand, (tstart, tend), (qstart, qend)) = spans(1);
+        assert_eq!(strand, Strand::Forward);
+        assert!(tstart.abs_diff(6_000) < 50 && tend.abs_diff(12_000) < 50);
+        assert!(qstart < 50 && qend.abs_diff(6_000) < 50);
+
+        let (strand, (tstart, tend), (qstart, qend)) = spans(2);
+        assert_eq!(strand, Strand::Reverse);
+        assert!(tstart.abs_diff(3_000) < 50 && tend.abs_diff(12_000) < 50);
+        assert!(qstart.abs_diff(3_000) < 50 && qend.abs_diff(12_000) < 50);
+    }
+}
@@ -1,28 +1,135 @@
-use crossbeam_channel::Sender;
+use clap::ValueEnum;
+use crossbeam_channel::{bounded, Sender};
 use glob::glob;
 use rustc_hash::FxHashMap as HashMap;
 use rustc_hash::FxHashSet as HashSet;
+use tracing::{debug, info, warn};
 use zstd::stream::AutoFinishEncoder;
 use zstd::Encoder;
 
+use std::env;
 use std::fmt;
 
 use std::fs::create_dir_all;
-use std::fs::File;
-use std::io::prelude::*;
+use std::fs::{File, OpenOptions};
 use std::io::BufReader;
 use std::io::BufWriter;
-use std::path::Path;
+use std::io::{self, prelude::*};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Instant;
 
 use crate::aligners::{cigar_to_string, CigarOp};
+use crate::error::{HerroError, Result};
+use crate::haec_io::bytes_to_f32;
 use crate::haec_io::bytes_to_u32;
+use crate::haec_io::open_text_file;
 use crate::haec_io::HAECRecord;
+use crate::m4;
 use crate::mm2;
+use crate::overlapper;
+use crate::sam;
+use crate::tempdir::TempDir;
+use crate::WindowReadSelection;
 
 use crate::pbars::PBarNotification;
 use crate::AlnMode;
+use crate::TargetBatch;
 use crate::LINE_ENDING;
-use crate::READS_BATCH_SIZE;
+
+const CIGAR_TAG: &[u8] = b"cg:Z:";
+const GAP_DIVERGENCE_TAG: &[u8] = b"de:f:";
+const SEQ_DIVERGENCE_TAG: &[u8] = b"dv:f:";
+const ALIGNMENT_TYPE_TAG: &[u8] = b"tp:A:";
+/// Mapping quality of alignments without one (PAF and SAM)
+const MISSING_MAPQ: u8 = 255;
+
+const PAF_CHUNK_SIZE: usize = 1 << 22;
+/// Number of reported examples of the skipped malformed lines
+const MAX_MALFORMED_EXAMPLES: usize = 3;
+/// Resolution of the coverage used for subsampling the overlaps
+const COVERAGE_BIN_SIZE: usize = 256;
+/// Memory of the overlaps buffered while splitting them by batch
+const SPLIT_BUFFERS_SIZE: usize = 256 << 20;
+const MIN_SPLIT_FLUSH_SIZE: usize = 64 << 10;
+/// Split overlaps are written once and read once, so they favour speed
+const SPLIT_ZSTD_LEVEL: i32 = 1;
+
+/// Alignment with its (residue matches, alignment block length) score
+type ScoredAlignment = (Alignment, (u32, u32));
+/// Parsed overlap, or the reason it was discarded
+pub(crate) type PafRecord = std::result::Result<ScoredAlignment, Rejection>;
+
+/// Reason for discarding an overlap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Rejection {
+    /// Query or target read is not in the reads (or is shorter than the window)
+    UnknownRead,
+    /// Neither read is in the current batch of target reads
+    OtherTarget,
+    SelfOverlap,
+    /// Target and query spans differ more than the maximum span ratio
+    SpanRatio,
+    /// Unaligned overhang is larger than the overlap threshold (internal overlap)
+    Overhang,
+    /// Divergence is larger than the maximum divergence
+    Divergence,
+    /// Mapping quality is lower than the minimum mapping quality
+    MappingQuality,
+    /// Alignment is not primary (tp:A tag other than P), only primary
+    /// alignments are kept
+    Secondary,
+    /// Target or query span is shorter than the minimum overlap length
+    ShortOverlap,
+}
+
+impl Rejection {
+    pub(crate) fn description(&self) -> &'static str {
+        match self {
+            Self::UnknownRead => "unknown or short read",
+            Self::OtherTarget => "reads in another batch",
+            Self::SelfOverlap => "self-overlap",
+            Self::SpanRatio => "span ratio",
+            Self::Overhang => "overhang (internal overlap)",
+            Self::Divergence => "divergence",
+            Self::MappingQuality => "mapping quality",
+            Self::Secondary => "secondary alignment",
+            Self::ShortOverlap => "short overlap",
+        }
+    }
+
+    /// Name of the filter in the run report
+    pub(crate) fn key(&self) -> &'static str {
+        match self {
+            Self::UnknownRead => "unknown_read",
+            Self::OtherTarget => "other_target",
+            Self::SelfOverlap => "self_overlap",
+            Self::SpanRatio => "span_ratio",
+            Self::Overhang => "overhang",
+            Self::Divergence => "divergence",
+            Self::MappingQuality => "mapping_quality",
+            Self::Secondary => "secondary",
+            Self::ShortOverlap => "short_overlap",
+        }
+    }
+}
+
+/// Number of the reasons for discarding an overlap
+const N_REJECTIONS: usize = 9;
+/// Rejections counted in the run report. Overlaps of targets in other batches
+/// are read again with their batch.
+const COUNTED_REJECTIONS: [Rejection; N_REJECTIONS - 1] = [
+    Rejection::UnknownRead,
+    Rejection::SelfOverlap,
+    Rejection::SpanRatio,
+    Rejection::Overhang,
+    Rejection::Divergence,
+    Rejection::MappingQuality,
+    Rejection::Secondary,
+    Rejection::ShortOverlap,
+];
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Strand {
@@ -88,15 +195,42 @@ impl Overlap {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Alignment {
     pub overlap: Overlap,
     pub cigar: Vec<CigarOp>,
+    /// Residue matches and alignment block length, set when the alignment is
+    /// selected for its read pair
+    pub(crate) score: (u32, u32),
 }
 
 impl Alignment {
     pub fn new(overlap: Overlap, cigar: Vec<CigarOp>) -> Self {
-        Alignment { overlap, cigar }
+        Alignment {
+            overlap,
+            cigar,
+            score: (0, 0),
+        }
+    }
+
+    /// Divergence of the reads estimated from the residue matches and the
+    /// alignment block length, None if they are not known
+    pub(crate) fn divergence(&self) -> Option<f32> {
+        let (matches, block_len) = self.score;
+        match block_len {
+            0 => None,
+            _ => Some(1. - matches as f32 / block_len as f32),
+        }
+    }
+
+    /// Region of the target read covered by the alignment, which can be given
+    /// by either read of the overlap
+    pub(crate) fn target_span(&self, tid: u32) -> (u32, u32) {
+        let overlap = &self.overlap;
+        match overlap.tid == tid {
+            true => (overlap.tstart, overlap.tend),
+            false => (overlap.qstart, overlap.qend),
+        }
     }
 }
 
@@ -114,83 +248,685 @@ impl PartialEq for Overlap {
 
 impl Eq for Overlap {}
 
+/// Malformed PAF lines which were skipped, with the first few of them as
+/// examples.
+#[derive(Debug, Clone, Default)]
+pub struct MalformedLines {
+    count: usize,
+    examples: Vec<(usize, String)>,
+}
+
+impl MalformedLines {
+    /// Adds the malformed line, or returns it as an error if malformed lines
+    /// are not allowed.
+    pub(crate) fn add(&mut self, line: usize, message: String, strict: bool) -> Result<()> {
+        if strict {
+            return Err(HerroError::InvalidLine { line, message });
+        }
+
+        self.count += 1;
+        if self.examples.len() < MAX_MALFORMED_EXAMPLES {
+            self.examples.push((line, message));
+        }
+        Ok(())
+    }
+
+    /// Adds the lines skipped in the following part of the input.
+    fn extend(&mut self, other: MalformedLines) {
+        self.count += other.count;
+        let n_examples = MAX_MALFORMED_EXAMPLES - self.examples.len();
+        self.examples
+            .extend(other.examples.into_iter().take(n_examples));
+    }
+
+    #[cfg(test)]
+    pub(crate) fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Logs the number of skipped lines in the input and the examples.
+    pub(crate) fn report(&self, input: &dyn fmt::Display) {
+        if self.count == 0 {
+            return;
+        }
+
+        let examples: Vec<_> = self
+            .examples
+            .iter()
+            .map(|(line, message)| format!("line {}: {}", line, message))
+            .collect();
+        warn!(
+            "Skipped {} malformed lines in {}, e.g. {}",
+            self.count,
+            input,
+            examples.join("; ")
+        );
+    }
+}
+
+/// Parses the overlaps in PAF. Malformed lines are skipped and added to
+/// `malformed`, unless the filter is strict, in which case parsing stops at
+/// the first one. The last line does not have to end with a newline. Valid
+/// overlaps are added to `selector`, which can already contain the overlaps
+/// from other files.
 pub fn parse_paf(
     mut reader: impl BufRead,
     name_to_id: &HashMap<&[u8], u32>,
+    overlap_filter: OverlapFilter,
+    targets: Option<&Range<u32>>,
     mut alns_writer: Option<&mut AutoFinishEncoder<BufWriter<File>>>,
-) -> HashMap<u32, Vec<Alignment>> {
-    //let mut reader = BufReader::new(read);
-
+    malformed: &mut MalformedLines,
+    selector: &mut impl OverlapSink,
+) -> Result<()> {
     let mut buffer = Vec::new();
-    let mut processed = HashSet::default();
-
-    //let mut alignments = Vec::new();
-    let mut tid_to_alns = HashMap::default();
-    while let Ok(len) = reader.read_until(LINE_ENDING, &mut buffer) {
+    for line_idx in 1.. {
+        buffer.clear();
+        let len = reader.read_until(LINE_ENDING, &mut buffer)?;
         if len == 0 {
             break;
         }
 
-        let mut data = buffer[..len - 1].split(|&c| c == b'\t');
-
-        let qid = match name_to_id.get(data.next().unwrap()) {
-            Some(qid) => *qid,
-            None => {
-                buffer.clear();
+        let line = buffer.strip_suffix(&[LINE_ENDING]).unwrap_or(&buffer);
+        let record = match classify_paf_record(line, name_to_id, &overlap_filter, targets) {
+            Ok(record) => record,
+            Err(message) => {
+                malformed.add(line_idx, message, overlap_filter.strict)?;
                 continue;
             }
         };
-        let qlen = bytes_to_u32(data.next().unwrap());
-        let qstart = bytes_to_u32(data.next().unwrap());
-        let qend = bytes_to_u32(data.next().unwrap());
-
-        let strand = match data.next().unwrap()[0] {
-            b'+' => Strand::Forward,
-            b'-' => Strand::Reverse,
-            _ => panic!("Invalid strand character."),
-        };
+        match record {
+            Ok((alignment, score)) => {
+                selector.insert(alignment, score);
 
-        let tid = match name_to_id.get(data.next().unwrap()) {
-            Some(tid) => *tid,
-            None => {
-                buffer.clear();
-                continue;
+                // All valid overlaps are saved, pair selection is repeated when reading
+                if let Some(ref mut aw) = alns_writer {
+                    aw.write_all(line)?;
+                    aw.write_all(&[LINE_ENDING])?;
+                }
             }
-        };
-        let tlen: u32 = bytes_to_u32(data.next().unwrap());
-        let tstart: u32 = bytes_to_u32(data.next().unwrap());
-        let tend: u32 = bytes_to_u32(data.next().unwrap());
+            Err(rejection) => selector.reject(rejection),
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses PAF using multiple threads. Reader thread splits the input into chunks
+/// of whole lines which are parsed by the workers. Parsed chunks are merged in
+/// the input order, so the result (and the skipped malformed lines) is the same
+/// as for `parse_paf`.
+pub(crate) fn parse_paf_parallel(
+    mut reader: impl BufRead + Send,
+    name_to_id: &HashMap<&[u8], u32>,
+    overlap_filter: OverlapFilter,
+    targets: Option<&Range<u32>>,
+    threads: usize,
+    malformed: &mut MalformedLines,
+    selector: &mut impl OverlapSink,
+) -> Result<()> {
+    if threads <= 1 {
+        return parse_paf(
+            reader,
+            name_to_id,
+            overlap_filter,
+            targets,
+            None,
+            malformed,
+            selector,
+        );
+    }
+
+    let (chunk_sender, chunk_receiver) = bounded(2 * threads);
+    let (parsed_sender, parsed_receiver) = bounded(2 * threads);
+    thread::scope(|s| {
+        let reader_handle = s.spawn(move || -> Result<()> {
+            let mut n_lines = 0;
+            for chunk_idx in 0.. {
+                let first_line = n_lines + 1;
+                let mut chunk = Vec::with_capacity(PAF_CHUNK_SIZE);
+                while chunk.len() < PAF_CHUNK_SIZE {
+                    if reader.read_until(LINE_ENDING, &mut chunk)? == 0 {
+                        break;
+                    }
+                    n_lines += 1;
+                }
+
+                // Sending fails if the workers stopped because of an error
+                if chunk.is_empty() || chunk_sender.send((chunk_idx, first_line, chunk)).is_err() {
+                    break;
+                }
+            }
+
+            Ok(())
+        });
+
+        for _ in 0..threads {
+            let chunk_r = chunk_receiver.clone();
+            let parsed_s = parsed_sender.clone();
+            let overlap_filter = &overlap_filter;
+
+            s.spawn(move || {
+                for (chunk_idx, first_line, chunk) in chunk_r {
+                    let mut chunk_malformed = MalformedLines::default();
+                    let records: Result<Vec<_>> = chunk
+                        .split(|&c| c == LINE_ENDING)
+                        .zip(first_line..)
+                        .filter(|(line, _)| !line.is_empty())
+                        .filter_map(|(line, line_idx)| {
+                            match classify_paf_record(line, name_to_id, overlap_filter, targets) {
+                                Ok(record) => Some(Ok(record)),
+                                Err(message) => chunk_malformed
+                                    .add(line_idx, message, overlap_filter.strict)
+                                    .err()
+                                    .map(Err),
+                            }
+                        })
+                        .collect();
+                    let parsed = records.map(|records| (records, chunk_malformed));
+
+                    if parsed_s.send((chunk_idx, parsed)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(chunk_receiver);
+        drop(parsed_sender);
+
+        // Chunks can arrive out of order
+        let mut pending = HashMap::default();
+        let mut next_idx = 0;
+        for (chunk_idx, parsed) in parsed_receiver {
+            pending.insert(chunk_idx, parsed);
+
+            while let Some(parsed) = pending.remove(&next_idx) {
+                let (records, chunk_malformed) = parsed?;
+                for record in records {
+                    match record {
+                        Ok((alignment, score)) => selector.insert(alignment, score),
+                        Err(rejection) => selector.reject(rejection),
+                    }
+                }
+                malformed.extend(chunk_malformed);
+                next_idx += 1;
+            }
+        }
+
+        reader_handle.join().unwrap()
+    })
+}
+
+/// Parses a single PAF line. Returns the reason why the overlap was discarded
+/// if any of the reads is not in the reads set or if the overlap is filtered
+/// out. Score is given as (residue matches, alignment block length). Error
+/// contains the description of the invalid field.
+fn classify_paf_record(
+    line: &[u8],
+    name_to_id: &HashMap<&[u8], u32>,
+    overlap_filter: &OverlapFilter,
+    targets: Option<&Range<u32>>,
+) -> std::result::Result<PafRecord, String> {
+    let mut data = line.split(|&c| c == b'\t');
+
+    let Some(&qid) = name_to_id.get(next_field(&mut data, "query name")?) else {
+        return Ok(Err(Rejection::UnknownRead));
+    };
+    let qlen = next_u32(&mut data, "query length")?;
+    let qstart = next_u32(&mut data, "query start")?;
+    let qend = next_u32(&mut data, "query end")?;
+
+    let strand = match next_field(&mut data, "strand")? {
+        b"+" => Strand::Forward,
+        b"-" => Strand::Reverse,
+        s => return Err(format!("invalid strand '{}'", String::from_utf8_lossy(s))),
+    };
+
+    let Some(&tid) = name_to_id.get(next_field(&mut data, "target name")?) else {
+        return Ok(Err(Rejection::UnknownRead));
+    };
+    if targets.is_some_and(|t| !t.contains(&tid) && !t.contains(&qid)) {
+        return Ok(Err(Rejection::OtherTarget));
+    }
+    let tlen = next_u32(&mut data, "target length")?;
+    let tstart = next_u32(&mut data, "target start")?;
+    let tend = next_u32(&mut data, "target end")?;
+
+    if qstart >= qend || qend > qlen || tstart >= tend || tend > tlen {
+        return Err("invalid overlap coordinates".to_string());
+    }
+
+    let overlap = Overlap::new(qid, qlen, qstart, qend, strand, tid, tlen, tstart, tend);
+    if qid == tid {
+        return Ok(Err(Rejection::SelfOverlap));
+    }
+    if let Err(rejection) = check_overlap(&overlap, overlap_filter) {
+        return Ok(Err(rejection));
+    }
+
+    let n_matches = next_u32(&mut data, "number of residue matches")?;
+    let block_len = next_u32(&mut data, "alignment block length")?;
+    let mapq = next_field(&mut data, "mapping quality")?;
+    let mapq = bytes_to_u32(mapq)
+        .and_then(|q| u8::try_from(q).ok())
+        .ok_or_else(|| {
+            format!(
+                "invalid mapping quality '{}'",
+                String::from_utf8_lossy(mapq)
+            )
+        })?;
+    if !overlap_filter.is_valid_mapq(mapq) {
+        return Ok(Err(Rejection::MappingQuality));
+    }
+
+    let (mut cigar, mut divergence, mut primary) = (None, None, true);
+    for field in data {
+        if let Some(c) = field.strip_prefix(CIGAR_TAG) {
+            cigar = Some(c);
+        } else if let Some(d) = field.strip_prefix(GAP_DIVERGENCE_TAG) {
+            divergence = Some(parse_divergence(d)?);
+        } else if let Some(d) = field.strip_prefix(SEQ_DIVERGENCE_TAG) {
+            // Gap-compressed divergence is preferred if both are present
+            divergence = divergence.or(Some(parse_divergence(d)?));
+        } else if let Some(t) = field.strip_prefix(ALIGNMENT_TYPE_TAG) {
+            primary = t == b"P";
+        }
+    }
+
+    if overlap_filter.primary_only && !primary {
+        return Ok(Err(Rejection::Secondary));
+    }
+
+    match (overlap_filter.max_divergence, divergence) {
+        (Some(max), Some(d)) if d > max => return Ok(Err(Rejection::Divergence)),
+        _ => (),
+    }
+
+    // Missing CIGAR -> overlap is aligned during feature generation
+    let cigar = cigar.map(parse_cigar).transpose()?.unwrap_or_default();
+
+    Ok(Ok((Alignment::new(overlap, cigar), (n_matches, block_len))))
+}
+
+pub(crate) fn next_field<'a>(
+    data: &mut impl Iterator<Item = &'a [u8]>,
+    name: &str,
+) -> std::result::Result<&'a [u8], String> {
+    data.next().ok_or_else(|| format!("missing {}", name))
+}
+
+pub(crate) fn next_u32<'a>(
+    data: &mut impl Iterator<Item = &'a [u8]>,
+    name: &str,
+) -> std::result::Result<u32, String> {
+    let field = next_field(data, name)?;
+    bytes_to_u32(field)
+        .ok_or_else(|| format!("invalid {} '{}'", name, String::from_utf8_lossy(field)))
+}
+
+fn parse_divergence(field: &[u8]) -> std::result::Result<f32, String> {
+    bytes_to_f32(field)
+        .ok_or_else(|| format!("invalid divergence '{}'", String::from_utf8_lossy(field)))
+}
+
+/// Filtering parameters for the overlaps used in correction.
+#[derive(Debug, Clone, Copy)]
+pub struct OverlapFilter {
+    /// Maximum number of unaligned bases at each end of the overlap
+    pub ol_threshold: u32,
+    /// Maximum ratio between the target and query spans (and vice versa)
+    pub max_span_ratio: f32,
+    /// Keep the first overlap for each read pair instead of the best one
+    pub keep_first: bool,
+    /// Maximum divergence given by the de:f (or dv:f) PAF tag
+    pub max_divergence: Option<f32>,
+    /// Maximum number of overlaps kept for each target read
+    pub max_overlaps: Option<usize>,
+    /// Maximum coverage of each target read by its overlaps
+    pub max_coverage: Option<u32>,
+    /// Minimum mapping quality (PAF column 12, MAPQ in SAM/BAM)
+    pub min_mapq: Option<u8>,
+    /// Minimum length of the target and query spans of the overlap
+    pub min_overlap_len: u32,
+    /// Stop at the first malformed PAF line instead of skipping it
+    pub strict: bool,
+    /// Keep only primary alignments (tp:A:P PAF tag, no secondary flag in
+    /// SAM/BAM), alignments without the tag are kept
+    pub primary_only: bool,
+    /// Number of bases by which overlaps are extended at each end before
+    /// realignment, at most `ol_threshold`
+    pub extend: u32,
+    /// Minimum accuracy of an overlap aligned to a window, less accurately
+    /// aligned overlaps do not support the window
+    pub min_window_accuracy: Option<f32>,
+    /// Order in which the overlaps of a window are chosen as its supporting
+    /// reads
+    pub window_reads: WindowReadSelection,
+}
+
+impl Default for OverlapFilter {
+    fn default() -> Self {
+        Self {
+            ol_threshold: 2500,
+            max_span_ratio: 1.111,
+            keep_first: false,
+            max_divergence: None,
+            max_overlaps: None,
+            max_coverage: None,
+            min_mapq: None,
+            min_overlap_len: 0,
+            primary_only: false,
+            strict: false,
+            extend: 2500,
+            min_window_accuracy: None,
+            window_reads: WindowReadSelection::default(),
+        }
+    }
+}
+
+impl OverlapFilter {
+    /// Mapping quality 255 means that it is not available, so such overlaps
+    /// are always kept.
+    pub(crate) fn is_valid_mapq(&self, mapq: u8) -> bool {
+        mapq == MISSING_MAPQ || self.min_mapq.is_none_or(|min| mapq >= min)
+    }
+}
+
+/// Overlap is valid if it is a dovetail overlap or one read is contained in the
+/// other one, allowing at most `ol_threshold` unaligned bases at each end.
+/// Internal overlaps are usually caused by repeats. Overlaps whose target and
+/// query spans differ too much or are shorter than the minimum overlap length
+/// are also rejected.
+pub(crate) fn is_valid_overlap(overlap: &Overlap, filter: &OverlapFilter) -> bool {
+    check_overlap(overlap, filter).is_ok()
+}
+
+/// Same as `is_valid_overlap`, but returns the filter which rejected the overlap.
+pub(crate) fn check_overlap(
+    overlap: &Overlap,
+    filter: &OverlapFilter,
+) -> std::result::Result<(), Rejection> {
+    let span = (overlap.tend - overlap.tstart).min(overlap.qend - overlap.qstart);
+    if span < filter.min_overlap_len {
+        return Err(Rejection::ShortOverlap);
+    }
+
+    let span_ratio =
+        (overlap.tend - overlap.tstart) as f32 / (overlap.qend - overlap.qstart) as f32;
+    if span_ratio < 1. / filter.max_span_ratio || span_ratio > filter.max_span_ratio {
+        return Err(Rejection::SpanRatio);
+    }
+
+    // Query coordinates in the target orientation
+    let (qstart, qend) = match overlap.strand {
+        Strand::Forward => (overlap.qstart, overlap.qend),
+        Strand::Reverse => (overlap.qlen - overlap.qend, overlap.qlen - overlap.qstart),
+    };
+
+    let left_overhang = overlap.tstart.min(qstart);
+    let right_overhang = (overlap.tlen - overlap.tend).min(overlap.qlen - qend);
+
+    if left_overhang > filter.ol_threshold || right_overhang > filter.ol_threshold {
+        return Err(Rejection::Overhang);
+    }
+
+    Ok(())
+}
+
+/// Extends the overlap at both ends by at most `extend` bases (bounded by the
+/// ends of both reads), so the unaligned overhangs are included when the
+/// overlap is realigned.
+pub(crate) fn extend_overlap(overlap: &mut Overlap, extend: u32) {
+    // Query overhangs in the target orientation
+    let (qleft, qright) = match overlap.strand {
+        Strand::Forward => (overlap.qstart, overlap.qlen - overlap.qend),
+        Strand::Reverse => (overlap.qlen - overlap.qend, overlap.qstart),
+    };
+
+    let left = overlap.tstart.min(qleft).min(extend);
+    let right = (overlap.tlen - overlap.tend).min(qright).min(extend);
+
+    overlap.tstart -= left;
+    overlap.tend += right;
+    match overlap.strand {
+        Strand::Forward => {
+            overlap.qstart -= left;
+            overlap.qend += right;
+        }
+        Strand::Reverse => {
+            overlap.qend += left;
+            overlap.qstart -= right;
+        }
+    }
+}
+
+/// Receiver of the parsed overlaps and of the reasons why the other overlaps
+/// were discarded.
+pub(crate) trait OverlapSink {
+    /// Score is given as (residue matches, alignment block length).
+    fn insert(&mut self, alignment: Alignment, score: (u32, u32));
+    fn reject(&mut self, rejection: Rejection);
+}
 
-        let cigar = data.last().unwrap();
-        let cigar = parse_cigar(&cigar[5..]);
+/// Keeps a single overlap per read pair, in either orientation, so the
+/// (q, t) and (t, q) records of all-vs-all overlaps are not processed twice.
+/// By default, the overlap with the most residue matches (ties broken by the
+/// alignment block length) is kept, otherwise the first overlap in the input
+/// is used. Self-overlaps are ignored.
+/// Kept overlaps are grouped by both of their reads which are targets, since
+/// the features of a read can be extracted from either side of the alignment.
+/// Overlaps discarded by the filters and the duplicates of the kept pairs are
+/// counted.
+pub(crate) struct PairSelector {
+    keep_first: bool,
+    /// Target reads of the batch, all reads if not given
+    targets: Option<Range<u32>>,
+    /// Index of the kept alignment of each pair, keyed by the smaller read id
+    pairs: HashMap<(u32, u32), usize>,
+    /// Kept alignments in the order of the input
+    alignments: Vec<Alignment>,
+    rejected: [u64; N_REJECTIONS],
+    n_duplicates: u64,
+}
 
-        if tid == qid {
+impl PairSelector {
+    pub(crate) fn new(keep_first: bool) -> Self {
+        PairSelector {
+            keep_first,
+            targets: None,
+            pairs: HashMap::default(),
+            alignments: Vec::new(),
+            rejected: [0; N_REJECTIONS],
+            n_duplicates: 0,
+        }
+    }
+
+    /// Groups the alignments only by the reads in `targets`.
+    pub(crate) fn with_targets(mut self, targets: Range<u32>) -> Self {
+        self.targets = Some(targets);
+        self
+    }
+
+    /// Adds the alignment unless the pair already has a better one.
+    /// Score is given as (residue matches, alignment block length).
+    pub(crate) fn insert(&mut self, mut alignment: Alignment, score: (u32, u32)) {
+        let (qid, tid) = (alignment.overlap.qid, alignment.overlap.tid);
+        if qid == tid {
             // Cannot have self-overlaps
-            buffer.clear();
-            continue;
+            self.reject(Rejection::SelfOverlap);
+            return;
         }
 
-        if processed.contains(&(qid, tid)) {
-            buffer.clear();
-            continue; // We assume the first overlap between two reads is the best one
+        alignment.score = score;
+        match self.pairs.get(&(qid.min(tid), qid.max(tid))) {
+            None => {
+                self.pairs
+                    .insert((qid.min(tid), qid.max(tid)), self.alignments.len());
+                self.alignments.push(alignment);
+            }
+            Some(&idx) => {
+                self.n_duplicates += 1;
+                if !self.keep_first && score > self.alignments[idx].score {
+                    self.alignments[idx] = alignment;
+                }
+            }
         }
-        processed.insert((qid, tid));
+    }
+
+    /// Counts the overlap discarded by the filter.
+    pub(crate) fn reject(&mut self, rejection: Rejection) {
+        self.rejected[rejection as usize] += 1;
+    }
+
+    /// Number of overlaps discarded for the reason.
+    pub(crate) fn n_rejected(&self, rejection: Rejection) -> u64 {
+        self.rejected[rejection as usize]
+    }
+
+    /// Number of overlaps dropped because their read pair was already seen.
+    pub(crate) fn n_duplicates(&self) -> u64 {
+        self.n_duplicates
+    }
+
+    /// Groups the kept alignments by their target reads. Alignments with both
+    /// reads in the targets are given to both of them, so a CIGAR of the
+    /// overlap is used for both reads, while overlaps without one are aligned
+    /// for each of them.
+    pub(crate) fn into_alignments(self) -> HashMap<u32, Vec<Alignment>> {
+        let is_target = |id| self.targets.as_ref().is_none_or(|t| t.contains(&id));
 
-        let overlap = Overlap::new(qid, qlen, qstart, qend, strand, tid, tlen, tstart, tend);
-        let alignment = Alignment::new(overlap, cigar);
+        let mut tid_to_alns: HashMap<_, Vec<_>> = HashMap::default();
+        for alignment in self.alignments {
+            let (qid, tid) = (alignment.overlap.qid, alignment.overlap.tid);
+            if is_target(qid) {
+                tid_to_alns.entry(qid).or_default().push(alignment.clone());
+            }
+            if is_target(tid) {
+                tid_to_alns.entry(tid).or_default().push(alignment);
+            }
+        }
         tid_to_alns
-            .entry(tid)
-            .or_insert_with(|| Vec::new())
-            .push(alignment);
+    }
+}
+
+impl OverlapSink for PairSelector {
+    fn insert(&mut self, alignment: Alignment, score: (u32, u32)) {
+        PairSelector::insert(self, alignment, score)
+    }
 
-        if let Some(ref mut aw) = alns_writer {
-            aw.write_all(&buffer[..len]).unwrap();
+    fn reject(&mut self, rejection: Rejection) {
+        PairSelector::reject(self, rejection)
+    }
+}
+
+/// Overlaps kept and dropped by each filter during a run, summed over the
+/// batches of target reads.
+#[derive(Debug, Default)]
+pub(crate) struct OverlapCounts {
+    kept: AtomicU64,
+    rejected: [AtomicU64; N_REJECTIONS],
+    duplicates: AtomicU64,
+    max_overlaps: AtomicU64,
+    max_coverage: AtomicU64,
+}
+
+impl OverlapCounts {
+    /// Adds the counts of the batch.
+    fn add_batch(&self, selector: &PairSelector) {
+        for (total, &n) in self.rejected.iter().zip(&selector.rejected) {
+            total.fetch_add(n, Ordering::Relaxed);
         }
+        self.duplicates
+            .fetch_add(selector.n_duplicates, Ordering::Relaxed);
+    }
 
-        buffer.clear();
+    /// Returns the number of kept overlaps and the number of overlaps dropped
+    /// by each filter.
+    pub(crate) fn counts(&self) -> (u64, Vec<(&'static str, u64)>) {
+        let mut dropped: Vec<_> = COUNTED_REJECTIONS
+            .iter()
+            .map(|&r| (r.key(), self.rejected[r as usize].load(Ordering::Relaxed)))
+            .collect();
+        dropped.extend([
+            ("duplicate_pair", self.duplicates.load(Ordering::Relaxed)),
+            ("max_overlaps", self.max_overlaps.load(Ordering::Relaxed)),
+            ("max_coverage", self.max_coverage.load(Ordering::Relaxed)),
+        ]);
+        (self.kept.load(Ordering::Relaxed), dropped)
+    }
+}
+
+/// Score of the SAM/BAM alignment computed from its CIGAR, same as the PAF
+/// residue matches and alignment block length (mismatches are counted as matches).
+pub(crate) fn cigar_score(cigar: &[CigarOp]) -> (u32, u32) {
+    cigar
+        .iter()
+        .fold((0, 0), |(matches, block_len), op| match op {
+            CigarOp::Match(l) => (matches + l, block_len + l),
+            _ => (matches, block_len + op.get_length()),
+        })
+}
+
+/// Keeps only the `max_overlaps` longest overlaps for each target read. Reads
+/// in repeats can have thousands of overlaps, most of which add little support.
+/// Returns the number of removed overlaps.
+pub(crate) fn prune_overlaps(
+    tid_to_alns: &mut HashMap<u32, Vec<Alignment>>,
+    max_overlaps: usize,
+) -> usize {
+    let mut n_pruned = 0;
+    tid_to_alns.values_mut().for_each(|alns| {
+        if alns.len() > max_overlaps {
+            alns.sort_by_key(|aln| std::cmp::Reverse(aln.overlap.tend - aln.overlap.tstart));
+            n_pruned += alns.len() - max_overlaps;
+            alns.truncate(max_overlaps);
+        }
+    });
+
+    n_pruned
+}
+
+/// Subsamples the overlaps of each target read to at most `max_coverage`
+/// overlaps at any position, counted in bins of `COVERAGE_BIN_SIZE` bases.
+/// Overlaps with the most residue matches are preferred, which are the long
+/// overlaps with high identity (the longest ones if the residue matches are not
+/// known), and an overlap is kept if it covers a bin below the limit. Returns
+/// the number of removed overlaps.
+pub(crate) fn subsample_overlaps(
+    tid_to_alns: &mut HashMap<u32, Vec<Alignment>>,
+    max_coverage: u32,
+) -> usize {
+    let mut n_removed = 0;
+    for (&tid, alns) in tid_to_alns.iter_mut() {
+        if alns.len() <= max_coverage as usize {
+            continue;
+        }
+
+        alns.sort_by_cached_key(|aln| {
+            let (start, end) = aln.target_span(tid);
+            std::cmp::Reverse((aln.score.0, end - start))
+        });
+
+        let tlen = match alns[0].overlap.tid == tid {
+            true => alns[0].overlap.tlen,
+            false => alns[0].overlap.qlen,
+        };
+        let mut coverage = vec![0u32; tlen as usize / COVERAGE_BIN_SIZE + 1];
+        let n_alns = alns.len();
+        alns.retain(|aln| {
+            let (start, end) = aln.target_span(tid);
+            let last = end.max(start + 1) as usize - 1;
+            let bins = &mut coverage[start as usize / COVERAGE_BIN_SIZE..=last / COVERAGE_BIN_SIZE];
+            if bins.iter().all(|&c| c >= max_coverage) {
+                return false;
+            }
+
+            bins.iter_mut().for_each(|c| *c += 1);
+            true
+        });
+        n_removed += n_alns - alns.len();
     }
 
-    tid_to_alns
+    n_removed
 }
 
 #[allow(dead_code)]
@@ -212,7 +948,7 @@ pub(crate) fn print_alignments(alignments: &[Alignment], reads: &[HAECRecord]) {
     }
 }
 
-fn parse_cigar(cigar: &[u8]) -> Vec<CigarOp> {
+pub(crate) fn parse_cigar(cigar: &[u8]) -> std::result::Result<Vec<CigarOp>, String> {
     let n_ops = cigar.iter().filter(|c| c.is_ascii_alphabetic()).count();
     let mut ops = Vec::with_capacity(n_ops);
 
@@ -225,122 +961,683 @@ fn parse_cigar(cigar: &[u8]) -> Vec<CigarOp> {
                 b'M' => ops.push(CigarOp::Match(l)),
                 b'I' => ops.push(CigarOp::Insertion(l)),
                 b'D' => ops.push(CigarOp::Deletion(l)),
-                _ => panic!("Invalid CIGAR character."),
+                _ => return Err(format!("invalid CIGAR operation '{}'", c as char)),
             }
 
             l = 0;
         }
     }
 
-    ops
+    Ok(ops)
 }
 
 pub(crate) fn generate_batches<'a, P, T>(
     reads: &'a [HAECRecord],
     name_to_id: &'a HashMap<&[u8], u32>,
     reads_path: P,
+    targets: Vec<Range<usize>>,
     threads: usize,
+    overlap_filter: OverlapFilter,
     alns_path: Option<T>,
-) -> impl Iterator<Item = HashMap<u32, Vec<Alignment>>> + 'a
+) -> impl Iterator<Item = Result<PairSelector>> + 'a
 where
     P: AsRef<Path>,
     P: 'a,
     T: AsRef<Path> + 'a,
 {
-    if let Some(ref ap) = alns_path {
-        create_dir_all(ap).unwrap();
-    }
-
-    reads
-        .chunks(READS_BATCH_SIZE)
+    targets
+        .into_iter()
         .enumerate()
-        .map(move |(batch_idx, batch)| {
-            let mm2_out = BufReader::new(mm2::call_mm2(batch, &reads_path, threads));
+        .map(move |(batch_idx, targets)| {
+            let batch_ids = targets.start as u32..targets.end as u32;
+            let batch = &reads[targets];
+            let mut writer = match alns_path {
+                Some(ref ap) => Some(create_batch_file(ap.as_ref(), batch_idx, batch)?),
+                None => None,
+            };
+
+            let mm2_out = BufReader::new(mm2::call_mm2(batch, &reads_path, threads)?);
+            let mut malformed = MalformedLines::default();
+            let mut selector = PairSelector::new(overlap_filter.keep_first).with_targets(batch_ids);
+            parse_paf(
+                mm2_out,
+                name_to_id,
+                overlap_filter,
+                None,
+                writer.as_mut(),
+                &mut malformed,
+                &mut selector,
+            )?;
 
-            let mut writer = alns_path.as_ref().map(|ap| {
-                let batch_path = ap.as_ref().join(format!("{batch_idx}.oec.zst"));
-                let file = File::create(batch_path).unwrap();
-                let mut w = Encoder::new(BufWriter::new(file), 0).unwrap().auto_finish();
+            malformed.report(&"the minimap2 output");
+            Ok(selector)
+        })
+}
 
-                // Write header
-                writeln!(&mut w, "{}", batch.len()).unwrap();
-                batch.iter().for_each(|r| {
-                    writeln!(&mut w, "{}", std::str::from_utf8(&r.id).unwrap()).unwrap()
-                });
+/// Creates the file for the overlaps of the batch and writes its header which
+/// contains the number of target reads and their names.
+fn create_batch_file(
+    alns_path: &Path,
+    batch_idx: usize,
+    batch: &[HAECRecord],
+) -> Result<AutoFinishEncoder<'static, BufWriter<File>>> {
+    let batch_path = alns_path.join(format!("{batch_idx}.oec.zst"));
+    let in_file = |e: io::Error| HerroError::from(e).in_file(&batch_path);
 
-                w
-            });
+    create_dir_all(alns_path).map_err(|e| HerroError::from(e).in_file(alns_path))?;
+    let file = File::create(&batch_path).map_err(in_file)?;
+    let mut w = Encoder::new(BufWriter::new(file), 0)
+        .map_err(in_file)?
+        .auto_finish();
 
-            parse_paf(mm2_out, &name_to_id, writer.as_mut())
-        })
+    // Write header
+    writeln!(&mut w, "{}", batch.len()).map_err(in_file)?;
+    for r in batch {
+        w.write_all(&r.id).map_err(in_file)?;
+        w.write_all(&[LINE_ENDING]).map_err(in_file)?;
+    }
+
+    Ok(w)
 }
 
 pub(crate) fn read_batches<'a, P>(
     name_to_id: &'a HashMap<&[u8], u32>,
     batches: P,
-) -> impl Iterator<Item = HashMap<u32, Vec<Alignment>>> + 'a
+    overlap_filter: OverlapFilter,
+    threads: usize,
+) -> Result<impl Iterator<Item = Result<PairSelector>> + 'a>
 where
     P: AsRef<Path>,
     P: 'a,
 {
     let g = batches.as_ref().join("*.oec.zst");
-    glob(g.to_str().unwrap()).unwrap().map(|p| {
-        let mut reader = {
-            let file = File::open(p.unwrap()).unwrap();
-            let reader = zstd::Decoder::new(file).unwrap();
-            BufReader::with_capacity(65_536, reader)
+    let paths = glob(&g.to_string_lossy())
+        .map_err(|e| HerroError::InvalidConfig(format!("invalid alignments directory: {}", e)))?;
+
+    Ok(paths.map(move |p| {
+        let path = p.map_err(|e| HerroError::from(io::Error::from(e)))?;
+        read_batch(&path, name_to_id, overlap_filter, threads).map_err(|e| e.in_file(&path))
+    }))
+}
+
+fn read_batch(
+    path: &Path,
+    name_to_id: &HashMap<&[u8], u32>,
+    overlap_filter: OverlapFilter,
+    threads: usize,
+) -> Result<PairSelector> {
+    let mut reader = {
+        let file = File::open(path)?;
+        let reader = zstd::Decoder::new(file)?;
+        BufReader::with_capacity(65_536, reader)
+    };
+
+    // Read number of target reads
+    let mut buf = Vec::new();
+    reader.read_until(LINE_ENDING, &mut buf)?;
+    let n_targets = buf
+        .strip_suffix(&[LINE_ENDING])
+        .and_then(bytes_to_u32)
+        .ok_or_else(|| HerroError::InvalidLine {
+            line: 1,
+            message: "invalid number of target reads".to_string(),
+        })?;
+
+    let mut tids = HashSet::default();
+    for _ in 0..n_targets {
+        buf.clear();
+        reader.read_until(LINE_ENDING, &mut buf)?;
+
+        let name = buf.strip_suffix(&[LINE_ENDING]).unwrap_or(&buf);
+        if let Some(&tid) = name_to_id.get(name) {
+            tids.insert(tid);
+        }
+    }
+
+    // Line numbers of the overlaps start after the header
+    let header_lines = n_targets as usize + 1;
+    let mut malformed = MalformedLines::default();
+    let mut selector = PairSelector::new(overlap_filter.keep_first);
+    // Batches are written for consecutive target reads
+    if let (Some(&first), Some(&last)) = (tids.iter().min(), tids.iter().max()) {
+        selector = selector.with_targets(first..last + 1);
+    }
+    parse_paf_parallel(
+        &mut reader,
+        name_to_id,
+        overlap_filter,
+        None,
+        threads,
+        &mut malformed,
+        &mut selector,
+    )
+    .map_err(|e| match e {
+        HerroError::InvalidLine { line, message } => HerroError::InvalidLine {
+            line: line + header_lines,
+            message,
+        },
+        e => e,
+    })?;
+
+    for (line, _) in malformed.examples.iter_mut() {
+        *line += header_lines;
+    }
+    malformed.report(&path.display());
+    Ok(selector)
+}
+
+/// Format of the overlaps given with `AlnMode::Overlaps`, detected from the
+/// file extension unless it is set in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OverlapsFormat {
+    Paf,
+    Sam,
+    Bam,
+    /// M4 written by MHAP and BLASR
+    M4,
+}
+
+impl OverlapsFormat {
+    pub(crate) fn from_path(path: &Path) -> Self {
+        // Ignore the compression extension, e.g. alns.paf.gz
+        let path = match path.extension().and_then(|e| e.to_str()) {
+            Some("gz" | "bgz" | "zst") => path.with_extension(""),
+            _ => path.to_owned(),
         };
 
-        // Read number of target reads
-        let mut buf = Vec::new();
-        let len = reader.read_until(LINE_ENDING, &mut buf).unwrap();
-        let n_targets = buf[..len - 1]
-            .iter()
-            .fold(0, |acc, &d| acc * 10 + (d - b'0') as u32);
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("sam") => Self::Sam,
+            Some("bam") => Self::Bam,
+            Some("m4" | "mhap") => Self::M4,
+            _ => Self::Paf,
+        }
+    }
+}
 
-        let _tids: HashSet<_> = (0..n_targets)
-            .filter_map(|_| {
-                buf.clear();
-                let len = reader.read_until(LINE_ENDING, &mut buf).unwrap();
+/// Splits the target reads, given by their lengths, into consecutive batches.
+pub(crate) fn target_batches(
+    lengths: impl ExactSizeIterator<Item = usize>,
+    target_batch: TargetBatch,
+) -> Vec<Range<usize>> {
+    let n_reads = lengths.len();
+    let mut batches = Vec::new();
+    let (mut start, mut n_bases) = (0, 0);
+    for (i, length) in lengths.enumerate() {
+        let is_full = match target_batch {
+            TargetBatch::Reads(n) => i - start == n,
+            TargetBatch::Bases(n) => i > start && n_bases + length as u64 > n,
+        };
+        if is_full {
+            batches.push(start..i);
+            (start, n_bases) = (i, 0);
+        }
+
+        n_bases += length as u64;
+    }
+
+    if start < n_reads {
+        batches.push(start..n_reads);
+    }
+    batches
+}
+
+/// Reads overlaps from the files in batches of target reads, so only the
+/// overlaps of a single batch are kept in memory. Overlaps from all files are
+/// merged, keeping a single overlap per read pair. With multiple batches, the
+/// files (or the standard input) are read only once and their valid overlaps
+/// are split by batch into temporary files, which are read back one batch at
+/// a time.
+pub(crate) fn read_overlaps<'a, P>(
+    name_to_id: &'a HashMap<&[u8], u32>,
+    overlaps_paths: Vec<P>,
+    targets: Vec<Range<usize>>,
+    overlaps_format: Option<OverlapsFormat>,
+    overlap_filter: OverlapFilter,
+    threads: usize,
+) -> Result<Box<dyn Iterator<Item = Result<PairSelector>> + 'a>>
+where
+    P: AsRef<Path>,
+    P: 'a,
+{
+    if targets.len() <= 1 {
+        let targets = targets
+            .first()
+            .map_or(0..0, |t| t.start as u32..t.end as u32);
+        let mut selector =
+            PairSelector::new(overlap_filter.keep_first).with_targets(targets.clone());
+        let parsed = overlaps_paths.iter().try_for_each(|path| {
+            parse_overlaps_file(
+                path.as_ref(),
+                name_to_id,
+                overlaps_format,
+                overlap_filter,
+                Some(&targets),
+                threads,
+                &mut selector,
+            )
+        });
+        return Ok(Box::new(std::iter::once(parsed.map(|_| selector))));
+    }
+
+    let mut splitter = OverlapSplitter::new(&targets)?;
+    for path in overlaps_paths.iter() {
+        parse_overlaps_file(
+            path.as_ref(),
+            name_to_id,
+            overlaps_format,
+            overlap_filter,
+            None,
+            threads,
+            &mut splitter,
+        )?;
+    }
+    let split = splitter.finish()?;
+
+    let batches = targets
+        .into_iter()
+        .enumerate()
+        .map(move |(batch_idx, targets)| {
+            let targets = targets.start as u32..targets.end as u32;
+            let selector = PairSelector::new(overlap_filter.keep_first).with_targets(targets);
+            let mut selector = split.read_batch(batch_idx, selector)?;
+            // Overlaps discarded while splitting are counted with the first batch
+            if batch_idx == 0 {
+                selector.rejected = split.rejected;
+            }
+            Ok(selector)
+        });
+    Ok(Box::new(batches))
+}
+
+/// Parses the overlaps in the file into `sink`, keeping only the overlaps of
+/// the `targets` if given. Skipped malformed lines are reported.
+fn parse_overlaps_file(
+    path: &Path,
+    name_to_id: &HashMap<&[u8], u32>,
+    overlaps_format: Option<OverlapsFormat>,
+    overlap_filter: OverlapFilter,
+    targets: Option<&Range<u32>>,
+    threads: usize,
+    sink: &mut impl OverlapSink,
+) -> Result<()> {
+    let reader = open_text_file(path)?;
+
+    let format = overlaps_format.unwrap_or_else(|| OverlapsFormat::from_path(path));
+    let mut malformed = MalformedLines::default();
+    let parsed = match format {
+        OverlapsFormat::Paf => parse_paf_parallel(
+            reader,
+            name_to_id,
+            overlap_filter,
+            targets,
+            threads,
+            &mut malformed,
+            sink,
+        ),
+        OverlapsFormat::Sam => sam::parse_sam(reader, name_to_id, overlap_filter, targets, sink),
+        OverlapsFormat::Bam => sam::parse_bam(reader, name_to_id, overlap_filter, targets, sink),
+        OverlapsFormat::M4 => m4::parse_m4(
+            reader,
+            name_to_id,
+            overlap_filter,
+            targets,
+            &mut malformed,
+            sink,
+        ),
+    };
+    parsed.map_err(|e| e.in_file(path))?;
+
+    malformed.report(&path.display());
+    Ok(())
+}
+
+/// Splits the valid overlaps by the batches of their reads into temporary
+/// files, each of them a sequence of zstd frames with the binary alignments.
+/// An overlap is written into the batches of both of its reads, where the
+/// best overlap of each read pair is selected when the batch is read back.
+struct OverlapSplitter {
+    dir: TempDir,
+    /// First target read of each batch
+    starts: Vec<u32>,
+    /// Encoded alignments of each batch, compressed and appended to the file
+    /// of the batch when they reach `flush_size`
+    buffers: Vec<Vec<u8>>,
+    flush_size: usize,
+    rejected: [u64; N_REJECTIONS],
+    /// First error while writing the files, since the sink cannot fail
+    error: Option<io::Error>,
+}
+
+/// Overlaps split by batch, removed when dropped.
+struct SplitOverlaps {
+    dir: TempDir,
+    rejected: [u64; N_REJECTIONS],
+}
+
+fn batch_path(dir: &Path, batch_idx: usize) -> PathBuf {
+    dir.join(format!("{}.alns.zst", batch_idx))
+}
+
+impl OverlapSplitter {
+    fn new(targets: &[Range<usize>]) -> Result<Self> {
+        let dir = TempDir::new_in(&env::temp_dir(), "herro-overlaps")?;
+        Ok(OverlapSplitter {
+            dir,
+            starts: targets.iter().map(|t| t.start as u32).collect(),
+            buffers: vec![Vec::new(); targets.len()],
+            flush_size: (SPLIT_BUFFERS_SIZE / targets.len()).max(MIN_SPLIT_FLUSH_SIZE),
+            rejected: [0; N_REJECTIONS],
+            error: None,
+        })
+    }
+
+    fn batch(&self, id: u32) -> usize {
+        self.starts.partition_point(|&start| start <= id) - 1
+    }
+
+    fn flush(&mut self, batch_idx: usize) -> io::Result<()> {
+        let buffer = &mut self.buffers[batch_idx];
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        let compressed = zstd::encode_all(&buffer[..], SPLIT_ZSTD_LEVEL)?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(batch_path(self.dir.path(), batch_idx))?
+            .write_all(&compressed)?;
+        buffer.clear();
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<SplitOverlaps> {
+        for batch_idx in 0..self.buffers.len() {
+            if let Err(e) = self.flush(batch_idx) {
+                self.error.get_or_insert(e);
+            }
+        }
+        if let Some(e) = self.error {
+            return Err(HerroError::from(e).in_file(self.dir.path()));
+        }
+
+        Ok(SplitOverlaps {
+            dir: self.dir,
+            rejected: self.rejected,
+        })
+    }
+}
+
+impl OverlapSink for OverlapSplitter {
+    fn insert(&mut self, alignment: Alignment, score: (u32, u32)) {
+        let (qid, tid) = (alignment.overlap.qid, alignment.overlap.tid);
+        if qid == tid {
+            self.reject(Rejection::SelfOverlap);
+            return;
+        }
+
+        let (qbatch, tbatch) = (self.batch(qid), self.batch(tid));
+        for batch_idx in [Some(qbatch), (tbatch != qbatch).then_some(tbatch)]
+            .into_iter()
+            .flatten()
+        {
+            write_alignment(&mut self.buffers[batch_idx], &alignment, score);
+            if self.buffers[batch_idx].len() >= self.flush_size {
+                if let Err(e) = self.flush(batch_idx) {
+                    self.error.get_or_insert(e);
+                    self.buffers[batch_idx].clear();
+                }
+            }
+        }
+    }
+
+    fn reject(&mut self, rejection: Rejection) {
+        self.rejected[rejection as usize] += 1;
+    }
+}
+
+impl SplitOverlaps {
+    /// Selects the overlaps of the batch, which has no file if none of its
+    /// reads has an overlap.
+    fn read_batch(&self, batch_idx: usize, mut selector: PairSelector) -> Result<PairSelector> {
+        let path = batch_path(self.dir.path(), batch_idx);
+        if !path.exists() {
+            return Ok(selector);
+        }
+
+        let read = || -> io::Result<PairSelector> {
+            // Decoder reads all concatenated frames
+            let mut reader = BufReader::new(zstd::Decoder::new(File::open(&path)?)?);
+            while let Some((alignment, score)) = read_alignment(&mut reader)? {
+                selector.insert(alignment, score);
+            }
+            Ok(selector)
+        };
+        read().map_err(|e| HerroError::from(e).in_file(&path))
+    }
+}
+
+/// Appends the alignment as little-endian numbers: the overlap, its score and
+/// the CIGAR operations with their kind in the lowest two bits.
+fn write_alignment(buffer: &mut Vec<u8>, alignment: &Alignment, score: (u32, u32)) {
+    let o = &alignment.overlap;
+    let strand = match o.strand {
+        Strand::Forward => 0,
+        Strand::Reverse => 1,
+    };
+    let numbers = [
+        o.qid,
+        o.qlen,
+        o.qstart,
+        o.qend,
+        strand,
+        o.tid,
+        o.tlen,
+        o.tstart,
+        o.tend,
+        score.0,
+        score.1,
+        alignment.cigar.len() as u32,
+    ];
+    numbers
+        .iter()
+        .for_each(|n| buffer.extend_from_slice(&n.to_le_bytes()));
 
-                name_to_id.get(&buf[..len - 1]).copied()
+    for op in alignment.cigar.iter() {
+        let kind = match op {
+            CigarOp::Match(_) => 0,
+            CigarOp::Mismatch(_) => 1,
+            CigarOp::Insertion(_) => 2,
+            CigarOp::Deletion(_) => 3,
+        };
+        buffer.extend_from_slice(&(op.get_length() << 2 | kind).to_le_bytes());
+    }
+}
+
+/// Reads the alignment written by `write_alignment`, None at the end of the
+/// input.
+fn read_alignment(reader: &mut impl BufRead) -> io::Result<Option<ScoredAlignment>> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+
+    let mut read_u32 = || -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        reader.read_exact(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    };
+    let mut numbers = [0; 12];
+    for n in numbers.iter_mut() {
+        *n = read_u32()?;
+    }
+    let [qid, qlen, qstart, qend, strand, tid, tlen, tstart, tend, matches, block_len, n_ops] =
+        numbers;
+    let strand = match strand {
+        0 => Strand::Forward,
+        _ => Strand::Reverse,
+    };
+
+    let cigar = (0..n_ops)
+        .map(|_| {
+            let op = read_u32()?;
+            Ok(match op & 3 {
+                0 => CigarOp::Match(op >> 2),
+                1 => CigarOp::Mismatch(op >> 2),
+                2 => CigarOp::Insertion(op >> 2),
+                _ => CigarOp::Deletion(op >> 2),
             })
-            .collect();
+        })
+        .collect::<io::Result<_>>()?;
 
-        parse_paf(&mut reader, name_to_id, None)
-    })
+    let overlap = Overlap::new(qid, qlen, qstart, qend, strand, tid, tlen, tstart, tend);
+    Ok(Some((Alignment::new(overlap, cigar), (matches, block_len))))
 }
 
+/// Sends the overlaps of the target reads, batch by batch. With `send_empty`,
+/// targets without overlaps are sent as well, so each target is corrected or
+/// reported as uncorrected.
 pub(crate) fn alignment_reader<T: AsRef<Path>, U: AsRef<Path>>(
     reads: &[HAECRecord],
     reads_path: &T,
     aln_mode: AlnMode<U>,
+    overlaps_format: Option<OverlapsFormat>,
     n_threads: usize,
+    overlap_filter: OverlapFilter,
+    trust_cigar: bool,
+    target_batch: TargetBatch,
+    skipped: &HashSet<u32>,
+    send_empty: bool,
     alns_sender: Sender<(u32, Vec<Alignment>)>,
     pbar_sender: Sender<PBarNotification>,
-) {
+    overlap_counts: &OverlapCounts,
+) -> Result<()> {
     let name_to_id: HashMap<_, _> = reads
         .iter()
         .enumerate()
         .map(|(i, e)| (&*e.id, i as u32))
         .collect();
+    let targets = target_batches(reads.iter().map(|r| r.seq.len()), target_batch);
 
-    let batches: Box<dyn Iterator<Item = HashMap<u32, Vec<Alignment>>>> = match aln_mode {
+    let batches: Box<dyn Iterator<Item = Result<PairSelector>>> = match aln_mode {
         AlnMode::None => {
-            let batches = generate_batches(&reads, &name_to_id, &reads_path, n_threads, None::<T>);
+            let batches = generate_batches(
+                &reads,
+                &name_to_id,
+                &reads_path,
+                targets,
+                n_threads,
+                overlap_filter,
+                None::<T>,
+            );
             Box::new(batches)
         }
         AlnMode::Read(path) => {
-            let batches = read_batches(&name_to_id, path);
+            let batches = read_batches(&name_to_id, path, overlap_filter, n_threads)?;
             Box::new(batches)
         }
         AlnMode::Write(path) => {
-            let batches = generate_batches(&reads, &name_to_id, &reads_path, n_threads, Some(path));
+            let batches = generate_batches(
+                &reads,
+                &name_to_id,
+                &reads_path,
+                targets,
+                n_threads,
+                overlap_filter,
+                Some(path),
+            );
+            Box::new(batches)
+        }
+        AlnMode::Overlaps(paths) => read_overlaps(
+            &name_to_id,
+            paths,
+            targets,
+            overlaps_format,
+            overlap_filter,
+            n_threads,
+        )?,
+        AlnMode::Internal => {
+            let batches = overlapper::overlap_batches(reads, targets, n_threads, overlap_filter);
             Box::new(batches)
         }
     };
 
-    for alignments in batches {
+    let mut batch_start = Instant::now();
+    for (batch_idx, selector) in batches.enumerate() {
+        let selector = selector?;
+        overlap_counts.add_batch(&selector);
+        let dropped: Vec<_> = COUNTED_REJECTIONS
+            .iter()
+            .map(|&r| (r.description(), selector.rejected[r as usize]))
+            .chain([("duplicate pair", selector.n_duplicates)])
+            .collect();
+        debug!(
+            "Batch {}: dropped overlaps by filter: {}",
+            batch_idx + 1,
+            describe_dropped(&dropped)
+        );
+        let batch_targets = selector.targets.clone();
+        let mut alignments = selector.into_alignments();
+        let n_overlaps: usize = alignments.values().map(|alns| alns.len()).sum();
+        debug!(
+            "Batch {}: {} target reads, {} overlaps, loaded in {:.2?}",
+            batch_idx + 1,
+            alignments.len(),
+            n_overlaps,
+            batch_start.elapsed()
+        );
+
+        // Targets corrected in the resumed run
+        if !skipped.is_empty() {
+            alignments.retain(|tid, _| !skipped.contains(tid));
+        }
+        if send_empty {
+            let batch_targets = batch_targets.unwrap_or(0..reads.len() as u32);
+            for tid in batch_targets.filter(|tid| !skipped.contains(tid)) {
+                alignments.entry(tid).or_default();
+            }
+        }
+
+        if let Some(max_overlaps) = overlap_filter.max_overlaps {
+            let n_pruned = prune_overlaps(&mut alignments, max_overlaps);
+            overlap_counts
+                .max_overlaps
+                .fetch_add(n_pruned as u64, Ordering::Relaxed);
+            debug!(
+                "Batch {}: dropped {} overlaps over the limit of {} per read",
+                batch_idx + 1,
+                n_pruned,
+                max_overlaps
+            );
+        }
+
+        if let Some(max_coverage) = overlap_filter.max_coverage {
+            let n_removed = subsample_overlaps(&mut alignments, max_coverage);
+            overlap_counts
+                .max_coverage
+                .fetch_add(n_removed as u64, Ordering::Relaxed);
+            debug!(
+                "Batch {}: dropped {} overlaps over the coverage of {}",
+                batch_idx + 1,
+                n_removed,
+                max_coverage
+            );
+        }
+
+        if !trust_cigar {
+            // Empty CIGARs are recomputed during feature generation
+            alignments
+                .values_mut()
+                .flatten()
+                .for_each(|aln| aln.cigar.clear());
+        }
+        if overlap_filter.extend > 0 {
+            alignments
+                .values_mut()
+                .flatten()
+                .filter(|aln| aln.cigar.is_empty())
+                .for_each(|aln| extend_overlap(&mut aln.overlap, overlap_filter.extend));
+        }
+
         /*let mut read_to_alns = HashMap::default();
         alignments.into_iter().for_each(|aln| {
             if tids.contains(&aln.overlap.tid) {
@@ -351,15 +1648,46 @@ pub(crate) fn alignment_reader<T: AsRef<Path>, U: AsRef<Path>>(
             }
         });*/
 
+        let n_kept = alignments.values().map(|alns| alns.len() as u64).sum();
+        overlap_counts.kept.fetch_add(n_kept, Ordering::Relaxed);
+
         // Notify pbar about the batch size
         pbar_sender
             .send(PBarNotification::BatchLen(alignments.len() as u64))
             .unwrap();
 
+        // Targets are sent in the order of the input, so the corrected reads
+        // arrive at the writers almost in order
+        let mut alignments: Vec<_> = alignments.into_iter().collect();
+        alignments.sort_unstable_by_key(|(tid, _)| *tid);
         alignments.into_iter().for_each(|example| {
             //println!("Aln reader: {}", alns_sender.len());
             alns_sender.send(example).unwrap();
         });
+
+        batch_start = Instant::now();
+    }
+
+    let (n_kept, dropped) = overlap_counts.counts();
+    info!(
+        "Kept {} overlaps, dropped overlaps by filter: {}",
+        n_kept,
+        describe_dropped(&dropped)
+    );
+    Ok(())
+}
+
+/// Lists the filters which dropped overlaps with their counts.
+fn describe_dropped(dropped: &[(&str, u64)]) -> String {
+    let dropped: Vec<_> = dropped
+        .iter()
+        .filter(|(_, n)| *n > 0)
+        .map(|(filter, n)| format!("{}: {}", filter, n))
+        .collect();
+    if dropped.is_empty() {
+        "none".to_string()
+    } else {
+        dropped.join(", ")
     }
 }
 
@@ -427,3 +1755,639 @@ pub(crate) fn alignment_reader<T: AsRef<Path>, U: AsRef<Path>>(
         });
     });
 }*/
+
+#[cfg(test)]
+mod tests {
+    use rustc_hash::FxHashMap as HashMap;
+    use std::ops::Range;
+
+    use crate::aligners::CigarOp;
+    use crate::error::{HerroError, Result};
+
+    use super::{
+        check_overlap, extend_overlap, is_valid_overlap, parse_paf_parallel, prune_overlaps,
+        read_overlaps, subsample_overlaps, target_batches, Alignment, MalformedLines, Overlap,
+        OverlapCounts, OverlapFilter, PairSelector, Rejection, Strand,
+    };
+    use crate::TargetBatch;
+
+    /// Parses PAF with `parse_paf_parallel`, which uses `parse_paf` for a
+    /// single thread.
+    fn parse(
+        paf: &str,
+        name_to_id: &HashMap<&[u8], u32>,
+        filter: OverlapFilter,
+        targets: Option<&Range<u32>>,
+        threads: usize,
+        malformed: &mut MalformedLines,
+    ) -> Result<HashMap<u32, Vec<Alignment>>> {
+        let mut selector = PairSelector::new(filter.keep_first);
+        if let Some(targets) = targets {
+            selector = selector.with_targets(targets.clone());
+        }
+        parse_paf_parallel(
+            paf.as_bytes(),
+            name_to_id,
+            filter,
+            targets,
+            threads,
+            malformed,
+            &mut selector,
+        )?;
+        Ok(selector.into_alignments())
+    }
+
+    fn filter(ol_threshold: u32) -> OverlapFilter {
+        OverlapFilter {
+            ol_threshold,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn valid_overlap_dovetail() {
+        // Query suffix overlaps target prefix
+        let overlap = Overlap::new(
+            0,
+            20_000,
+            8_000,
+            20_000,
+            Strand::Forward,
+            1,
+            30_000,
+            100,
+            12_000,
+        );
+        assert!(is_valid_overlap(&overlap, &filter(2500)));
+    }
+
+    #[test]
+    fn valid_overlap_reverse_containment() {
+        // Query is contained, overhangs are computed in the target orientation
+        let overlap = Overlap::new(
+            0,
+            10_000,
+            1_000,
+            10_000,
+            Strand::Reverse,
+            1,
+            30_000,
+            5_000,
+            14_000,
+        );
+        assert!(is_valid_overlap(&overlap, &filter(2500)));
+        assert!(!is_valid_overlap(&overlap, &filter(500)));
+    }
+
+    #[test]
+    fn extend_overlaps() {
+        let mut overlap = Overlap::new(
+            0,
+            20_000,
+            8_000,
+            20_000,
+            Strand::Forward,
+            1,
+            30_000,
+            100,
+            12_000,
+        );
+        extend_overlap(&mut overlap, 2500);
+        assert_eq!((overlap.qstart, overlap.qend), (7_900, 20_000));
+        assert_eq!((overlap.tstart, overlap.tend), (0, 12_000));
+
+        // Right end of the target is extended with the query prefix
+        let mut overlap = Overlap::new(
+            0,
+            10_000,
+            1_000,
+            10_000,
+            Strand::Reverse,
+            1,
+            30_000,
+            5_000,
+            14_000,
+        );
+        extend_overlap(&mut overlap, 500);
+        assert_eq!((overlap.qstart, overlap.qend), (500, 10_000));
+        assert_eq!((overlap.tstart, overlap.tend), (5_000, 14_500));
+        extend_overlap(&mut overlap, 2500);
+        assert_eq!((overlap.qstart, overlap.qend), (0, 10_000));
+        assert_eq!((overlap.tstart, overlap.tend), (5_000, 15_000));
+    }
+
+    #[test]
+    fn invalid_overlap_internal() {
+        let overlap = Overlap::new(
+            0,
+            20_000,
+            5_000,
+            10_000,
+            Strand::Forward,
+            1,
+            30_000,
+            10_000,
+            15_000,
+        );
+        assert!(!is_valid_overlap(&overlap, &filter(2500)));
+    }
+
+    #[test]
+    fn invalid_overlap_span_ratio() {
+        // Target span is 20% longer than the query span
+        let overlap = Overlap::new(0, 10_000, 0, 10_000, Strand::Forward, 1, 12_000, 0, 12_000);
+        assert!(!is_valid_overlap(&overlap, &filter(2500)));
+
+        let filter = OverlapFilter {
+            max_span_ratio: 1.25,
+            ..filter(2500)
+        };
+        assert!(is_valid_overlap(&overlap, &filter));
+    }
+
+    #[test]
+    fn invalid_overlap_short() {
+        let overlap = Overlap::new(0, 20_000, 0, 1_500, Strand::Forward, 1, 1_600, 0, 1_600);
+        assert!(is_valid_overlap(&overlap, &filter(2500)));
+
+        let filter = OverlapFilter {
+            min_overlap_len: 2_000,
+            ..filter(2500)
+        };
+        assert_eq!(
+            check_overlap(&overlap, &filter),
+            Err(Rejection::ShortOverlap)
+        );
+    }
+
+    #[test]
+    fn pair_selection() {
+        let alignment = |qstart| {
+            let overlap = Overlap::new(
+                0,
+                10_000,
+                qstart,
+                10_000,
+                Strand::Forward,
+                1,
+                10_000,
+                0,
+                10_000 - qstart,
+            );
+            Alignment::new(overlap, Vec::new())
+        };
+
+        let mut best = HashMap::default();
+        let mut first = HashMap::default();
+        for (keep_first, tid_to_alns) in [(false, &mut best), (true, &mut first)] {
+            let mut selector = PairSelector::new(keep_first);
+            selector.insert(alignment(500), (9_000, 9_500));
+            selector.insert(alignment(100), (9_800, 9_900));
+            selector.insert(alignment(200), (9_800, 9_800));
+            *tid_to_alns = selector.into_alignments();
+        }
+
+        assert_eq!(best[&1].len(), 1);
+        assert_eq!(best[&1][0].overlap.qstart, 100);
+        assert_eq!(first[&1].len(), 1);
+        assert_eq!(first[&1][0].overlap.qstart, 500);
+
+        // Pairs are deduplicated in both orientations, while the kept
+        // alignment is given to each target read of the pair
+        let reversed = Overlap::new(1, 10_000, 0, 9_900, Strand::Forward, 0, 10_000, 100, 10_000);
+        let mut selector = PairSelector::new(false).with_targets(0..2);
+        selector.insert(alignment(500), (9_000, 9_500));
+        selector.insert(Alignment::new(reversed, Vec::new()), (9_800, 9_900));
+        let tid_to_alns = selector.into_alignments();
+        assert_eq!(tid_to_alns[&0].len(), 1);
+        assert_eq!(tid_to_alns[&0][0].overlap.qid, 1);
+        assert_eq!(tid_to_alns[&1].len(), 1);
+        assert_eq!(tid_to_alns[&1][0].overlap.qid, 1);
+
+        let mut selector = PairSelector::new(false).with_targets(1..2);
+        selector.insert(alignment(500), (9_000, 9_500));
+        assert!(!selector.into_alignments().contains_key(&0));
+    }
+
+    #[test]
+    fn parse_paf_divergence() {
+        let paf = "r1\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t990\t1000\t60\tde:f:0.01\tcg:Z:1000M\n\
+                   r2\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t900\t1000\t60\tdv:f:0.08\n\
+                   r3\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t800\t1000\t60\n";
+        let name_to_id: HashMap<&[u8], u32> = [&b"r0"[..], b"r1", b"r2", b"r3"]
+            .into_iter()
+            .zip(0..)
+            .collect();
+
+        let filter = OverlapFilter {
+            max_divergence: Some(0.05),
+            ..filter(2500)
+        };
+        let alns = parse(
+            paf,
+            &name_to_id,
+            filter,
+            None,
+            1,
+            &mut MalformedLines::default(),
+        )
+        .unwrap();
+        let alns = &alns[&0];
+
+        let qids: Vec<_> = alns.iter().map(|a| a.overlap.qid).collect();
+        assert_eq!(qids, [1, 3]);
+        assert_eq!(alns[0].cigar, [CigarOp::Match(1000)]);
+        assert!(alns[1].cigar.is_empty());
+    }
+
+    #[test]
+    fn count_dropped_overlaps() {
+        let paf = "r1\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t990\t1000\t60\n\
+                   r1\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t980\t1000\t60\n\
+                   r2\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t900\t1000\t60\tdv:f:0.08\n\
+                   r0\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t1000\t1000\t60\n\
+                   r9\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t990\t1000\t60\n";
+        let name_to_id: HashMap<&[u8], u32> =
+            [&b"r0"[..], b"r1", b"r2"].into_iter().zip(0..).collect();
+        let filter = OverlapFilter {
+            max_divergence: Some(0.05),
+            ..filter(2500)
+        };
+
+        let counts = OverlapCounts::default();
+        let mut selector = PairSelector::new(filter.keep_first);
+        parse_paf_parallel(
+            paf.as_bytes(),
+            &name_to_id,
+            filter,
+            None,
+            1,
+            &mut MalformedLines::default(),
+            &mut selector,
+        )
+        .unwrap();
+        counts.add_batch(&selector);
+
+        let (_, dropped) = counts.counts();
+        let dropped: HashMap<_, _> = dropped.into_iter().collect();
+        assert_eq!(dropped["unknown_read"], 1);
+        assert_eq!(dropped["divergence"], 1);
+        assert_eq!(dropped["self_overlap"], 1);
+        assert_eq!(dropped["duplicate_pair"], 1);
+        assert_eq!(dropped["span_ratio"], 0);
+    }
+
+    #[test]
+    fn split_overlaps_by_batch() {
+        // r0-r1 is given in both orientations and spans both batches
+        let paf = "r1\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t900\t1000\t60\tcg:Z:1000M\n\
+                   r0\t1000\t0\t1000\t+\tr1\t1000\t0\t1000\t990\t1000\t60\tcg:Z:500M2I498M2D\n\
+                   r2\t1000\t0\t1000\t-\tr3\t1000\t0\t1000\t990\t1000\t60\n\
+                   r2\t1000\t0\t1000\t+\tr2\t1000\t0\t1000\t990\t1000\t60\n\
+                   r9\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t990\t1000\t60\n";
+        let dir = std::env::temp_dir().join(format!("herro-split-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("overlaps.paf");
+        std::fs::write(&path, paf).unwrap();
+        let name_to_id: HashMap<&[u8], u32> = [&b"r0"[..], b"r1", b"r2", b"r3"]
+            .into_iter()
+            .zip(0..)
+            .collect();
+
+        let batches: Vec<_> = read_overlaps(
+            &name_to_id,
+            vec![&path],
+            vec![0..1, 1..4],
+            None,
+            filter(2500),
+            1,
+        )
+        .unwrap()
+        .collect::<Result<_>>()
+        .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let counts = OverlapCounts::default();
+        batches
+            .iter()
+            .for_each(|selector| counts.add_batch(selector));
+        let (_, dropped) = counts.counts();
+        let dropped: HashMap<_, _> = dropped.into_iter().collect();
+        assert_eq!(dropped["unknown_read"], 1);
+        assert_eq!(dropped["self_overlap"], 1);
+
+        let alns: Vec<_> = batches
+            .into_iter()
+            .map(PairSelector::into_alignments)
+            .collect();
+        assert_eq!(alns[0].len(), 1);
+        assert_eq!(alns[0][&0].len(), 1);
+        // Better overlap of the pair is kept in both batches, with its CIGAR
+        for batch in alns.iter() {
+            assert_eq!(batch.values().next().unwrap()[0].score, (990, 1000));
+        }
+        let aln = &alns[1][&1][0];
+        assert_eq!(aln.cigar.len(), 4);
+        assert_eq!(aln.cigar[3], CigarOp::Deletion(2));
+        assert_eq!(aln.overlap.qid, 0);
+        assert_eq!(alns[1][&2][0].overlap.strand, Strand::Reverse);
+        assert_eq!(alns[1][&3].len(), 1);
+    }
+
+    #[test]
+    fn parse_paf_mapq() {
+        let paf = "r1\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t990\t1000\t60\n\
+                   r2\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t990\t1000\t3\n\
+                   r3\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t990\t1000\t255\n";
+        let name_to_id: HashMap<&[u8], u32> = [&b"r0"[..], b"r1", b"r2", b"r3"]
+            .into_iter()
+            .zip(0..)
+            .collect();
+
+        let filter = OverlapFilter {
+            min_mapq: Some(10),
+            ..filter(2500)
+        };
+        let alns = parse(
+            paf,
+            &name_to_id,
+            filter,
+            None,
+            1,
+            &mut MalformedLines::default(),
+        )
+        .unwrap();
+        let qids: Vec<_> = alns[&0].iter().map(|a| a.overlap.qid).collect();
+        assert_eq!(qids, [1, 3]);
+
+        let paf = "r1\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t990\t1000\t300\n";
+        let mut malformed = MalformedLines::default();
+        let alns = parse(paf, &name_to_id, filter, None, 1, &mut malformed).unwrap();
+        assert!(alns.is_empty());
+        assert_eq!(malformed.count(), 1);
+    }
+
+    #[test]
+    fn parse_paf_primary_only() {
+        let paf = "r1\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t900\t1000\t0\ttp:A:S\n\
+                   r1\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t800\t1000\t60\ttp:A:P\n\
+                   r2\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t990\t1000\t0\ttp:A:i\n\
+                   r3\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t990\t1000\t60\n";
+        let name_to_id: HashMap<&[u8], u32> = [&b"r0"[..], b"r1", b"r2", b"r3"]
+            .into_iter()
+            .zip(0..)
+            .collect();
+
+        let primary = OverlapFilter {
+            primary_only: true,
+            keep_first: true,
+            ..filter(2500)
+        };
+        let alns = parse(
+            paf,
+            &name_to_id,
+            primary,
+            None,
+            1,
+            &mut MalformedLines::default(),
+        )
+        .unwrap();
+        let alns: Vec<_> = alns[&0]
+            .iter()
+            .map(|a| (a.overlap.qid, a.overlap.tend))
+            .collect();
+        assert_eq!(alns, [(1, 1000), (3, 1000)]);
+
+        let alns = parse(
+            paf,
+            &name_to_id,
+            filter(2500),
+            None,
+            1,
+            &mut MalformedLines::default(),
+        )
+        .unwrap();
+        assert_eq!(alns[&0].len(), 3);
+    }
+
+    #[test]
+    fn prune_longest_overlaps() {
+        let alignment = |qid, tend| {
+            let overlap = Overlap::new(qid, 10_000, 0, tend, Strand::Forward, 0, 10_000, 0, tend);
+            Alignment::new(overlap, Vec::new())
+        };
+
+        let mut tid_to_alns = HashMap::default();
+        tid_to_alns.insert(
+            0,
+            vec![
+                alignment(1, 5_000),
+                alignment(2, 9_000),
+                alignment(3, 7_000),
+            ],
+        );
+        prune_overlaps(&mut tid_to_alns, 2);
+
+        let qids: Vec<_> = tid_to_alns[&0].iter().map(|a| a.overlap.qid).collect();
+        assert_eq!(qids, [2, 3]);
+    }
+
+    #[test]
+    fn subsample_coverage() {
+        let alignment = |qid, tstart, tend, matches| {
+            let len = tend - tstart;
+            let overlap = Overlap::new(qid, len, 0, len, Strand::Forward, 0, 10_000, tstart, tend);
+            let mut aln = Alignment::new(overlap, Vec::new());
+            aln.score = (matches, len);
+            aln
+        };
+
+        let mut tid_to_alns = HashMap::default();
+        tid_to_alns.insert(
+            0,
+            vec![
+                alignment(1, 0, 5_000, 5_000),
+                alignment(2, 0, 6_000, 5_900),
+                alignment(3, 0, 5_000, 4_950),
+                alignment(4, 4_000, 10_000, 5_100),
+                alignment(5, 5_000, 10_000, 4_900),
+            ],
+        );
+        assert_eq!(subsample_overlaps(&mut tid_to_alns, 2), 1);
+
+        // Read 3 is in the region already covered twice by better overlaps
+        let qids: Vec<_> = tid_to_alns[&0].iter().map(|a| a.overlap.qid).collect();
+        assert_eq!(qids, [2, 4, 1, 5]);
+    }
+
+    #[test]
+    fn parse_paf_target_batch() {
+        let paf = "r1\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t990\t1000\t60\n\
+                   r0\t1000\t0\t1000\t+\tr1\t1000\t0\t1000\t990\t1000\t60\n\
+                   r0\t1000\t0\t1000\t+\tr2\t1000\t0\t1000\t990\t1000\t60\n";
+        let name_to_id: HashMap<&[u8], u32> =
+            [&b"r0"[..], b"r1", b"r2"].into_iter().zip(0..).collect();
+
+        let alns = parse(
+            paf,
+            &name_to_id,
+            filter(2500),
+            Some(&(1..3)),
+            1,
+            &mut MalformedLines::default(),
+        )
+        .unwrap();
+        let mut tids: Vec<_> = alns.keys().copied().collect();
+        tids.sort();
+        assert_eq!(tids, [1, 2]);
+    }
+
+    #[test]
+    fn parse_paf_parallel_keeps_order() {
+        let name_to_id: HashMap<&[u8], u32> =
+            [&b"r0"[..], b"r1", b"r2"].into_iter().zip(0..).collect();
+
+        // Multiple chunks with duplicated pairs, first overlap has to be kept
+        let paf: String = (0..200_000)
+            .map(|i| {
+                let (qname, qstart) = (["r1", "r2"][i % 2], i as u32 % 100);
+                format!(
+                    "{qname}\t1000\t{qstart}\t1000\t+\tr0\t1000\t0\t{}\t900\t1000\t60\n",
+                    1000 - qstart
+                )
+            })
+            .collect();
+        let filter = OverlapFilter {
+            keep_first: true,
+            ..filter(2500)
+        };
+
+        let alns = parse(
+            &paf,
+            &name_to_id,
+            filter,
+            None,
+            4,
+            &mut MalformedLines::default(),
+        )
+        .unwrap();
+        let expected = parse(
+            &paf,
+            &name_to_id,
+            filter,
+            None,
+            1,
+            &mut MalformedLines::default(),
+        )
+        .unwrap();
+        assert_eq!(alns[&0].len(), 2);
+        alns[&0]
+            .iter()
+            .zip(expected[&0].iter())
+            .for_each(|(a, e)| assert_eq!(a.overlap, e.overlap));
+        assert_eq!(alns[&0][1].overlap.qstart, 1);
+    }
+
+    #[test]
+    fn parse_paf_merge_files() {
+        let name_to_id: HashMap<&[u8], u32> =
+            [&b"r0"[..], b"r1", b"r2"].into_iter().zip(0..).collect();
+        let first = "r1\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t900\t1000\t60\n";
+        let second = "r1\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t990\t1000\t60\tcg:Z:1000M\n\
+                      r2\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t990\t1000\t60\n";
+
+        // Pair overlapping in both files is kept once, with the better score
+        let mut selector = PairSelector::new(false);
+        for paf in [first, second] {
+            parse_paf_parallel(
+                paf.as_bytes(),
+                &name_to_id,
+                filter(2500),
+                None,
+                1,
+                &mut MalformedLines::default(),
+                &mut selector,
+            )
+            .unwrap();
+        }
+
+        let alns = selector.into_alignments();
+        assert_eq!(alns[&0].len(), 2);
+        assert_eq!(alns[&0][0].overlap.qid, 1);
+        assert_eq!(alns[&0][0].cigar, [CigarOp::Match(1000)]);
+    }
+
+    #[test]
+    fn parse_paf_invalid_line() {
+        let name_to_id: HashMap<&[u8], u32> = [&b"r0"[..], b"r1"].into_iter().zip(0..).collect();
+
+        let mut paf = "r1\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t990\t1000\t60\n".repeat(100_000);
+        paf.push_str("r1\t1000\t0\t1000\t+\tr0\t1000\t0\tx\t990\t1000\t60\n");
+
+        let strict = OverlapFilter {
+            strict: true,
+            ..filter(2500)
+        };
+        for threads in [1, 4] {
+            let mut malformed = MalformedLines::default();
+            let err = parse(&paf, &name_to_id, strict, None, threads, &mut malformed).unwrap_err();
+            assert!(matches!(err, HerroError::InvalidLine { line: 100_001, .. }));
+            assert_eq!(
+                err.to_string(),
+                "Invalid record at line 100001: invalid target end 'x'"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_paf_skip_malformed_lines() {
+        let name_to_id: HashMap<&[u8], u32> = [&b"r0"[..], b"r1"].into_iter().zip(0..).collect();
+
+        let line = "r1\t1000\t0\t1000\t+\tr0\t1000\t0\t1000\t990\t1000\t60\n";
+        let mut paf = line.repeat(100_000);
+        paf.push_str("r1\t1000\t0\t1000\t+\tr0\t1000\t0\tx\t990\t1000\t60\n");
+        paf.push_str(&line.repeat(100_000));
+        paf.push_str("r1\t1000\t0\t1000\t-\tr0\t1000\t0\t1000\t990\t1000\t60\n");
+        // Truncated last line without a newline
+        paf.push_str("r1\t1000\t0\t1000\t+\tr0\t10");
+
+        for threads in [1, 4] {
+            let mut malformed = MalformedLines::default();
+            let alns = parse(
+                &paf,
+                &name_to_id,
+                filter(2500),
+                None,
+                threads,
+                &mut malformed,
+            )
+            .unwrap();
+
+            assert_eq!(alns[&0].len(), 1);
+            assert_eq!(malformed.count(), 2);
+            assert_eq!(
+                malformed.examples,
+                [
+                    (100_001, "invalid target end 'x'".to_string()),
+                    (200_003, "missing target start".to_string())
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn split_target_batches() {
+        let lengths = [10, 20, 30, 40, 50];
+        let batches = target_batches(lengths.iter().copied(), TargetBatch::Reads(2));
+        assert_eq!(batches, vec![0..2, 2..4, 4..5]);
+
+        let batches = target_batches(lengths.iter().copied(), TargetBatch::Bases(60));
+        assert_eq!(batches, vec![0..3, 3..4, 4..5]);
+
+        // Reads longer than the batch are in their own batch
+        let batches = target_batches(lengths.iter().copied(), TargetBatch::Bases(15));
+        assert_eq!(batches, vec![0..1, 1..2, 2..3, 3..4, 4..5]);
+
+        assert!(target_batches([].into_iter(), TargetBatch::Reads(2)).is_empty());
+    }
+}
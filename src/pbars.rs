@@ -1,18 +1,39 @@
-use std::time::Duration;
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
 
 use crossbeam_channel::Receiver;
-use indicatif::{FormattedDuration, MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{
+    FormattedDuration, HumanCount, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle,
+};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::error::{HerroError, Result};
+
+/// Minimum time between the JSON progress events of a stage
+const JSON_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
 
 pub(super) enum PBarNotification {
+    /// Number of target reads in the next batch of alignments
     BatchLen(u64),
+    /// Target read was processed
     Inc,
+    /// Windows written as features or queued for inference
+    Windows(u64),
+    /// Batch of windows was processed by the model
+    InferredBatch,
 }
 
-pub(super) fn get_parse_reads_spinner(multi: Option<&MultiProgress>) -> ProgressBar {
+pub(super) fn get_parse_reads_spinner(multi: Option<&MultiProgress>, quiet: bool) -> ProgressBar {
     let spinner = multi.map_or_else(
         || ProgressBar::new_spinner(),
         |m| m.add(ProgressBar::new_spinner()),
     );
+    if quiet {
+        spinner.set_draw_target(ProgressDrawTarget::hidden());
+    }
     spinner.enable_steady_tick(Duration::from_millis(80));
     spinner.set_style(
         ProgressStyle::with_template("[{elapsed_precise}] {msg} {spinner:.blue}")
@@ -51,7 +72,7 @@ fn get_in_batch_pbar() -> ProgressBar {
     let pbar = ProgressBar::hidden();
 
     pbar.set_style(
-        ProgressStyle::with_template("[{bar:40.cyan/blue}] {pos}/{len}")
+        ProgressStyle::with_template("[{bar:40.cyan/blue}] {pos}/{len} reads (ETA {eta})")
             .unwrap()
             .progress_chars("#>-"),
     );
@@ -59,11 +80,131 @@ fn get_in_batch_pbar() -> ProgressBar {
     pbar
 }
 
-pub(super) fn track_progress(pbar_receiver: Receiver<PBarNotification>) {
-    let mbar = MultiProgress::new();
+fn get_windows_pbar(multi: &MultiProgress) -> ProgressBar {
+    let pbar = multi.add(ProgressBar::new_spinner());
+    pbar.set_style(ProgressStyle::with_template("{msg}").unwrap());
+
+    pbar
+}
+
+fn windows_message(n_windows: u64, n_inferred: u64) -> String {
+    if n_inferred == 0 {
+        format!("{} windows", HumanCount(n_windows))
+    } else {
+        format!(
+            "{} windows queued, {} batches inferred",
+            HumanCount(n_windows),
+            HumanCount(n_inferred)
+        )
+    }
+}
+
+/// Progress event written with `--progress-json`. Total is not known for
+/// the stages which are processed in batches of unknown number.
+#[derive(Debug, Serialize)]
+struct ProgressEvent<'a> {
+    stage: &'a str,
+    done: u64,
+    total: Option<u64>,
+    /// Items per second since the start of the run
+    rate: f64,
+    elapsed_secs: f64,
+}
+
+/// Newline-delimited JSON progress events for workflow managers and
+/// dashboards, written to a file or to stderr for `-`.
+pub(super) struct ProgressJson {
+    writer: Box<dyn Write>,
+    start: Instant,
+    last_event: Instant,
+}
+
+impl ProgressJson {
+    pub(super) fn open(path: &Path) -> Result<Self> {
+        let writer: Box<dyn Write> = match path.as_os_str() == "-" {
+            true => Box::new(io::stderr()),
+            false => {
+                let file = File::create(path).map_err(|e| HerroError::from(e).in_file(path))?;
+                Box::new(LineWriter::new(file))
+            }
+        };
+
+        let start = Instant::now();
+        Ok(Self {
+            writer,
+            start,
+            last_event: start,
+        })
+    }
+
+    /// Writes the event of the stage. Progress is not stopped if the events
+    /// cannot be written, they are only dropped.
+    pub(super) fn emit(&mut self, stage: &str, done: u64, total: Option<u64>) {
+        let elapsed_secs = self.start.elapsed().as_secs_f64();
+        let event = ProgressEvent {
+            stage,
+            done,
+            total,
+            rate: done as f64 / elapsed_secs.max(f64::EPSILON),
+            elapsed_secs,
+        };
+
+        let result = serde_json::to_writer(&mut self.writer, &event)
+            .map_err(io::Error::from)
+            .and_then(|_| self.writer.write_all(b"\n"));
+        if let Err(e) = result {
+            warn!("Cannot write the progress event: {}", e);
+        }
+    }
+
+    /// Returns true if the periodic events are due.
+    fn is_due(&mut self) -> bool {
+        if self.last_event.elapsed() < JSON_PROGRESS_INTERVAL {
+            return false;
+        }
+
+        self.last_event = Instant::now();
+        true
+    }
+}
+
+/// Totals of the progress notifications of a run
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct Progress {
+    pub(super) n_reads: u64,
+    pub(super) n_windows: u64,
+    pub(super) n_batches: u64,
+}
+
+/// Writes the progress events of the stages tracked by `track_progress`.
+fn emit_progress(json: &mut ProgressJson, n_batches: u64, pbar: &ProgressBar, progress: &Progress) {
+    json.emit("overlap_batches", n_batches, None);
+    json.emit("reads", progress.n_reads, pbar.length());
+    json.emit("windows", progress.n_windows, None);
+    if progress.n_batches > 0 {
+        json.emit("inference_batches", progress.n_batches, None);
+    }
+}
+
+/// Shows progress until all senders are dropped, logs the totals and returns
+/// them. With `quiet`, the bars are hidden. Progress is also written as JSON
+/// events if they are enabled.
+pub(super) fn track_progress(
+    pbar_receiver: Receiver<PBarNotification>,
+    quiet: bool,
+    mut json: Option<&mut ProgressJson>,
+) -> Progress {
+    let mbar = if quiet {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    } else {
+        MultiProgress::new()
+    };
     let batches_bar = get_alns_batches_pbar(Some(&mbar));
+    let windows_bar = get_windows_pbar(&mbar);
     let mut pbar = get_in_batch_pbar();
 
+    let (mut n_windows, mut n_inferred) = (0, 0);
+
     let mut n_batch = 0;
     while let Ok(notification) = pbar_receiver.recv() {
         match notification {
@@ -81,15 +222,92 @@ pub(super) fn track_progress(pbar_receiver: Receiver<PBarNotification>) {
             PBarNotification::Inc => {
                 pbar.inc(1);
             }
+            PBarNotification::Windows(n) => {
+                n_windows += n;
+                windows_bar.set_message(windows_message(n_windows, n_inferred));
+            }
+            PBarNotification::InferredBatch => {
+                n_inferred += 1;
+                windows_bar.set_message(windows_message(n_windows, n_inferred));
+            }
+        }
+
+        if let Some(json) = json.as_deref_mut() {
+            if json.is_due() {
+                let progress = Progress {
+                    n_reads: pbar.position(),
+                    n_windows,
+                    n_batches: n_inferred,
+                };
+                emit_progress(json, n_batch, &pbar, &progress);
+            }
         }
     }
 
+    // Finished bar is moved to its end
+    let progress = Progress {
+        n_reads: pbar.position(),
+        n_windows,
+        n_batches: n_inferred,
+    };
+    if let Some(json) = json {
+        emit_progress(json, n_batch, &pbar, &progress);
+    }
+
     batches_bar.finish_and_clear();
+    windows_bar.finish_and_clear();
     pbar.finish_and_clear();
-
-    eprintln!(
-        "[{}] Processed {} reads.",
-        FormattedDuration(batches_bar.elapsed()),
-        pbar.position()
+    info!(
+        "Processed {} reads in {}",
+        pbar.position(),
+        FormattedDuration(batches_bar.elapsed())
     );
+    progress
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crossbeam_channel::unbounded;
+
+    use super::{track_progress, PBarNotification, ProgressJson};
+
+    #[test]
+    fn progress_json() {
+        let path =
+            std::env::temp_dir().join(format!("herro-progress-{}.ndjson", std::process::id()));
+        let mut json = ProgressJson::open(&path).unwrap();
+        json.emit("parse_reads", 3, Some(3));
+
+        let (sender, receiver) = unbounded();
+        sender.send(PBarNotification::BatchLen(3)).unwrap();
+        sender.send(PBarNotification::Inc).unwrap();
+        sender.send(PBarNotification::Windows(5)).unwrap();
+        sender.send(PBarNotification::Inc).unwrap();
+        drop(sender);
+        let progress = track_progress(receiver, true, Some(&mut json));
+        assert_eq!(progress.n_reads, 2);
+        drop(json);
+
+        let events: Vec<serde_json::Value> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        let stages: Vec<_> = events
+            .iter()
+            .map(|e| e["stage"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            stages,
+            ["parse_reads", "overlap_batches", "reads", "windows"]
+        );
+        assert_eq!(events[2]["done"], 2);
+        assert_eq!(events[2]["total"], 3);
+        assert!(events[3]["total"].is_null());
+        assert!(events[3]["rate"].as_f64().unwrap() > 0.);
+
+        fs::remove_file(&path).unwrap();
+    }
 }
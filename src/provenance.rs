@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::error::{HerroError, Result};
+use crate::features::WindowParams;
+use crate::inference::FeatureEncoder;
+use crate::models::sha256;
+use crate::CorrectionConfig;
+
+/// Metadata of the corrected reads, written next to the output, so it is known
+/// how they were produced.
+#[derive(Debug, Serialize)]
+pub(crate) struct Provenance {
+    herro_version: &'static str,
+    command_line: Vec<String>,
+    /// Seconds since the Unix epoch
+    started_at: u64,
+    reads: PathBuf,
+    models: Vec<ModelProvenance>,
+    /// Resolved parameters of the correction
+    parameters: BTreeMap<&'static str, String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ModelProvenance {
+    path: PathBuf,
+    sha256: String,
+}
+
+/// Path of the metadata of the output.
+pub(crate) fn provenance_path<P: AsRef<Path>>(output_path: P) -> PathBuf {
+    let mut path = output_path.as_ref().as_os_str().to_owned();
+    path.push(".herro.json");
+    PathBuf::from(path)
+}
+
+impl Provenance {
+    /// Collects the metadata of the run with the resolved windows and feature
+    /// encoder. Models are hashed, so their checksum is recorded also when
+    /// they are given by path.
+    pub(crate) fn new<P: AsRef<Path>>(
+        reads_path: P,
+        model_paths: &[&str],
+        config: &CorrectionConfig,
+        windows: WindowParams,
+        encoder: &dyn FeatureEncoder,
+    ) -> Result<Self> {
+        let models = model_paths
+            .iter()
+            .map(|&path| {
+                let sha256 = sha256(path).map_err(|e| HerroError::from(e).in_file(path))?;
+                Ok(ModelProvenance {
+                    path: PathBuf::from(path),
+                    sha256,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut parameters = parameters(config, windows);
+        parameters.insert("feature_set", encoder.name().to_string());
+        parameters.insert("features", encoder.features().join(","));
+        if models.len() > 1 {
+            parameters.insert("ensemble", format!("{:?}", config.ensemble));
+        }
+
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Ok(Self {
+            herro_version: env!("CARGO_PKG_VERSION"),
+            command_line: env::args().collect(),
+            started_at,
+            reads: reads_path.as_ref().to_path_buf(),
+            models,
+            parameters,
+        })
+    }
+
+    pub(crate) fn write<P: AsRef<Path>>(&self, output_path: P) -> Result<()> {
+        let path = provenance_path(output_path);
+        let file = File::create(&path).map_err(|e| HerroError::from(e).in_file(&path))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)
+            .map_err(|e| HerroError::from(io::Error::from(e)).in_file(&path))
+    }
+}
+
+/// Parameters which change the corrected reads.
+fn parameters(config: &CorrectionConfig, windows: WindowParams) -> BTreeMap<&'static str, String> {
+    let devices: Vec<_> = config.devices.iter().map(|d| d.to_string()).collect();
+    let mut parameters = BTreeMap::from([
+        ("window_size", windows.size.to_string()),
+        ("window_stride", windows.stride.to_string()),
+        ("top_k", windows.top_k.to_string()),
+        ("min_coverage", config.min_coverage.to_string()),
+        ("batch_size", config.batch_size.to_string()),
+        ("backend", format!("{:?}", config.backend)),
+        ("precision", format!("{:?}", config.precision)),
+        ("devices", devices.join(",")),
+        ("aligner", format!("{:?}", config.aligner)),
+        ("overlap_filter", format!("{:?}", config.overlap_filter)),
+        ("trust_cigar", config.trust_cigar.to_string()),
+        ("output_format", format!("{:?}", config.output_format)),
+        ("keep_uncorrected", config.keep_uncorrected.to_string()),
+        ("deterministic", config.deterministic.to_string()),
+    ]);
+
+    let optional = [
+        ("trim_ends", config.trim_ends.map(|c| c.to_string())),
+        ("chimeras", config.chimeras.map(|m| format!("{:?}", m))),
+        ("shard", config.shard.map(|s| s.to_string())),
+        (
+            "read_ids",
+            config.read_ids.as_ref().map(|p| p.display().to_string()),
+        ),
+        (
+            "from_features",
+            config
+                .from_features
+                .as_ref()
+                .map(|p| p.display().to_string()),
+        ),
+    ];
+    parameters.extend(
+        optional
+            .into_iter()
+            .filter_map(|(name, value)| value.map(|v| (name, v))),
+    );
+
+    parameters
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{provenance_path, Provenance};
+    use crate::features::WindowParams;
+    use crate::inference::feature_encoder;
+    use crate::{CorrectionConfig, FeatureSet};
+
+    #[test]
+    fn write_provenance() {
+        let dir = std::env::temp_dir().join(format!("herro-provenance-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let model = dir.join("model.pt");
+        fs::write(&model, "abc").unwrap();
+        let output = dir.join("corrected.fasta");
+
+        let config = CorrectionConfig::builder().build().unwrap();
+        let encoder = feature_encoder(FeatureSet::Strandless, Default::default());
+        let provenance = Provenance::new(
+            "reads.fastq",
+            &[model.to_str().unwrap()],
+            &config,
+            WindowParams {
+                size: 4096,
+                stride: 2048,
+                top_k: 30,
+                min_accuracy: None,
+                selection: Default::default(),
+            },
+            encoder.as_ref(),
+        )
+        .unwrap();
+        provenance.write(&output).unwrap();
+
+        let path = provenance_path(&output);
+        assert_eq!(path, dir.join("corrected.fasta.herro.json"));
+        let json: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(json["herro_version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            json["models"][0]["sha256"],
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(json["parameters"]["window_stride"], "2048");
+        assert_eq!(json["parameters"]["feature_set"], "strandless");
+        assert_eq!(json["parameters"]["features"], "bases,quals");
+        assert!(json["parameters"]["ensemble"].is_null());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
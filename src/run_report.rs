@@ -0,0 +1,93 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::error::{HerroError, Result};
+use crate::overlaps::OverlapCounts;
+use crate::pbars::Progress;
+use crate::telemetry::{process_memory, DeviceReport};
+
+/// Summary of a correction run written with `--report`.
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct RunReport {
+    pub(crate) input: InputCounts,
+    pub(crate) overlaps: OverlapReport,
+    pub(crate) reads_processed: u64,
+    pub(crate) reads_corrected: u64,
+    pub(crate) windows_processed: u64,
+    pub(crate) batches_inferred: u64,
+    /// Throughput, utilization and memory of each inference device
+    pub(crate) devices: Vec<DeviceReport>,
+    /// Wall time of each stage in seconds
+    pub(crate) stage_seconds: BTreeMap<&'static str, f64>,
+    /// Peak resident set size, only known on Linux
+    pub(crate) peak_rss_bytes: Option<u64>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct InputCounts {
+    pub(crate) reads: u64,
+    pub(crate) bases: u64,
+    /// Reads which were corrected in this run, excluding the reads of other
+    /// shards and the reads corrected before the run was resumed
+    pub(crate) target_reads: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct OverlapReport {
+    pub(crate) kept: u64,
+    /// Overlaps dropped by each filter
+    pub(crate) dropped: BTreeMap<&'static str, u64>,
+}
+
+impl RunReport {
+    pub(crate) fn set_overlaps(&mut self, counts: &OverlapCounts) {
+        let (kept, dropped) = counts.counts();
+        self.overlaps = OverlapReport {
+            kept,
+            dropped: dropped.into_iter().collect(),
+        };
+    }
+
+    pub(crate) fn set_progress(&mut self, progress: Progress) {
+        self.reads_processed = progress.n_reads;
+        self.windows_processed = progress.n_windows;
+        self.batches_inferred = progress.n_batches;
+    }
+
+    pub(crate) fn add_stage(&mut self, stage: &'static str, elapsed: Duration) {
+        self.stage_seconds.insert(stage, elapsed.as_secs_f64());
+    }
+
+    pub(crate) fn write<P: AsRef<Path>>(mut self, path: P) -> Result<()> {
+        self.peak_rss_bytes = process_memory("VmHWM");
+
+        let path = path.as_ref();
+        let file = File::create(path).map_err(|e| HerroError::from(e).in_file(path))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &self)
+            .map_err(|e| HerroError::from(io::Error::from(e)).in_file(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::RunReport;
+
+    #[test]
+    fn report_json() {
+        let mut report = RunReport::default();
+        report.input.reads = 3;
+        report.add_stage("parse_reads", Duration::from_millis(1500));
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["input"]["reads"], 3);
+        assert_eq!(json["stage_seconds"]["parse_reads"], 1.5);
+        assert!(json["peak_rss_bytes"].is_null());
+    }
+}
@@ -0,0 +1,896 @@
+use rustc_hash::FxHashMap as HashMap;
+
+use std::io::{self, prelude::*, ErrorKind};
+use std::ops::Range;
+
+use crate::aligners::CigarOp;
+use crate::error::{HerroError, Result};
+use crate::haec_io::bytes_to_u32;
+use crate::overlaps::{
+    check_overlap, cigar_score, is_valid_overlap, next_field, next_u32, Alignment, Overlap,
+    OverlapFilter, OverlapSink, Rejection, Strand,
+};
+use crate::LINE_ENDING;
+
+const FLAG_UNMAPPED: u16 = 0x4;
+const FLAG_REVERSE: u16 = 0x10;
+const FLAG_SECONDARY: u16 = 0x100;
+const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+pub(crate) const BAM_MAGIC: &[u8; 4] = b"BAM\x01";
+/// Size of the fixed-length fields at the start of the BAM record
+const BAM_FIXED_SIZE: usize = 32;
+const BAM_CIGAR_OPS: &[u8; 9] = b"MIDNSHP=X";
+const BAM_SEQ_DECODING: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+
+/// Target reads from the SAM/BAM header: reference index -> (tid, tlen).
+/// References that are not in the reads set are stored as None.
+type References = Vec<Option<(u32, u32)>>;
+
+pub(crate) fn parse_sam(
+    mut reader: impl BufRead,
+    name_to_id: &HashMap<&[u8], u32>,
+    overlap_filter: OverlapFilter,
+    targets: Option<&Range<u32>>,
+    selector: &mut impl OverlapSink,
+) -> Result<()> {
+    let mut buffer = Vec::new();
+    let mut ref_name_to_idx = HashMap::default();
+    let mut refs = References::new();
+
+    for line_idx in 1.. {
+        buffer.clear();
+        let len = reader.read_until(LINE_ENDING, &mut buffer)?;
+        if len == 0 {
+            break;
+        }
+
+        let invalid_line = |message: String| HerroError::InvalidLine {
+            line: line_idx,
+            message,
+        };
+
+        let line = buffer.strip_suffix(&[LINE_ENDING]).unwrap_or(&buffer);
+        if line.starts_with(b"@SQ") {
+            let (mut name, mut length) = (None, None);
+            for field in line.split(|&c| c == b'\t').skip(1) {
+                if let Some(n) = field.strip_prefix(b"SN:") {
+                    name = Some(n);
+                } else if let Some(l) = field.strip_prefix(b"LN:") {
+                    length = bytes_to_u32(l);
+                }
+            }
+
+            let (Some(name), Some(length)) = (name, length) else {
+                return Err(invalid_line(
+                    "@SQ header line should contain SN and LN tags".to_string(),
+                ));
+            };
+            ref_name_to_idx.insert(name.to_owned(), refs.len());
+            refs.push(name_to_id.get(name).map(|&tid| (tid, length)));
+
+            continue;
+        } else if line.starts_with(b"@") {
+            continue;
+        }
+
+        let mut data = line.split(|&c| c == b'\t');
+        let qname = next_field(&mut data, "query name").map_err(invalid_line)?;
+        let flag = next_u32(&mut data, "flag").map_err(invalid_line)? as u16;
+        let rname = next_field(&mut data, "reference name").map_err(invalid_line)?;
+        let pos = next_u32(&mut data, "position").map_err(invalid_line)?;
+        let mapq = next_u32(&mut data, "mapping quality").map_err(invalid_line)?;
+        let cigar = next_field(&mut data, "CIGAR").map_err(invalid_line)?;
+
+        if flag & FLAG_UNMAPPED != 0 || cigar == b"*" {
+            continue;
+        }
+        if !overlap_filter.is_valid_mapq(mapq.min(u8::MAX as u32) as u8) {
+            selector.reject(Rejection::MappingQuality);
+            continue;
+        }
+        if overlap_filter.primary_only && flag & FLAG_SECONDARY != 0 {
+            selector.reject(Rejection::Secondary);
+            continue;
+        }
+        if pos == 0 {
+            return Err(invalid_line(
+                "mapped record should have a position".to_string(),
+            ));
+        }
+
+        let qid = name_to_id.get(qname).copied();
+        let target = ref_name_to_idx.get(rname).and_then(|&idx| refs[idx]);
+        let (qid, (tid, tlen)) = match (qid, target) {
+            (Some(qid), Some(target)) if targets.is_none_or(|t| t.contains(&target.0)) => {
+                (qid, target)
+            }
+            (Some(_), Some(_)) => continue,
+            _ => {
+                selector.reject(Rejection::UnknownRead);
+                continue;
+            }
+        };
+
+        let alignment = convert_record(qid, flag, tid, tlen, pos - 1, parse_sam_cigar(cigar))
+            .map_err(invalid_line)?;
+        if let Some(aln) = alignment {
+            match check_overlap(&aln.overlap, &overlap_filter) {
+                Ok(()) => {
+                    let score = cigar_score(&aln.cigar);
+                    selector.insert(aln, score);
+                }
+                Err(rejection) => selector.reject(rejection),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn parse_bam(
+    mut reader: impl BufRead,
+    name_to_id: &HashMap<&[u8], u32>,
+    overlap_filter: OverlapFilter,
+    targets: Option<&Range<u32>>,
+    selector: &mut impl OverlapSink,
+) -> Result<()> {
+    let mut refs = References::new();
+    read_bam_header(&mut reader, |name, length| {
+        refs.push(name_to_id.get(name).map(|&tid| (tid, length)))
+    })?;
+
+    let mut buffer = Vec::new();
+    for record_idx in 1.. {
+        if !read_bam_block(&mut reader, &mut buffer)? {
+            break;
+        }
+
+        let alignment = parse_bam_record(&buffer, &refs, name_to_id, overlap_filter, targets)
+            .map_err(|message| HerroError::InvalidRecord {
+                record: record_idx,
+                message,
+            })?;
+        if let Some(alignment) = alignment {
+            let score = cigar_score(&alignment.cigar);
+            selector.insert(alignment, score);
+        }
+    }
+
+    Ok(())
+}
+
+/// Read of an unmapped BAM file, e.g. written by dorado.
+#[derive(Debug)]
+pub(crate) struct BamRead {
+    pub(crate) name: Vec<u8>,
+    pub(crate) seq: Vec<u8>,
+    /// Phred+33 qualities, None if the record has no qualities
+    pub(crate) qual: Option<Vec<u8>>,
+    /// Tags in the SAM text format, separated by tabs
+    pub(crate) tags: Vec<u8>,
+}
+
+/// Parses the reads of a BAM file and calls `f` for each read. Secondary and
+/// supplementary records are skipped, and reads of reverse strand records are
+/// returned in their original orientation, so mapped BAM files can be used as
+/// well.
+pub(crate) fn parse_bam_reads(
+    mut reader: impl BufRead,
+    mut f: impl FnMut(BamRead) -> Result<()>,
+) -> Result<()> {
+    read_bam_header(&mut reader, |_, _| ())?;
+
+    let mut buffer = Vec::new();
+    for record_idx in 1.. {
+        if !read_bam_block(&mut reader, &mut buffer)? {
+            break;
+        }
+
+        let read = parse_bam_read(&buffer).map_err(|message| HerroError::InvalidRecord {
+            record: record_idx,
+            message,
+        })?;
+        if let Some(read) = read {
+            f(read)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the magic number, skips the SAM text header and calls `reference`
+/// with the name and length of each reference.
+fn read_bam_header(reader: &mut impl BufRead, mut reference: impl FnMut(&[u8], u32)) -> Result<()> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != BAM_MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "invalid BAM magic number").into());
+    }
+
+    // Skip SAM text header
+    let l_text = read_u32(reader)? as u64;
+    io::copy(&mut reader.take(l_text), &mut io::sink())?;
+
+    let n_refs = read_u32(reader)? as usize;
+    let mut buffer = Vec::new();
+    for _ in 0..n_refs {
+        let l_name = read_u32(reader)? as usize;
+        buffer.resize(l_name, 0);
+        reader.read_exact(&mut buffer)?;
+        let length = read_u32(reader)?;
+
+        // Name is NUL-terminated
+        reference(buffer.strip_suffix(&[0]).unwrap_or(&buffer), length);
+    }
+
+    Ok(())
+}
+
+/// Reads the next record into the buffer, returns false at the end of the file.
+fn read_bam_block(reader: &mut impl Read, buffer: &mut Vec<u8>) -> Result<bool> {
+    let mut block_size = [0u8; 4];
+    match reader.read_exact(&mut block_size) {
+        Ok(_) => (),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+        Err(e) => return Err(e.into()),
+    }
+
+    buffer.resize(u32::from_le_bytes(block_size) as usize, 0);
+    reader.read_exact(buffer)?;
+    Ok(true)
+}
+
+/// Parses the read of a single BAM record without the block size. Returns None
+/// for secondary and supplementary records.
+fn parse_bam_read(record: &[u8]) -> std::result::Result<Option<BamRead>, String> {
+    if record.len() < BAM_FIXED_SIZE {
+        return Err("record is too short".to_string());
+    }
+
+    let l_read_name = record[8] as usize;
+    let n_cigar_ops = u16::from_le_bytes(record[12..14].try_into().unwrap()) as usize;
+    let flag = u16::from_le_bytes(record[14..16].try_into().unwrap());
+    let l_seq = u32::from_le_bytes(record[16..20].try_into().unwrap()) as usize;
+
+    if flag & (FLAG_SECONDARY | FLAG_SUPPLEMENTARY) != 0 {
+        return Ok(None);
+    }
+
+    let seq_start = BAM_FIXED_SIZE + l_read_name + 4 * n_cigar_ops;
+    let qual_start = seq_start + ((l_seq + 1) >> 1);
+    let tags_start = qual_start + l_seq;
+    if record.len() < tags_start {
+        return Err("record is too short".to_string());
+    }
+
+    // Read name is NUL-terminated
+    let name = &record[BAM_FIXED_SIZE..BAM_FIXED_SIZE + l_read_name];
+    let name = name.strip_suffix(&[0]).unwrap_or(name).to_owned();
+    if l_seq == 0 {
+        return Err(format!(
+            "sequence should be present for read {}",
+            String::from_utf8_lossy(&name)
+        ));
+    }
+
+    let mut seq: Vec<_> = (0..l_seq)
+        .map(|i| {
+            let packed = record[seq_start + i / 2];
+            let code = if i % 2 == 0 {
+                packed >> 4
+            } else {
+                packed & 0xf
+            };
+            BAM_SEQ_DECODING[code as usize]
+        })
+        .collect();
+
+    // Missing qualities are stored as 0xff
+    let qual = &record[qual_start..tags_start];
+    let mut qual: Option<Vec<_>> =
+        (qual[0] != 0xff).then(|| qual.iter().map(|q| q.saturating_add(33)).collect());
+
+    if flag & FLAG_REVERSE != 0 {
+        seq.reverse();
+        seq.iter_mut().for_each(|b| *b = complement(*b));
+        if let Some(qual) = qual.as_mut() {
+            qual.reverse();
+        }
+    }
+
+    let tags = format_tags(&record[tags_start..]).ok_or("invalid tags")?;
+    Ok(Some(BamRead {
+        name,
+        seq,
+        qual,
+        tags,
+    }))
+}
+
+fn complement(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        b'M' => b'K',
+        b'K' => b'M',
+        b'R' => b'Y',
+        b'Y' => b'R',
+        b'V' => b'B',
+        b'B' => b'V',
+        b'H' => b'D',
+        b'D' => b'H',
+        b => b,
+    }
+}
+
+/// Converts binary BAM tags into the SAM text format, e.g. `RG:Z:run1` or
+/// `ch:i:42`, separated by tabs. Returns None if the tags are malformed.
+fn format_tags(mut tags: &[u8]) -> Option<Vec<u8>> {
+    let mut text = Vec::new();
+    while !tags.is_empty() {
+        let (tag, value_type) = (tags.get(..2)?, *tags.get(2)?);
+        tags = &tags[3..];
+
+        if !text.is_empty() {
+            text.push(b'\t');
+        }
+        text.extend_from_slice(tag);
+        let size = match value_type {
+            b'A' => {
+                text.extend_from_slice(b":A:");
+                text.push(*tags.first()?);
+                1
+            }
+            b'Z' | b'H' => {
+                let end = tags.iter().position(|&c| c == 0)?;
+                text.extend_from_slice(&[b':', value_type, b':']);
+                text.extend_from_slice(&tags[..end]);
+                end + 1
+            }
+            b'f' => {
+                let value = f32::from_le_bytes(tags.get(..4)?.try_into().unwrap());
+                write!(text, ":f:{}", value).unwrap();
+                4
+            }
+            b'B' => {
+                let elem_type = *tags.first()?;
+                let count = u32::from_le_bytes(tags.get(1..5)?.try_into().unwrap()) as usize;
+                let elem_size = tag_value_size(elem_type)?;
+                let values = tags.get(5..5 + elem_size * count)?;
+
+                write!(text, ":B:{}", elem_type as char).unwrap();
+                for value in values.chunks_exact(elem_size) {
+                    text.push(b',');
+                    format_tag_value(&mut text, elem_type, value)?;
+                }
+                5 + elem_size * count
+            }
+            _ => {
+                let size = tag_value_size(value_type)?;
+                text.extend_from_slice(b":i:");
+                format_tag_value(&mut text, value_type, tags.get(..size)?)?;
+                size
+            }
+        };
+
+        tags = tags.get(size..)?;
+    }
+
+    Some(text)
+}
+
+/// Size of a numeric tag value of the given type.
+fn tag_value_size(value_type: u8) -> Option<usize> {
+    match value_type {
+        b'c' | b'C' => Some(1),
+        b's' | b'S' => Some(2),
+        b'i' | b'I' | b'f' => Some(4),
+        _ => None,
+    }
+}
+
+fn format_tag_value(text: &mut Vec<u8>, value_type: u8, value: &[u8]) -> Option<()> {
+    match value_type {
+        b'c' => write!(text, "{}", value[0] as i8),
+        b'C' => write!(text, "{}", value[0]),
+        b's' => write!(text, "{}", i16::from_le_bytes(value.try_into().ok()?)),
+        b'S' => write!(text, "{}", u16::from_le_bytes(value.try_into().ok()?)),
+        b'i' => write!(text, "{}", i32::from_le_bytes(value.try_into().ok()?)),
+        b'I' => write!(text, "{}", u32::from_le_bytes(value.try_into().ok()?)),
+        b'f' => write!(text, "{}", f32::from_le_bytes(value.try_into().ok()?)),
+        _ => return None,
+    }
+    .unwrap();
+    Some(())
+}
+
+/// Parses a single BAM record without the block size. Error contains the
+/// description of the invalid field.
+fn parse_bam_record(
+    record: &[u8],
+    refs: &References,
+    name_to_id: &HashMap<&[u8], u32>,
+    overlap_filter: OverlapFilter,
+    targets: Option<&Range<u32>>,
+) -> std::result::Result<Option<Alignment>, String> {
+    if record.len() < BAM_FIXED_SIZE {
+        return Err("record is too short".to_string());
+    }
+
+    let ref_id = i32::from_le_bytes(record[0..4].try_into().unwrap());
+    let pos = i32::from_le_bytes(record[4..8].try_into().unwrap());
+    let l_read_name = record[8] as usize;
+    let mapq = record[9];
+    let n_cigar_ops = u16::from_le_bytes(record[12..14].try_into().unwrap()) as usize;
+    let flag = u16::from_le_bytes(record[14..16].try_into().unwrap());
+    let l_seq = u32::from_le_bytes(record[16..20].try_into().unwrap());
+
+    if flag & FLAG_UNMAPPED != 0 || ref_id < 0 || n_cigar_ops == 0 {
+        return Ok(None);
+    }
+    if !overlap_filter.is_valid_mapq(mapq)
+        || (overlap_filter.primary_only && flag & FLAG_SECONDARY != 0)
+    {
+        return Ok(None);
+    }
+
+    let cigar_start = BAM_FIXED_SIZE + l_read_name;
+    let mut cigar = record
+        .get(cigar_start..cigar_start + 4 * n_cigar_ops)
+        .ok_or("record is too short")?;
+
+    // Read name is NUL-terminated
+    let read_name = &record[BAM_FIXED_SIZE..cigar_start];
+    let Some(&qid) = name_to_id.get(read_name.strip_suffix(&[0]).unwrap_or(read_name)) else {
+        return Ok(None);
+    };
+    let Some((tid, tlen)) = *refs
+        .get(ref_id as usize)
+        .ok_or_else(|| format!("invalid reference id {}", ref_id))?
+    else {
+        return Ok(None);
+    };
+    if targets.is_some_and(|t| !t.contains(&tid) && !t.contains(&qid)) {
+        return Ok(None);
+    }
+
+    // CIGARs with more than 65535 operations are stored in the CG tag
+    let ops: Vec<_> = decode_bam_cigar(cigar).collect();
+    if ops.len() == 2 && ops[0] == (l_seq, b'S') && ops[1].1 == b'N' {
+        let tags_start =
+            cigar_start + 4 * n_cigar_ops + ((l_seq as usize + 1) >> 1) + l_seq as usize;
+        cigar = record
+            .get(tags_start..)
+            .and_then(find_cg_tag)
+            .ok_or("CG tag should be present")?;
+    }
+
+    let alignment = convert_record(qid, flag, tid, tlen, pos as u32, decode_bam_cigar(cigar))?;
+    Ok(alignment.filter(|aln| is_valid_overlap(&aln.overlap, &overlap_filter)))
+}
+
+fn decode_bam_cigar(cigar: &[u8]) -> impl Iterator<Item = (u32, u8)> + '_ {
+    cigar.chunks_exact(4).map(|op| {
+        let op = u32::from_le_bytes(op.try_into().unwrap());
+        (op >> 4, BAM_CIGAR_OPS[(op & 0xf) as usize])
+    })
+}
+
+/// Finds the value of the CG tag, returns None if the tag is missing or the
+/// tags are malformed.
+fn find_cg_tag(mut tags: &[u8]) -> Option<&[u8]> {
+    while tags.len() >= 3 {
+        let (tag, value_type) = (&tags[..2], tags[2]);
+        tags = &tags[3..];
+
+        let size = match value_type {
+            b'A' | b'c' | b'C' => 1,
+            b's' | b'S' => 2,
+            b'i' | b'I' | b'f' => 4,
+            b'Z' | b'H' => tags.iter().position(|&c| c == 0)? + 1,
+            b'B' => {
+                let elem_size = match tags.first()? {
+                    b'c' | b'C' => 1,
+                    b's' | b'S' => 2,
+                    _ => 4,
+                };
+                let count = u32::from_le_bytes(tags.get(1..5)?.try_into().unwrap()) as usize;
+
+                if tag == b"CG" {
+                    return tags.get(5..5 + elem_size * count);
+                }
+
+                5 + elem_size * count
+            }
+            _ => return None,
+        };
+
+        tags = tags.get(size..)?;
+    }
+
+    None
+}
+
+fn parse_sam_cigar(cigar: &[u8]) -> impl Iterator<Item = (u32, u8)> + '_ {
+    let mut l = 0;
+    cigar.iter().filter_map(move |&c| {
+        if c.is_ascii_digit() {
+            l = l * 10 + (c - b'0') as u32;
+            None
+        } else {
+            let op = (l, c);
+            l = 0;
+            Some(op)
+        }
+    })
+}
+
+/// Converts SAM alignment into alignment between two reads. Query coordinates
+/// are given on the forward strand and CIGAR is in the target orientation, same
+/// as for PAF alignments.
+fn convert_record(
+    qid: u32,
+    flag: u16,
+    tid: u32,
+    tlen: u32,
+    tstart: u32,
+    ops: impl Iterator<Item = (u32, u8)>,
+) -> std::result::Result<Option<Alignment>, String> {
+    let (mut lclip, mut rclip) = (0, 0);
+    let (mut qspan, mut tspan) = (0, 0);
+
+    let mut cigar: Vec<CigarOp> = Vec::new();
+    for (l, op) in ops {
+        let op = match op {
+            b'S' | b'H' => {
+                if cigar.is_empty() {
+                    lclip += l;
+                } else {
+                    rclip += l;
+                }
+                continue;
+            }
+            b'P' => continue,
+            b'M' | b'=' | b'X' => {
+                qspan += l;
+                tspan += l;
+                CigarOp::Match(l)
+            }
+            b'I' => {
+                qspan += l;
+                CigarOp::Insertion(l)
+            }
+            b'D' => {
+                tspan += l;
+                CigarOp::Deletion(l)
+            }
+            b'N' => return Ok(None), // Skipped regions are not valid for read overlaps
+            _ => return Err(format!("invalid CIGAR operation '{}'", op as char)),
+        };
+
+        // Merge ops -> because of converting =/X to match
+        match cigar.last_mut() {
+            Some(last) if std::mem::discriminant(last) == std::mem::discriminant(&op) => {
+                *last = last.with_length(last.get_length() + l)
+            }
+            _ => cigar.push(op),
+        }
+    }
+
+    if cigar.is_empty() {
+        return Ok(None);
+    }
+    if tstart + tspan > tlen {
+        return Err("alignment should end within the reference".to_string());
+    }
+
+    let qlen = lclip + qspan + rclip;
+    let (strand, qstart) = if flag & FLAG_REVERSE != 0 {
+        (Strand::Reverse, rclip)
+    } else {
+        (Strand::Forward, lclip)
+    };
+
+    let overlap = Overlap::new(
+        qid,
+        qlen,
+        qstart,
+        qstart + qspan,
+        strand,
+        tid,
+        tlen,
+        tstart,
+        tstart + tspan,
+    );
+    Ok(Some(Alignment::new(overlap, cigar)))
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_hash::FxHashMap as HashMap;
+
+    use crate::aligners::CigarOp;
+    use crate::error::Result;
+    use crate::overlaps::{Alignment, OverlapFilter, PairSelector, Strand};
+    use crate::WindowReadSelection;
+
+    use super::{find_cg_tag, parse_bam, parse_bam_reads, parse_sam};
+
+    /// Accept every overlap, tests only check the record conversion.
+    const NO_FILTER: OverlapFilter = OverlapFilter {
+        ol_threshold: u32::MAX,
+        max_span_ratio: f32::INFINITY,
+        keep_first: false,
+        max_divergence: None,
+        max_overlaps: None,
+        max_coverage: None,
+        min_mapq: None,
+        min_overlap_len: 0,
+        primary_only: false,
+        strict: false,
+        extend: 0,
+        min_window_accuracy: None,
+        window_reads: WindowReadSelection::Identity,
+    };
+
+    fn name_to_id() -> HashMap<&'static [u8], u32> {
+        [(&b"r0"[..], 0), (&b"r1"[..], 1), (&b"r2"[..], 2)]
+            .into_iter()
+            .collect()
+    }
+
+    fn sam_alignments(sam: &str, filter: OverlapFilter) -> Result<HashMap<u32, Vec<Alignment>>> {
+        let mut selector = PairSelector::new(filter.keep_first);
+        parse_sam(sam.as_bytes(), &name_to_id(), filter, None, &mut selector)?;
+        Ok(selector.into_alignments())
+    }
+
+    #[test]
+    fn parse_sam_records() {
+        let sam = "@HD\tVN:1.6\n\
+                   @SQ\tSN:r0\tLN:100\n\
+                   @SQ\tSN:r1\tLN:80\n\
+                   r1\t0\tr0\t11\t60\t5S10M2I3=1X4D20M\t*\t0\t0\t*\t*\n\
+                   r2\t16\tr0\t1\t60\t3H30M7S\t*\t0\t0\t*\t*\n\
+                   r1\t256\tr0\t1\t0\t40M\t*\t0\t0\t*\t*\n\
+                   r0\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n";
+
+        // Secondary alignment has more matches and replaces the first one
+        let alns = sam_alignments(sam, NO_FILTER).unwrap();
+        let alns = alns.get(&0).unwrap();
+        assert_eq!(alns.len(), 2);
+        assert_eq!(alns[0].cigar, [CigarOp::Match(40)]);
+
+        let filter = OverlapFilter {
+            keep_first: true,
+            ..NO_FILTER
+        };
+        let alns = sam_alignments(sam, filter).unwrap();
+        let alns = alns.get(&0).unwrap();
+        assert_eq!(alns.len(), 2);
+
+        let ovlp = &alns[0].overlap;
+        assert_eq!(
+            (ovlp.qid, ovlp.qlen, ovlp.qstart, ovlp.qend),
+            (1, 41, 5, 41)
+        );
+        assert_eq!(
+            (ovlp.tid, ovlp.tlen, ovlp.tstart, ovlp.tend),
+            (0, 100, 10, 48)
+        );
+        assert_eq!(ovlp.strand, Strand::Forward);
+        assert_eq!(
+            alns[0].cigar,
+            [
+                CigarOp::Match(10),
+                CigarOp::Insertion(2),
+                CigarOp::Match(4),
+                CigarOp::Deletion(4),
+                CigarOp::Match(20)
+            ]
+        );
+
+        let ovlp = &alns[1].overlap;
+        assert_eq!(
+            (ovlp.qid, ovlp.qlen, ovlp.qstart, ovlp.qend),
+            (2, 40, 7, 37)
+        );
+        assert_eq!((ovlp.tstart, ovlp.tend), (0, 30));
+        assert_eq!(ovlp.strand, Strand::Reverse);
+    }
+
+    fn bam_record(qname: &[u8], flag: u16, ref_id: i32, pos: i32, cigar: &[(u32, u32)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(ref_id.to_le_bytes());
+        data.extend(pos.to_le_bytes());
+        data.push(qname.len() as u8 + 1);
+        data.push(60);
+        data.extend(0u16.to_le_bytes());
+        data.extend((cigar.len() as u16).to_le_bytes());
+        data.extend(flag.to_le_bytes());
+        data.extend(0u32.to_le_bytes());
+        data.extend((-1i32).to_le_bytes());
+        data.extend((-1i32).to_le_bytes());
+        data.extend(0i32.to_le_bytes());
+        data.extend(qname);
+        data.push(0);
+        cigar
+            .iter()
+            .for_each(|(l, op)| data.extend((l << 4 | op).to_le_bytes()));
+
+        let mut record = (data.len() as u32).to_le_bytes().to_vec();
+        record.extend(data);
+        record
+    }
+
+    #[test]
+    fn parse_bam_records() {
+        let mut bam = b"BAM\x01".to_vec();
+        bam.extend(0u32.to_le_bytes());
+        bam.extend(1u32.to_le_bytes());
+        bam.extend(3u32.to_le_bytes());
+        bam.extend(b"r0\x00");
+        bam.extend(100u32.to_le_bytes());
+
+        bam.extend(bam_record(
+            b"r1",
+            0,
+            0,
+            10,
+            &[(5, 4), (30, 0), (2, 1), (10, 0)],
+        ));
+        bam.extend(bam_record(
+            b"r2",
+            16,
+            0,
+            0,
+            &[(40, 0), (3, 2), (5, 0), (6, 5)],
+        ));
+
+        let mut selector = PairSelector::new(false);
+        parse_bam(&bam[..], &name_to_id(), NO_FILTER, None, &mut selector).unwrap();
+        let alns = selector.into_alignments();
+        let alns = alns.get(&0).unwrap();
+        assert_eq!(alns.len(), 2);
+
+        let ovlp = &alns[0].overlap;
+        assert_eq!(
+            (ovlp.qid, ovlp.qlen, ovlp.qstart, ovlp.qend),
+            (1, 47, 5, 47)
+        );
+        assert_eq!((ovlp.tstart, ovlp.tend), (10, 50));
+        assert_eq!(
+            alns[0].cigar,
+            [
+                CigarOp::Match(30),
+                CigarOp::Insertion(2),
+                CigarOp::Match(10)
+            ]
+        );
+
+        let ovlp = &alns[1].overlap;
+        assert_eq!(
+            (ovlp.qid, ovlp.qlen, ovlp.qstart, ovlp.qend),
+            (2, 51, 6, 51)
+        );
+        assert_eq!((ovlp.tstart, ovlp.tend), (0, 48));
+        assert_eq!(ovlp.strand, Strand::Reverse);
+    }
+
+    #[test]
+    fn find_cg_tag_after_other_tags() {
+        let mut tags = b"NMi".to_vec();
+        tags.extend(3u32.to_le_bytes());
+        tags.extend(b"tpAP");
+        tags.extend(b"CGBI");
+        tags.extend(1u32.to_le_bytes());
+        tags.extend((100u32 << 4).to_le_bytes());
+
+        assert_eq!(find_cg_tag(&tags), Some(&(100u32 << 4).to_le_bytes()[..]));
+    }
+
+    fn unmapped_record(qname: &[u8], flag: u16, seq: &[u8], qual: &[u8], tags: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend((-1i32).to_le_bytes());
+        data.extend((-1i32).to_le_bytes());
+        data.push(qname.len() as u8 + 1);
+        data.push(0);
+        data.extend(4680u16.to_le_bytes());
+        data.extend(0u16.to_le_bytes());
+        data.extend((flag | 0x4).to_le_bytes());
+        data.extend((seq.len() as u32).to_le_bytes());
+        data.extend((-1i32).to_le_bytes());
+        data.extend((-1i32).to_le_bytes());
+        data.extend(0i32.to_le_bytes());
+        data.extend(qname);
+        data.push(0);
+
+        let code = |b: &u8| b"=ACMGRSVTWYHKDBN".iter().position(|c| c == b).unwrap() as u8;
+        data.extend(
+            seq.chunks(2)
+                .map(|p| code(&p[0]) << 4 | p.get(1).map_or(0, code)),
+        );
+        data.extend(qual);
+        data.extend(tags);
+
+        let mut record = (data.len() as u32).to_le_bytes().to_vec();
+        record.extend(data);
+        record
+    }
+
+    #[test]
+    fn parse_bam_unmapped_reads() {
+        let mut bam = b"BAM\x01".to_vec();
+        bam.extend(0u32.to_le_bytes());
+        bam.extend(0u32.to_le_bytes());
+
+        let mut tags = b"RGZrun1_model\x00chC".to_vec();
+        tags.push(42);
+        tags.extend(b"qsf");
+        tags.extend(12.5f32.to_le_bytes());
+        tags.extend(b"mvBc");
+        tags.extend(2u32.to_le_bytes());
+        tags.extend([5, 0xff]);
+        bam.extend(unmapped_record(
+            b"r1",
+            0,
+            b"ACGTN",
+            &[10, 20, 30, 40, 0],
+            &tags,
+        ));
+        bam.extend(unmapped_record(b"r2", 0x10, b"AACG", &[1, 2, 3, 4], b""));
+        bam.extend(unmapped_record(b"r3", 0x900, b"ACGT", &[1, 2, 3, 4], b""));
+        bam.extend(unmapped_record(b"r4", 0, b"ACG", &[0xff, 0xff, 0xff], b""));
+
+        let mut reads = Vec::new();
+        parse_bam_reads(&bam[..], |read| {
+            reads.push(read);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(reads.len(), 3);
+
+        assert_eq!(reads[0].name, b"r1");
+        assert_eq!(reads[0].seq, b"ACGTN");
+        assert_eq!(reads[0].qual.as_deref(), Some(&b"+5?I!"[..]));
+        assert_eq!(
+            reads[0].tags,
+            b"RG:Z:run1_model\tch:i:42\tqs:f:12.5\tmv:B:c,5,-1"
+        );
+
+        // Reverse strand records are stored reverse complemented
+        assert_eq!(reads[1].name, b"r2");
+        assert_eq!(reads[1].seq, b"CGTT");
+        assert_eq!(reads[1].qual.as_deref(), Some(&b"%$#\""[..]));
+        assert!(reads[1].tags.is_empty());
+
+        assert_eq!(reads[2].name, b"r4");
+        assert_eq!(reads[2].qual, None);
+    }
+
+    #[test]
+    fn parse_sam_invalid_records() {
+        let sam = "@SQ\tSN:r0\tLN:100\n\
+                   r1\t0\tr0\t11\t60\t10M\t*\t0\t0\t*\t*\n\
+                   r2\t0\tr0\n";
+        let err = sam_alignments(sam, NO_FILTER).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid record at line 3: missing position"
+        );
+
+        // Alignment ends after the end of the reference
+        let sam = "@SQ\tSN:r0\tLN:100\n\
+                   r1\t0\tr0\t95\t60\t10M\t*\t0\t0\t*\t*\n";
+        assert!(sam_alignments(sam, NO_FILTER).is_err());
+    }
+}
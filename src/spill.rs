@@ -0,0 +1,340 @@
+use std::fs::{self, File};
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+use crossbeam_channel::{Receiver, Sender};
+use ndarray::Array2;
+use tracing::info;
+
+use crate::error::{HerroError, Result};
+use crate::features::SupportedPos;
+use crate::inference::{prepare_examples, FeatureEncoder, InferenceData, WindowExample};
+
+/// Spilling is on the path of feature generation, so it favours speed
+const SPILL_ZSTD_LEVEL: i32 = 1;
+
+/// Batch of window features spilled to disk, with the estimated memory of its
+/// features.
+pub(crate) type SpilledBatch = (PathBuf, u64);
+
+/// Memory budget of the feature batches waiting for inference. Batches which
+/// do not fit into the budget are spilled to disk by feature generation and
+/// read back once inference releases enough memory, instead of growing the
+/// queue on dense repeats until the process is killed.
+pub(crate) struct FeatureSpill {
+    dir: PathBuf,
+    /// Memory budget in bytes
+    budget: u64,
+    /// Memory of the queued batches in bytes
+    used: Mutex<u64>,
+    released: Condvar,
+    n_files: AtomicU64,
+    n_windows: AtomicU64,
+}
+
+/// Memory of a queued batch, released when the batch is dropped after
+/// inference.
+pub(crate) struct Reservation {
+    spill: Arc<FeatureSpill>,
+    bytes: u64,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        *self.spill.used.lock().unwrap() -= self.bytes;
+        self.spill.released.notify_all();
+    }
+}
+
+impl FeatureSpill {
+    /// Spills batches into a new directory in `dir` when the queued batches
+    /// take more than `budget` bytes.
+    pub(crate) fn new<P: AsRef<Path>>(dir: P, budget: u64) -> Result<Arc<Self>> {
+        let dir = dir.as_ref().join(format!("herro-spill-{}", process::id()));
+        fs::create_dir_all(&dir).map_err(|e| HerroError::from(e).in_file(&dir))?;
+
+        Ok(Arc::new(Self {
+            dir,
+            budget,
+            used: Mutex::new(0),
+            released: Condvar::new(),
+            n_files: AtomicU64::new(0),
+            n_windows: AtomicU64::new(0),
+        }))
+    }
+
+    /// Reserves the memory of a batch if it fits into the budget. A batch is
+    /// always queued when the queue is empty, also if it is larger than the
+    /// budget.
+    pub(crate) fn try_reserve(self: &Arc<Self>, bytes: u64) -> Option<Reservation> {
+        let mut used = self.used.lock().unwrap();
+        if *used > 0 && *used + bytes > self.budget {
+            return None;
+        }
+
+        *used += bytes;
+        Some(Reservation {
+            spill: Arc::clone(self),
+            bytes,
+        })
+    }
+
+    /// Waits until the batch fits into the budget and reserves its memory.
+    fn reserve(self: &Arc<Self>, bytes: u64) -> Reservation {
+        let mut used = self.used.lock().unwrap();
+        while *used > 0 && *used + bytes > self.budget {
+            used = self.released.wait(used).unwrap();
+        }
+
+        *used += bytes;
+        Reservation {
+            spill: Arc::clone(self),
+            bytes,
+        }
+    }
+
+    /// Writes the windows into a new zstd-compressed file of the spill
+    /// directory.
+    pub(crate) fn write(
+        &self,
+        examples: impl IntoIterator<Item = WindowExample>,
+    ) -> Result<PathBuf> {
+        let n_file = self.n_files.fetch_add(1, Ordering::Relaxed);
+        let path = self.dir.join(format!("{}.bin.zst", n_file));
+
+        let write = || -> io::Result<()> {
+            let file = BufWriter::new(File::create(&path)?);
+            let mut writer = zstd::Encoder::new(file, SPILL_ZSTD_LEVEL)?;
+            for example in examples {
+                writer.write_all(&[1])?;
+                write_example(&mut writer, &example)?;
+                self.n_windows.fetch_add(1, Ordering::Relaxed);
+            }
+            writer.write_all(&[0])?;
+            writer.finish()?.flush()
+        };
+        write().map_err(|e| HerroError::from(e).in_file(&path))?;
+
+        Ok(path)
+    }
+}
+
+impl Drop for FeatureSpill {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Estimated memory of the features of the windows in bytes.
+pub(crate) fn features_memory<'a>(examples: impl IntoIterator<Item = &'a WindowExample>) -> u64 {
+    examples
+        .into_iter()
+        .map(|e| {
+            e.bases.len() * (1 + 4)
+                + e.supported.len() * size_of::<SupportedPos>()
+                + e.qids.len() * 4
+                + e.breaks.len() * 2
+        })
+        .sum::<usize>() as u64
+}
+
+/// Reads the spilled batches back once their features fit into the memory
+/// budget and sends them to inference. Stops when all feature generation
+/// threads are done.
+pub(crate) fn spill_reader(
+    spill: Arc<FeatureSpill>,
+    spilled: Receiver<SpilledBatch>,
+    sender: Sender<InferenceData>,
+    batch_size: usize,
+    encoder: &dyn FeatureEncoder,
+) -> Result<()> {
+    let mut n_batches = 0;
+    for (path, bytes) in spilled {
+        let reservation = spill.reserve(bytes);
+        let examples = read_examples(&path).map_err(|e| HerroError::from(e).in_file(&path))?;
+        fs::remove_file(&path).map_err(|e| HerroError::from(e).in_file(&path))?;
+
+        let data = prepare_examples(examples, batch_size, encoder).with_reservation(reservation);
+        sender.send(data).unwrap();
+        n_batches += 1;
+    }
+
+    if n_batches > 0 {
+        info!(
+            "Spilled {} windows in {} batches to disk, the features exceeded the memory budget",
+            spill.n_windows.load(Ordering::Relaxed),
+            n_batches
+        );
+    }
+    Ok(())
+}
+
+fn read_examples(path: &Path) -> io::Result<Vec<WindowExample>> {
+    let mut reader = zstd::Decoder::new(BufReader::new(File::open(path)?))?;
+    let mut examples = Vec::new();
+    while read_array::<1>(&mut reader)?[0] == 1 {
+        examples.push(read_example(&mut reader)?);
+    }
+    Ok(examples)
+}
+
+/// Writes the window as little-endian integers: read id, window id, number of
+/// windows of the read, number of supporting reads, the shape and values of
+/// the bases and quals, and the lengths and values of the supported positions,
+/// supporting read ids and breaks.
+fn write_example<W: Write>(writer: &mut W, example: &WindowExample) -> io::Result<()> {
+    writer.write_all(&example.rid.to_le_bytes())?;
+    writer.write_all(&example.wid.to_le_bytes())?;
+    writer.write_all(&example.n_total_wins.to_le_bytes())?;
+    writer.write_all(&[example.n_alns])?;
+
+    let (length, n_rows) = example.bases.dim();
+    writer.write_all(&(length as u32).to_le_bytes())?;
+    writer.write_all(&(n_rows as u32).to_le_bytes())?;
+    let bases: Vec<_> = example.bases.iter().copied().collect();
+    writer.write_all(&bases)?;
+    for q in example.quals.iter() {
+        writer.write_all(&q.to_le_bytes())?;
+    }
+
+    writer.write_all(&(example.supported.len() as u32).to_le_bytes())?;
+    for s in example.supported.iter() {
+        writer.write_all(&s.pos.to_le_bytes())?;
+        writer.write_all(&[s.ins])?;
+    }
+    writer.write_all(&(example.qids.len() as u32).to_le_bytes())?;
+    for qid in example.qids.iter() {
+        writer.write_all(&qid.to_le_bytes())?;
+    }
+    writer.write_all(&(example.breaks.len() as u32).to_le_bytes())?;
+    for b in example.breaks.iter() {
+        writer.write_all(&b.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buffer = [0; N];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    read_array(reader).map(u32::from_le_bytes)
+}
+
+fn read_u16(reader: &mut impl Read) -> io::Result<u16> {
+    read_array(reader).map(u16::from_le_bytes)
+}
+
+fn read_example(reader: &mut impl Read) -> io::Result<WindowExample> {
+    let rid = read_u32(reader)?;
+    let wid = read_u16(reader)?;
+    let n_total_wins = read_u16(reader)?;
+    let n_alns = read_array::<1>(reader)?[0];
+
+    let shape = (read_u32(reader)? as usize, read_u32(reader)? as usize);
+    let mut bases = vec![0; shape.0 * shape.1];
+    reader.read_exact(&mut bases)?;
+    let quals = (0..bases.len())
+        .map(|_| read_array(reader).map(f32::from_le_bytes))
+        .collect::<io::Result<Vec<_>>>()?;
+    let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+    let bases = Array2::from_shape_vec(shape, bases).map_err(invalid)?;
+    let quals = Array2::from_shape_vec(shape, quals).map_err(invalid)?;
+
+    let n_supported = read_u32(reader)?;
+    let supported = (0..n_supported)
+        .map(|_| {
+            Ok(SupportedPos::new(
+                read_u16(reader)?,
+                read_array::<1>(reader)?[0],
+            ))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let n_qids = read_u32(reader)?;
+    let qids = (0..n_qids)
+        .map(|_| read_u32(reader))
+        .collect::<io::Result<Vec<_>>>()?;
+    let n_breaks = read_u32(reader)?;
+    let breaks = (0..n_breaks)
+        .map(|_| read_u16(reader))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut example = WindowExample::new(rid, wid, bases, quals, supported, qids, n_total_wins);
+    example.n_alns = n_alns;
+    Ok(example.with_breaks(breaks))
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+
+    use super::{features_memory, read_examples, FeatureSpill};
+    use crate::features::SupportedPos;
+    use crate::inference::WindowExample;
+
+    fn example(rid: u32, length: usize) -> WindowExample {
+        let bases = Array2::from_shape_fn((length, 3), |(i, j)| b"ACGT*"[(i + j) % 5]);
+        let quals = Array2::from_shape_fn((length, 3), |(i, j)| (i * 3 + j) as f32 / 2.);
+        let supported = vec![SupportedPos::new(1, 0), SupportedPos::new(2, 3)];
+        WindowExample::new(rid, 4, bases, quals, supported, vec![5, 7], 9).with_breaks(vec![2])
+    }
+
+    #[test]
+    fn spill_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("herro-spill-test-{}", std::process::id()));
+        let spill = FeatureSpill::new(&dir, 1_000).unwrap();
+
+        let path = spill.write([example(1, 10), example(2, 4)]).unwrap();
+        let examples = read_examples(&path).unwrap();
+        assert_eq!(examples.len(), 2);
+        for (read, expected) in examples.iter().zip([example(1, 10), example(2, 4)]) {
+            assert_eq!(
+                (read.rid, read.wid, read.n_total_wins),
+                (expected.rid, 4, 9)
+            );
+            assert_eq!(read.n_alns, 2);
+            assert_eq!(
+                (read.bases.clone(), read.quals.clone()),
+                (expected.bases, expected.quals)
+            );
+            assert_eq!(read.supported, expected.supported);
+            assert_eq!(
+                (read.qids.clone(), read.breaks.clone()),
+                (vec![5, 7], vec![2])
+            );
+        }
+
+        drop(spill);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn memory_budget() {
+        let dir = std::env::temp_dir().join(format!("herro-budget-test-{}", std::process::id()));
+        let spill = FeatureSpill::new(&dir, 1_000).unwrap();
+        assert_eq!(
+            features_memory(&[example(0, 10)]),
+            10 * 3 * 5 + 2 * 4 + 2 * 4 + 2
+        );
+
+        // The first batch is queued even if it is larger than the budget
+        let first = spill.try_reserve(1_500).unwrap();
+        assert!(spill.try_reserve(1).is_none());
+        drop(first);
+        let second = spill.try_reserve(600).unwrap();
+        assert!(spill.try_reserve(500).is_none());
+        let third = spill.try_reserve(400).unwrap();
+        drop((second, third));
+        assert_eq!(*spill.used.lock().unwrap(), 0);
+
+        drop(spill);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,341 @@
+use std::fmt;
+use std::path::Path;
+
+use rustc_hash::FxHashMap as HashMap;
+use tracing::info;
+
+use crate::config::available_threads;
+use crate::error::Result;
+use crate::haec_io;
+use crate::overlaps::{read_overlaps, OverlapFilter, OverlapsFormat, PairSelector, Rejection};
+
+/// Rejections reported by the stats, in order. Targets are not batched.
+const REPORTED_REJECTIONS: [Rejection; 8] = [
+    Rejection::UnknownRead,
+    Rejection::SelfOverlap,
+    Rejection::SpanRatio,
+    Rejection::Overhang,
+    Rejection::Divergence,
+    Rejection::MappingQuality,
+    Rejection::Secondary,
+    Rejection::ShortOverlap,
+];
+
+/// Statistics of the reads and their overlaps, used to check whether the
+/// overlaps are suitable for correction. Reads shorter than the window size
+/// are not corrected, so only longer reads are used as targets.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    window_size: u32,
+    max_overlaps: Option<usize>,
+    reads: ReadStats,
+    overlaps: OverlapStats,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ReadStats {
+    n_reads: usize,
+    n_bases: u64,
+    n50: u32,
+}
+
+impl ReadStats {
+    fn new(mut lengths: Vec<u32>) -> Self {
+        lengths.sort_unstable_by(|a, b| b.cmp(a));
+        let n_bases: u64 = lengths.iter().map(|&l| l as u64).sum();
+
+        let mut cumulative = 0;
+        let n50 = lengths
+            .iter()
+            .find(|&&l| {
+                cumulative += l as u64;
+                2 * cumulative >= n_bases
+            })
+            .copied()
+            .unwrap_or(0);
+
+        ReadStats {
+            n_reads: lengths.len(),
+            n_bases,
+            n50,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct OverlapStats {
+    n_records: u64,
+    n_valid: u64,
+    n_duplicates: u64,
+    rejected: [u64; REPORTED_REJECTIONS.len()],
+    /// Lengths of the target reads
+    lengths: Vec<u32>,
+    /// Number of valid overlaps of each target read
+    n_overlaps: Vec<u32>,
+    /// Sum of the spans on each target read of its valid overlaps
+    covered: Vec<u64>,
+}
+
+impl OverlapStats {
+    fn new(lengths: Vec<u32>) -> Self {
+        let n_targets = lengths.len();
+        OverlapStats {
+            n_records: 0,
+            n_valid: 0,
+            n_duplicates: 0,
+            rejected: [0; REPORTED_REJECTIONS.len()],
+            lengths,
+            n_overlaps: vec![0; n_targets],
+            covered: vec![0; n_targets],
+        }
+    }
+
+    /// Counts the overlaps selected as for correction, a single overlap per
+    /// read pair, which is credited to both of its reads with their spans.
+    fn count(&mut self, selector: PairSelector) {
+        for (i, &rejection) in REPORTED_REJECTIONS.iter().enumerate() {
+            self.rejected[i] += selector.n_rejected(rejection);
+        }
+        self.n_duplicates += selector.n_duplicates();
+
+        for (id, alignments) in selector.into_alignments() {
+            let id = id as usize;
+            self.n_overlaps[id] += alignments.len() as u32;
+            for alignment in alignments {
+                let overlap = &alignment.overlap;
+                let span = if overlap.qid as usize == id {
+                    overlap.qend - overlap.qstart
+                } else {
+                    overlap.tend - overlap.tstart
+                };
+                self.covered[id] += span as u64;
+            }
+        }
+
+        // Only targets have ids, so each pair is counted with both of its reads
+        self.n_valid = self.n_overlaps.iter().map(|&n| n as u64).sum::<u64>() / 2;
+        self.n_records = self.n_valid + self.n_duplicates + self.rejected.iter().sum::<u64>();
+    }
+}
+
+/// Computes the statistics of the reads and their overlaps in PAF, SAM, BAM or
+/// M4 (PAF/SAM/M4 can be compressed, - for PAF from stdin). Overlaps from all
+/// files are merged as for correction.
+pub fn overlap_stats<T, U>(
+    reads_path: T,
+    overlaps_paths: Vec<U>,
+    overlaps_format: Option<OverlapsFormat>,
+    window_size: u32,
+    overlap_filter: OverlapFilter,
+) -> Result<Stats>
+where
+    T: AsRef<Path>,
+    U: AsRef<Path>,
+{
+    let reads = haec_io::read_lengths(&reads_path)?;
+    info!(
+        "Parsed {} reads from {}",
+        reads.len(),
+        reads_path.as_ref().display()
+    );
+
+    let read_stats = ReadStats::new(reads.iter().map(|(_, l)| *l).collect());
+    let targets: Vec<_> = reads.iter().filter(|(_, l)| *l >= window_size).collect();
+    let name_to_id: HashMap<_, _> = targets
+        .iter()
+        .enumerate()
+        .map(|(i, (id, _))| (&**id, i as u32))
+        .collect();
+
+    let mut overlap_stats = OverlapStats::new(targets.iter().map(|(_, l)| *l).collect());
+    // All targets in a single batch
+    for selector in read_overlaps(
+        &name_to_id,
+        overlaps_paths,
+        std::iter::once(0..targets.len()).collect(),
+        overlaps_format,
+        overlap_filter,
+        available_threads(),
+    )? {
+        overlap_stats.count(selector?);
+    }
+
+    Ok(Stats {
+        window_size,
+        max_overlaps: overlap_filter.max_overlaps,
+        reads: read_stats,
+        overlaps: overlap_stats,
+    })
+}
+
+fn percent(n: u64, total: u64) -> f64 {
+    match total {
+        0 => 0.,
+        _ => 100. * n as f64 / total as f64,
+    }
+}
+
+fn median<T: Copy + Ord + Default>(mut values: Vec<T>) -> T {
+    values.sort_unstable();
+    values.get(values.len() / 2).copied().unwrap_or_default()
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reads = &self.reads;
+        let overlaps = &self.overlaps;
+        let n_targets = overlaps.lengths.len();
+
+        writeln!(f, "{:<40}{}", "Reads", reads.n_reads)?;
+        writeln!(f, "{:<40}{}", "Bases", reads.n_bases)?;
+        writeln!(f, "{:<40}{}", "Read N50", reads.n50)?;
+        writeln!(
+            f,
+            "{:<40}{} ({:.1}%)",
+            format!("Target reads (at least {} bases)", self.window_size),
+            n_targets,
+            percent(n_targets as u64, reads.n_reads as u64)
+        )?;
+
+        writeln!(f)?;
+        writeln!(f, "{:<40}{}", "Overlaps", overlaps.n_records)?;
+        writeln!(
+            f,
+            "{:<40}{} ({:.1}%)",
+            "Valid overlaps",
+            overlaps.n_valid,
+            percent(overlaps.n_valid, overlaps.n_records)
+        )?;
+        writeln!(
+            f,
+            "{:<40}{} ({:.1}%)",
+            "Duplicate read pairs",
+            overlaps.n_duplicates,
+            percent(overlaps.n_duplicates, overlaps.n_records)
+        )?;
+        for (rejection, &n) in REPORTED_REJECTIONS.iter().zip(overlaps.rejected.iter()) {
+            writeln!(
+                f,
+                "{:<40}{} ({:.1}%)",
+                format!("Rejected: {}", rejection.description()),
+                n,
+                percent(n, overlaps.n_records)
+            )?;
+        }
+
+        let n_without = overlaps.n_overlaps.iter().filter(|&&n| n == 0).count();
+        let max_overlaps = overlaps.n_overlaps.iter().max().copied().unwrap_or(0);
+        let mean_overlaps = match n_targets {
+            0 => 0.,
+            _ => 2. * overlaps.n_valid as f64 / n_targets as f64,
+        };
+
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:<40}{} ({:.1}%)",
+            "Target reads without overlaps",
+            n_without,
+            percent(n_without as u64, n_targets as u64)
+        )?;
+        writeln!(
+            f,
+            "{:<40}median {}, mean {:.1}, max {}",
+            "Overlaps per target read",
+            median(overlaps.n_overlaps.clone()),
+            mean_overlaps,
+            max_overlaps
+        )?;
+        if let Some(limit) = self.max_overlaps {
+            let n_over = overlaps
+                .n_overlaps
+                .iter()
+                .filter(|&&n| n as usize > limit)
+                .count();
+            writeln!(
+                f,
+                "{:<40}{}",
+                format!("Target reads over {} overlaps", limit),
+                n_over
+            )?;
+        }
+
+        // Coverage is given as the mean depth of the overlaps over the target
+        let coverage: Vec<_> = overlaps
+            .covered
+            .iter()
+            .zip(overlaps.lengths.iter())
+            .map(|(&c, &l)| c as f64 / l as f64)
+            .collect();
+        let mean_coverage = match n_targets {
+            0 => 0.,
+            _ => coverage.iter().sum::<f64>() / n_targets as f64,
+        };
+        let mut sorted = coverage;
+        sorted.sort_unstable_by(f64::total_cmp);
+        writeln!(
+            f,
+            "{:<40}median {:.1}, mean {:.1}",
+            "Estimated coverage per target read",
+            sorted.get(n_targets / 2).copied().unwrap_or(0.),
+            mean_coverage
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_hash::FxHashMap as HashMap;
+
+    use crate::overlaps::{parse_paf, MalformedLines, OverlapFilter, PairSelector};
+
+    use super::{median, OverlapStats, ReadStats};
+
+    #[test]
+    fn read_n50() {
+        let stats = ReadStats::new(vec![2, 3, 4, 5, 6, 10]);
+        assert_eq!((stats.n_reads, stats.n_bases, stats.n50), (6, 30, 6));
+
+        assert_eq!(ReadStats::new(Vec::new()).n50, 0);
+        assert_eq!(median(vec![3, 1, 2]), 2);
+    }
+
+    #[test]
+    fn count_rejected_overlaps() {
+        let name_to_id: HashMap<&[u8], u32> = [(&b"r0"[..], 0), (&b"r1"[..], 1), (&b"r2"[..], 2)]
+            .into_iter()
+            .collect();
+        let paf = "r1\t10000\t0\t6000\t+\tr0\t10000\t4000\t10000\t6000\t6000\t60\n\
+                   r2\t10000\t5000\t9000\t+\tr0\t10000\t0\t4000\t4000\t4000\t60\n\
+                   r0\t10000\t0\t5000\t+\tr0\t10000\t0\t5000\t5000\t5000\t60\n\
+                   r1\t10000\t0\t2000\t+\tr0\t10000\t4000\t8000\t2000\t4000\t60\n\
+                   r1\t10000\t4000\t7000\t+\tr2\t10000\t4000\t7000\t3000\t3000\t60\n\
+                   r3\t10000\t0\t6000\t+\tr0\t10000\t4000\t10000\t6000\t6000\t60\n\
+                   r0\t10000\t4000\t10000\t+\tr1\t10000\t0\t6000\t6000\t6000\t60\n";
+
+        let mut selector = PairSelector::new(false);
+        parse_paf(
+            paf.as_bytes(),
+            &name_to_id,
+            OverlapFilter::default(),
+            None,
+            None,
+            &mut MalformedLines::default(),
+            &mut selector,
+        )
+        .unwrap();
+        let mut stats = OverlapStats::new(vec![10_000; 3]);
+        stats.count(selector);
+
+        assert_eq!(
+            (stats.n_records, stats.n_valid, stats.n_duplicates),
+            (7, 2, 1)
+        );
+        // Unknown read, self-overlap, span ratio, overhang, divergence, mapping
+        // quality, secondary
+        assert_eq!(stats.rejected, [1, 1, 1, 1, 0, 0, 0, 0]);
+        // Both reads of an overlap are counted, with their own spans
+        assert_eq!(stats.n_overlaps, [2, 1, 1]);
+        assert_eq!(stats.covered, [10_000, 6000, 4000]);
+    }
+}
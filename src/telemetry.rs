@@ -0,0 +1,269 @@
+use std::fs;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use serde::Serialize;
+use tracing::info;
+
+use crate::inference::Device;
+
+const MIB: u64 = 1024 * 1024;
+
+/// Inference statistics of a device, shared by its inference workers.
+#[derive(Debug)]
+pub(crate) struct DeviceStats {
+    device: Device,
+    n_workers: usize,
+    batches: AtomicU64,
+    windows: AtomicU64,
+    /// Time spent in the inference by all workers of the device
+    busy_ns: AtomicU64,
+    peak_memory: AtomicU64,
+}
+
+/// Statistics of a device in the run report
+#[derive(Debug, Serialize)]
+pub(crate) struct DeviceReport {
+    device: String,
+    batches: u64,
+    windows: u64,
+    inference_seconds: f64,
+    batches_per_second: f64,
+    windows_per_second: f64,
+    /// Fraction of the run in which the inference workers were busy
+    utilization: f64,
+    /// Peak memory used on the device, the whole GPU for CUDA devices and the
+    /// process for the CPU
+    peak_memory_mib: Option<u64>,
+}
+
+impl DeviceStats {
+    pub(crate) fn new(device: Device, n_workers: usize) -> Self {
+        Self {
+            device,
+            n_workers,
+            batches: AtomicU64::new(0),
+            windows: AtomicU64::new(0),
+            busy_ns: AtomicU64::new(0),
+            peak_memory: AtomicU64::new(0),
+        }
+    }
+
+    /// Adds the batch with `n_windows` windows inferred in `elapsed`.
+    pub(crate) fn add_batch(&self, n_windows: usize, elapsed: Duration) {
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.windows.fetch_add(n_windows as u64, Ordering::Relaxed);
+        self.busy_ns
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the used and total memory of the device in bytes and updates
+    /// the peak memory.
+    fn sample_memory(&self) -> Option<(u64, Option<u64>)> {
+        let (used, total) = device_memory(self.device)?;
+        self.peak_memory.fetch_max(used, Ordering::Relaxed);
+        Some((used, total))
+    }
+
+    /// Returns the numbers of inferred batches and windows and the busy time.
+    fn snapshot(&self) -> (u64, u64, Duration) {
+        (
+            self.batches.load(Ordering::Relaxed),
+            self.windows.load(Ordering::Relaxed),
+            Duration::from_nanos(self.busy_ns.load(Ordering::Relaxed)),
+        )
+    }
+
+    /// Summary of the device over the `elapsed` time of the run.
+    pub(crate) fn report(&self, elapsed: Duration) -> DeviceReport {
+        let (batches, windows, busy) = self.snapshot();
+        let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+        let peak_memory = self.peak_memory.load(Ordering::Relaxed);
+
+        DeviceReport {
+            device: self.device.to_string(),
+            batches,
+            windows,
+            inference_seconds: busy.as_secs_f64(),
+            batches_per_second: batches as f64 / secs,
+            windows_per_second: windows as f64 / secs,
+            utilization: busy.as_secs_f64() / (secs * self.n_workers as f64),
+            peak_memory_mib: (peak_memory > 0).then_some(peak_memory / MIB),
+        }
+    }
+}
+
+/// Logs the throughput and utilization of the devices over the `elapsed` time
+/// of the run and returns their reports.
+pub(crate) fn summarize_devices(stats: &[DeviceStats], elapsed: Duration) -> Vec<DeviceReport> {
+    stats
+        .iter()
+        .map(|device| {
+            device.sample_memory();
+            let report = device.report(elapsed);
+            info!(
+                "Device {}: inferred {} batches, {:.1} batches/s, {:.0} windows/s, {:.0}% busy",
+                report.device,
+                report.batches,
+                report.batches_per_second,
+                report.windows_per_second,
+                100. * report.utilization
+            );
+            report
+        })
+        .collect()
+}
+
+/// Logs the throughput, utilization and memory of the devices every
+/// `interval` until the channel is disconnected. Low utilization means the
+/// devices wait for features, while free memory allows a larger batch size.
+pub(crate) fn monitor_devices(stats: &[DeviceStats], interval: Duration, stop: Receiver<()>) {
+    let mut last: Vec<_> = stats.iter().map(|s| s.snapshot()).collect();
+    let mut last_time = Instant::now();
+
+    while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(interval) {
+        let secs = last_time.elapsed().as_secs_f64();
+        last_time = Instant::now();
+        for (device, last) in stats.iter().zip(last.iter_mut()) {
+            let (batches, windows, busy) = device.snapshot();
+            let utilization = (busy - last.2).as_secs_f64() / (secs * device.n_workers as f64);
+            let memory = match device.sample_memory() {
+                Some((used, Some(total))) => format!("{}/{} MiB", used / MIB, total / MIB),
+                Some((used, None)) => format!("{} MiB", used / MIB),
+                None => "unknown".to_string(),
+            };
+
+            info!(
+                "Device {}: {:.1} batches/s, {:.0} windows/s, {:.0}% busy, memory {}",
+                device.device,
+                (batches - last.0) as f64 / secs,
+                (windows - last.1) as f64 / secs,
+                100. * utilization,
+                memory
+            );
+            *last = (batches, windows, busy);
+        }
+    }
+}
+
+/// Used and total memory of the device in bytes. Memory of CUDA and HIP
+/// devices is queried with `nvidia-smi` and `rocm-smi` and includes other
+/// processes, the CPU uses the resident memory of the process.
+fn device_memory(device: Device) -> Option<(u64, Option<u64>)> {
+    match device {
+        Device::Cpu => process_memory("VmRSS").map(|rss| (rss, None)),
+        Device::Cuda(id) => {
+            let output = Command::new("nvidia-smi")
+                .args([
+                    "--query-gpu=memory.used,memory.total",
+                    "--format=csv,noheader,nounits",
+                ])
+                .arg(format!("--id={}", id))
+                .stdin(Stdio::null())
+                .stderr(Stdio::null())
+                .output()
+                .ok()
+                .filter(|o| o.status.success())?;
+            let (used, total) = parse_gpu_memory(&String::from_utf8_lossy(&output.stdout))?;
+            Some((used, Some(total)))
+        }
+        Device::Hip(id) => {
+            let output = Command::new("rocm-smi")
+                .args(["--showmeminfo", "vram", "--csv", "-d"])
+                .arg(id.to_string())
+                .stdin(Stdio::null())
+                .stderr(Stdio::null())
+                .output()
+                .ok()
+                .filter(|o| o.status.success())?;
+            let (used, total) = parse_rocm_memory(&String::from_utf8_lossy(&output.stdout))?;
+            Some((used, Some(total)))
+        }
+        Device::Mps => None,
+    }
+}
+
+/// Parses the `used, total` memory in MiB reported by `nvidia-smi`.
+fn parse_gpu_memory(output: &str) -> Option<(u64, u64)> {
+    let (used, total) = output.lines().next()?.split_once(',')?;
+    let used: u64 = used.trim().parse().ok()?;
+    let total: u64 = total.trim().parse().ok()?;
+    Some((used * MIB, total * MIB))
+}
+
+/// Parses the `device,total,used` memory in bytes from the CSV reported by
+/// `rocm-smi`, skipping its header.
+fn parse_rocm_memory(output: &str) -> Option<(u64, u64)> {
+    let line = output.lines().find(|l| l.starts_with("card"))?;
+    let mut fields = line.split(',').skip(1);
+    let total: u64 = fields.next()?.trim().parse().ok()?;
+    let used: u64 = fields.next()?.trim().parse().ok()?;
+    Some((used, total))
+}
+
+/// Memory of the process in bytes from the `key` line of `/proc/self/status`,
+/// e.g. `VmRSS` for the resident memory or `VmHWM` for its peak. Only known
+/// on Linux.
+pub(crate) fn process_memory(key: &str) -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    parse_status_memory(&status, key)
+}
+
+fn parse_status_memory(status: &str, key: &str) -> Option<u64> {
+    let line = status
+        .lines()
+        .find(|l| l.strip_prefix(key).is_some_and(|l| l.starts_with(':')))?;
+    let mut fields = line[key.len() + 1..].split_whitespace();
+    let value: u64 = fields.next()?.parse().ok()?;
+    match fields.next() {
+        Some("kB") => Some(value * 1024),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{parse_gpu_memory, parse_rocm_memory, parse_status_memory, DeviceStats, MIB};
+    use crate::inference::Device;
+
+    #[test]
+    fn process_memory() {
+        let status = "Name:\therro\nVmPeak:\t 4096 kB\nVmHWM:\t    2048 kB\nVmRSS:\t 1024 kB\n";
+        assert_eq!(parse_status_memory(status, "VmHWM"), Some(2048 * 1024));
+        assert_eq!(parse_status_memory(status, "VmRSS"), Some(1024 * 1024));
+        assert_eq!(parse_status_memory("Name:\therro\n", "VmHWM"), None);
+    }
+
+    #[test]
+    fn gpu_memory() {
+        assert_eq!(
+            parse_gpu_memory("10240, 24576\n"),
+            Some((10240 * MIB, 24576 * MIB))
+        );
+        assert_eq!(parse_gpu_memory("[N/A], 24576\n"), None);
+        assert_eq!(parse_gpu_memory(""), None);
+
+        let rocm =
+            "device,VRAM Total Memory (B),VRAM Total Used Memory (B)\ncard0,68702699520,10960896\n";
+        assert_eq!(parse_rocm_memory(rocm), Some((10960896, 68702699520)));
+        assert_eq!(parse_rocm_memory("device,VRAM Total Memory (B)\n"), None);
+    }
+
+    #[test]
+    fn device_report() {
+        let stats = DeviceStats::new(Device::Cuda(1), 2);
+        stats.add_batch(64, Duration::from_secs(3));
+        stats.add_batch(32, Duration::from_secs(1));
+
+        let report = stats.report(Duration::from_secs(4));
+        assert_eq!(report.device, "cuda:1");
+        assert_eq!((report.batches, report.windows), (2, 96));
+        assert_eq!(report.windows_per_second, 24.);
+        assert_eq!(report.utilization, 0.5);
+        assert_eq!(report.peak_memory_mib, None);
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::hash_map::RandomState;
+use std::fs::{self, DirBuilder};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::error::{HerroError, Result};
+
+/// Number of random names tried before giving up
+const MAX_ATTEMPTS: usize = 16;
+
+/// Directory with a random name, removed with its contents when dropped.
+/// The directory is created atomically and only readable by the user, so a
+/// directory created in advance by someone else under the same name is
+/// never used.
+#[derive(Debug)]
+pub(crate) struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates the directory `<prefix>-<pid>-<random>` in `parent`.
+    pub(crate) fn new_in(parent: &Path, prefix: &str) -> Result<Self> {
+        let mut builder = DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+
+        for _ in 0..MAX_ATTEMPTS {
+            let path = parent.join(format!("{}-{}-{:016x}", prefix, process::id(), random()));
+            match builder.create(&path) {
+                Ok(()) => return Ok(TempDir { path }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(HerroError::from(e).in_file(&path)),
+            }
+        }
+
+        let e = io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "cannot create a temporary directory with a unique name",
+        );
+        Err(HerroError::from(e).in_file(parent))
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Random number from the keys of the standard hasher, which are seeded by
+/// the OS for every new `RandomState`.
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::TempDir;
+
+    #[test]
+    fn unique_temp_dirs() {
+        let parent = std::env::temp_dir();
+        let first = TempDir::new_in(&parent, "herro-tempdir-test").unwrap();
+        let second = TempDir::new_in(&parent, "herro-tempdir-test").unwrap();
+        assert_ne!(first.path(), second.path());
+        assert!(first.path().is_dir() && second.path().is_dir());
+
+        fs::write(first.path().join("file"), b"data").unwrap();
+        let path = first.path().to_owned();
+        drop(first);
+        assert!(!path.exists());
+    }
+}
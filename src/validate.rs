@@ -0,0 +1,449 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::BufRead;
+use std::path::Path;
+
+use glob::glob;
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::config::{CorrectionConfig, DEFAULT_WINDOW_SIZE};
+use crate::error::{HerroError, Result};
+use crate::faidx::IndexedFile;
+use crate::haec_io::{self, open_text_file, STDIN_PATH};
+use crate::inference::{
+    feature_encoder, load_models, resolve_ensemble_window_size, resolve_feature_layout,
+    resolve_feature_set, resolve_top_k, verify_model, ModelSpec,
+};
+use crate::mm2;
+use crate::overlaps::{next_field, next_u32, OverlapsFormat};
+use crate::{AlnMode, DuplicateIds, LINE_ENDING};
+
+/// Number of reported examples of each inconsistency
+const MAX_EXAMPLES: usize = 3;
+
+/// Outcome of a single check, a summary if it passed or the description of
+/// the problem.
+type CheckResult = std::result::Result<String, String>;
+
+/// Report of the checks done by `validate_inputs`.
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    checks: Vec<(&'static str, CheckResult)>,
+}
+
+impl ValidationReport {
+    fn add(&mut self, name: &'static str, result: CheckResult) {
+        self.checks.push((name, result));
+    }
+
+    pub fn n_failed(&self) -> usize {
+        self.checks.iter().filter(|(_, r)| r.is_err()).count()
+    }
+
+    /// Error if any of the checks failed.
+    pub fn result(&self) -> Result<()> {
+        match self.n_failed() {
+            0 => Ok(()),
+            n => Err(HerroError::Validation(n)),
+        }
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, result) in self.checks.iter() {
+            match result {
+                Ok(summary) => writeln!(f, "{:<10}{:<10}{}", "[ok]", name, summary)?,
+                Err(problem) => writeln!(f, "{:<10}{:<10}{}", "[FAILED]", name, problem)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks the inputs of `error_correction` without running it: the model
+/// loads on all devices, overlaps refer to the reads with their lengths and
+/// the output can be written. All checks are run, so the report contains every
+/// problem found.
+pub fn validate_inputs<T, U, V>(
+    reads_path: T,
+    model_paths: &[&str],
+    output_path: U,
+    aln_mode: AlnMode<V>,
+    config: &CorrectionConfig,
+) -> ValidationReport
+where
+    T: AsRef<Path>,
+    U: AsRef<Path>,
+    V: AsRef<Path>,
+{
+    let mut report = ValidationReport::default();
+
+    let model = check_model(model_paths, config);
+    let window_size = match model {
+        Ok((_, window_size)) => window_size,
+        Err(_) => config.window_size.unwrap_or(DEFAULT_WINDOW_SIZE),
+    };
+    report.add("model", model.map(|(summary, _)| summary));
+
+    let reads_path = reads_path.as_ref();
+    match haec_io::read_lengths(reads_path) {
+        Ok(reads) => {
+            report.add(
+                "reads",
+                check_reads(reads_path, &reads, window_size, config),
+            );
+            match config.from_features.as_ref() {
+                Some(dir) => report.add("features", check_features(dir, &reads)),
+                None => report.add("overlaps", check_overlaps(&reads, aln_mode, config)),
+            }
+        }
+        Err(e) => report.add("reads", Err(e.to_string())),
+    }
+
+    report.add("output", check_output(output_path.as_ref(), config));
+    report
+}
+
+fn check_model(
+    model_paths: &[&str],
+    config: &CorrectionConfig,
+) -> std::result::Result<(String, u32), String> {
+    let load = || -> Result<u32> {
+        let specs = model_paths
+            .iter()
+            .map(|&path| {
+                File::open(path).map_err(|e| HerroError::from(e).in_file(path))?;
+                ModelSpec::load(path.as_ref())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let window_size = resolve_ensemble_window_size(config.window_size, &specs)?;
+        let layout = resolve_feature_layout(
+            config.qual_encoding,
+            config.homopolymer_channel,
+            config.feature_layout,
+            &specs,
+        )?;
+        let encoder = feature_encoder(resolve_feature_set(config.feature_set, &specs)?, layout);
+        let top_k = resolve_top_k(config.top_k, &specs)?;
+
+        for &device in config.devices.iter() {
+            let model = load_models(config, model_paths, device)?;
+            verify_model(model.as_ref(), (window_size, top_k), encoder.as_ref())?;
+        }
+        Ok(window_size)
+    };
+
+    let window_size = load().map_err(|e| e.to_string())?;
+    let devices: Vec<_> = config.devices.iter().map(|d| d.to_string()).collect();
+    let mut summary = format!(
+        "loaded on {}, window size {}",
+        devices.join(", "),
+        window_size
+    );
+    if model_paths.len() > 1 {
+        summary += &format!(", ensemble of {} models", model_paths.len());
+    }
+    Ok((summary, window_size))
+}
+
+fn check_reads(
+    reads_path: &Path,
+    reads: &[(Vec<u8>, u32)],
+    window_size: u32,
+    config: &CorrectionConfig,
+) -> CheckResult {
+    let mut lengths = HashMap::default();
+    let mut duplicates = Vec::new();
+    for (id, length) in reads {
+        if lengths.insert(&**id, *length).is_some() {
+            duplicates.push(String::from_utf8_lossy(id));
+        }
+    }
+    if !duplicates.is_empty() && config.duplicate_ids == DuplicateIds::Error {
+        return Err(format!(
+            "{} duplicate read ids, e.g. {}",
+            duplicates.len(),
+            examples(&duplicates)
+        ));
+    }
+
+    let n_targets = reads.iter().filter(|(_, l)| *l >= window_size).count();
+    if n_targets == 0 {
+        return Err(format!("no reads with at least {} bases", window_size));
+    }
+
+    if config.indexed_reads {
+        if reads_path == Path::new(STDIN_PATH) {
+            return Err("indexed reads cannot be read from stdin".to_string());
+        }
+        IndexedFile::open(reads_path).map_err(|e| e.to_string())?;
+    }
+
+    let mut summary = format!(
+        "{} reads, {} with at least {} bases",
+        reads.len(),
+        n_targets,
+        window_size
+    );
+    if !duplicates.is_empty() {
+        let action = match config.duplicate_ids {
+            DuplicateIds::Rename => "renamed",
+            _ => "skipped",
+        };
+        summary += &format!(", {} duplicate ids will be {}", duplicates.len(), action);
+    }
+    Ok(summary)
+}
+
+/// Counts the reads with the features of their first window in the directory.
+fn check_features(dir: &Path, reads: &[(Vec<u8>, u32)]) -> CheckResult {
+    let n_found = reads
+        .iter()
+        .filter(|(id, _)| {
+            let read_dir = dir.join(&*String::from_utf8_lossy(id));
+            read_dir.join("0.features.npy").is_file()
+        })
+        .count();
+    match n_found {
+        0 => Err(format!("no features of the reads in {}", dir.display())),
+        n => Ok(format!("features of {} of {} reads", n, reads.len())),
+    }
+}
+
+fn check_overlaps<P: AsRef<Path>>(
+    reads: &[(Vec<u8>, u32)],
+    aln_mode: AlnMode<P>,
+    config: &CorrectionConfig,
+) -> CheckResult {
+    match aln_mode {
+        AlnMode::None => mm2::minimap2_version()
+            .map(|v| format!("computed with minimap2 {}", v))
+            .map_err(|e| e.to_string()),
+        AlnMode::Internal => Ok("computed with the built-in overlapper".to_string()),
+        AlnMode::Read(dir) => {
+            let pattern = dir.as_ref().join("*.oec.zst");
+            let n_batches = glob(&pattern.to_string_lossy())
+                .map_err(|e| format!("invalid alignments directory: {}", e))?
+                .count();
+            match n_batches {
+                0 => Err(format!(
+                    "no alignment batches in {}",
+                    dir.as_ref().display()
+                )),
+                n => Ok(format!("{} alignment batches", n)),
+            }
+        }
+        AlnMode::Write(dir) => {
+            let dir = dir.as_ref();
+            fs::create_dir_all(dir).map_err(|e| HerroError::from(e).in_file(dir).to_string())?;
+            check_writable(&dir.join(".herro_validate"))?;
+            Ok(format!(
+                "computed with minimap2, written to {}",
+                dir.display()
+            ))
+        }
+        AlnMode::Overlaps(paths) => {
+            let lengths: HashMap<_, _> = reads.iter().map(|(id, l)| (&**id, *l)).collect();
+            let (summaries, problems): (Vec<_>, Vec<_>) = paths
+                .iter()
+                .map(|path| check_overlaps_file(path.as_ref(), &lengths, config))
+                .partition(|r| r.is_ok());
+            if !problems.is_empty() {
+                let problems: Vec<_> = problems.into_iter().filter_map(|r| r.err()).collect();
+                return Err(problems.join("; "));
+            }
+
+            // Files are named in the summary only if there are more of them
+            let mut summaries: Vec<_> = summaries.into_iter().filter_map(|r| r.ok()).collect();
+            match paths.len() {
+                0 => Err("no overlaps files given".to_string()),
+                1 => Ok(summaries.remove(0)),
+                _ => {
+                    let summaries: Vec<_> = paths
+                        .iter()
+                        .zip(summaries)
+                        .map(|(p, s)| format!("{}: {}", p.as_ref().display(), s))
+                        .collect();
+                    Ok(summaries.join("; "))
+                }
+            }
+        }
+    }
+}
+
+fn check_overlaps_file(
+    path: &Path,
+    lengths: &HashMap<&[u8], u32>,
+    config: &CorrectionConfig,
+) -> CheckResult {
+    if path == Path::new(STDIN_PATH) {
+        return Ok("not checked, read from stdin".to_string());
+    }
+    let format = config
+        .overlaps_format
+        .unwrap_or_else(|| OverlapsFormat::from_path(path));
+    if format != OverlapsFormat::Paf {
+        return Ok("not checked, only PAF overlaps are checked".to_string());
+    }
+
+    let reader = open_text_file(path).map_err(|e| e.to_string())?;
+    check_paf(reader, lengths).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Checks that the reads of all overlaps are present, with the same lengths.
+fn check_paf(mut reader: impl BufRead, lengths: &HashMap<&[u8], u32>) -> CheckResult {
+    let (mut unknown, mut mismatched) = (Vec::new(), Vec::new());
+    let mut n_records = 0;
+
+    let mut buffer = Vec::new();
+    for line_idx in 1.. {
+        buffer.clear();
+        match reader.read_until(LINE_ENDING, &mut buffer) {
+            Ok(0) => break,
+            Ok(_) => (),
+            Err(e) => return Err(e.to_string()),
+        }
+
+        let line = buffer.strip_suffix(&[LINE_ENDING]).unwrap_or(&buffer);
+        if line.is_empty() {
+            continue;
+        }
+        n_records += 1;
+
+        let mut data = line.split(|&c| c == b'\t');
+        let invalid_line = |message| {
+            HerroError::InvalidLine {
+                line: line_idx,
+                message,
+            }
+            .to_string()
+        };
+        let (qname, qlen) = name_and_length(&mut data, "query").map_err(invalid_line)?;
+        let (tname, tlen) = name_and_length(&mut data, "target").map_err(invalid_line)?;
+
+        for (name, length) in [(qname, qlen), (tname, tlen)] {
+            match lengths.get(name) {
+                None => unknown.push(format!(
+                    "line {}: {}",
+                    line_idx,
+                    String::from_utf8_lossy(name)
+                )),
+                Some(&l) if l != length => mismatched.push(format!(
+                    "line {}: {} has {} bases, {} in the reads",
+                    line_idx,
+                    String::from_utf8_lossy(name),
+                    length,
+                    l
+                )),
+                _ => (),
+            }
+        }
+    }
+
+    let mut problems = Vec::new();
+    if !unknown.is_empty() {
+        problems.push(format!(
+            "{} read names are not in the reads, e.g. {}",
+            unknown.len(),
+            examples(&unknown)
+        ));
+    }
+    if !mismatched.is_empty() {
+        problems.push(format!(
+            "{} read lengths differ from the reads, e.g. {}",
+            mismatched.len(),
+            examples(&mismatched)
+        ));
+    }
+
+    match problems.is_empty() {
+        true => Ok(format!("{} overlaps", n_records)),
+        false => Err(problems.join("; ")),
+    }
+}
+
+/// Returns the read name and length, skipping the start and end columns (and
+/// the strand for the query).
+fn name_and_length<'a>(
+    data: &mut impl Iterator<Item = &'a [u8]>,
+    read: &str,
+) -> std::result::Result<(&'a [u8], u32), String> {
+    let name = next_field(data, &format!("{} name", read))?;
+    let length = next_u32(data, &format!("{} length", read))?;
+    next_u32(data, &format!("{} start", read))?;
+    next_u32(data, &format!("{} end", read))?;
+    if read == "query" {
+        next_field(data, "strand")?;
+    }
+
+    Ok((name, length))
+}
+
+fn check_output(output_path: &Path, config: &CorrectionConfig) -> CheckResult {
+    let exists = output_path.exists();
+    check_writable(output_path)?;
+
+    match (exists, config.resume) {
+        (true, true) => Ok(format!("{} will be resumed", output_path.display())),
+        (true, false) => Ok(format!("{} will be overwritten", output_path.display())),
+        (false, _) => Ok(format!("{} is writable", output_path.display())),
+    }
+}
+
+/// Opens the file for writing without truncating it, the file is removed if it
+/// did not exist.
+fn check_writable(path: &Path) -> std::result::Result<(), String> {
+    let exists = path.exists();
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .map_err(|e| HerroError::from(e).in_file(path).to_string())?;
+
+    if !exists {
+        fs::remove_file(path).map_err(|e| HerroError::from(e).in_file(path).to_string())?;
+    }
+    Ok(())
+}
+
+fn examples<T: fmt::Display>(items: &[T]) -> String {
+    let examples: Vec<_> = items
+        .iter()
+        .take(MAX_EXAMPLES)
+        .map(|i| i.to_string())
+        .collect();
+    examples.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_hash::FxHashMap as HashMap;
+
+    use super::check_paf;
+
+    #[test]
+    fn paf_consistent_with_reads() {
+        let lengths: HashMap<&[u8], u32> = [(&b"r0"[..], 10_000), (&b"r1"[..], 8_000)]
+            .into_iter()
+            .collect();
+
+        let paf = "r1\t8000\t0\t6000\t+\tr0\t10000\t4000\t10000\t6000\t6000\t60\n";
+        assert_eq!(check_paf(paf.as_bytes(), &lengths).unwrap(), "1 overlaps");
+
+        let paf = "r1\t8000\t0\t6000\t+\tr0\t10000\t4000\t10000\t6000\t6000\t60\n\
+                   r2\t8000\t0\t6000\t+\tr0\t9000\t3000\t9000\t6000\t6000\t60\n";
+        let problems = check_paf(paf.as_bytes(), &lengths).unwrap_err();
+        assert_eq!(
+            problems,
+            "1 read names are not in the reads, e.g. line 2: r2; \
+             1 read lengths differ from the reads, e.g. line 2: r0 has 9000 bases, 10000 in the reads"
+        );
+
+        let paf = "r1\t8000\t0\n";
+        assert!(check_paf(paf.as_bytes(), &lengths)
+            .unwrap_err()
+            .starts_with("Invalid record at line 1"));
+    }
+}
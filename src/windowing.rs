@@ -38,14 +38,18 @@ impl<'a> OverlapWindow<'a> {
 
 type Windows<'a> = Vec<Vec<OverlapWindow<'a>>>;
 
+/// Splits the overlap into the windows of the read. Window boundaries are at
+/// the read positions p for which (p + shift) is a multiple of the window size,
+/// so window i covers [i * W - shift, (i + 1) * W - shift) of the read. Shifted
+/// boundaries are used for the overlapping windows.
 pub(crate) fn extract_windows<'a>(
     windows: &mut Windows<'a>,
     overlap: &'a Overlap,
     cigar: &[CigarOp],
-    tshift: u32,
-    qshift: u32,
+    (tshift, qshift): (u32, u32),
     is_target: bool,
     window_size: u32,
+    shift: u32,
 ) {
     if (is_target && (overlap.tend - overlap.tstart) < window_size)
         || ((overlap.qend - overlap.qstart) < window_size)
@@ -72,13 +76,13 @@ pub(crate) fn extract_windows<'a>(
         first_window = if overlap.tstart < zeroth_window_thresh {
             0
         } else {
-            (overlap.tstart + window_size - 1) / window_size
+            (overlap.tstart + shift + window_size - 1) / window_size
         };
 
         last_window = if overlap.tend > nth_window_thresh {
-            (overlap.tend - 1) / window_size + 1
+            (overlap.tend + shift - 1) / window_size + 1
         } else {
-            overlap.tend / window_size
+            (overlap.tend + shift) / window_size
         };
 
         tstart = overlap.tstart;
@@ -87,13 +91,13 @@ pub(crate) fn extract_windows<'a>(
         first_window = if overlap.qstart < zeroth_window_thresh {
             0
         } else {
-            (overlap.qstart + window_size - 1) / window_size
+            (overlap.qstart + shift + window_size - 1) / window_size
         };
 
         last_window = if overlap.qend > nth_window_thresh {
-            (overlap.qend - 1) / window_size + 1
+            (overlap.qend + shift - 1) / window_size + 1
         } else {
-            overlap.qend / window_size
+            (overlap.qend + shift) / window_size
         };
 
         tstart = overlap.qstart;
@@ -114,7 +118,7 @@ pub(crate) fn extract_windows<'a>(
     qpos += qshift;
 
     // Start of the window OR beginning of the target
-    if tpos % window_size == 0 || tstart < zeroth_window_thresh {
+    if (tpos + shift).is_multiple_of(window_size) || tstart < zeroth_window_thresh {
         t_window_start = Some(tpos);
         q_window_start = Some(qpos);
         cigar_start_idx = Some(0);
@@ -132,8 +136,8 @@ pub(crate) fn extract_windows<'a>(
             }
         };
 
-        let current_w = tpos / window_size;
-        let new_w = tnew / window_size;
+        let current_w = (tpos + shift) / window_size;
+        let new_w = (tnew + shift) / window_size;
         let diff_w = new_w - current_w; // Can span more than one window
 
         if diff_w == 0 {
@@ -145,7 +149,7 @@ pub(crate) fn extract_windows<'a>(
 
         // Handle first diff_w - 1 windows
         for i in 1..diff_w {
-            let offset = (current_w + i) * window_size - tpos;
+            let offset = (current_w + i) * window_size - shift - tpos;
 
             let q_start_new = if let CigarOp::Match(_) | CigarOp::Mismatch(_) = op {
                 qpos + offset
@@ -192,7 +196,7 @@ pub(crate) fn extract_windows<'a>(
         }
 
         // Handle the last one
-        let offset = new_w * window_size - tpos;
+        let offset = new_w * window_size - shift - tpos;
 
         let mut qend = if let CigarOp::Match(_) | CigarOp::Mismatch(_) = op {
             qpos + offset
@@ -202,7 +206,7 @@ pub(crate) fn extract_windows<'a>(
 
         let cigar_end_idx;
         let cigar_end_offset;
-        if tnew == new_w * window_size {
+        if tnew == new_w * window_size - shift {
             if let Some(CigarOp::Insertion(l)) = cigar_iter.peek().map(|(_, op)| op) {
                 qend += *l;
                 cigar_end_idx = cigar_idx + 2;
@@ -244,7 +248,7 @@ pub(crate) fn extract_windows<'a>(
     }
 
     // End of the target, emitted already for tlen % W = 0
-    if tpos > nth_window_thresh && tpos % window_size != 0 {
+    if tpos > nth_window_thresh && !(tpos + shift).is_multiple_of(window_size) {
         windows[last_window as usize - 1].push(OverlapWindow::new(
             overlap,
             t_window_start.unwrap(),